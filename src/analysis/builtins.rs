@@ -0,0 +1,71 @@
+// Builtin functions are called like any other function, but have no Nom definition.
+// Instead, the code generator emits their instructions inline.
+
+use super::types::{Type, BuiltIn};
+
+
+#[derive(Clone, Debug)]
+pub enum BuiltinKind {
+    Alloc,  // alloc(size: u64, align: u64) -> ptr
+    Free,  // free(p: ptr) -> unit, which also takes function values
+    Null,  // null() -> ptr
+    Load (BuiltIn),  // load_<type>(p: ptr, offset: u64) -> <type>
+    Store (BuiltIn),  // store_<type>(p: ptr, offset: u64, value: <type>) -> unit
+    Assert,  // assert(condition: bool) -> unit
+    AssertEq,  // assert_eq(a: T, b: T) -> unit, for any T that can be compared with ==
+    Unreachable,  // unreachable() -> never returns
+}
+
+#[derive(Clone, Debug)]
+pub struct BuiltinFunction {
+    pub kind: BuiltinKind,
+    pub parameter_types: Vec<Type>,
+    pub return_type: Type,
+}
+
+pub fn lookup_builtin(name: &str) -> Option<BuiltinFunction> {
+    let pointer = Type::BuiltIn(BuiltIn::Pointer);
+    let size = Type::BuiltIn(BuiltIn::U64);
+    let unit = Type::BuiltIn(BuiltIn::Unit);
+    let boolean = Type::BuiltIn(BuiltIn::Boolean);
+
+    let (kind, parameter_types, return_type) = match name {
+        "alloc" => (BuiltinKind::Alloc, vec![size.clone(), size], pointer),
+        "free" => (BuiltinKind::Free, vec![pointer], unit),
+        "null" => (BuiltinKind::Null, vec![], pointer),
+        "assert" => (BuiltinKind::Assert, vec![boolean], unit),
+        // The two arguments are checked together, like the sides of ==, so they are not listed.
+        "assert_eq" => (BuiltinKind::AssertEq, vec![], unit),
+        "unreachable" => (BuiltinKind::Unreachable, vec![], Type::BuiltIn(BuiltIn::Bottom)),
+        _ => {
+            if let Some(loaded) = name.strip_prefix("load_").and_then(memory_type) {
+                (BuiltinKind::Load(loaded.clone()), vec![pointer, size], Type::BuiltIn(loaded))
+            }
+            else if let Some(stored) = name.strip_prefix("store_").and_then(memory_type) {
+                (BuiltinKind::Store(stored.clone()), vec![pointer, size, Type::BuiltIn(stored)], unit)
+            }
+            else {
+                return None;
+            }
+        }
+    };
+
+    Some(BuiltinFunction { kind, parameter_types, return_type })
+}
+
+// Types that can be moved to and from the heap.
+fn memory_type(name: &str) -> Option<BuiltIn> {
+    match name {
+        "u8" => Some(BuiltIn::U8),
+        "u16" => Some(BuiltIn::U16),
+        "u32" => Some(BuiltIn::U32),
+        "u64" => Some(BuiltIn::U64),
+        "i8" => Some(BuiltIn::I8),
+        "i16" => Some(BuiltIn::I16),
+        "i32" => Some(BuiltIn::I32),
+        "i64" => Some(BuiltIn::I64),
+        "bool" => Some(BuiltIn::Boolean),
+        "ptr" => Some(BuiltIn::Pointer),
+        _ => None,
+    }
+}
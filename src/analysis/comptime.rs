@@ -0,0 +1,224 @@
+// Code that runs during compilation: the values of constants, and `comptime` blocks. Each
+// becomes a function, which is checked like any other. Once every goal is complete, each is
+// compiled into a tiny program of its own and run on the VM, and the value it leaves on
+// the stack replaces the constant or block wherever it is used.
+//
+// Comptime code may only use constants and functions, so its value never depends on the
+// state of the running program. Anything that would be a runtime error (overflow included)
+// is a compile error instead, and a limited amount of fuel stops code that never finishes.
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use crate::CompilationEnvironment;
+use crate::ast::{AnyAST, ExprAST};
+use crate::error::{AnalysisError, GenerateError};
+use crate::generate::CodeGenerator;
+use crate::instructions::IntSize;
+use crate::runtime::Runtime;
+use crate::token::Span;
+
+use super::globals::{Global, constant_name};
+use super::scope_check::closure_name;
+use super::types::{Type, BuiltIn, representation};
+
+
+const COMPTIME_FUEL: u64 = 10_000_000;  // Instructions a single evaluation may run.
+
+pub struct Comptime {
+    pub value_type: Type,
+    pub span: Span,  // Where errors are reported.
+    pub value: Option<ConstantValue>,  // Decided once evaluated.
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConstantValue {
+    Integer (i128),
+    Boolean (bool),
+}
+
+impl Comptime {
+    pub fn new(value_type: Type, span: Span) -> Comptime {
+        Comptime { value_type, span, value: None }
+    }
+}
+
+// The name of the function made from a comptime block.
+pub(crate) fn comptime_block_name(id: u32) -> String {
+    format!("<comptime {id}>")
+}
+
+// Evaluates everything in env.comptime. Requires every goal to be complete, so that all
+// the functions comptime code calls are ready to be generated.
+pub(crate) fn evaluate_comptime(env: &mut CompilationEnvironment) -> Result<(), AnalysisError> {
+    let mut names = env.comptime.keys().cloned().collect::<Vec<_>>();
+    names.sort();  // Reports errors in a consistent order.
+
+    for name in names {
+        evaluate(env, &name, &mut vec![])?;
+    }
+
+    Ok(())
+}
+
+// Anything this evaluation uses is evaluated first. `waiting` holds the evaluations
+// waiting on this one, so cycles can be found.
+fn evaluate(env: &mut CompilationEnvironment, name: &str, waiting: &mut Vec<String>) -> Result<(), AnalysisError> {
+    if env.comptime[name].value.is_some() {
+        return Ok(());
+    }
+
+    if waiting.iter().any(|waiting_name| waiting_name == name) {
+        return Err(format!("{}: The value of {name} depends on itself", env.comptime[name].span).into());
+    }
+
+    let (functions, dependencies) = find_dependencies(env, name)?;
+
+    waiting.push(name.to_string());
+    for dependency in dependencies {
+        evaluate(env, &dependency, waiting)?;
+    }
+    waiting.pop();
+
+    let value = run(env, name, &functions)?;
+    env.comptime.get_mut(name).expect("known exists").value = Some(value);
+
+    Ok(())
+}
+
+// Finds every function the evaluation could call, and the other evaluations those need.
+fn find_dependencies(env: &mut CompilationEnvironment, name: &str) -> Result<(Vec<String>, Vec<String>), AnalysisError> {
+    let mut functions = vec![name.to_string()];
+    let mut dependencies = vec![];
+    let mut next = 0;
+
+    while next < functions.len() {
+        let function_name = functions[next].clone();
+        next += 1;
+
+        let function = env.functions.get_mut(&function_name).expect("Functions are checked before evaluation");
+        let mut ast = std::mem::take(&mut function.ast);
+        let mut uses = vec![];
+        find_uses(&mut AnyAST::Expression(&mut ast), &mut uses);
+        let function = env.functions.get_mut(&function_name).expect("known exists");
+        function.ast = ast;
+
+        for found in uses {
+            match found {
+                Use::Call(called) => {
+                    if env.functions.contains_key(&called) && !functions.contains(&called) {
+                        functions.push(called);
+                    }
+                },
+                Use::Variable(variable) if !env.functions[&function_name].local_types.contains_key(&variable) => {
+                    match env.globals.get(&variable) {
+                        Some(Global::Constant { .. }) => dependencies.push(constant_name(&variable)),
+                        Some(Global::Variable { .. }) => return Err(format!("{}: Compile time code cannot use the global variable {variable}",
+                            env.comptime[name].span).into()),
+                        None => (),
+                    }
+                },
+                Use::Variable(_) => (),
+                Use::Comptime(id) => dependencies.push(comptime_block_name(id)),
+            }
+        }
+    }
+
+    Ok((functions, dependencies))
+}
+
+enum Use {
+    Call (String),
+    Variable (String),
+    Comptime (u32),
+}
+
+fn find_uses<'a>(ast: &'a mut AnyAST<'a>, uses: &mut Vec<Use>) {
+    match ast {
+        AnyAST::Expression(ExprAST::FunctionCall(name, ..) | ExprAST::FunctionValue(name, _)) => uses.push(Use::Call(name.clone())),
+        AnyAST::Expression(ExprAST::Closure { data, .. }) => uses.push(Use::Call(closure_name(data.id))),
+        AnyAST::Expression(ExprAST::Variable(name, ..)) => uses.push(Use::Variable(name.clone())),
+        AnyAST::Expression(ExprAST::Comptime(_, data)) => uses.push(Use::Comptime(data.id)),
+        _ => (),
+    }
+
+    for mut child in ast.children() {
+        find_uses(&mut child, uses);
+    }
+}
+
+fn run(env: &CompilationEnvironment, name: &str, functions: &[String]) -> Result<ConstantValue, AnalysisError> {
+    let comptime = &env.comptime[name];
+
+    let program = CodeGenerator::new().generate_evaluation(env, name, functions)
+        .map_err(|GenerateError(message)| AnalysisError(message))?;
+
+    let mut runtime = Runtime::new(program);
+    runtime.set_fuel(Some(COMPTIME_FUEL));
+    runtime.set_overflow_checks(true);
+
+    // Running out of fuel stops the runtime, but other runtime errors are panics. Those are
+    // caught here, so they can be reported where the code is, rather than where it was run.
+    // Until then, the panic hook is kept from printing them as if the compiler crashed.
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(quiet_runtime_errors));
+
+    let result = catch_unwind(AssertUnwindSafe(|| runtime.try_run()));
+
+    std::panic::set_hook(default_hook);
+
+    let message = match result {
+        Ok(Ok(())) => None,
+        Ok(Err(error)) => Some(format!("Critical Runtime Error: {error}")),
+        Err(payload) => Some(payload.downcast_ref::<&str>().map(ToString::to_string)
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or("Unknown error".to_string())),
+    };
+
+    if let Some(message) = message {
+        return Err(format!("{}: Error in compile time code. {message}", comptime.span).into());
+    }
+
+    decode(runtime.stack_contents(), representation(&env.distinct_types, &comptime.value_type))
+        .map_err(|AnalysisError(message)| AnalysisError(format!("{}: {message}", comptime.span)))
+}
+
+// A panic hook for while compile time code runs. Runtime errors become compile errors, so
+// only other panics, which are bugs in the compiler, are printed.
+fn quiet_runtime_errors(info: &std::panic::PanicHookInfo) {
+    let message = info.payload().downcast_ref::<String>().map(String::as_str)
+        .or_else(|| info.payload().downcast_ref::<&str>().copied());
+
+    if !message.is_some_and(|message| message.starts_with("Critical Runtime Error")) {
+        eprintln!("{info}");
+    }
+}
+
+// Reads a value of the given type from the start of the bytes.
+fn decode(bytes: &[u8], value_type: &Type) -> Result<ConstantValue, AnalysisError> {
+    let Type::BuiltIn(builtin) = value_type
+        else { return Err(format!("Compile time values cannot have type {value_type}").into()) };
+
+    if *builtin == BuiltIn::Boolean {
+        return Ok(ConstantValue::Boolean(bytes[0] != 0));
+    }
+
+    let Some(int_size) = builtin.get_int_size()
+        else { return Err(format!("Compile time values cannot have type {value_type}").into()) };
+
+    let unsigned = match int_size {
+        IntSize::OneByte => u64::from(bytes[0]),
+        IntSize::TwoByte => u64::from(u16::from_ne_bytes(bytes[..2].try_into().expect("sized"))),
+        IntSize::FourByte => u64::from(u32::from_ne_bytes(bytes[..4].try_into().expect("sized"))),
+        IntSize::EightByte => u64::from_ne_bytes(bytes[..8].try_into().expect("sized")),
+    };
+
+    // Sign extends, by moving the sign bit to the top and shifting back.
+    let unused_bits = 64 - 8 * int_size.to_usize() as u32;
+
+    if builtin.is_signed() {
+        Ok(ConstantValue::Integer(i128::from(((unsigned << unused_bits) as i64) >> unused_bits)))
+    }
+    else {
+        Ok(ConstantValue::Integer(i128::from(unsigned)))
+    }
+}
@@ -2,14 +2,17 @@
 // early so that later analysis steps can take place with a somewhat normalized
 // format.
 
-use crate::{ast::{AST, ExprAST, StatementAST, ASTNodeData, AnyAST}, token::Span};
+use crate::{ast::{AST, ExprAST, StatementAST, DeclarationAST, ASTNodeData, AnyAST, Mutability}, token::Span};
+use crate::instructions::Comparison;
+use crate::error::AnalysisError;
+use crate::util;
 
 
-pub(crate) fn desugar(ast: &mut AST)  {
+pub(crate) fn desugar(ast: &mut AST) -> Result<(), AnalysisError> {
     desugar_ast(&mut AnyAST::File(ast))
 }
 
-fn desugar_ast<'a>(ast: &'a mut AnyAST<'a>) {
+fn desugar_ast<'a>(ast: &'a mut AnyAST<'a>) -> Result<(), AnalysisError> {
     match ast {
         /* Compound assignment simply becomes normal assignment after performing the
          * operation. */
@@ -35,14 +38,139 @@ fn desugar_ast<'a>(ast: &'a mut AnyAST<'a>) {
 
             desugar_ast(&mut AnyAST::Statement(statement))
         }
+        /* A for loop becomes a while loop over a hidden counter. */
+        AnyAST::Expression(expr @ ExprAST::For { .. }) => {
+            let ExprAST::For { label, variable, mut start, mut end, inclusive, mut block, data } = std::mem::take(*expr)
+                else { panic!("Known to be variant") };
+
+            // Inner loops go first, so renaming this loop's variable leaves theirs alone.
+            desugar_ast(&mut AnyAST::Expression(start.as_mut()))?;
+            desugar_ast(&mut AnyAST::Expression(end.as_mut()))?;
+            desugar_ast(&mut AnyAST::Expression(block.as_mut()))?;
+
+            **expr = desugar_for(label, &variable, *start, *end, inclusive, *block, &data.span)?;
+
+            Ok(())
+        }
         _ => {
             for mut child in ast.children() {
-                desugar_ast(&mut child)
+                desugar_ast(&mut child)?;
             }
+
+            Ok(())
         }
     }
 }
 
+// Builds the while loop for `for variable in start..end { block }`, which looks like:
+//
+//     {
+//         var <counter> = start;
+//         val <end> = end;
+//         while <counter> < <end> {
+//             val <variable> = <counter>;
+//             <counter> = <counter> + 1;
+//             block
+//         };
+//     }
+//
+// The loop variable is renamed, so it cannot be seen outside the body, and is a `val`, so
+// assigning to it is an error. An inclusive range instead keeps a flag, `<more>`, since counting
+// past the end could overflow when the end is the largest value of its type. The counter
+// moves on before the body runs, so `continue` needs nothing special, and the while loop
+// takes the label of the for loop.
+fn desugar_for(label: Option<String>, variable: &str, start: ExprAST, end: ExprAST, inclusive: bool, mut block: ExprAST, span: &Span) -> Result<ExprAST, AnalysisError> {
+
+    let id = util::next_id();
+    let (counter, end_name, more, loop_variable) = 
+        (format!("<counter {id}>"), format!("<end {id}>"), format!("<more {id}>"), format!("<{variable} {id}>"));
+
+    rename_loop_variable(&mut AnyAST::Expression(&mut block), variable, &loop_variable)?;
+
+    let node = || ASTNodeData::new(span.clone());
+    let read = |name: &str| Box::new(ExprAST::Variable(name.to_string(), node()));
+    let declare = |mutability: Mutability, name: &str, expr: ExprAST| StatementAST::Declaration(
+        DeclarationAST::Variable { public: false, mutability, name: name.to_string(), expr, type_ascription: None, inferred: true, node_data: node() }, node());
+    let assign = |name: &str, expr: ExprAST| StatementAST::Assignment(*read(name), expr, node());
+
+    // The hidden variables are inferred, so they take the type of the range. An integer
+    // literal at one end takes the type of the other end.
+    let mut statements = if matches!(start, ExprAST::IntegerLiteral(..)) {
+        vec![
+            declare(Mutability::Val, &end_name, end),
+            declare(Mutability::Var, &counter, *read(&end_name)),
+            assign(&counter, start),
+        ]
+    }
+    else if matches!(end, ExprAST::IntegerLiteral(..)) {
+        vec![
+            declare(Mutability::Var, &counter, start),
+            declare(Mutability::Var, &end_name, *read(&counter)),
+            assign(&end_name, end),
+        ]
+    }
+    else {
+        vec![
+            declare(Mutability::Var, &counter, start),
+            declare(Mutability::Val, &end_name, end),
+        ]
+    };
+
+    let increment = assign(&counter, ExprAST::Add(read(&counter), Box::new(ExprAST::IntegerLiteral(1, node())), node()));
+
+    let (condition, body) = if inclusive {
+        statements.push(declare(Mutability::Var, &more, ExprAST::Comparison(read(&counter), read(&end_name), Comparison::LessEquals, node())));
+
+        let body = vec![
+            declare(Mutability::Val, &loop_variable, *read(&counter)),
+            assign(&more, ExprAST::Comparison(read(&counter), read(&end_name), Comparison::Less, node())),
+            StatementAST::ExpressionStatement(ExprAST::If {
+                condition: read(&more),
+                block: Box::new(ExprAST::Block(vec![increment], None, node())),
+                else_branch: None,
+                data: node(),
+            }, node()),
+        ];
+
+        (*read(&more), body)
+    }
+    else {
+        let body = vec![
+            declare(Mutability::Val, &loop_variable, *read(&counter)),
+            increment,
+        ];
+
+        (ExprAST::Comparison(read(&counter), read(&end_name), Comparison::Less, node()), body)
+    };
+
+    let while_loop = ExprAST::While {
+        label,
+        condition: Box::new(condition),
+        block: Box::new(ExprAST::Block(body, Some(Box::new(block)), node())),
+        data: node(),
+    };
+
+    statements.push(StatementAST::ExpressionStatement(while_loop, node()));
+
+    Ok(ExprAST::Block(statements, None, node()))
+}
+
+fn rename_loop_variable<'a>(ast: &'a mut AnyAST<'a>, from: &str, to: &str) -> Result<(), AnalysisError> {
+    match ast {
+        AnyAST::Statement(StatementAST::Assignment(ExprAST::Variable(name, _), ..)) if name == from =>
+            return Err(format!("Cannot assign to the loop variable {from}").into()),
+        AnyAST::Expression(ExprAST::Variable(name, _))
+        | AnyAST::Declaration(DeclarationAST::Variable { name, .. }) if name == from => *name = to.to_string(),
+        _ => (),
+    }
+
+    for mut child in ast.children() {
+        rename_loop_variable(&mut child, from, to)?;
+    }
+
+    Ok(())
+}
+
 // TODO: Desugar final while
 // TODO: Figure out what I meant by this ^. Did I possibly mean desugar final return
 // into normal final expression?
@@ -0,0 +1,130 @@
+// Generic functions are monomorphized. Type checking infers the type arguments at each
+// call to a generic function, and requests an instance for them. An instance is an
+// ordinary function with a mangled name, made by copying the generic function and
+// substituting the type arguments for its type parameters.
+
+use std::collections::HashMap;
+
+use crate::CompilationEnvironment;
+use crate::ast::{AnyAST, DeclarationAST, ExprAST};
+use crate::error::AnalysisError;
+
+use super::{Function, types::Type};
+
+
+// The name of an instance, e.g. `max<i32>`. Identifiers cannot contain angle brackets,
+// so this can never collide with a function the user wrote.
+pub(crate) fn mangle(name: &str, type_args: &[Type]) -> String {
+    let type_args = type_args.iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    format!("{name}<{type_args}>")
+}
+
+// Replaces the type parameters named in a type ascription with the matching type arguments.
+// Function types name several types, e.g. `fn(T) -> T`, so each name is replaced.
+pub(crate) fn substitute(type_name: &str, bindings: &HashMap<String, Type>) -> String {
+    let mut result = String::new();
+    let mut name = String::new();
+
+    for ch in type_name.chars().chain(std::iter::once(' ')) {
+        if ch.is_alphanumeric() || ch == '_' {
+            name.push(ch);
+            continue;
+        }
+
+        match bindings.get(&name) {
+            Some(bound) => result.push_str(&bound.to_string()),
+            None => result.push_str(&name),
+        }
+
+        name.clear();
+        result.push(ch);
+    }
+
+    result.pop();  // The space added at the end.
+    result
+}
+
+// Adds the instance of a generic function to env.functions, unless it already exists.
+// Returns the name of the instance.
+pub(crate) fn instantiate(env: &mut CompilationEnvironment, name: &str, type_args: &[Type]) -> Result<String, AnalysisError> {
+    let instance_name = mangle(name, type_args);
+
+    if env.functions.contains_key(&instance_name) {
+        return Ok(instance_name);
+    }
+
+    let generic = env.generic_functions.get(name)
+        .ok_or(AnalysisError::from(format!("Could not find generic function {name}")))?;
+
+    if generic.type_parameters.len() != type_args.len() {
+        return Err(format!("{name} takes {} type arguments, but {} were given", generic.type_parameters.len(), type_args.len()).into());
+    }
+
+    let bindings = generic.type_parameters.iter()
+        .map(|(param, _)| param.clone())
+        .zip(type_args.iter().cloned())
+        .collect::<HashMap<_, _>>();
+
+    let mut block = generic.ast.duplicate();
+    substitute_ascriptions(&mut AnyAST::Expression(&mut block), &bindings);
+
+    let params = generic.parameters.iter()
+        .map(|(param_name, type_name)| (param_name.clone(), substitute(type_name, &bindings)))
+        .collect();
+    let return_type = substitute(&generic.return_type, &bindings);
+
+    let function = Function::new(env, block, params, return_type, &generic.module, generic.public);
+    env.functions.insert(instance_name.clone(), function);
+
+    Ok(instance_name)
+}
+
+// Substitutes every type ascription in the tree. This also replaces the type names declared
+// in a file, once it is imported.
+pub(crate) fn substitute_ascriptions<'a>(ast: &'a mut AnyAST<'a>, bindings: &HashMap<String, Type>) {
+    match ast {
+        AnyAST::Declaration(DeclarationAST::Variable { type_ascription: Some(type_name), .. }) => {
+            *type_name = substitute(type_name, bindings);
+        },
+        AnyAST::Declaration(DeclarationAST::Function { params, return_type, .. }) => {
+            for (_, type_name) in params {
+                *type_name = substitute(type_name, bindings);
+            }
+
+            *return_type = substitute(return_type, bindings);
+        },
+        AnyAST::Declaration(DeclarationAST::Trait { methods, .. }) => {
+            for method in methods {
+                for (_, type_name) in &mut method.params {
+                    *type_name = substitute(type_name, bindings);
+                }
+
+                method.return_type = substitute(&method.return_type, bindings);
+            }
+        },
+        AnyAST::Declaration(DeclarationAST::Impl { target_type, .. }) => {
+            *target_type = substitute(target_type, bindings);
+        },
+        AnyAST::Expression(ExprAST::Cast(_, type_name, _)) => {
+            *type_name = substitute(type_name, bindings);
+        },
+        AnyAST::Expression(ExprAST::Closure { params, return_type, .. }) => {
+            for (_, type_name) in params {
+                *type_name = substitute(type_name, bindings);
+            }
+
+            if let Some(return_type) = return_type {
+                *return_type = substitute(return_type, bindings);
+            }
+        },
+        _ => (),
+    }
+
+    for mut child in ast.children() {
+        substitute_ascriptions(&mut child, bindings);
+    }
+}
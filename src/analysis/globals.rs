@@ -0,0 +1,39 @@
+// Top level variables. A `val` is a constant, which is evaluated during compilation (see
+// comptime), and every use is replaced with its value. A `var` is stored in the data
+// segment, and is initialized before main runs, by a function made from its initializer.
+//
+// Like functions, globals are stored under their qualified names, and are private to their
+// module unless marked `pub`.
+
+use super::types::Type;
+
+
+pub enum Global {
+    Constant { global_type: Type, module: String, public: bool },  // The value is computed by a function, see constant_name.
+    Variable { offset: usize, global_type: Type, module: String, public: bool },  // Offset in bytes into the data segment.
+}
+
+impl Global {
+    pub fn global_type(&self) -> &Type {
+        match self {
+            Global::Constant { global_type, .. } | Global::Variable { global_type, .. } => global_type,
+        }
+    }
+
+    // The module the global is declared in, and whether other modules may use it.
+    pub fn visibility(&self) -> (&str, bool) {
+        match self {
+            Global::Constant { module, public, .. } | Global::Variable { module, public, .. } => (module, *public),
+        }
+    }
+}
+
+// The name of the function that computes the initial value of a global variable.
+pub(crate) fn initializer_name(global_name: &str) -> String {
+    format!("<initialize {global_name}>")
+}
+
+// The name of the function that computes the value of a constant.
+pub(crate) fn constant_name(global_name: &str) -> String {
+    format!("<constant {global_name}>")
+}
@@ -0,0 +1,100 @@
+// Matches each break and continue with the loop it jumps out of, or back to the start of.
+// Without a label, that is the innermost loop around it. The matches are recorded in
+// env.loop_targets, for type checking and code generation. Deferred expressions are also
+// checked here, since they may not jump out of the block they run in.
+
+use crate::CompilationEnvironment;
+use crate::ast::{AnyAST, ExprAST, DeclarationAST, StatementAST};
+use crate::error::AnalysisError;
+
+
+struct EnclosingLoop {
+    label: Option<String>,
+    id: u32,
+    has_value: bool,  // Only `loop` can break with a value. A while loop is always unit.
+}
+
+pub(super) fn resolve_loops(env: &mut CompilationEnvironment, block: &mut ExprAST) -> Result<(), AnalysisError> {
+    resolve(env, &mut AnyAST::Expression(block), &mut vec![], None)
+}
+
+// Inside a defer, defer_start is the number of loops around the defer itself.
+fn resolve<'a>(env: &mut CompilationEnvironment, ast: &'a mut AnyAST<'a>, loops: &mut Vec<EnclosingLoop>, 
+    defer_start: Option<usize>) -> Result<(), AnalysisError> {
+
+    match ast {
+        AnyAST::Expression(ExprAST::While { label, data, .. }) =>
+            loops.push(EnclosingLoop { label: label.clone(), id: data.id, has_value: false }),
+        AnyAST::Expression(ExprAST::Loop { label, data, .. }) =>
+            loops.push(EnclosingLoop { label: label.clone(), id: data.id, has_value: true }),
+        AnyAST::Expression(ExprAST::Break { label, value, data }) => {
+            let target = find_target(loops, label.as_ref(), "break", defer_start)?;
+
+            if value.is_some() && !target.has_value {
+                return Err("Only `loop` can break with a value. While and for loops are always unit".into());
+            }
+
+            env.loop_targets.insert(data.id, target.id);
+        },
+        AnyAST::Expression(ExprAST::Continue { label, data }) => {
+            let target = find_target(loops, label.as_ref(), "continue", defer_start)?;
+
+            env.loop_targets.insert(data.id, target.id);
+        },
+        // Comptime blocks, closures, and nested functions become functions of their own, so they
+        // cannot jump out to the loops around them.
+        AnyAST::Expression(ExprAST::Comptime(..) | ExprAST::Closure { .. }) | AnyAST::Declaration(DeclarationAST::Function { .. }) => {
+            for mut child in ast.children() {
+                resolve(env, &mut child, &mut vec![], None)?;
+            }
+
+            return Ok(());
+        },
+        AnyAST::Statement(StatementAST::Defer(..)) => {
+            let defer_start = Some(loops.len());
+
+            for mut child in ast.children() {
+                resolve(env, &mut child, loops, defer_start)?;
+            }
+
+            return Ok(());
+        },
+        AnyAST::Expression(ExprAST::Return(..)) if defer_start.is_some() =>
+            return Err("Cannot return from inside a defer".into()),
+        AnyAST::Expression(ExprAST::Try(..)) if defer_start.is_some() =>
+            return Err("Cannot use `try` inside a defer, since it may return".into()),
+        _ => (),
+    }
+
+    let is_loop = matches!(ast, AnyAST::Expression(ExprAST::While { .. } | ExprAST::Loop { .. }));
+
+    for mut child in ast.children() {
+        resolve(env, &mut child, loops, defer_start)?;
+    }
+
+    if is_loop {
+        loops.pop();
+    }
+
+    Ok(())
+}
+
+fn find_target<'a>(loops: &'a [EnclosingLoop], label: Option<&String>, keyword: &str, 
+    defer_start: Option<usize>) -> Result<&'a EnclosingLoop, AnalysisError> {
+
+    let target = match label {
+        Some(label) => loops.iter().rev()
+            .find(|enclosing| enclosing.label.as_ref() == Some(label))
+            .ok_or(AnalysisError::from(format!("Could not find a loop labeled '{label} around this `{keyword}`"))),
+        None => loops.last()
+            .ok_or(AnalysisError::from(format!("`{keyword}` can only be used inside a loop"))),
+    }?;
+
+    let position = loops.iter().position(|enclosing| enclosing.id == target.id).expect("known exists");
+
+    if defer_start.is_some_and(|start| position < start) {
+        return Err(format!("Cannot `{keyword}` out of a defer").into());
+    }
+
+    Ok(target)
+}
@@ -1,15 +1,36 @@
+#[cfg(test)]
+mod tests;
+
 
 pub mod types;
+pub mod builtins;
 
 mod desugar;
 pub(crate) use desugar::desugar;  // Desugaring should happen right after the AST is created.
 
 mod scope_check;
-pub(crate) use scope_check::scope_check;  // Scope check happens next. This task enters the compilation queue.
+pub(crate) use scope_check::{scope_check, closure_name};  // Scope check happens next. This task enters the compilation queue.
 
 mod type_check;
 pub(crate) use type_check::type_check;  // Finally, types are analyzed and decided. This also enters the compilation queue.
 
+mod generics;
+pub(crate) use generics::{instantiate, substitute, substitute_ascriptions};  // Type checking requests copies of generic functions, which are made here.
+
+mod modules;
+pub(crate) use modules::{qualify, qualify_names};  // Names are qualified as soon as a file is parsed.
+
+mod globals;
+pub(crate) use globals::{Global, constant_name, initializer_name};  // Top level variables and constants.
+
+mod comptime;
+pub(crate) use comptime::{Comptime, ConstantValue, comptime_block_name, evaluate_comptime};  // Runs code during compilation, once every goal is complete.
+
+mod loops;  // Break and continue are matched with their loops during scope checking.
+
+mod traits;
+pub(crate) use traits::{Trait, check_impl, method_function_name};  // Method calls are resolved to functions during type checking.
+
 
 use std::collections::HashMap;
 
@@ -26,12 +47,36 @@ pub struct Function {
     // Local order *kinda* doesn't matter, so we have a hash map
     // None means the type has not yet been decided.
     pub local_types: HashMap<String, Option<Type>>,  
-    pub scope: HashMap<String, bool>,  // Temporary - the bool being true means mutable (aka `var`).
+    pub scope: LocalScope,  // The blocks of the function, and the locals declared in each.
+    // Only closures capture. Each pair names a local of the function around the closure, and the
+    // local of the closure it is copied into. Closures that capture are passed a hidden parameter.
+    pub captures: Vec<(String, String)>,
+    pub module: String,  // The module the function was declared in. Empty for the first file compiled.
+    pub public: bool,  // Private functions can only be called from their own module.
+}
+
+// The locals declared directly in a block (by their unique names), and the blocks inside it.
+// Locals of sibling blocks are never alive at the same time, so they may share stack space.
+#[derive(Default)]
+pub struct LocalScope {
+    pub locals: Vec<String>,
+    pub blocks: Vec<LocalScope>,
+}
+
+// A generic function is only a template. It is never checked or generated itself, but
+// each distinct list of type arguments it is called with gets its own Function.
+pub struct GenericFunction {
+    pub ast: ExprAST,
+    pub module: String,
+    pub public: bool,
+    pub type_parameters: Vec<(String, Vec<String>)>,  // Names, and the traits each must implement.
+    pub parameters: Vec<(String, String)>,  // Names and type ascriptions, which may name type parameters.
+    pub return_type: String,
 }
 
 impl Function {
     pub(super) fn new(_env: &CompilationEnvironment, ast: ExprAST, 
-        params: Vec<(String, String)>, return_type: String) -> Function { // Could become Result
+        params: Vec<(String, String)>, return_type: String, module: &str, public: bool) -> Function { // Could become Result
 
         // TODO: Someday we might want this to add type generation requests to _env
         
@@ -43,7 +88,17 @@ impl Function {
             return_type: return_type.into(), 
             parameter_types, 
             local_types: HashMap::new(), 
-            scope: HashMap::new(), 
+            scope: LocalScope::default(), 
+            captures: vec![],
+            module: module.to_string(),
+            public,
         }
     }
+
+    // The type of the function as a value.
+    pub fn function_type(&self) -> Type {
+        let parameter_types = self.parameter_types.iter().map(|(_, param_type)| param_type.clone()).collect();
+
+        Type::Function(parameter_types, Box::new(self.return_type.clone()))
+    }
 }
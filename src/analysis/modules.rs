@@ -0,0 +1,83 @@
+// Each file is a module. Functions, globals, and traits are stored under their qualified
+// name, which is the module's name, then `::`, then the name, e.g. `math::gcd`. Names in the
+// file compilation started from are not qualified, so `main` is always just `main`.
+//
+// Names written with a module, and trait names, are rewritten to be qualified as soon as a
+// file is parsed, since only then is it known which names are declared in the file and which
+// modules it imports. Other names may be locals, so scope checking qualifies them.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::ast::{AnyAST, ExprAST, DeclarationAST};
+use crate::error::AnalysisError;
+
+
+// The qualified name of something declared in a module. A name that is already qualified,
+// e.g. `math::LIMIT` used from another module, is left as it is.
+pub(crate) fn qualify(module: &str, name: &str) -> String {
+    if module.is_empty() || name.contains("::") {
+        name.to_string()
+    }
+    else {
+        format!("{module}::{name}")
+    }
+}
+
+// Rewrites every call to a module's function, every use of another module's function or
+// global, and every trait name to be qualified. `local_names` are the functions declared in
+// the module, and `imports` maps the names a module is imported under to the name of the
+// module.
+pub(crate) fn qualify_names<'a>(ast: &'a mut AnyAST<'a>, module: &str, local_names: &HashSet<String>,
+    imports: &HashMap<String, String>) -> Result<(), AnalysisError> {
+
+    match ast {
+        AnyAST::Expression(ExprAST::FunctionCall(name, ..)) => {
+            *name = if name.contains("::") {
+                qualify_imported(name, imports)?
+            }
+            else if local_names.contains(name) {
+                qualify(module, name)
+            }
+            else {
+                // A builtin, a nested function, or a variable holding a function. Scope checking decides.
+                name.clone()
+            };
+        },
+        AnyAST::Expression(ExprAST::Variable(name, _)) if name.contains("::") =>
+            *name = qualify_imported(name, imports)?,
+        // There are no builtin traits, so an unqualified trait is one declared in the module.
+        AnyAST::Declaration(DeclarationAST::Impl { trait_name, .. }) =>
+            *trait_name = qualify_trait(module, trait_name, imports)?,
+        AnyAST::Declaration(DeclarationAST::Function { type_params, .. }) => {
+            for bound in type_params.iter_mut().flat_map(|(_, bounds)| bounds) {
+                *bound = qualify_trait(module, bound, imports)?;
+            }
+        },
+        _ => (),
+    }
+
+    for mut child in ast.children() {
+        qualify_names(&mut child, module, local_names, imports)?;
+    }
+
+    Ok(())
+}
+
+// Qualifies a name written as `import_name::name` with the module imported under that name.
+fn qualify_imported(name: &str, imports: &HashMap<String, String>) -> Result<String, AnalysisError> {
+    let (import_name, inner_name) = name.split_once("::").expect("known qualified");
+
+    let imported_module = imports.get(import_name)
+        .ok_or(AnalysisError::from(format!("{import_name} has not been imported")))?;
+
+    Ok(qualify(imported_module, inner_name))
+}
+
+fn qualify_trait(module: &str, trait_name: &str, imports: &HashMap<String, String>) -> Result<String, AnalysisError> {
+    if trait_name.contains("::") {
+        qualify_imported(trait_name, imports)
+    }
+    else {
+        Ok(qualify(module, trait_name))
+    }
+}
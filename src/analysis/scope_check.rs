@@ -1,35 +1,157 @@
 
 use std::collections::HashMap;
 
-use crate::{CompilationEnvironment, error::AnalysisError, ast::{ExprAST, StatementAST, DeclarationAST}};
-use super::{Function, types::Type};
+use crate::{CompilationEnvironment, CompilationGoal, error::AnalysisError, ast::{ExprAST, StatementAST, DeclarationAST}};
+use super::{types::{Type, BuiltIn}, builtins::lookup_builtin, modules::qualify, globals::Global, loops::resolve_loops, Function, LocalScope};
 
 
 // Checks the scope (as well as const-ness) rules, and builds a table of local variables.
+//
+// Variables are visible from their declaration to the end of their block, and may be shadowed,
+// like Rust. Since later steps know nothing of blocks, a local that reuses the name of another
+// local (or a parameter, or a global) is renamed, e.g. to `<x 42>`, along with its uses.
+//
+// Closures and nested functions are checked along with the function around them, and then
+// become functions of their own. A nested function is visible throughout its block, and is
+// renamed like a local, but cannot use the locals around it. A closure copies the locals it
+// uses when it is made.
 pub(crate) fn scope_check(env: &mut CompilationEnvironment, name: &str) -> Result<(), AnalysisError> {
     let function = env.functions.get_mut(name).ok_or(AnalysisError("Could not find function".into()))?;
-    let block = std::mem::take(&mut function.ast);
+    let mut block = std::mem::take(&mut function.ast);
     
-    let mut local_types = HashMap::new();
-    for (name, param_type) in &function.parameter_types {
-        local_types.insert(name.clone(), Some(param_type.clone()));
-    }
+    let mut frames = vec![Frame::new(&function.parameter_types, false)];
+
+    resolve_loops(env, &mut block)?;
 
     scope_check_expression(
-        &env.functions,
-        &mut local_types, 
-        &block
+        env,
+        name,
+        &mut frames, 
+        &mut block
     )?;
     
     // We need the old lifetime to die.
     let function = env.functions.get_mut(name).expect("known exists");
     function.ast = block;
-    function.local_types = local_types;
+    frames.pop().expect("known exists").finish(function);
 
     Ok(())
 }
 
-fn scope_check_expression(functions: &HashMap<String, Function>, local_types: &mut HashMap<String, Option<Type>>, expr: &ExprAST) -> Result<(), AnalysisError> {
+// The name of the function made from a closure.
+pub(crate) fn closure_name(id: u32) -> String {
+    format!("<closure {id}>")
+}
+
+fn nested_function_name(name: &str, id: u32) -> String {
+    format!("<{name} {id}>")
+}
+
+// The locals of a function being checked. A closure or nested function gets a frame of its
+// own, on top of the frame of the function around it.
+struct Frame {
+    types: HashMap<String, Option<Type>>,  // Every local and parameter, by its unique name.
+    visible: Vec<HashMap<String, Name>>,  // Maps names to what they refer to, for each block being checked, innermost last.
+    scopes: Vec<LocalScope>,  // The blocks being checked. Each is added to the one around it when it ends.
+    captures: Option<Vec<(String, String)>>,  // Only closures capture, so this is None for functions.
+}
+
+#[derive(Clone)]
+enum Name {
+    Local (String),  // By unique name.
+    Function (String),  // A nested function, by unique name.
+}
+
+impl Frame {
+    fn new(parameters: &[(String, Type)], is_closure: bool) -> Frame {
+        let mut frame = Frame { 
+            types: HashMap::new(), 
+            visible: vec![HashMap::new()], 
+            scopes: vec![LocalScope::default()], 
+            captures: is_closure.then(Vec::new),
+        };
+
+        for (name, param_type) in parameters {
+            frame.types.insert(name.clone(), Some(param_type.clone()));
+            frame.visible[0].insert(name.clone(), Name::Local(name.clone()));
+        }
+
+        frame
+    }
+
+    fn declare(&mut self, name: &str, unique_name: &str) {
+        self.types.insert(unique_name.to_string(), None);
+        self.visible.last_mut().expect("known exists").insert(name.to_string(), Name::Local(unique_name.to_string()));
+        self.scopes.last_mut().expect("known exists").locals.push(unique_name.to_string());
+    }
+
+    fn enter_block(&mut self) {
+        self.visible.push(HashMap::new());
+        self.scopes.push(LocalScope::default());
+    }
+
+    fn exit_block(&mut self) {
+        self.visible.pop();
+        let scope = self.scopes.pop().expect("known exists");
+        self.scopes.last_mut().expect("known exists").blocks.push(scope);
+    }
+
+    fn is_capture(&self, unique_name: &str) -> bool {
+        self.captures.iter().flatten().any(|(_, inner)| inner == unique_name)
+    }
+
+    // Moves the tables into the function made from the frame.
+    fn finish(mut self, function: &mut Function) {
+        function.local_types = self.types;
+        function.scope = self.scopes.pop().expect("known exists");
+        function.captures = self.captures.unwrap_or_default();
+    }
+}
+
+fn current(frames: &mut [Frame]) -> &mut Frame {
+    frames.last_mut().expect("known exists")
+}
+
+// Finds what a name refers to, searching the frames from the innermost out. A local of an
+// outer frame is captured by every closure between it and the use, so that each closure
+// has a copy to give to the closures inside it.
+fn resolve(frames: &mut [Frame], name: &str) -> Result<Option<Name>, AnalysisError> {
+    let Some((found, found_name)) = frames.iter().enumerate().rev()
+        .find_map(|(i, frame)| frame.visible.iter().rev().find_map(|names| names.get(name)).map(|found| (i, found.clone())))
+        else { return Ok(None) };
+
+    let Name::Local(mut unique_name) = found_name
+        else { return Ok(Some(found_name)) };
+
+    for frame in &mut frames[found + 1..] {
+        let Some(captures) = &mut frame.captures
+            else { return Err(format!("{name} is a local of the function around this one. Only closures can capture locals").into()) };
+
+        unique_name = if let Some((_, inner)) = captures.iter().find(|(outer, _)| *outer == unique_name) {
+            inner.clone()
+        }
+        else {
+            // A capture is a local of the closure, alive for the whole closure.
+            let inner = if frame.types.contains_key(&unique_name) {
+                format!("<{unique_name} {}>", crate::util::next_id())
+            }
+            else {
+                unique_name.clone()
+            };
+
+            captures.push((unique_name, inner.clone()));
+            frame.types.insert(inner.clone(), None);
+            frame.scopes[0].locals.push(inner.clone());
+
+            inner
+        };
+    }
+
+    Ok(Some(Name::Local(unique_name)))
+}
+
+#[allow(clippy::too_many_lines)]
+fn scope_check_expression(env: &mut CompilationEnvironment, function_name: &str, frames: &mut Vec<Frame>, expr: &mut ExprAST) -> Result<(), AnalysisError> {
     match expr {
         ExprAST::Add(left, right, _) 
         | ExprAST::Subtract(left, right, _)
@@ -39,34 +161,103 @@ fn scope_check_expression(functions: &HashMap<String, Function>, local_types: &m
         | ExprAST::Comparison(left, right, _, _)
         | ExprAST::Or(left, right, _)
         | ExprAST::And(left, right, _) => {
-            scope_check_expression(functions, local_types, left)?;
-            scope_check_expression(functions, local_types, right)?;
+            scope_check_expression(env, function_name, frames, left)?;
+            scope_check_expression(env, function_name, frames, right)?;
         },
-        ExprAST::Not(inner, _) => {
-            scope_check_expression(functions, local_types, inner)?;
+        ExprAST::Not(inner, _) | ExprAST::ErrorValue(inner, _) | ExprAST::Try(inner, _) | ExprAST::Cast(inner, ..) => {
+            scope_check_expression(env, function_name, frames, inner)?;
+        }
+        ExprAST::Orelse(left, right, _) => {
+            scope_check_expression(env, function_name, frames, left)?;
+            scope_check_expression(env, function_name, frames, right)?;
+        }
+        // The error is named in a block of its own around the handler.
+        ExprAST::Catch { expr, binding, handler, data } => {
+            scope_check_expression(env, function_name, frames, expr)?;
+
+            current(frames).enter_block();
+
+            if let Some(name) = binding {
+                let unique_name = if current(frames).types.contains_key(name) || lookup_global(env, function_name, name).is_some() {
+                    format!("<{name} {}>", data.id)
+                }
+                else {
+                    name.clone()
+                };
+
+                current(frames).declare(name, &unique_name);
+                *name = unique_name;
+            }
+
+            scope_check_expression(env, function_name, frames, handler)?;
+
+            current(frames).exit_block();
         }
         ExprAST::Block(statements, final_expr, _) => {
+            current(frames).enter_block();
+
+            // Nested functions are visible throughout their block, so they are found first.
+            let (functions, rest): (Vec<_>, Vec<_>) = std::mem::take(statements).into_iter()
+                .partition(|statement| matches!(statement, StatementAST::Declaration(DeclarationAST::Function { .. }, _)));
+            *statements = rest;
+
+            for function in &functions {
+                if let StatementAST::Declaration(DeclarationAST::Function { name, node_data, .. }, _) = function {
+                    current(frames).visible.last_mut().expect("known exists")
+                        .insert(name.clone(), Name::Function(nested_function_name(name, node_data.id)));
+                }
+            }
+
+            for function in functions {
+                scope_check_nested_function(env, function_name, frames, function)?;
+            }
+
             for statement in statements {
                 match statement {
-                    StatementAST::ExpressionStatement(expr, _) => 
-                        scope_check_expression(functions, local_types, expr)?,
+                    StatementAST::ExpressionStatement(expr, _) | StatementAST::Defer(expr, _) => 
+                        scope_check_expression(env, function_name, frames, expr)?,
                     StatementAST::Assignment(left, right, _) => {
-                        scope_check_expression(functions, local_types, left)?;
-                        scope_check_expression(functions, local_types, right)?;
+                        if let ExprAST::Variable(name, _) = left {
+                            match resolve(frames, name)? {
+                                None if matches!(lookup_global(env, function_name, name), Some(Global::Constant { .. })) =>
+                                    return Err(format!("Cannot assign to the constant {name}").into()),
+                                Some(Name::Local(unique_name)) if current(frames).is_capture(&unique_name) =>
+                                    return Err(format!("{name} is a copy captured by this closure, so it cannot be assigned to").into()),
+                                _ => (),
+                            }
+                        }
+
+                        scope_check_expression(env, function_name, frames, left)?;
+                        scope_check_expression(env, function_name, frames, right)?;
+
+                        if let ExprAST::FunctionValue(name, _) = left {
+                            return Err(format!("Cannot assign to the function {name}").into());
+                        }
                     },
                     StatementAST::Declaration(decl, _) => {
                         match decl {
                             DeclarationAST::Function { .. } => {
-                                return Err("Did not expect function".into());
+                                return Err("Expected nested functions to have been checked".into());
                             }
-                            DeclarationAST::Variable { name, expr, .. } => {
-                                if local_types.contains_key(name) {
-                                    return Err("Variable redeclared. Shadowing not yet implemented.".into())
-                                }
+                            DeclarationAST::Trait { .. } | DeclarationAST::Impl { .. } | DeclarationAST::Import { .. } | DeclarationAST::Type { .. } => {
+                                return Err("Traits, impls, imports, and types must be declared at the top level".into());
+                            }
+                            DeclarationAST::Variable { public: true, name, .. } => {
+                                return Err(format!("The local variable {name} cannot be public").into());
+                            }
+                            DeclarationAST::Variable { name, expr, node_data, .. } => {
+                                // The initializer still sees any variable being shadowed, as in `val x: i32 = x + 1;`
+                                scope_check_expression(env, function_name, frames, expr)?;
 
-                                local_types.insert(name.clone(), None);
+                                let unique_name = if current(frames).types.contains_key(name) || lookup_global(env, function_name, name).is_some() {
+                                    format!("<{name} {}>", node_data.id)
+                                }
+                                else {
+                                    name.clone()
+                                };
 
-                                scope_check_expression(functions, local_types, expr)?;
+                                current(frames).declare(name, &unique_name);
+                                *name = unique_name;
                             }
                         }
                     },
@@ -76,43 +267,199 @@ fn scope_check_expression(functions: &HashMap<String, Function>, local_types: &m
             }
 
             if let Some(expr) = final_expr {
-                scope_check_expression(functions, local_types, expr)?;
+                scope_check_expression(env, function_name, frames, expr)?;
             }
+
+            current(frames).exit_block();
         },
-        ExprAST::FunctionCall(name, subexprs, ..) => {
-            if !functions.contains_key(name) {
-                return Err("Could not find function".into());
+        ExprAST::FunctionCall(name, subexprs, data) => {
+            for subexpr in subexprs.iter_mut() {
+                scope_check_expression(env, function_name, frames, subexpr)?;
             }
-            
+
+            // A call naming a variable calls the function value it holds.
+            let variable = match resolve(frames, name)? {
+                Some(Name::Function(unique_name)) => {
+                    *name = unique_name;
+                    return Ok(());
+                },
+                Some(Name::Local(unique_name)) => unique_name,
+                None => match lookup_global(env, function_name, name) {
+                    Some(global @ Global::Variable { .. }) => {
+                        check_visible(env, function_name, name, global.visibility())?;
+                        name.clone()
+                    },
+                    _ => {
+                        scope_check_call(env, function_name, name)?;
+                        return Ok(());
+                    },
+                },
+            };
+
+            let callee = ExprAST::Variable(variable, data.relabel());
+            *expr = ExprAST::IndirectCall(Box::new(callee), std::mem::take(subexprs), data.clone());
+        }
+        // Which function is called depends on the receiver's type, so it is checked later.
+        ExprAST::MethodCall(receiver, _, subexprs, _) => {
+            scope_check_expression(env, function_name, frames, receiver)?;
+
             for subexpr in subexprs {
-                scope_check_expression(functions, local_types, subexpr)?;
+                scope_check_expression(env, function_name, frames, subexpr)?;
             }
         }
-        ExprAST::Variable(name, ..) => {
-            if !local_types.contains_key(name) {
-                return Err(format!("{name} not found in local scope.").into());
-            }
+        ExprAST::Variable(name, data) => {
+            // A variable naming a function is a function value.
+            let function = match resolve(frames, name)? {
+                Some(Name::Local(unique_name)) => {
+                    *name = unique_name;
+                    return Ok(());
+                },
+                Some(Name::Function(unique_name)) => unique_name,
+                None => {
+                    if let Some(global) = lookup_global(env, function_name, name) {
+                        check_visible(env, function_name, name, global.visibility())?;
+                        return Ok(());
+                    }
+
+                    let qualified_name = qualify(&env.functions[function_name].module, name);
+
+                    if let Some(function) = env.functions.get(&qualified_name) {
+                        check_visible(env, function_name, name, (&function.module, function.public))?;
+                        env.queue.add_goal(CompilationGoal::ScopeCheck(qualified_name.clone()));
+                        qualified_name
+                    }
+                    else if env.generic_functions.contains_key(&qualified_name) {
+                        return Err(format!("The generic function {name} cannot be used as a value. Call it from a closure instead").into());
+                    }
+                    else if lookup_builtin(name).is_some() {
+                        return Err(format!("The builtin {name} cannot be used as a value. Call it from a closure instead").into());
+                    }
+                    else {
+                        return Err(format!("{name} not found in local scope.").into());
+                    }
+                },
+            };
+
+            *expr = ExprAST::FunctionValue(function, data.clone());
         }, 
-        ExprAST::IntegerLiteral(..) | ExprAST::BooleanLiteral(..) => (),
+        ExprAST::IntegerLiteral(..) | ExprAST::BooleanLiteral(..) | ExprAST::NoneLiteral(..) => (),
         ExprAST::If { condition, block, else_branch, .. } => {
-            scope_check_expression(functions, local_types, condition)?;
-            scope_check_expression(functions, local_types, block)?;
+            scope_check_expression(env, function_name, frames, condition)?;
+            scope_check_expression(env, function_name, frames, block)?;
 
             if let Some(branch) = else_branch {
-                scope_check_expression(functions, local_types, branch)?;
+                scope_check_expression(env, function_name, frames, branch)?;
             }
         },
         ExprAST::While { condition, block, .. } => {
-            scope_check_expression(functions, local_types, condition)?;
-            scope_check_expression(functions, local_types, block)?;
+            scope_check_expression(env, function_name, frames, condition)?;
+            scope_check_expression(env, function_name, frames, block)?;
+        },
+        ExprAST::Loop { block, .. } => {
+            scope_check_expression(env, function_name, frames, block)?;
         },
-        ExprAST::Return(expr, ..) => {
+        ExprAST::Continue { .. } => (),
+        ExprAST::Return(expr, ..) | ExprAST::Break { value: expr, .. } => {
             if let Some(expr) = expr {
-                scope_check_expression(functions, local_types, expr)?;
+                scope_check_expression(env, function_name, frames, expr)?;
             }
         },
+        // The closure becomes a function of its own. Without an ascription, the return type is
+        // decided during type checking, and is Bottom until then.
+        ExprAST::Closure { params, return_type, body, data } => {
+            let module = env.functions[function_name].module.clone();
+            let mut function = Function::new(env, ExprAST::Moved, params.clone(), "unit".to_string(), &module, false);
+            function.return_type = return_type.clone().map_or(Type::BuiltIn(BuiltIn::Bottom), Type::from);
+
+            frames.push(Frame::new(&function.parameter_types, true));
+            scope_check_expression(env, function_name, frames, body)?;
+
+            function.ast = std::mem::take(body.as_mut());
+            frames.pop().expect("known exists").finish(&mut function);
+
+            env.functions.insert(closure_name(data.id), function);
+        },
+        // The block becomes a function of its own during type checking, and is checked then.
+        ExprAST::Comptime(..) => (),
+        ExprAST::IndirectCall(..) | ExprAST::FunctionValue(..) => return Err("Expected indirect calls and function values to be made here".into()),
+        ExprAST::Wrap(..) => return Err("Expected values to be wrapped during type checking".into()),
+        ExprAST::For { .. } => return Err("Expected for loops to have been desugared".into()),
         ExprAST::Moved => panic!("ExprAST was moved"),
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+// Checks a call to a function declared at the top level, or a builtin.
+fn scope_check_call(env: &mut CompilationEnvironment, function_name: &str, name: &str) -> Result<(), AnalysisError> {
+    let callee = if let Some(function) = env.functions.get(name) {
+        Some((function.module.as_str(), function.public))
+    }
+    else if let Some(generic) = env.generic_functions.get(name) {
+        Some((generic.module.as_str(), generic.public))
+    }
+    else if lookup_builtin(name).is_some() {
+        None
+    }
+    else {
+        return Err(format!("Could not find function {name}").into());
+    };
+
+    if let Some(visibility) = callee {
+        check_visible(env, function_name, name, visibility)?;
+    }
+
+    // Called functions need to be defined too. Generic functions are instead 
+    // instantiated once their type arguments are known.
+    if env.functions.contains_key(name) {
+        env.queue.add_goal(CompilationGoal::ScopeCheck(name.to_string()));
+    }
+
+    Ok(())
+}
+
+// A nested function becomes a function of its own. It is checked here, where the other
+// nested functions around it are visible, and then goes straight to type checking.
+fn scope_check_nested_function(env: &mut CompilationEnvironment, function_name: &str, frames: &mut Vec<Frame>, 
+    declaration: StatementAST) -> Result<(), AnalysisError> {
+
+    let StatementAST::Declaration(DeclarationAST::Function { public, name, type_params, params, mut block, return_type, node_data }, _) = declaration
+        else { return Err("Expected a function declaration".into()) };
+
+    if !type_params.is_empty() {
+        return Err(format!("The nested function {name} cannot be generic").into());
+    }
+    if public {
+        return Err(format!("The nested function {name} cannot be public").into());
+    }
+
+    let module = env.functions[function_name].module.clone();
+    let mut function = Function::new(env, ExprAST::Moved, params, return_type, &module, false);
+
+    frames.push(Frame::new(&function.parameter_types, false));
+    scope_check_expression(env, function_name, frames, &mut block)?;
+
+    function.ast = block;
+    frames.pop().expect("known exists").finish(&mut function);
+
+    let unique_name = nested_function_name(&name, node_data.id);
+    env.functions.insert(unique_name.clone(), function);
+    env.queue.finalize_goal(CompilationGoal::ScopeCheck(unique_name.clone()));
+    env.queue.add_goal(CompilationGoal::TypeCheck(unique_name));
+
+    Ok(())
+}
+
+// Finds a global declared in the same module as the function, or named with its module.
+fn lookup_global<'a>(env: &'a CompilationEnvironment, function_name: &str, name: &str) -> Option<&'a Global> {
+    env.globals.get(&qualify(&env.functions[function_name].module, name))
+}
+
+// Functions and globals of other modules can only be used if they are public.
+fn check_visible(env: &CompilationEnvironment, function_name: &str, name: &str, (module, public): (&str, bool)) -> Result<(), AnalysisError> {
+    if !public && module != env.functions[function_name].module {
+        return Err(format!("{name} is private to its module").into());
+    }
+
+    Ok(())
+}
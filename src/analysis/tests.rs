@@ -0,0 +1,40 @@
+use crate::{CompilationEnvironment, CompilationGoal, FileOrString};
+
+use super::evaluate_comptime;
+
+
+// Checks the source, and gives the error from running its compile time code.
+fn comptime_error(source: &str) -> String {
+    let mut env = CompilationEnvironment::new();
+    env.queue.add_goal(CompilationGoal::ImportFile { file: FileOrString::String("<input>".to_string(), source.to_string()), define_all: true });
+    env.process_goals().expect("Goals should complete");
+
+    evaluate_comptime(&mut env).expect_err("Should fail").0
+}
+
+#[test]
+fn comptime_overflow_is_a_compile_error() {
+    let error = comptime_error("
+val BIG: i32 = 2147483647 + 1;
+
+fn main() -> i32 {
+    BIG
+}");
+
+    assert!(error.starts_with("<input>:2:1: Error in compile time code. Critical Runtime Error: Integer Overflow"), "{error}");
+}
+
+#[test]
+fn comptime_running_out_of_fuel_is_a_compile_error() {
+    let error = comptime_error("
+fn forever() -> i32 {
+    while true {};
+    0
+}
+
+fn main() -> i32 {
+    comptime { forever() }
+}");
+
+    assert_eq!(error, "<input>:8:5: Error in compile time code. Critical Runtime Error: Out of Fuel");
+}
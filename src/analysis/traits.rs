@@ -0,0 +1,86 @@
+// Traits are resolved statically. Each method in an impl becomes an ordinary function
+// with a mangled name, and type checking decides which of these a method call refers to,
+// using the type of the receiver. Nothing about traits remains by code generation.
+
+use std::collections::HashMap;
+
+use crate::CompilationEnvironment;
+use crate::ast::{DeclarationAST, MethodSignature};
+use crate::error::AnalysisError;
+
+use super::types::Type;
+
+
+pub struct Trait {
+    pub methods: Vec<MethodSignature>,
+}
+
+// The name of the function implementing a method, e.g. `<i32 as Shape>::area`. Like the
+// names of generic instances, this can never collide with a function the user wrote.
+pub(crate) fn method_function_name(trait_name: &str, target_type: &Type, method: &str) -> String {
+    format!("<{target_type} as {trait_name}>::{method}")
+}
+
+pub(crate) fn implements(env: &CompilationEnvironment, target_type: &Type, trait_name: &str) -> bool {
+    env.impls.contains(&(trait_name.to_string(), target_type.clone()))
+}
+
+// Finds the function a method call refers to. Exactly one trait implemented by the
+// receiver's type may have a method with that name.
+pub(crate) fn resolve_method(env: &CompilationEnvironment, receiver_type: &Type, method: &str) -> Result<String, AnalysisError> {
+    let mut candidates = env.traits.iter()
+        .filter(|(trait_name, trait_info)|
+            trait_info.methods.iter().any(|signature| signature.name == method)
+            && implements(env, receiver_type, trait_name))
+        .map(|(trait_name, _)| trait_name);
+
+    match (candidates.next(), candidates.next()) {
+        (Some(trait_name), None) => Ok(method_function_name(trait_name, receiver_type, method)),
+        (None, _) => Err(format!("No method {method} found for {receiver_type}").into()),
+        (Some(_), Some(_)) => Err(format!("Method {method} is ambiguous for {receiver_type}").into()),
+    }
+}
+
+// Checks that an impl provides exactly the methods of its trait, with matching signatures.
+// `Self` in the trait stands for the type the trait is implemented for.
+pub(crate) fn check_impl(env: &CompilationEnvironment, trait_name: &str, target_type: &str, methods: &[DeclarationAST]) -> Result<(), AnalysisError> {
+    let trait_info = env.traits.get(trait_name)
+        .ok_or(AnalysisError::from(format!("Could not find trait {trait_name}")))?;
+
+    let bindings = HashMap::from([("Self".to_string(), Type::from(target_type.to_string()))]);
+    let substitute = |type_name: &String| super::generics::substitute(type_name, &bindings);
+
+    for signature in &trait_info.methods {
+        let Some(DeclarationAST::Function { params, return_type, .. }) = methods.iter()
+            .find(|method| matches!(method, DeclarationAST::Function { name, .. } if *name == signature.name))
+            else { return Err(format!("Missing method {} in impl {trait_name} for {target_type}", signature.name).into()) };
+
+        // The parser puts self first, but impls can be built some other way.
+        let Some((first_name, first_type)) = params.first()
+            else { return Err(format!("Method {} in impl {trait_name} for {target_type} does not take self", signature.name).into()) };
+
+        if first_name != "self" || Type::from(first_type.clone()) != Type::from(target_type.to_string()) {
+            return Err(format!("Method {} in impl {trait_name} for {target_type} must take self, of type Self, first", signature.name).into());
+        }
+
+        let expected_params = signature.params.iter()
+            .map(|(_, type_name)| substitute(type_name));
+        let found_params = params.iter()
+            .skip(1)  // self
+            .map(|(_, type_name)| type_name.clone());
+
+        if !expected_params.eq(found_params) || substitute(&signature.return_type) != *return_type {
+            return Err(format!("Method {} in impl {trait_name} for {target_type} does not match the trait", signature.name).into());
+        }
+    }
+
+    for method in methods {
+        if let DeclarationAST::Function { name, .. } = method {
+            if !trait_info.methods.iter().any(|signature| signature.name == *name) {
+                return Err(format!("{name} is not a method of {trait_name}").into());
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -1,9 +1,15 @@
 
-use crate::{CompilationEnvironment, ast::StatementAST};
-use crate::ast::{ExprAST, DeclarationAST};
+use std::collections::HashMap;
+
+use crate::{CompilationEnvironment, CompilationGoal, ast::StatementAST};
+use crate::ast::{ExprAST, DeclarationAST, ASTNodeData};
 use crate::error::AnalysisError;
 
-use super::types::{PartialType, Type, upper_bound_type, BuiltIn};
+use super::types::{PartialType, Type, upper_bound_type, BuiltIn, add_type_info, representation};
+use super::builtins::lookup_builtin;
+use super::{generics, traits, modules};
+use super::comptime::{Comptime, comptime_block_name};
+use super::{Function, closure_name};
 
 
 pub(crate) fn type_check(env: &mut CompilationEnvironment, name: &str) -> Result<(), AnalysisError> {
@@ -11,6 +17,13 @@ pub(crate) fn type_check(env: &mut CompilationEnvironment, name: &str) -> Result
     let mut block = std::mem::take(&mut function.ast);
     let return_type = function.return_type.clone();
 
+    let parameter_types = function.parameter_types.iter().map(|(_, param_type)| param_type.clone()).collect::<Vec<_>>();
+
+    for param_type in &parameter_types {
+        add_checked_type_info(env, param_type)?;
+    }
+    add_checked_type_info(env, &return_type)?;
+
     type_check_expression(env, &mut block, name, &Some(return_type))?;
     finalize_partial_types_expr(env, &mut block, name)?;
 
@@ -38,9 +51,13 @@ fn type_check_expression(env: &mut CompilationEnvironment, expr: &mut ExprAST, f
         | ExprAST::Multiply(left, right, _)
         | ExprAST::Divide(left, right, _)
         | ExprAST::Modulus(left, right, _) => {
+            let expected = &unwrap_expected(expected);
             let left_type = type_check_expression(env, left, function_name, expected)?;
             let right_type = type_check_expression(env, right, function_name, expected)?;
 
+            reject_distinct(&left_type, "arithmetic")?;
+            reject_distinct(&right_type, "arithmetic")?;
+
             if left_type != right_type {
                 if left_type == Type::PartiallyKnown(PartialType::IntLiteral) {
                     type_check_expression(env, left, function_name, &Some(right_type))?;
@@ -56,27 +73,7 @@ fn type_check_expression(env: &mut CompilationEnvironment, expr: &mut ExprAST, f
             left_type
         },
         ExprAST::Comparison(left, right, _, _) => {
-            let left_type = type_check_expression(env, left, function_name, &None)?;
-            let right_type = type_check_expression(env, right, function_name, &None)?;
-
-            if left_type != right_type {
-                if left_type == Type::PartiallyKnown(PartialType::IntLiteral) {
-                    type_check_expression(env, left, function_name, &Some(right_type))?;
-                }
-                else if right_type == Type::PartiallyKnown(PartialType::IntLiteral) {
-                    type_check_expression(env, right, function_name, &Some(left_type))?;
-                }
-                else {
-                    let Some(bound) = upper_bound_type(&left_type, &right_type)
-                        else { return Err("Types don't match".into()); };
-
-                    // TODO: This repeat definitely could cause some efficiency issues. 
-                    // We need a smarter unification algorithm perhaps...
-
-                    type_check_expression(env, left, function_name, &Some(bound.clone()))?;
-                    type_check_expression(env, right, function_name, &Some(bound))?;
-                }
-            }
+            type_check_comparison(env, left, right, function_name)?;
 
             Type::BuiltIn(BuiltIn::Boolean)
         },
@@ -100,14 +97,13 @@ fn type_check_expression(env: &mut CompilationEnvironment, expr: &mut ExprAST, f
                     },
                     StatementAST::CompoundAssignment(..) =>
                         return Err("Expected Compound Assignment to have been desugared".into()),
-                    StatementAST::ExpressionStatement(expr, _) => {
+                    StatementAST::ExpressionStatement(expr, _) | StatementAST::Defer(expr, _) => {
                         type_check_expression(env, expr, function_name, &None)?;
                     }
-                    StatementAST::Declaration(DeclarationAST::Variable { expr, name, type_ascription, .. }, _) => {
+                    StatementAST::Declaration(DeclarationAST::Variable { expr, name, type_ascription: Some(type_ascription), .. }, _) => {
 
-                        let var_type: Type = type_ascription.clone()
-                            .ok_or(AnalysisError::from(format!("Type inference not yet supported - give {name} (in {function_name}) an explicit type.")))?
-                            .into();
+                        let var_type: Type = type_ascription.clone().into();
+                        add_checked_type_info(env, &var_type)?;
 
                         env.functions.get_mut(function_name).expect("known").local_types.insert(name.clone(), Some(var_type.clone()));
 
@@ -117,8 +113,25 @@ fn type_check_expression(env: &mut CompilationEnvironment, expr: &mut ExprAST, f
                             return Err("Type checking failed at variable declaration".into())
                         }
                     }
+                    StatementAST::Declaration(DeclarationAST::Variable { name, type_ascription: None, inferred: false, .. }, _) =>
+                        return Err(format!("Type inference not yet supported - give {name} (in {function_name}) an explicit type.").into()),
+                    // The variables made for a for loop take the type of the range, or i32 if
+                    // both ends are literals.
+                    StatementAST::Declaration(DeclarationAST::Variable { expr, name, type_ascription: None, inferred: true, .. }, _) => {
+                        let var_type = match type_check_expression(env, expr, function_name, &None)? {
+                            Type::PartiallyKnown(PartialType::IntLiteral) => 
+                                type_check_expression(env, expr, function_name, &Some(Type::BuiltIn(BuiltIn::I32)))?,
+                            Type::BuiltIn(BuiltIn::Bottom) =>
+                                return Err(format!("Could not infer the type of {name} (in {function_name})").into()),
+                            var_type => var_type,
+                        };
+
+                        env.functions.get_mut(function_name).expect("known").local_types.insert(name.clone(), Some(var_type));
+                    }
                     StatementAST::Declaration(DeclarationAST::Function { .. }, _) => 
-                        return Err("Can not process function definition here".into()),
+                        return Err("Expected nested functions to have become functions of their own".into()),
+                    StatementAST::Declaration(DeclarationAST::Trait { .. } | DeclarationAST::Impl { .. } | DeclarationAST::Import { .. } | DeclarationAST::Type { .. }, _) => 
+                        return Err("Can not process trait, impl, import, or type here".into()),
                     
                 }
             }
@@ -129,21 +142,133 @@ fn type_check_expression(env: &mut CompilationEnvironment, expr: &mut ExprAST, f
                 Type::BuiltIn(BuiltIn::Unit)
             }
         },
+        ExprAST::FunctionCall(name, exprs, data) if env.generic_functions.contains_key(name) => {
+            let (type_args, return_type) = type_check_generic_call(env, name, exprs, function_name, &unwrap_expected(expected))?;
+
+            env.call_targets.insert(data.id, generics::mangle(name, &type_args));
+            env.queue.add_goal(CompilationGoal::Instantiate { function: name.clone(), type_args });
+
+            return_type
+        },
+        ExprAST::FunctionCall(name, exprs, _) if name == "assert_eq" => {
+            let [left, right] = exprs.as_mut_slice()
+                else { return Err(format!("assert_eq takes 2 arguments, but {} were given", exprs.len()).into()) };
+
+            type_check_comparison(env, left, right, function_name)?;
+
+            Type::BuiltIn(BuiltIn::Unit)
+        },
+        // A closure that captured something lives in the heap, so it can be freed like a ptr.
+        ExprAST::FunctionCall(name, exprs, _) if name == "free" && exprs.len() == 1 => {
+            let freed_type = type_check_expression(env, &mut exprs[0], function_name, &None)?;
+
+            if !matches!(freed_type, Type::Function(..)) {
+                type_check_expression(env, &mut exprs[0], function_name, &Some(Type::BuiltIn(BuiltIn::Pointer)))?;
+            }
+
+            Type::BuiltIn(BuiltIn::Unit)
+        },
         ExprAST::FunctionCall(name, exprs, _) => {
-            let func = env.functions.get(name).ok_or(AnalysisError::from("Could not lookup function"))?;
-            let return_type = func.return_type.clone();
+            let (parameter_types, return_type) = if let Some(builtin) = lookup_builtin(name) {
+                (builtin.parameter_types, builtin.return_type)
+            }
+            else {
+                let func = env.functions.get(name).ok_or(AnalysisError::from("Could not lookup function"))?;
+                (func.parameter_types.iter().map(|(_, param_type)| param_type.clone()).collect(), func.return_type.clone())
+            };
+
+            if exprs.len() != parameter_types.len() {
+                return Err(format!("{name} takes {} arguments, but {} were given", parameter_types.len(), exprs.len()).into());
+            }
 
-            for (expr, (_, expected_type)) in exprs.iter_mut().zip(func.parameter_types.clone()) {
+            for (expr, expected_type) in exprs.iter_mut().zip(parameter_types) {
                 type_check_expression(env, expr, function_name, &Some(expected_type))?;
             }
 
             return_type            
         },
+        // Static dispatch: the receiver's type decides the function, which is then checked
+        // like any other call, with the receiver as the first argument.
+        ExprAST::MethodCall(receiver, method, exprs, data) => {
+            let mut receiver_type = type_check_expression(env, receiver, function_name, &None)?;
+
+            if receiver_type == Type::PartiallyKnown(PartialType::IntLiteral) {
+                receiver_type = type_check_expression(env, receiver, function_name, &Some(Type::BuiltIn(BuiltIn::I32)))?;
+            }
+
+            let target = traits::resolve_method(env, &receiver_type, method)?;
+
+            let func = env.functions.get(&target).ok_or(AnalysisError::from("Could not lookup method"))?;
+            let parameter_types = func.parameter_types.iter().skip(1).map(|(_, param_type)| param_type.clone()).collect::<Vec<_>>();
+            let return_type = func.return_type.clone();
+
+            if exprs.len() != parameter_types.len() {
+                return Err(format!("{method} takes {} arguments, but {} were given", parameter_types.len(), exprs.len()).into());
+            }
+
+            for (expr, expected_type) in exprs.iter_mut().zip(parameter_types) {
+                type_check_expression(env, expr, function_name, &Some(expected_type))?;
+            }
+
+            env.call_targets.insert(data.id, target.clone());
+            env.queue.add_goal(CompilationGoal::ScopeCheck(target));
+
+            return_type
+        },
+        ExprAST::IndirectCall(callee, exprs, _) => {
+            let Type::Function(parameter_types, return_type) = type_check_expression(env, callee, function_name, &None)?
+                else { return Err("Only functions can be called".into()) };
+
+            if exprs.len() != parameter_types.len() {
+                return Err(format!("The function takes {} arguments, but {} were given", parameter_types.len(), exprs.len()).into());
+            }
+
+            for (expr, expected_type) in exprs.iter_mut().zip(parameter_types) {
+                type_check_expression(env, expr, function_name, &Some(expected_type))?;
+            }
+
+            *return_type
+        },
+        ExprAST::FunctionValue(name, _) => {
+            env.functions.get(name).ok_or(AnalysisError::from("Could not lookup function"))?.function_type()
+        },
+        // The closure was made into a function during scope checking. The first time through, the
+        // captured locals are given their types, and the return type is decided if it was not
+        // written: from the expected type if there is one, and otherwise from the body.
+        ExprAST::Closure { data, .. } => {
+            let name = closure_name(data.id);
+
+            if !env.type_index.contains_key(&data.id) {
+                for (outer, inner) in env.functions[&name].captures.clone() {
+                    let capture_type = env.functions[function_name].local_types.get(&outer).cloned().flatten()
+                        .ok_or(AnalysisError::from(format!("Could not find the type of {outer}, which a closure captures")))?;
+
+                    env.functions.get_mut(&name).expect("known exists").local_types.insert(inner, Some(capture_type));
+                }
+
+                if env.functions[&name].return_type == Type::BuiltIn(BuiltIn::Bottom) {
+                    let return_type = match expected {
+                        Some(Type::Function(_, return_type)) => return_type.as_ref().clone(),
+                        _ => infer_return_type(env, &name)?,
+                    };
+
+                    env.functions.get_mut(&name).expect("known exists").return_type = return_type;
+                }
+
+                env.queue.add_goal(CompilationGoal::TypeCheck(name.clone()));
+            }
+
+            env.functions[&name].function_type()
+        },
         ExprAST::IntegerLiteral(literal, _) => {
-            match expected {
+            match &unwrap_expected(expected) {
                 Some(inner_type) => {
+                    if let Type::Distinct(_) = inner_type {
+                        return Err(format!("{literal} is not a {inner_type}. Distinct types must be made with `as`").into())
+                    }
+
                     if !integer_literal_fits(*literal, inner_type) {
-                        return Err("Literal does not fit in type".into())
+                        return Err(format!("{literal} does not fit in {inner_type}").into())
                     }
                     
                     inner_type.clone()
@@ -168,16 +293,73 @@ fn type_check_expression(env: &mut CompilationEnvironment, expr: &mut ExprAST, f
                 .find(|(p_name, _p_type)| p_name == name) {
                 inner.clone()
             }
+            else if let Some(global) = env.globals.get(&modules::qualify(&env.functions[function_name].module, name)) {
+                global.global_type().clone()
+            }
             else {
                 return Err("AKJSnagkj".into())
             }
     
         }
-        ExprAST::If { condition, block, else_branch: None, .. }
-        | ExprAST::While { condition, block, .. } => {
+        ExprAST::If { condition, block, else_branch: None, .. } => {
+            type_check_expression(env, condition, function_name, &Some(Type::BuiltIn(BuiltIn::Boolean)))?;
+            type_check_expression(env, block, function_name, &Some(Type::BuiltIn(BuiltIn::Unit)))?
+        }
+        // Breaks find the type of their loop under its id. A while loop only breaks with unit.
+        ExprAST::While { condition, block, data, .. } => {
+            env.type_index.insert(data.id, Type::BuiltIn(BuiltIn::Unit));
+
             type_check_expression(env, condition, function_name, &Some(Type::BuiltIn(BuiltIn::Boolean)))?;
             type_check_expression(env, block, function_name, &Some(Type::BuiltIn(BuiltIn::Unit)))?
         }
+        // A loop has the type of the values it breaks with. Unless it is expected, the type is
+        // decided by the first break, so the block is checked again in case earlier breaks were
+        // literals. A loop that never breaks never finishes, so it is like a return.
+        ExprAST::Loop { block, data, .. } => {
+            match expected {
+                Some(expected_type) => env.type_index.insert(data.id, expected_type.clone()),
+                None => env.type_index.remove(&data.id),
+            };
+
+            type_check_expression(env, block, function_name, &Some(Type::BuiltIn(BuiltIn::Unit)))?;
+
+            match env.type_index.get(&data.id).cloned() {
+                Some(loop_type) => {
+                    if expected.is_none() && !matches!(loop_type, Type::PartiallyKnown(PartialType::IntLiteral) | Type::BuiltIn(BuiltIn::Unit)) {
+                        type_check_expression(env, block, function_name, &Some(Type::BuiltIn(BuiltIn::Unit)))?;
+                    }
+
+                    loop_type
+                },
+                None => Type::BuiltIn(BuiltIn::Bottom),
+            }
+        },
+        ExprAST::Break { value, data, .. } => {
+            let loop_id = *env.loop_targets.get(&data.id)
+                .ok_or(AnalysisError::from("Break was not matched with a loop"))?;
+            let loop_type = env.type_index.get(&loop_id).cloned();
+
+            let value_type = match (value, &loop_type) {
+                (Some(value), Some(loop_type)) if *loop_type != Type::PartiallyKnown(PartialType::IntLiteral) => 
+                    type_check_expression(env, value, function_name, &Some(loop_type.clone()))?,
+                (Some(value), _) => 
+                    type_check_expression(env, value, function_name, &None)?,
+                (None, _) => Type::BuiltIn(BuiltIn::Unit),
+            };
+
+            match loop_type {
+                _ if value_type == Type::BuiltIn(BuiltIn::Bottom) => (),
+                None | Some(Type::PartiallyKnown(PartialType::IntLiteral)) => { 
+                    env.type_index.insert(loop_id, value_type); 
+                },
+                Some(loop_type) if loop_type != value_type => 
+                    return Err(format!("Expected to break with {loop_type}, not {value_type}").into()),
+                Some(_) => (),
+            }
+
+            Type::BuiltIn(BuiltIn::Bottom)
+        },
+        ExprAST::Continue { .. } => Type::BuiltIn(BuiltIn::Bottom),
         ExprAST::If { condition, block, else_branch: Some(else_branch), .. } => {
             type_check_expression(env, condition, function_name, &Some(Type::BuiltIn(BuiltIn::Boolean)))?;
             let if_type = type_check_expression(env, block, function_name, expected)?;
@@ -209,6 +391,11 @@ fn type_check_expression(env: &mut CompilationEnvironment, expr: &mut ExprAST, f
         ExprAST::Return(expr, _) => {
             let return_type = env.functions.get(function_name).expect("Function exists").return_type.clone();
 
+            // Only a closure still deciding its return type has this one.
+            if return_type == Type::BuiltIn(BuiltIn::Bottom) {
+                return Err("A closure that returns early needs its return type written, e.g. `|x: i32| -> i32 { ... }`".into());
+            }
+
             if let Some(inner) = expr {
                 type_check_expression(env, inner, function_name, &Some(return_type))?;
             }
@@ -218,24 +405,361 @@ fn type_check_expression(env: &mut CompilationEnvironment, expr: &mut ExprAST, f
 
             Type::BuiltIn(BuiltIn::Bottom)
         },
+        // The first time through, the block is moved into a function, to be run once every
+        // goal is complete. The comptime node stays behind, to be replaced with the value.
+        ExprAST::Comptime(block, data) => {
+            let block_name = comptime_block_name(data.id);
+
+            if !env.comptime.contains_key(&block_name) {
+                let value_type = unwrap_expected(expected)
+                    .ok_or(AnalysisError::from("The type of a comptime block must be known from its context"))?;
+
+                let module = env.functions[function_name].module.clone();
+                let function = Function::new(env, std::mem::take(block.as_mut()), vec![], value_type.to_string(), &module, false);
+
+                env.functions.insert(block_name.clone(), function);
+                env.comptime.insert(block_name.clone(), Comptime::new(value_type, data.span.clone()));
+                env.queue.add_goal(CompilationGoal::ScopeCheck(block_name.clone()));
+            }
+
+            env.comptime[&block_name].value_type.clone()
+        },
+        ExprAST::NoneLiteral(_) => {
+            let Some(optional_type @ Type::Optional(_)) = expected
+                else { return Err("The type of none must be known from its context, e.g. `val x: ?i32 = none;`".into()) };
+
+            optional_type.clone()
+        },
+        ExprAST::ErrorValue(inner, _) => {
+            let Some(union_type @ Type::ErrorUnion(error_type, _)) = expected
+                else { return Err("The type of an error must be known from its context, e.g. `val x: u8!i32 = error(1);`".into()) };
+
+            type_check_expression(env, inner, function_name, &Some(error_type.as_ref().clone()))?;
+
+            union_type.clone()
+        },
+        // Checked again, the value is still checked against the payload.
+        ExprAST::Wrap(inner, data) => {
+            let wrapper_type = env.type_index[&data.id].clone();
+            type_check_expression(env, inner, function_name, &wrapper_type.payload().cloned())?;
+
+            wrapper_type
+        },
+        ExprAST::Orelse(left, right, _) => {
+            let Type::Optional(payload_type) = type_check_expression(env, left, function_name, &None)?
+                else { return Err("orelse needs an optional on its left".into()) };
+
+            type_check_expression(env, right, function_name, &Some(payload_type.as_ref().clone()))?;
+
+            *payload_type
+        },
+        // The handler sees the error as a local, much like a variable declared in a block.
+        ExprAST::Catch { expr: inner, binding, handler, .. } => {
+            let Type::ErrorUnion(error_type, payload_type) = type_check_expression(env, inner, function_name, &None)?
+                else { return Err("catch needs an error union on its left".into()) };
+
+            if let Some(binding) = binding {
+                env.functions.get_mut(function_name).expect("known").local_types.insert(binding.clone(), Some(*error_type));
+            }
+
+            type_check_expression(env, handler, function_name, &Some(payload_type.as_ref().clone()))?;
+
+            *payload_type
+        },
+        // Much like a return, the error must fit the return type of the function.
+        ExprAST::Try(inner, _) => {
+            let return_type = env.functions.get(function_name).expect("Function exists").return_type.clone();
+
+            if return_type == Type::BuiltIn(BuiltIn::Bottom) {
+                return Err("A closure that uses try needs its return type written, e.g. `|x: i32| -> u8!i32 { ... }`".into());
+            }
+
+            let Type::ErrorUnion(error_type, payload_type) = type_check_expression(env, inner, function_name, &None)?
+                else { return Err("try needs an error union".into()) };
+
+            match &return_type {
+                Type::ErrorUnion(return_error_type, _) if return_error_type == &error_type => (),
+                _ => return Err(format!("try passes on errors of type {error_type}, but {function_name} returns {return_type}").into()),
+            }
+
+            *payload_type
+        },
+        // A literal takes the type on the other side, like `5 as UserId` does with the base type.
+        ExprAST::Cast(inner, type_name, _) => {
+            let target_type = Type::from(type_name.clone());
+            add_checked_type_info(env, &target_type)?;
+
+            let inner_type = match type_check_expression(env, inner, function_name, &None)? {
+                Type::PartiallyKnown(PartialType::IntLiteral) => {
+                    let literal_type = distinct_base(env, &target_type).unwrap_or(target_type.clone());
+                    type_check_expression(env, inner, function_name, &Some(literal_type))?
+                },
+                inner_type => inner_type,
+            };
+
+            if inner_type != target_type 
+                && distinct_base(env, &inner_type).as_ref() != Some(&target_type) 
+                && distinct_base(env, &target_type).as_ref() != Some(&inner_type) {
+
+                return Err(format!("Cannot convert {inner_type} to {target_type}. `as` only converts distinct types to and from their base types").into());
+            }
+
+            target_type
+        },
+        ExprAST::For { .. } => return Err("Expected for loops to have been desugared".into()),
         ExprAST::Moved => panic!("ExprAST moved"),
     };
 
+    // A value where an optional or error union is expected is wrapped in one.
+    let expr_type = match expected {
+        Some(wrapper_type) if wrapper_type.payload() == Some(&expr_type) => {
+            add_type_info(&mut env.types, &expr_type);
+            env.type_index.insert(expr.get_node_data().id, expr_type);
+
+            let data = ASTNodeData::new(expr.get_node_data().span.clone());
+            *expr = ExprAST::Wrap(Box::new(std::mem::take(expr)), data);
+
+            wrapper_type.clone()
+        },
+        _ => expr_type,
+    };
+
     if expr_type != Type::BuiltIn(BuiltIn::Bottom) {
         if let Some(inner) = expected {
             if *inner != expr_type {
+                if matches!(inner, Type::Distinct(_)) || matches!(expr_type, Type::Distinct(_)) {
+                    return Err(format!("Expected {inner}, but found {expr_type}. Distinct types only convert with `as`").into());
+                }
                 return Err("Type did not matched expected".into());
             }
         }
     }
 
 
+    add_checked_type_info(env, &expr_type)?;
     env.type_index.insert(expr.get_node_data().id, expr_type.clone());
     Ok(expr_type)
 }
 
-// Converts partial types to final types
-#[allow(clippy::only_used_in_recursion)]
+// Arithmetic and literals make the value of an expected optional or error union, which is
+// then wrapped.
+fn unwrap_expected(expected: &Option<Type>) -> Option<Type> {
+    expected.as_ref().map(|expected_type| expected_type.payload().unwrap_or(expected_type).clone())
+}
+
+// The type a distinct type was declared from. None for any other type.
+fn distinct_base(env: &CompilationEnvironment, value_type: &Type) -> Option<Type> {
+    match value_type {
+        Type::Distinct(name) => env.distinct_types.get(name).cloned(),
+        _ => None,
+    }
+}
+
+// Both sides of a comparison (or of assert_eq) must have the same type. A literal takes the type of the other side.
+fn type_check_comparison(env: &mut CompilationEnvironment, left: &mut ExprAST, right: &mut ExprAST, function_name: &str) 
+    -> Result<(), AnalysisError> {
+
+    let left_type = type_check_expression(env, left, function_name, &None)?;
+    let right_type = type_check_expression(env, right, function_name, &None)?;
+
+    reject_distinct(&left_type, "comparing")?;
+    reject_distinct(&right_type, "comparing")?;
+
+    if left_type != right_type {
+        if left_type == Type::PartiallyKnown(PartialType::IntLiteral) {
+            type_check_expression(env, left, function_name, &Some(right_type))?;
+        }
+        else if right_type == Type::PartiallyKnown(PartialType::IntLiteral) {
+            type_check_expression(env, right, function_name, &Some(left_type))?;
+        }
+        else {
+            let Some(bound) = upper_bound_type(&left_type, &right_type)
+                else { return Err("Types don't match".into()); };
+
+            // TODO: This repeat definitely could cause some efficiency issues. 
+            // We need a smarter unification algorithm perhaps...
+
+            type_check_expression(env, left, function_name, &Some(bound.clone()))?;
+            type_check_expression(env, right, function_name, &Some(bound))?;
+        }
+    }
+
+    Ok(())
+}
+
+// Distinct types have none of the operations of their base types.
+fn reject_distinct(operand_type: &Type, operation: &str) -> Result<(), AnalysisError> {
+    match operand_type {
+        Type::Distinct(_) => Err(format!("{operand_type} is a distinct type, so it must be converted with `as` before {operation}").into()),
+        _ => Ok(()),
+    }
+}
+
+// Adds the type like add_type_info, but rejects those that the VM cannot handle yet.
+fn add_checked_type_info(env: &mut CompilationEnvironment, new_type: &Type) -> Result<(), AnalysisError> {
+    add_type_info(&mut env.types, new_type);
+
+    let parts = match new_type {
+        Type::Function(parameter_types, return_type) => parameter_types.iter().chain(std::iter::once(return_type.as_ref())).collect(),
+        Type::ErrorUnion(error_type, payload_type) => vec![error_type.as_ref(), payload_type.as_ref()],
+        Type::Optional(payload_type) => vec![payload_type.as_ref()],
+        _ => vec![],
+    };
+
+    for part in parts {
+        add_checked_type_info(env, part)?;
+    }
+
+    if matches!(new_type, Type::Distinct(_)) && !env.types.contains_key(new_type) {
+        return Err(format!("Could not find a type named {new_type}").into());
+    }
+
+    if let Type::ErrorUnion(error_type, _) = new_type {
+        if !matches!(representation(&env.distinct_types, error_type), Type::BuiltIn(builtin) if builtin.get_int_size().is_some()) {
+            return Err(format!("The error type of {new_type} must be an integer type").into());
+        }
+    }
+
+    if new_type.payload().is_some() {
+        let size = env.types[new_type].size;
+        if size > 16 {
+            return Err(format!("{new_type} takes {size} bytes, but optionals and error unions larger than 16 bytes are not supported yet").into());
+        }
+    }
+
+    Ok(())
+}
+
+// Decides the return type of a closure by checking its body, the way the type of a variable
+// without an ascription is decided by its initializer.
+fn infer_return_type(env: &mut CompilationEnvironment, name: &str) -> Result<Type, AnalysisError> {
+    let mut body = std::mem::take(&mut env.functions.get_mut(name).expect("known exists").ast);
+
+    let return_type = match type_check_expression(env, &mut body, name, &None) {
+        Ok(Type::PartiallyKnown(PartialType::IntLiteral)) => 
+            type_check_expression(env, &mut body, name, &Some(Type::BuiltIn(BuiltIn::I32))),
+        Ok(Type::BuiltIn(BuiltIn::Bottom)) => 
+            Err("Could not infer the return type of a closure. Write it, e.g. `|x: i32| -> i32 { ... }`".into()),
+        other => other,
+    };
+
+    env.functions.get_mut(name).expect("known exists").ast = body;
+    return_type
+}
+
+// Infers the type arguments of a call to a generic function, from the types of the
+// arguments, or from the expected type of the call. Each is matched against the type it
+// is for, part by part. Returns the type arguments (in declaration order) and the type
+// of the call.
+fn type_check_generic_call(env: &mut CompilationEnvironment, name: &str, exprs: &mut [ExprAST], 
+    function_name: &str, expected: &Option<Type>) -> Result<(Vec<Type>, Type), AnalysisError> {
+
+    let generic = &env.generic_functions[name];
+    let bounds = generic.type_parameters.clone();
+    let type_parameters = bounds.iter().map(|(param, _)| param.clone()).collect::<Vec<_>>();
+    let parameter_types = generic.parameters.iter().map(|(_, type_name)| type_name.clone()).collect::<Vec<_>>();
+    let return_type = generic.return_type.clone();
+
+    if exprs.len() != parameter_types.len() {
+        return Err(format!("{name} takes {} arguments, but {} were given", parameter_types.len(), exprs.len()).into());
+    }
+
+    let mut bindings: HashMap<String, Type> = HashMap::new();
+
+    if let Some(expected) = expected {
+        unify(&return_type.clone().into(), expected, &type_parameters, &mut bindings);
+    }
+
+    // Literals (and return expressions) say little about their type, so they only decide
+    // a type parameter if no other argument does.
+    let mut undecided = vec![];
+
+    for (i, (expr, param_type)) in exprs.iter_mut().zip(&parameter_types).enumerate() {
+        let pattern = Type::from(param_type.clone());
+
+        if is_bound(&pattern, &type_parameters, &bindings) {
+            type_check_expression(env, expr, function_name, &Some(generics::substitute(param_type, &bindings).into()))?;
+            continue;
+        }
+
+        match type_check_expression(env, expr, function_name, &None)? {
+            Type::PartiallyKnown(PartialType::IntLiteral) | Type::BuiltIn(BuiltIn::Bottom) => undecided.push(i),
+            arg_type => {
+                unify(&pattern, &arg_type, &type_parameters, &mut bindings);
+
+                // The argument may still need converting, e.g. to an optional.
+                let bound = Type::from(generics::substitute(param_type, &bindings));
+
+                if !is_bound(&pattern, &type_parameters, &bindings) {
+                    undecided.push(i);
+                }
+                else if bound != arg_type {
+                    type_check_expression(env, expr, function_name, &Some(bound))?;
+                }
+            },
+        }
+    }
+
+    // Anything still unbound is reported below, rather than guessed.
+    for i in undecided {
+        if is_bound(&parameter_types[i].clone().into(), &type_parameters, &bindings) {
+            type_check_expression(env, &mut exprs[i], function_name, &Some(generics::substitute(&parameter_types[i], &bindings).into()))?;
+        }
+    }
+
+    let type_args = type_parameters.iter()
+        .map(|param| bindings.get(param).cloned()
+            .ok_or(AnalysisError::from(format!("Could not infer type parameter {param} of {name}"))))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for ((_, traits), type_arg) in bounds.iter().zip(&type_args) {
+        for trait_name in traits {
+            if !traits::implements(env, type_arg, trait_name) {
+                return Err(format!("{type_arg} does not implement {trait_name}, as required by {name}").into());
+            }
+        }
+    }
+
+    Ok((type_args, generics::substitute(&return_type, &bindings).into()))
+}
+
+// Binds the type parameters named in a type by matching it against a known type. A value
+// that is not an optional or an error union may still become the payload of one.
+fn unify(pattern: &Type, known: &Type, type_parameters: &[String], bindings: &mut HashMap<String, Type>) {
+    match (pattern, known) {
+        (_, Type::PartiallyKnown(_) | Type::BuiltIn(BuiltIn::Bottom)) => (),
+        (Type::Distinct(name), _) if type_parameters.contains(name) => {
+            bindings.entry(name.clone()).or_insert_with(|| known.clone());
+        },
+        (Type::Optional(pattern_payload), Type::Optional(known_payload)) => unify(pattern_payload, known_payload, type_parameters, bindings),
+        (Type::ErrorUnion(pattern_error, pattern_payload), Type::ErrorUnion(known_error, known_payload)) => {
+            unify(pattern_error, known_error, type_parameters, bindings);
+            unify(pattern_payload, known_payload, type_parameters, bindings);
+        },
+        (Type::Function(pattern_params, pattern_return), Type::Function(known_params, known_return)) if pattern_params.len() == known_params.len() => {
+            for (pattern_param, known_param) in pattern_params.iter().zip(known_params) {
+                unify(pattern_param, known_param, type_parameters, bindings);
+            }
+            unify(pattern_return, known_return, type_parameters, bindings);
+        },
+        (Type::Optional(pattern_payload) | Type::ErrorUnion(_, pattern_payload), _) => unify(pattern_payload, known, type_parameters, bindings),
+        _ => (),
+    }
+}
+
+// Whether every type parameter named in the type has been bound.
+fn is_bound(pattern: &Type, type_parameters: &[String], bindings: &HashMap<String, Type>) -> bool {
+    match pattern {
+        Type::Distinct(name) => !type_parameters.contains(name) || bindings.contains_key(name),
+        Type::Optional(payload) => is_bound(payload, type_parameters, bindings),
+        Type::ErrorUnion(error, payload) => is_bound(error, type_parameters, bindings) && is_bound(payload, type_parameters, bindings),
+        Type::Function(params, return_type) => 
+            params.iter().chain(std::iter::once(return_type.as_ref())).all(|part| is_bound(part, type_parameters, bindings)),
+        _ => true,
+    }
+}
+
+// Converts partial types to final types. Also gives globals their qualified names.
 fn finalize_partial_types_expr(env: &mut CompilationEnvironment, expr: &mut ExprAST, func_name: &str) -> Result<(), AnalysisError> {
     let found_type = &env.type_index[&expr.get_node_data().id];
 
@@ -253,7 +777,9 @@ fn finalize_partial_types_expr(env: &mut CompilationEnvironment, expr: &mut Expr
         | ExprAST::Comparison(a, b, _, _)
         | ExprAST::Or(a, b, _)
         | ExprAST::And(a, b, _)
-        | ExprAST::While { condition: a, block: b, .. } => {
+        | ExprAST::While { condition: a, block: b, .. }
+        | ExprAST::Orelse(a, b, _)
+        | ExprAST::Catch { expr: a, handler: b, .. } => {
             finalize_partial_types_expr(env, a, func_name)?;
             finalize_partial_types_expr(env, b, func_name)?;
         },
@@ -264,13 +790,18 @@ fn finalize_partial_types_expr(env: &mut CompilationEnvironment, expr: &mut Expr
                 finalize_partial_types_expr(env, else_branch, func_name)?;
             }
         },
-        ExprAST::Not(a, _) => {
+        ExprAST::Not(a, _) 
+        | ExprAST::Loop { block: a, .. } 
+        | ExprAST::ErrorValue(a, _) 
+        | ExprAST::Wrap(a, _) 
+        | ExprAST::Try(a, _) 
+        | ExprAST::Cast(a, ..) => {
             finalize_partial_types_expr(env, a, func_name)?;
         },
         ExprAST::Block(statements, final_expr, _) => {
             for stmt in statements {
                 match stmt {
-                    StatementAST::ExpressionStatement(e, _) => {
+                    StatementAST::ExpressionStatement(e, _) | StatementAST::Defer(e, _) => {
                         finalize_partial_types_expr(env, e, func_name)?;
                     },
                     StatementAST::Assignment(a, b, _) => {
@@ -279,8 +810,8 @@ fn finalize_partial_types_expr(env: &mut CompilationEnvironment, expr: &mut Expr
                     },
                     StatementAST::CompoundAssignment(..) =>
                         return Err("Expected Compound Assignment to have been desugared".into()),
-                    StatementAST::Declaration(DeclarationAST::Function { .. }, _) => {
-                        panic!("Cannot yet handle functions in functions");
+                    StatementAST::Declaration(DeclarationAST::Function { .. } | DeclarationAST::Trait { .. } | DeclarationAST::Impl { .. } | DeclarationAST::Import { .. } | DeclarationAST::Type { .. }, _) => {
+                        return Err("Expected only variables to be declared in functions".into());
                     }
                     StatementAST::Declaration(DeclarationAST::Variable { expr, ..  }, _) => {
                         // TODO - type inference here?, use func_name field
@@ -293,20 +824,61 @@ fn finalize_partial_types_expr(env: &mut CompilationEnvironment, expr: &mut Expr
                 finalize_partial_types_expr(env, e, func_name)?;
             }
         },
-        ExprAST::FunctionCall(_, exprs, _) => {
+        ExprAST::FunctionCall(name, exprs, data) => {
+            // Calls to generic functions become calls to the instance chosen by type checking.
+            if let Some(instance_name) = env.call_targets.get(&data.id) {
+                *name = instance_name.clone();
+            }
+
             for e in exprs {
                 finalize_partial_types_expr(env, e, func_name)?;
             }
         },
-        ExprAST::Return(expr, _) => {
+        // Method calls become plain calls, keeping the same id so the type is still known.
+        ExprAST::MethodCall(..) => {
+            let ExprAST::MethodCall(receiver, _, mut exprs, data) = std::mem::take(expr)
+                else { unreachable!() };
+
+            let target = env.call_targets.get(&data.id)
+                .ok_or(AnalysisError::from("Method call was not resolved"))?
+                .clone();
+
+            exprs.insert(0, *receiver);
+            *expr = ExprAST::FunctionCall(target, exprs, data);
+
+            finalize_partial_types_expr(env, expr, func_name)?;
+        },
+        ExprAST::Return(expr, _) | ExprAST::Break { value: expr, .. } => {
             if let Some(expr) = expr {
                 finalize_partial_types_expr(env, expr, func_name)?;
             }
         },
+        ExprAST::IndirectCall(callee, exprs, _) => {
+            finalize_partial_types_expr(env, callee, func_name)?;
+
+            for e in exprs {
+                finalize_partial_types_expr(env, e, func_name)?;
+            }
+        },
+        ExprAST::Variable(name, _) if !env.functions[func_name].local_types.contains_key(name) => {
+            let qualified_name = modules::qualify(&env.functions[func_name].module, name);
+
+            if !env.globals.contains_key(&qualified_name) {
+                return Err(format!("Could not find variable {name}").into());
+            }
+
+            *name = qualified_name;
+        },
         ExprAST::IntegerLiteral(_, _)
         | ExprAST::BooleanLiteral(_, _)
-        | ExprAST::Variable(_, _) => 
+        | ExprAST::Variable(_, _)
+        | ExprAST::Continue { .. }
+        | ExprAST::Comptime(..)
+        | ExprAST::FunctionValue(..)
+        | ExprAST::Closure { .. }
+        | ExprAST::NoneLiteral(_) => 
             (),
+        ExprAST::For { .. } => return Err("Expected for loops to have been desugared".into()),
         ExprAST::Moved => panic!("AST moved"),
     }
 
@@ -314,7 +886,16 @@ fn finalize_partial_types_expr(env: &mut CompilationEnvironment, expr: &mut Expr
 }
 
 
-fn integer_literal_fits(_literal: i128, _expected: &Type) -> bool {
-    // TODO!
-    true
+fn integer_literal_fits(literal: i128, expected: &Type) -> bool {
+    let Some(int_size) = (match expected { Type::BuiltIn(builtin) => builtin.get_int_size(), _ => None })
+        else { return false };
+
+    let bits = 8 * int_size.to_usize() as u32;
+
+    if matches!(expected, Type::BuiltIn(builtin) if builtin.is_signed()) {
+        -(1 << (bits - 1)) <= literal && literal < (1 << (bits - 1))
+    }
+    else {
+        0 <= literal && literal < (1 << bits)
+    }
 }
@@ -9,6 +9,14 @@ pub enum Type {
     BuiltIn (BuiltIn),
     
     PartiallyKnown (PartialType),
+
+    Function (Vec<Type>, Box<Type>),  // Parameter types and return type. Functions and closures alike.
+
+    Optional (Box<Type>),  // Either holds a value, or is none.
+
+    ErrorUnion (Box<Type>, Box<Type>),  // The error type (an integer type) and the type of the value.
+
+    Distinct (String),  // A type declared with `distinct type`, by its qualified name. It is laid out like its base type.
 }
 
 #[derive(PartialEq, Eq, Hash, Clone, Debug)]
@@ -28,6 +36,7 @@ pub enum BuiltIn {
     I64,
     Unit,
     Boolean,
+    Pointer,  // An address in the VM heap. Null is 0.
     Bottom,  // The type of return expressions - this type is uninhabitted.
 }
 
@@ -67,6 +76,32 @@ fn type_fits(source: &BuiltIn, target: &BuiltIn) -> bool {
     }
 }
 
+impl Type {
+    // Values of this type are addresses the garbage collector must trace. A function value
+    // is only an address if it is a closure that captured something, but it may well be.
+    pub fn is_heap_reference(&self) -> bool {
+        matches!(self, Type::BuiltIn(BuiltIn::Pointer) | Type::Function(..))
+    }
+
+    // Where a value of this type may hold a heap address. An optional or an error union of
+    // one is 16 bytes, with the address in its second word.
+    pub fn heap_reference_offset(&self) -> Option<usize> {
+        match self {
+            _ if self.is_heap_reference() => Some(0),
+            Type::Optional(payload) | Type::ErrorUnion(_, payload) if payload.is_heap_reference() => Some(8),
+            _ => None,
+        }
+    }
+
+    // The type of the value held by an optional or an error union.
+    pub fn payload(&self) -> Option<&Type> {
+        match self {
+            Type::Optional(payload) | Type::ErrorUnion(_, payload) => Some(payload),
+            _ => None,
+        }
+    }
+}
+
 impl BuiltIn {
     pub fn is_signed(&self) -> bool {
         use BuiltIn as B;
@@ -96,6 +131,19 @@ impl BuiltIn {
 
 impl From<String> for Type {
     fn from(value: String) -> Self {
+        if let Some(payload) = value.strip_prefix('?') {
+            return Type::Optional(Box::new(Type::from(payload.to_string())));
+        }
+
+        if let Some(function_type) = parse_function_type(&value) {
+            return function_type;
+        }
+
+        // The error type is always a name, so the first `!` follows it.
+        if let Some((error, payload)) = value.split_once('!') {
+            return Type::ErrorUnion(Box::new(Type::from(error.to_string())), Box::new(Type::from(payload.to_string())));
+        }
+
         match &value[..] {
             "i8" => Type::BuiltIn(BuiltIn::I8),
             "i16" => Type::BuiltIn(BuiltIn::I16),
@@ -107,8 +155,81 @@ impl From<String> for Type {
             "u64" => Type::BuiltIn(BuiltIn::U64),
             "unit" => Type::BuiltIn(BuiltIn::Unit),
             "bool" => Type::BuiltIn(BuiltIn::Boolean),
-            _ => panic!("User defined type not yet implemented"),
+            "ptr" => Type::BuiltIn(BuiltIn::Pointer),
+            // Aliases were replaced by what they stand for when their file was imported, so any
+            // other name is a distinct type. Whether it was really declared is checked later.
+            name => Type::Distinct(name.to_string()),
+        }
+    }
+}
+
+// Function types are spelled like `fn(i32, fn(i32) -> i32) -> bool`. Parameters may be
+// function types themselves, so only the commas outside any parentheses separate them.
+fn parse_function_type(value: &str) -> Option<Type> {
+    let rest = value.strip_prefix("fn(")?;
+
+    let mut depth = 0;
+    let mut params = vec![];
+    let mut start = 0;
+
+    for (i, ch) in rest.char_indices() {
+        match ch {
+            '(' => depth += 1,
+            ')' if depth > 0 => depth -= 1,
+            ')' => {
+                if !rest[start..i].trim().is_empty() {
+                    params.push(Type::from(rest[start..i].trim().to_string()));
+                }
+
+                let return_type = rest[i + 1..].trim().strip_prefix("->")?.trim();
+
+                return Some(Type::Function(params, Box::new(Type::from(return_type.to_string()))));
+            },
+            ',' if depth == 0 => {
+                params.push(Type::from(rest[start..i].trim().to_string()));
+                start = i + 1;
+            },
+            _ => (),
+        }
+    }
+
+    None
+}
+
+// Writes types the way they are spelled in source, so the result can be parsed back.
+impl std::fmt::Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Type::Function(params, return_type) = self {
+            let params = params.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ");
+
+            return write!(f, "fn({params}) -> {return_type}");
         }
+
+        match self {
+            Type::Optional(payload) => return write!(f, "?{payload}"),
+            Type::ErrorUnion(error, payload) => return write!(f, "{error}!{payload}"),
+            Type::Distinct(name) => return write!(f, "{name}"),
+            _ => (),
+        }
+
+        let name = match self {
+            Type::BuiltIn(BuiltIn::I8) => "i8",
+            Type::BuiltIn(BuiltIn::I16) => "i16",
+            Type::BuiltIn(BuiltIn::I32) => "i32",
+            Type::BuiltIn(BuiltIn::I64) => "i64",
+            Type::BuiltIn(BuiltIn::U8) => "u8",
+            Type::BuiltIn(BuiltIn::U16) => "u16",
+            Type::BuiltIn(BuiltIn::U32) => "u32",
+            Type::BuiltIn(BuiltIn::U64) => "u64",
+            Type::BuiltIn(BuiltIn::Unit) => "unit",
+            Type::BuiltIn(BuiltIn::Boolean) => "bool",
+            Type::BuiltIn(BuiltIn::Pointer) => "ptr",
+            Type::BuiltIn(BuiltIn::Bottom) => "<bottom>",
+            Type::PartiallyKnown(PartialType::IntLiteral) => "<integer literal>",
+            Type::Function(..) | Type::Optional(..) | Type::ErrorUnion(..) | Type::Distinct(..) => unreachable!("Written above"),
+        };
+
+        write!(f, "{name}")
     }
 }
 
@@ -133,9 +254,73 @@ pub fn get_default_types() -> HashMap<Type, TypeInfo> {
     
     map.insert(Type::BuiltIn(BuiltIn::Boolean), TypeInfo { size: 1, alignment: 1 });
 
+    map.insert(Type::BuiltIn(BuiltIn::Pointer), TypeInfo { size: 8, alignment: 8 });
+
     map.insert(Type::BuiltIn(BuiltIn::Unit), TypeInfo { size: 0, alignment: 1 });  // Not sure if this should have an alignment
 
     map.insert(Type::BuiltIn(BuiltIn::Bottom), TypeInfo { size: 0, alignment: 1 });
 
     map
 }
+
+// There are too many function types to list up front, so each is added once it is used.
+// A function value is a single word: either an instruction index or the address of a closure.
+//
+// Optionals and error unions start with a tag byte, which is 1 if there is a value, followed
+// by the value (or the error) at payload_offset. Up to 8 bytes, they are moved as a single
+// value, so they are aligned to their size. Larger ones take 16 bytes, with the tag in the
+// first word and the payload in the second, and are moved a word at a time.
+pub fn add_type_info(types: &mut HashMap<Type, TypeInfo>, new_type: &Type) {
+    match new_type {
+        Type::Function(..) => {
+            types.entry(new_type.clone()).or_insert(TypeInfo { size: 8, alignment: 8 });
+        },
+        Type::Optional(..) | Type::ErrorUnion(..) => {
+            if types.contains_key(new_type) {
+                return;
+            }
+
+            let parts = match new_type {
+                Type::ErrorUnion(error, payload) => vec![error.as_ref(), payload.as_ref()],
+                _ => vec![new_type.payload().expect("known exists")],
+            };
+
+            for part in &parts {
+                add_type_info(types, part);
+            }
+
+            // A distinct type that was never declared has no layout.
+            if parts.iter().any(|part| !types.contains_key(*part)) {
+                return;
+            }
+
+            let offset = payload_offset(types, new_type);
+            let end = offset + parts.iter().map(|part| types[*part].size).max().unwrap_or(0);
+            let size = end.next_power_of_two();
+
+            types.insert(new_type.clone(), TypeInfo { size, alignment: size.min(8) });
+        },
+        _ => (),
+    }
+}
+
+// A distinct type is represented just like its base type, which may be distinct too.
+pub fn representation<'a>(distinct_types: &'a HashMap<String, Type>, mut value_type: &'a Type) -> &'a Type {
+    while let Type::Distinct(name) = value_type {
+        match distinct_types.get(name) {
+            Some(base) => value_type = base,
+            None => break,
+        }
+    }
+
+    value_type
+}
+
+// Where the value (or the error) of an optional or an error union is, after the tag.
+pub fn payload_offset(types: &HashMap<Type, TypeInfo>, wrapper: &Type) -> usize {
+    match wrapper {
+        Type::Optional(payload) => types[payload.as_ref()].alignment,
+        Type::ErrorUnion(error, payload) => types[error.as_ref()].alignment.max(types[payload.as_ref()].alignment),
+        _ => 0,
+    }
+}
@@ -44,38 +44,75 @@ impl ASTNodeData {
 // need to clone these structs you should use a different method (duplicate).
 #[derive(Debug)]
 pub enum DeclarationAST {
-    // The parameters are pairs of names and type ascriptions
-    Function { name: String, params: Vec<(String, String)>, block: ExprAST, return_type: String, node_data: ASTNodeData },
-    Variable { mutability: Mutability, name: String, expr: ExprAST, type_ascription: Option<String> , node_data: ASTNodeData }
+    // The parameters are pairs of names and type ascriptions. Type parameters (with their trait bounds) are 
+    // empty unless the function is generic.
+    Function { public: bool, name: String, type_params: Vec<(String, Vec<String>)>, params: Vec<(String, String)>, block: ExprAST, return_type: String, node_data: ASTNodeData },
+    // Only top level variables can be public. Written variables need a type ascription, while those made
+    // by desugaring are marked as inferred, and take the type of their initializer.
+    Variable { public: bool, mutability: Mutability, name: String, expr: ExprAST, type_ascription: Option<String>, inferred: bool, node_data: ASTNodeData },
+    Trait { name: String, methods: Vec<MethodSignature>, node_data: ASTNodeData },
+    // Each method is a Function, whose first parameter is `self` with the target type.
+    Impl { trait_name: String, target_type: String, methods: Vec<DeclarationAST>, node_data: ASTNodeData },
+    Import { path: String, node_data: ASTNodeData },  // The path as written, relative to the importing file.
+    Type { distinct: bool, name: String, base: String, node_data: ASTNodeData },  // An alias, unless distinct.
+}
+
+// A method in a trait. The parameters do not include `self`.
+#[derive(Debug, Clone)]
+pub struct MethodSignature {
+    pub name: String,
+    pub params: Vec<(String, String)>,
+    pub return_type: String,
 }
 
 impl DeclarationAST {
     // Creates an identical copy, except for the node_data which is intended to be unique.
     pub fn duplicate(&self) -> DeclarationAST {
         match self {
-            DeclarationAST::Function { name, params, block, return_type, node_data } =>
+            DeclarationAST::Function { public, name, type_params, params, block, return_type, node_data } =>
                 DeclarationAST::Function {
+                    public: *public,
                     name: name.clone(), 
+                    type_params: type_params.clone(),
                     params: params.clone(), 
                     block: block.duplicate(), 
                     return_type: return_type.clone(), 
                     node_data: node_data.relabel()
                 },
-            DeclarationAST::Variable { mutability, name, expr, type_ascription, node_data } => 
+            DeclarationAST::Variable { public, mutability, name, expr, type_ascription, inferred, node_data } => 
                 DeclarationAST::Variable { 
+                    public: *public,
                     mutability: mutability.clone(), 
                     name: name.clone(), 
                     expr: expr.duplicate(), 
                     type_ascription: type_ascription.clone(), 
+                    inferred: *inferred,
+                    node_data: node_data.relabel()
+                },
+            DeclarationAST::Trait { name, methods, node_data } =>
+                DeclarationAST::Trait { name: name.clone(), methods: methods.clone(), node_data: node_data.relabel() },
+            DeclarationAST::Impl { trait_name, target_type, methods, node_data } =>
+                DeclarationAST::Impl {
+                    trait_name: trait_name.clone(),
+                    target_type: target_type.clone(),
+                    methods: methods.iter().map(DeclarationAST::duplicate).collect(),
                     node_data: node_data.relabel()
                 },
+            DeclarationAST::Import { path, node_data } =>
+                DeclarationAST::Import { path: path.clone(), node_data: node_data.relabel() },
+            DeclarationAST::Type { distinct, name, base, node_data } =>
+                DeclarationAST::Type { distinct: *distinct, name: name.clone(), base: base.clone(), node_data: node_data.relabel() },
         }
     }
 
     pub fn get_node_data(&self) -> &ASTNodeData {
         match self {
             | DeclarationAST::Function { node_data, .. } 
-            | DeclarationAST::Variable { node_data, .. } => node_data
+            | DeclarationAST::Variable { node_data, .. }
+            | DeclarationAST::Trait { node_data, .. }
+            | DeclarationAST::Impl { node_data, .. }
+            | DeclarationAST::Import { node_data, .. }
+            | DeclarationAST::Type { node_data, .. } => node_data
         }
     }
 }
@@ -86,9 +123,20 @@ pub enum StatementAST {
     Assignment (ExprAST, ExprAST, ASTNodeData),  // There are restrictions on wbat goes on the left, but it is ultimately an expression too.
     CompoundAssignment (ExprAST, ExprAST, MathOperation, ASTNodeData),
     Declaration (DeclarationAST, ASTNodeData),  // Any declaration will be allowed, but for now only variable declarations work.
+    Defer (ExprAST, ASTNodeData),  // Runs when the enclosing block is left
 }
 
 impl StatementAST {
+    pub fn get_node_data(&self) -> &ASTNodeData {
+        match self {
+            StatementAST::ExpressionStatement(_, node_data)
+            | StatementAST::Assignment(_, _, node_data)
+            | StatementAST::CompoundAssignment(_, _, _, node_data)
+            | StatementAST::Declaration(_, node_data)
+            | StatementAST::Defer(_, node_data) => node_data
+        }
+    }
+
     // Creates an identical copy, except for the node_data which is intended to be unique.
     pub fn duplicate(&self) -> StatementAST {
         match self {
@@ -99,7 +147,9 @@ impl StatementAST {
             StatementAST::CompoundAssignment(left, right, op, node_data) => 
                 StatementAST::CompoundAssignment(left.duplicate(), right.duplicate(), op.clone(), node_data.relabel()),
             StatementAST::Declaration(decl, node_data) =>   
-                StatementAST::Declaration(decl.duplicate(), node_data.relabel())
+                StatementAST::Declaration(decl.duplicate(), node_data.relabel()),
+            StatementAST::Defer(expr, node_data) => 
+                StatementAST::Defer(expr.duplicate(), node_data.relabel()),
         }
     }
 }
@@ -121,10 +171,28 @@ pub enum ExprAST {
     BooleanLiteral(bool, ASTNodeData),
     Variable (String, ASTNodeData),
     FunctionCall (String, Vec<ExprAST>, ASTNodeData),  // The vec contains arguments
+    MethodCall (Box<ExprAST>, String, Vec<ExprAST>, ASTNodeData),  // Receiver, method name, and other arguments. Becomes a FunctionCall once resolved.
+    IndirectCall (Box<ExprAST>, Vec<ExprAST>, ASTNodeData),  // Calls a function value. Scope checking makes these from calls naming a variable.
+    FunctionValue (String, ASTNodeData),  // A function used as a value. Scope checking makes these from variables naming a function.
+    // The parameters are pairs of names and type ascriptions. Scope checking moves the body into a function of its own, leaving Moved.
+    Closure { params: Vec<(String, String)>, return_type: Option<String>, body: Box<ExprAST>, data: ASTNodeData },
     Block (Vec<StatementAST>, Option<Box<ExprAST>>, ASTNodeData),
     If { condition: Box<ExprAST>, block: Box<ExprAST>, else_branch: Option<Box<ExprAST>>, data: ASTNodeData },
-    While { condition: Box<ExprAST>, block: Box<ExprAST>, data: ASTNodeData },
+    While { label: Option<String>, condition: Box<ExprAST>, block: Box<ExprAST>, data: ASTNodeData },
+    For { label: Option<String>, variable: String, start: Box<ExprAST>, end: Box<ExprAST>, inclusive: bool, block: Box<ExprAST>, data: ASTNodeData },  // Desugared into While.
+    Loop { label: Option<String>, block: Box<ExprAST>, data: ASTNodeData },
     Return (Option<Box<ExprAST>>, ASTNodeData),
+    Break { label: Option<String>, value: Option<Box<ExprAST>>, data: ASTNodeData },  // Without a label, leaves the innermost loop.
+    Continue { label: Option<String>, data: ASTNodeData },
+    Comptime (Box<ExprAST>, ASTNodeData),  // Type checking moves the block into a function of its own, leaving Moved.
+    NoneLiteral (ASTNodeData),  // The empty optional.
+    ErrorValue (Box<ExprAST>, ASTNodeData),  // An error union holding the error code, e.g. `error(2)`
+    Wrap (Box<ExprAST>, ASTNodeData),  // Makes an optional or an error union holding a value. Type checking adds these.
+    Orelse (Box<ExprAST>, Box<ExprAST>, ASTNodeData),  // The value of an optional, or else the second expression.
+    // The value of an error union, or else the handler, which may name the error.
+    Catch { expr: Box<ExprAST>, binding: Option<String>, handler: Box<ExprAST>, data: ASTNodeData },
+    Try (Box<ExprAST>, ASTNodeData),  // The value of an error union, or else the error is returned.
+    Cast (Box<ExprAST>, String, ASTNodeData),  // Converts between a distinct type and its base. The string is the type converted to.
     
     // This is a hack that allows us to remove an AST, operate on it, and put it back. (Blame the borrow checker for this.)
     #[default] 
@@ -147,10 +215,26 @@ impl ExprAST {
             | ExprAST::BooleanLiteral(_, data)
             | ExprAST::Variable(_, data)
             | ExprAST::FunctionCall(_, _, data)
+            | ExprAST::MethodCall(_, _, _, data)
+            | ExprAST::IndirectCall(_, _, data)
+            | ExprAST::FunctionValue(_, data)
+            | ExprAST::Closure { data, .. }
             | ExprAST::Block(_, _, data)
             | ExprAST::If { data, .. }
             | ExprAST::While { data, .. }
-            | ExprAST::Return(_, data) => data,
+            | ExprAST::For { data, .. }
+            | ExprAST::Loop { data, .. }
+            | ExprAST::Return(_, data)
+            | ExprAST::Break { data, .. }
+            | ExprAST::Continue { data, .. }
+            | ExprAST::Comptime(_, data)
+            | ExprAST::NoneLiteral(data)
+            | ExprAST::ErrorValue(_, data)
+            | ExprAST::Wrap(_, data)
+            | ExprAST::Orelse(_, _, data)
+            | ExprAST::Catch { data, .. }
+            | ExprAST::Try(_, data)
+            | ExprAST::Cast(_, _, data) => data,
             ExprAST::Moved => panic!("ExprAST was moved"),
         }
     }
@@ -184,6 +268,28 @@ impl ExprAST {
                 ExprAST::Variable(name.clone(), node_data.relabel()),
             ExprAST::FunctionCall(name, exprs, node_data) => 
                 ExprAST::FunctionCall(name.clone(), exprs.iter().map(ExprAST::duplicate).collect(), node_data.relabel()),
+            ExprAST::MethodCall(receiver, name, exprs, node_data) => 
+                ExprAST::MethodCall(
+                    Box::new(receiver.duplicate()), 
+                    name.clone(), 
+                    exprs.iter().map(ExprAST::duplicate).collect(), 
+                    node_data.relabel()
+                ),
+            ExprAST::IndirectCall(callee, exprs, node_data) =>
+                ExprAST::IndirectCall(
+                    Box::new(callee.duplicate()),
+                    exprs.iter().map(ExprAST::duplicate).collect(),
+                    node_data.relabel()
+                ),
+            ExprAST::FunctionValue(name, node_data) =>
+                ExprAST::FunctionValue(name.clone(), node_data.relabel()),
+            ExprAST::Closure { params, return_type, body, data } =>
+                ExprAST::Closure { 
+                    params: params.clone(), 
+                    return_type: return_type.clone(), 
+                    body: Box::new(body.duplicate()), 
+                    data: data.relabel() 
+                },
             ExprAST::Block(statements, final_expr, node_data) => 
                 ExprAST::Block(
                     statements.iter().map(StatementAST::duplicate).collect(), 
@@ -197,12 +303,25 @@ impl ExprAST {
                     else_branch: else_branch.as_ref().map(|expr| Box::new(ExprAST::duplicate(expr.as_ref()))),
                     data: data.relabel()
                 },
-            ExprAST::While { condition, block, data } => 
+            ExprAST::While { label, condition, block, data } => 
                 ExprAST::While {
+                    label: label.clone(),
                     condition: Box::new(condition.as_ref().duplicate()),
                     block: Box::new(block.as_ref().duplicate()),
                     data: data.relabel()
                 },
+            ExprAST::For { label, variable, start, end, inclusive, block, data } =>
+                ExprAST::For {
+                    label: label.clone(),
+                    variable: variable.clone(),
+                    start: Box::new(start.duplicate()),
+                    end: Box::new(end.duplicate()),
+                    inclusive: *inclusive,
+                    block: Box::new(block.duplicate()),
+                    data: data.relabel()
+                },
+            ExprAST::Loop { label, block, data } =>
+                ExprAST::Loop { label: label.clone(), block: Box::new(block.duplicate()), data: data.relabel() },
             ExprAST::Return(expr, node_data) => {
                 if let Some(expr) = expr {
                     ExprAST::Return(Some(Box::new(expr.duplicate())), node_data.relabel())
@@ -211,6 +330,35 @@ impl ExprAST {
                     ExprAST::Return(None, node_data.relabel())
                 }
             }
+            ExprAST::Break { label, value, data } =>
+                ExprAST::Break { 
+                    label: label.clone(), 
+                    value: value.as_ref().map(|expr| Box::new(expr.duplicate())), 
+                    data: data.relabel() 
+                },
+            ExprAST::Continue { label, data } =>
+                ExprAST::Continue { label: label.clone(), data: data.relabel() },
+            ExprAST::Comptime(block, node_data) =>
+                ExprAST::Comptime(Box::new(block.duplicate()), node_data.relabel()),
+            ExprAST::NoneLiteral(node_data) =>
+                ExprAST::NoneLiteral(node_data.relabel()),
+            ExprAST::ErrorValue(inner, node_data) =>
+                ExprAST::ErrorValue(Box::new(inner.duplicate()), node_data.relabel()),
+            ExprAST::Wrap(inner, node_data) =>
+                ExprAST::Wrap(Box::new(inner.duplicate()), node_data.relabel()),
+            ExprAST::Orelse(left, right, node_data) =>
+                ExprAST::Orelse(Box::new(left.duplicate()), Box::new(right.duplicate()), node_data.relabel()),
+            ExprAST::Catch { expr, binding, handler, data } =>
+                ExprAST::Catch {
+                    expr: Box::new(expr.duplicate()),
+                    binding: binding.clone(),
+                    handler: Box::new(handler.duplicate()),
+                    data: data.relabel()
+                },
+            ExprAST::Try(inner, node_data) =>
+                ExprAST::Try(Box::new(inner.duplicate()), node_data.relabel()),
+            ExprAST::Cast(inner, type_name, node_data) =>
+                ExprAST::Cast(Box::new(inner.duplicate()), type_name.clone(), node_data.relabel()),
             ExprAST::Moved => panic!("ExprAST moved"),
         }
     }
@@ -245,6 +393,10 @@ impl<'a> AnyAST<'a> {
                 }).collect(),
             A::Declaration(D::Function { block: ref mut expr, .. } | D::Variable { ref mut expr, .. }) => 
                 vec![A::Expression(expr)],
+            A::Declaration(D::Trait { .. } | D::Import { .. } | D::Type { .. }) =>
+                vec![],
+            A::Declaration(D::Impl { methods, .. }) =>
+                methods.iter_mut().map(A::Declaration).collect(),
             A::Statement(
                 S::Assignment(ref mut expr_1, ref mut expr_2, ..) 
               | S::CompoundAssignment(ref mut expr_1, ref mut expr_2, ..)
@@ -252,20 +404,33 @@ impl<'a> AnyAST<'a> {
                 vec![A::Expression(expr_1), A::Expression(expr_2)],
             A::Statement(S::Declaration(ref mut dec, ..)) =>
                 vec![A::Declaration(dec)],
-            A::Statement(S::ExpressionStatement(ref mut expr, ..)) =>
+            A::Statement(S::ExpressionStatement(ref mut expr, ..) | S::Defer(ref mut expr, ..)) =>
                 vec![A::Expression(expr)],
             A::Expression(
                 E::IntegerLiteral(..)
                 | E::BooleanLiteral(..)
                 | E::Variable(..)
+                | E::FunctionValue(..)
+                | E::NoneLiteral(..)
                 | E::Return(None, ..)
+                | E::Break { value: None, .. }
+                | E::Continue { .. }
             ) => 
                 vec![],
             A::Expression(
                 E::Not(expr, ..)
               | E::Return(Some(expr), ..)
+              | E::Break { value: Some(expr), .. }
+              | E::Loop { block: expr, .. }
+              | E::ErrorValue(expr, ..)
+              | E::Wrap(expr, ..)
+              | E::Try(expr, ..)
+              | E::Cast(expr, ..)
             ) => 
                 vec![A::Expression(expr.as_mut())],
+            // Once its block has been moved into a function of its own, nothing is left to visit.
+            A::Expression(E::Comptime(block, ..) | E::Closure { body: block, .. }) =>
+                if matches!(block.as_ref(), E::Moved) { vec![] } else { vec![A::Expression(block.as_mut())] },
             A::Expression(
                 E::Add(expr_1, expr_2, ..)
               | E::Subtract(expr_1, expr_2, ..)
@@ -277,13 +442,23 @@ impl<'a> AnyAST<'a> {
               | E::And(expr_1, expr_2, ..)
               | E::If { condition: expr_1, block: expr_2, else_branch: None, .. }
               | E::While { condition: expr_1, block: expr_2, .. }
+              | E::Orelse(expr_1, expr_2, ..)
+              | E::Catch { expr: expr_1, handler: expr_2, .. }
             ) =>
                 vec![A::Expression(expr_1.as_mut()), A::Expression(expr_2.as_mut())],
-            A::Expression(E::If { condition: expr_1, block: expr_2, else_branch: Some(expr_3), .. }) => 
+            A::Expression(
+                E::If { condition: expr_1, block: expr_2, else_branch: Some(expr_3), .. }
+              | E::For { start: expr_1, end: expr_2, block: expr_3, .. }
+            ) => 
                 vec![A::Expression(expr_1.as_mut()), A::Expression(expr_2.as_mut()), A::Expression(expr_3.as_mut())],
             A::Expression(E::FunctionCall(_, exprs, _)) => {
                 exprs.iter_mut().map(A::Expression).collect()
             }     
+            A::Expression(E::MethodCall(receiver, _, exprs, _) | E::IndirectCall(receiver, exprs, _)) => {
+                std::iter::once(A::Expression(receiver.as_mut()))
+                    .chain(exprs.iter_mut().map(A::Expression))
+                    .collect()
+            }
             A::Expression(E::Block(stmts, maybe_expr, ..)) => {
                 let mut vec: Vec<_> = stmts.iter_mut().map(A::Statement).collect();
 
@@ -310,12 +485,17 @@ impl<'a> AnyAST<'a> {
           | A::Declaration(
               | D::Function { node_data, .. }
               | D::Variable { node_data, .. }
+              | D::Trait { node_data, .. }
+              | D::Impl { node_data, .. }
+              | D::Import { node_data, .. }
+              | D::Type { node_data, .. }
             )
           | A::Statement(
               | S::Assignment(_, _, node_data)
               | S::CompoundAssignment(_, _, _, node_data)
               | S::Declaration(_, node_data)
               | S::ExpressionStatement(_, node_data)
+              | S::Defer(_, node_data)
             )
           | A::Expression(
               | E::Add(_, _, node_data) 
@@ -325,6 +505,10 @@ impl<'a> AnyAST<'a> {
               | E::Comparison(_, _, _, node_data)
               | E::Divide(_, _, node_data)
               | E::FunctionCall(_, _, node_data)
+              | E::MethodCall(_, _, _, node_data)
+              | E::IndirectCall(_, _, node_data)
+              | E::FunctionValue(_, node_data)
+              | E::Closure { data: node_data, .. }
               | E::If { data: node_data, .. }
               | E::IntegerLiteral(_, node_data)
               | E::Modulus(_, _, node_data)
@@ -335,6 +519,18 @@ impl<'a> AnyAST<'a> {
               | E::Subtract(_, _, node_data)
               | E::Variable(_, node_data)
               | E::While { data: node_data, .. }
+              | E::For { data: node_data, .. }
+              | E::Loop { data: node_data, .. }
+              | E::Break { data: node_data, .. }
+              | E::Continue { data: node_data, .. }
+              | E::Comptime(_, node_data)
+              | E::NoneLiteral(node_data)
+              | E::ErrorValue(_, node_data)
+              | E::Wrap(_, node_data)
+              | E::Orelse(_, _, node_data)
+              | E::Catch { data: node_data, .. }
+              | E::Try(_, node_data)
+              | E::Cast(_, _, node_data)
             ) => 
                 node_data,
             A::Expression(E::Moved) =>
@@ -397,6 +593,18 @@ fn build_declaration_ast(tree: &ST<Token>) -> Result<DeclarationAST, ASTError> {
         , ST::TokenNode(Token { body: TB::Punctuation(Punc::Semicolon), .. })
         ] if rule_name == "VariableDeclaration" => 
             build_variable_declaration(decl),
+
+        [ ST::RuleNode { rule_name, ..  } ] if rule_name == "TraitDeclaration" =>
+            build_trait_declaration(&children[0]),
+
+        [ ST::RuleNode { rule_name, ..  } ] if rule_name == "ImplDeclaration" =>
+            build_impl_declaration(&children[0]),
+
+        [ ST::RuleNode { rule_name, ..  } ] if rule_name == "ImportDeclaration" =>
+            build_import_declaration(&children[0]),
+
+        [ ST::RuleNode { rule_name, ..  } ] if rule_name == "TypeDeclaration" =>
+            build_type_declaration(&children[0]),
             
         _ => Err("Failed to build Declaration AST".into())
     }
@@ -424,6 +632,28 @@ fn build_statement_ast(tree: &ST<Token>) -> Result<StatementAST, ASTError> {
         , ST::TokenNode (Token { body: TB::Punctuation(Punc::Semicolon), ..})
         ] if rule_name == "CompoundAssignmentStatement" => 
             build_compound_assignment_statement(stmt),
+
+        [ stmt @ ST::RuleNode { rule_name, .. }
+        , ST::TokenNode (Token { body: TB::Punctuation(Punc::Semicolon), span: semicolon_span })
+        ] if rule_name == "DeferStatement" => {
+            let children = assert_rule_get_children(stmt, "DeferStatement")?;
+
+            let [ ST::TokenNode(Token { body: TB::Keyword(Kw::Defer), span: defer_span }), deferred ] = children
+                else { return Err("Failed to build DeferStatement".into()) };
+
+            let span = Span::combine(defer_span, semicolon_span);
+
+            // A deferred assignment is kept as a block holding just that statement.
+            let expr = match deferred {
+                ST::RuleNode { rule_name, .. } if rule_name == "AssignmentStatement" =>
+                    ExprAST::Block(vec![build_assignment_statement(deferred)?], None, ASTNodeData::new(span.clone())),
+                ST::RuleNode { rule_name, .. } if rule_name == "CompoundAssignmentStatement" =>
+                    ExprAST::Block(vec![build_compound_assignment_statement(deferred)?], None, ASTNodeData::new(span.clone())),
+                _ => build_expr_ast(deferred)?,
+            };
+
+            Ok(StatementAST::Defer(expr, ASTNodeData::new(span)))
+        },
         
         [ stmt @ ST::RuleNode { rule_name, .. } ] if rule_name == "Declaration" => {
             let decl = build_declaration_ast(stmt)?;
@@ -470,8 +700,16 @@ fn build_expr_ast(tree: &ST<Token>) -> Result<ExprAST, ASTError> {
                 build_additive_expr(tree),
             ST::RuleNode { rule_name, .. } if rule_name == "MultiplicativeExpression" =>
                 build_multiplicative_expr(tree),
+            ST::RuleNode { rule_name, .. } if rule_name == "PostfixExpression" =>
+                build_postfix_expr(tree),
             ST::RuleNode { rule_name, .. } if rule_name == "ComparisonExpression" => 
                 build_comparision_expr(tree),
+            ST::RuleNode { rule_name, .. } if rule_name == "FallbackExpression" =>
+                build_fallback_expr(tree),
+            ST::RuleNode { rule_name, .. } if rule_name == "TryExpression" =>
+                build_try_expr(tree),
+            ST::RuleNode { rule_name, .. } if rule_name == "CastExpression" =>
+                build_cast_expr(tree),
             ST::RuleNode { rule_name, .. } if rule_name == "OrExpression" =>
                 build_or_expr(tree),
             ST::RuleNode { rule_name, .. } if rule_name == "AndExpression" =>
@@ -482,14 +720,34 @@ fn build_expr_ast(tree: &ST<Token>) -> Result<ExprAST, ASTError> {
                 build_literal_expr(tree),
             ST::RuleNode { ref rule_name, .. } if rule_name == "FunctionCall" => 
                 build_function_call_expr(tree),
+            ST::RuleNode { ref rule_name, .. } if rule_name == "QualifiedName" => {
+                let (name, span) = build_qualified_name(tree)?;
+                Ok(ExprAST::Variable(name, ASTNodeData::new(span)))
+            },
             ST::RuleNode { ref rule_name, .. } if rule_name == "BlockExpression" =>
                 build_block_expr(tree),
             ST::RuleNode { ref rule_name, .. } if rule_name == "IfExpression" => 
                 build_if_expr(tree),
+            ST::RuleNode { ref rule_name, .. } if rule_name == "LabeledLoop" =>
+                build_labeled_loop(tree),
             ST::RuleNode { ref rule_name, .. } if rule_name == "WhileExpression" =>
                 build_while_expr(tree),
             ST::RuleNode { ref rule_name, .. } if rule_name == "ReturnExpression" =>
                 build_return_expr(tree),
+            ST::RuleNode { ref rule_name, .. } if rule_name == "BreakExpression" =>
+                build_break_expr(tree),
+            ST::RuleNode { ref rule_name, .. } if rule_name == "ContinueExpression" =>
+                build_continue_expr(tree),
+            ST::RuleNode { ref rule_name, .. } if rule_name == "ForExpression" =>
+                build_for_expr(tree),
+            ST::RuleNode { ref rule_name, .. } if rule_name == "LoopExpression" =>
+                build_loop_expr(tree),
+            ST::RuleNode { ref rule_name, .. } if rule_name == "ComptimeExpression" =>
+                build_comptime_expr(tree),
+            ST::RuleNode { ref rule_name, .. } if rule_name == "ClosureExpression" =>
+                build_closure_expr(tree),
+            ST::RuleNode { ref rule_name, .. } if rule_name == "ErrorExpression" =>
+                build_error_expr(tree),
             ST::RuleNode { rule_name, subexpressions: _ } => 
                 Err(format!("Expected Expression. Unknown expression node name: {rule_name}").into()),
             ST::TokenNode (Token { body: TB::Identifier(name), span }) =>
@@ -506,7 +764,19 @@ fn build_expr_ast(tree: &ST<Token>) -> Result<ExprAST, ASTError> {
 fn build_function_declaration(tree: &ST<Token>) -> Result<DeclarationAST, ASTError> {
     let children = assert_rule_get_children(tree, "FunctionDeclaration")?;
 
-    if children.len() != 6 {
+    let (public, children) = match children.split_first() {
+        Some((ST::TokenNode(Token { body: TB::Keyword(Kw::Pub), .. }), rest)) => (true, rest),
+        _ => (false, children),
+    };
+
+    // Generic functions have a list of type parameters after the name, which shifts everything else over.
+    let (type_params, shift) = match children.get(2) {
+        Some(node @ ST::RuleNode { rule_name, .. }) if rule_name == "TypeParameterList" => 
+            (build_type_parameter_list(node)?, 1),
+        _ => (vec![], 0),
+    };
+
+    if children.len() != 6 + shift {
         return Err("Incorrect number of subnodes to function node".into());
     }
 
@@ -520,23 +790,67 @@ fn build_function_declaration(tree: &ST<Token>) -> Result<DeclarationAST, ASTErr
         return Err("Expected function name".into());
     };
 
-    if !matches!(children[3], ST::TokenNode(Token {body: TB::Operator(Op::ThinRightArrow), .. })) {
+    if !matches!(children[3 + shift], ST::TokenNode(Token {body: TB::Operator(Op::ThinRightArrow), .. })) {
         return Err("Expected `->` in function declaration".into());
     }
 
-    let return_type = build_type(&children[4])?;
+    let return_type = build_type(&children[4 + shift])?;
 
-    let params = build_parameter_list(&children[2])?;
-    let block = build_expr_ast(&children[5])?;
+    let params = build_parameter_list(&children[2 + shift])?;
+    let block = build_expr_ast(&children[5 + shift])?;
 
     let span = Span::combine(first_span, &block.get_node_data().span);
 
-    Ok(DeclarationAST::Function { name, params, block, node_data: ASTNodeData::new(span), return_type })
+    Ok(DeclarationAST::Function { public, name, type_params, params, block, node_data: ASTNodeData::new(span), return_type })
+}
+
+fn build_import_declaration(tree: &ST<Token>) -> Result<DeclarationAST, ASTError> {
+    let children = assert_rule_get_children(tree, "ImportDeclaration")?;
+
+    let [ ST::TokenNode(Token { body: TB::Keyword(Kw::Import), span: first_span })
+        , ST::TokenNode(Token { body: TB::StringLiteral(path), .. })
+        , ST::TokenNode(Token { body: TB::Punctuation(Punc::Semicolon), span: last_span })
+        ] = children
+        else { return Err("Failed to build import declaration".into()) };
+
+    Ok(DeclarationAST::Import { path: path.clone(), node_data: ASTNodeData::new(Span::combine(first_span, last_span)) })
+}
+
+fn build_type_declaration(tree: &ST<Token>) -> Result<DeclarationAST, ASTError> {
+    let children = assert_rule_get_children(tree, "TypeDeclaration")?;
+
+    let Some(ST::TokenNode(Token { span: first_span, .. })) = children.first()
+        else { return Err("Failed to build type declaration".into()) };
+
+    let (distinct, rest) = match children.split_first() {
+        Some((ST::TokenNode(Token { body: TB::Keyword(Kw::Distinct), .. }), rest)) => (true, rest),
+        _ => (false, children),
+    };
+
+    let [ ST::TokenNode(Token { body: TB::Keyword(Kw::Type), .. })
+        , ST::TokenNode(Token { body: TB::Identifier(name), .. })
+        , ST::TokenNode(Token { body: TB::Operator(Op::Equals), .. })
+        , base
+        , ST::TokenNode(Token { body: TB::Punctuation(Punc::Semicolon), span: last_span })
+        ] = rest
+        else { return Err("Failed to build type declaration".into()) };
+
+    Ok(DeclarationAST::Type { 
+        distinct, 
+        name: name.clone(), 
+        base: build_type(base)?, 
+        node_data: ASTNodeData::new(Span::combine(first_span, last_span)) 
+    })
 }
 
 fn build_variable_declaration(tree: &ST<Token>) -> Result<DeclarationAST, ASTError> {
     let children = assert_rule_get_children(tree, "VariableDeclaration")?;
 
+    let (public, children) = match children.split_first() {
+        Some((ST::TokenNode(Token { body: TB::Keyword(Kw::Pub), .. }), rest)) => (true, rest),
+        _ => (false, children),
+    };
+
     match children {
         [ ST::TokenNode(Token { body: TB::Keyword(keyword @ (Kw::Val | Kw::Var)), span: first_span })
         , ST::TokenNode(Token { body: TB::Identifier(name), .. })
@@ -564,17 +878,133 @@ fn build_variable_declaration(tree: &ST<Token>) -> Result<DeclarationAST, ASTErr
             let span = Span::combine(first_span, &expr.get_node_data().span);
 
             Ok(DeclarationAST::Variable { 
+                public,
                 mutability,
                 name: name.clone(), 
                 expr,
                 node_data: ASTNodeData::new(span),
                 type_ascription,
+                inferred: false,
             })
         }
         _ => Err("Failed to parse variable declaration".into())
     }
 }
 
+fn build_trait_declaration(tree: &ST<Token>) -> Result<DeclarationAST, ASTError> {
+    let children = assert_rule_get_children(tree, "TraitDeclaration")?;
+
+    let [ ST::TokenNode(Token { body: TB::Keyword(Kw::Trait), span: first_span })
+        , ST::TokenNode(Token { body: TB::Identifier(name), .. })
+        , ST::TokenNode(Token { body: TB::Punctuation(Punc::LeftCurlyBrace), .. })
+        , signatures @ ..
+        , ST::TokenNode(Token { body: TB::Punctuation(Punc::RightCurlyBrace), span: last_span })
+        ] = children
+        else { return Err("Failed to build trait declaration".into()) };
+
+    let methods = signatures.iter()
+        .map(build_method_signature)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(DeclarationAST::Trait { name: name.clone(), methods, node_data: ASTNodeData::new(Span::combine(first_span, last_span)) })
+}
+
+fn build_method_signature(tree: &ST<Token>) -> Result<MethodSignature, ASTError> {
+    let children = assert_rule_get_children(tree, "MethodSignature")?;
+
+    let [ ST::TokenNode(Token { body: TB::Keyword(Kw::Fn), .. })
+        , ST::TokenNode(Token { body: TB::Identifier(name), .. })
+        , params
+        , ST::TokenNode(Token { body: TB::Operator(Op::ThinRightArrow), .. })
+        , return_type
+        , ST::TokenNode(Token { body: TB::Punctuation(Punc::Semicolon), .. })
+        ] = children
+        else { return Err("Failed to build method signature".into()) };
+
+    Ok(MethodSignature { name: name.clone(), params: build_method_parameter_list(params)?, return_type: build_type(return_type)? })
+}
+
+fn build_impl_declaration(tree: &ST<Token>) -> Result<DeclarationAST, ASTError> {
+    let children = assert_rule_get_children(tree, "ImplDeclaration")?;
+
+    let [ ST::TokenNode(Token { body: TB::Keyword(Kw::Impl), span: first_span })
+        , trait_name
+        , ST::TokenNode(Token { body: TB::Keyword(Kw::For), .. })
+        , target_type
+        , ST::TokenNode(Token { body: TB::Punctuation(Punc::LeftCurlyBrace), .. })
+        , method_nodes @ ..
+        , ST::TokenNode(Token { body: TB::Punctuation(Punc::RightCurlyBrace), span: last_span })
+        ] = children
+        else { return Err("Failed to build impl declaration".into()) };
+
+    let target_type = build_type(target_type)?;
+
+    let methods = method_nodes.iter()
+        .map(|node| build_method_declaration(node, &target_type))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(DeclarationAST::Impl { 
+        trait_name: build_trait_name(trait_name)?, 
+        target_type, 
+        methods, 
+        node_data: ASTNodeData::new(Span::combine(first_span, last_span)) 
+    })
+}
+
+// The trait named by an impl or a bound, which may be in another module.
+fn build_trait_name(tree: &ST<Token>) -> Result<String, ASTError> {
+    let children = assert_rule_get_children(tree, "TraitName")?;
+
+    match children {
+        [ ST::TokenNode(Token { body: TB::Identifier(name), .. }) ] => Ok(name.clone()),
+        [ qualified_name ] => Ok(build_qualified_name(qualified_name)?.0),
+        _ => Err("Expected trait name".into()),
+    }
+}
+
+// A name is kept together with its module, e.g. `math::LIMIT`.
+fn build_qualified_name(tree: &ST<Token>) -> Result<(String, Span), ASTError> {
+    let children = assert_rule_get_children(tree, "QualifiedName")?;
+
+    let [ ST::TokenNode(Token { body: TB::Identifier(module), span: first_span })
+        , ST::TokenNode(Token { body: TB::Punctuation(Punc::DoubleColon), .. })
+        , ST::TokenNode(Token { body: TB::Identifier(name), span: last_span })
+        ] = children
+        else { return Err("Failed to build qualified name".into()) };
+
+    Ok((format!("{module}::{name}"), Span::combine(first_span, last_span)))
+}
+
+// Methods become ordinary functions, with `self` as the first parameter.
+fn build_method_declaration(tree: &ST<Token>, target_type: &str) -> Result<DeclarationAST, ASTError> {
+    let children = assert_rule_get_children(tree, "MethodDeclaration")?;
+
+    let [ ST::TokenNode(Token { body: TB::Keyword(Kw::Fn), span: first_span })
+        , ST::TokenNode(Token { body: TB::Identifier(name), .. })
+        , params
+        , ST::TokenNode(Token { body: TB::Operator(Op::ThinRightArrow), .. })
+        , return_type
+        , block
+        ] = children
+        else { return Err("Failed to build method declaration".into()) };
+
+    let mut all_params = vec![("self".to_string(), target_type.to_string())];
+    all_params.append(&mut build_method_parameter_list(params)?);
+
+    let block = build_expr_ast(block)?;
+    let span = Span::combine(first_span, &block.get_node_data().span);
+
+    Ok(DeclarationAST::Function { 
+        public: true,
+        name: name.clone(), 
+        type_params: vec![], 
+        params: all_params, 
+        block, 
+        return_type: build_type(return_type)?, 
+        node_data: ASTNodeData::new(span) 
+    })
+}
+
 fn build_assignment_statement(tree: &ST<Token>) -> Result<StatementAST, ASTError> {
     let children = assert_rule_get_children(tree, "AssignmentStatement")?;
 
@@ -670,6 +1100,36 @@ fn build_multiplicative_expr(tree: &ST<Token>) -> Result<ExprAST, ASTError> {
     })
 }
 
+fn build_postfix_expr(tree: &ST<Token>) -> Result<ExprAST, ASTError> {
+    let children = assert_rule_get_children(tree, "PostfixExpression")?;
+
+    let (first, method_calls) = children.split_first().ok_or(ASTError::from("Expected subtree"))?;
+    let mut receiver = build_expr_ast(first)?;
+
+    for method_call in method_calls {
+        let children = assert_rule_get_children(method_call, "MethodCall")?;
+
+        let [ ST::TokenNode(Token { body: TB::Operator(Op::Dot), .. })
+            , ST::TokenNode(Token { body: TB::Identifier(name), .. })
+            , ST::TokenNode(Token { body: TB::Punctuation(Punc::LeftParenthesis), .. })
+            , arg_list @ ..
+            , ST::TokenNode(Token { body: TB::Punctuation(Punc::RightParenthesis), span: last_span })
+            ] = children
+            else { return Err("Failed to build method call".into()) };
+
+        // Arguments at even positions, commas between them.
+        let args = arg_list.iter()
+            .step_by(2)
+            .map(build_expr_ast)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let span = Span::combine(&receiver.get_node_data().span, last_span);
+        receiver = ExprAST::MethodCall(Box::new(receiver), name.clone(), args, ASTNodeData::new(span));
+    }
+
+    Ok(receiver)
+}
+
 fn build_comparision_expr(tree: &ST<Token>) -> Result<ExprAST, ASTError> {
     let children = assert_rule_get_children(tree, "ComparisonExpression")?;
     
@@ -699,6 +1159,85 @@ fn build_comparision_expr(tree: &ST<Token>) -> Result<ExprAST, ASTError> {
     }
 }
 
+// Left to right, so `a orelse b orelse c` tries a, then b, then c.
+fn build_fallback_expr(tree: &ST<Token>) -> Result<ExprAST, ASTError> {
+    let children = assert_rule_get_children(tree, "FallbackExpression")?;
+
+    let (first, mut rest) = children.split_first().ok_or(ASTError::from("Expected subtree"))?;
+    let mut expr = build_expr_ast(first)?;
+
+    while !rest.is_empty() {
+        let (binding, fallback) = match rest {
+            [ ST::TokenNode(Token { body: TB::Keyword(Kw::Orelse), .. }), fallback, tail @ .. ] => {
+                rest = tail;
+                (None, fallback)
+            },
+            [ ST::TokenNode(Token { body: TB::Keyword(Kw::Catch), .. })
+            , ST::TokenNode(Token { body: TB::Operator(Op::Pipe), .. })
+            , ST::TokenNode(Token { body: TB::Identifier(name), .. })
+            , ST::TokenNode(Token { body: TB::Operator(Op::Pipe), .. })
+            , fallback
+            , tail @ ..
+            ] => {
+                rest = tail;
+                (Some(Some(name.clone())), fallback)
+            },
+            [ ST::TokenNode(Token { body: TB::Keyword(Kw::Catch), .. }), fallback, tail @ .. ] => {
+                rest = tail;
+                (Some(None), fallback)
+            },
+            _ => return Err("Expected orelse or catch".into()),
+        };
+
+        let fallback = Box::new(build_expr_ast(fallback)?);
+        let data = ASTNodeData::new(Span::combine(&expr.get_node_data().span, &fallback.get_node_data().span));
+
+        expr = match binding {
+            None => ExprAST::Orelse(Box::new(expr), fallback, data),
+            Some(binding) => ExprAST::Catch { expr: Box::new(expr), binding, handler: fallback, data },
+        };
+    }
+
+    Ok(expr)
+}
+
+fn build_try_expr(tree: &ST<Token>) -> Result<ExprAST, ASTError> {
+    let children = assert_rule_get_children(tree, "TryExpression")?;
+    
+    if children.len() == 2 {
+        let ST::TokenNode(Token {body: TB::Keyword(Kw::Try), span: ref first_span }) = children[0]
+            else { return Err("Expected keyword try".into()) };
+
+        let inner = build_expr_ast(&children[1])?;
+        let span = Span::combine(first_span, &inner.get_node_data().span);
+
+        Ok(ExprAST::Try(Box::new(inner), ASTNodeData::new(span)))
+    }
+    else {
+        build_expr_ast(&children[0])
+    }
+}
+
+// Casts chain left to right, e.g. `x as Meters as u32`
+fn build_cast_expr(tree: &ST<Token>) -> Result<ExprAST, ASTError> {
+    let children = assert_rule_get_children(tree, "CastExpression")?;
+
+    let Some((first, rest)) = children.split_first()
+        else { return Err("Expected an expression to cast".into()) };
+
+    let mut expr = build_expr_ast(first)?;
+
+    for pair in rest.chunks(2) {
+        let [ ST::TokenNode(Token { body: TB::Keyword(Kw::As), span: as_span }), type_node ] = pair
+            else { return Err("Expected `as` and a type".into()) };
+
+        let span = Span::combine(&expr.get_node_data().span, as_span);
+        expr = ExprAST::Cast(Box::new(expr), build_type(type_node)?, ASTNodeData::new(span));
+    }
+
+    Ok(expr)
+}
+
 fn build_or_expr(tree: &ST<Token>) -> Result<ExprAST, ASTError> {
     let children = assert_rule_get_children(tree, "OrExpression")?;
     
@@ -765,6 +1304,8 @@ fn build_literal_expr(tree: &ST<Token>) -> Result<ExprAST, ASTError> {
         },
         ST::RuleNode { .. } =>
             Err("Unexpected rule node under Literal node".into()),
+        ST::TokenNode(Token { body: TB::Keyword(Kw::None), span }) =>
+            Ok(ExprAST::NoneLiteral(ASTNodeData::new(span.clone()))),
         ST::TokenNode(Token { body: TB::NumericLiteral(str), span }) => {
             let num = str.parse()
                 .map_err(|_| ASTError("Integer parse failed. Literals must fit in i128".to_string()))?;
@@ -781,7 +1322,16 @@ fn build_function_call_expr(tree: &ST<Token>) -> Result<ExprAST, ASTError> {
     let ST::TokenNode (Token {body: TB::Identifier(name), span: first_span }) = &children[0]
         else { return Err("Could not find identifier in FunctionCall".into()) };
 
-    if !matches!(&children[1], ST::TokenNode (Token {body: TB::Punctuation(Punc::LeftParenthesis), .. })) {
+    // A qualified name is kept together, e.g. `math::gcd`.
+    let (name, children) = match &children[1..] {
+        [ ST::TokenNode (Token {body: TB::Punctuation(Punc::DoubleColon), .. })
+        , ST::TokenNode (Token {body: TB::Identifier(inner_name), .. })
+        , rest @ ..
+        ] => (format!("{name}::{inner_name}"), rest),
+        rest => (name.clone(), rest),
+    };
+
+    if !matches!(&children[0], ST::TokenNode (Token {body: TB::Punctuation(Punc::LeftParenthesis), .. })) {
         return Err("Expected left parenthesis".into());
     }
 
@@ -789,7 +1339,7 @@ fn build_function_call_expr(tree: &ST<Token>) -> Result<ExprAST, ASTError> {
         = &children[children.len() - 1]
         else { return Err("Expected right parenthesis".into()) };
 
-    let arg_list = &children[2..children.len() - 1];
+    let arg_list = &children[1..children.len() - 1];
     let mut iter = arg_list.iter();
 
     let mut expressions = vec![];
@@ -803,7 +1353,7 @@ fn build_function_call_expr(tree: &ST<Token>) -> Result<ExprAST, ASTError> {
         }
     }
 
-    Ok(ExprAST::FunctionCall(name.clone(), expressions, ASTNodeData::new(Span::combine(first_span, last_span))))
+    Ok(ExprAST::FunctionCall(name, expressions, ASTNodeData::new(Span::combine(first_span, last_span))))
 }
 
 fn build_block_expr(tree: &ST<Token>) -> Result<ExprAST, ASTError> {
@@ -887,7 +1437,78 @@ fn build_while_expr(tree: &ST<Token>) -> Result<ExprAST, ASTError> {
 
     let span = Span::combine(first_span, &block.get_node_data().span);
 
-    Ok(ExprAST::While { condition, block, data: ASTNodeData::new(span) })
+    Ok(ExprAST::While { label: None, condition, block, data: ASTNodeData::new(span) })
+}
+
+// The loop is built first, and then given the label, if there is one.
+fn build_labeled_loop(tree: &ST<Token>) -> Result<ExprAST, ASTError> {
+    let children = assert_rule_get_children(tree, "LabeledLoop")?;
+
+    match children {
+        [ loop_node ] => build_expr_ast(loop_node),
+        [ ST::TokenNode(Token { body: TB::Label(name), span: first_span })
+        , ST::TokenNode(Token { body: TB::Punctuation(Punc::Colon), .. })
+        , loop_node
+        ] => {
+            let mut expr = build_expr_ast(loop_node)?;
+
+            let (ExprAST::While { label, data, .. } | ExprAST::For { label, data, .. } | ExprAST::Loop { label, data, .. }) = &mut expr
+                else { return Err("Expected a loop after the label".into()) };
+
+            *label = Some(name.clone());
+            data.span = Span::combine(first_span, &data.span);
+
+            Ok(expr)
+        }
+        _ => Err("Failed to build labeled loop".into())
+    }
+}
+
+fn build_loop_expr(tree: &ST<Token>) -> Result<ExprAST, ASTError> {
+    let children = assert_rule_get_children(tree, "LoopExpression")?;
+
+    if children.len() != 2 {
+        return Err("Expected 2 subexpressions for LoopExpression".into());
+    }
+
+    let ST::TokenNode(Token {body: TB::Keyword(Kw::Loop), span: ref first_span }) = children[0]
+        else { return Err("Expected keyword loop".into()) };
+
+    let block = Box::new(build_block_expr(&children[1])?);
+
+    let span = Span::combine(first_span, &block.get_node_data().span);
+
+    Ok(ExprAST::Loop { label: None, block, data: ASTNodeData::new(span) })
+}
+
+fn build_for_expr(tree: &ST<Token>) -> Result<ExprAST, ASTError> {
+    let children = assert_rule_get_children(tree, "ForExpression")?;
+
+    if children.len() != 7 {
+        return Err("Expected 7 subexpressions for ForExpression".into());
+    }
+
+    let ST::TokenNode(Token {body: TB::Keyword(Kw::For), span: ref first_span }) = children[0]
+        else { return Err("Expected keyword for".into()) };
+
+    let ST::TokenNode(Token {body: TB::Identifier(ref variable), .. }) = children[1]
+        else { return Err("Expected loop variable".into()) };
+
+    let start = Box::new(build_expr_ast(&children[3])?);
+
+    let inclusive = match children[4] {
+        ST::TokenNode(Token {body: TB::Operator(Op::DotDot), .. }) => false,
+        ST::TokenNode(Token {body: TB::Operator(Op::DotDotEquals), .. }) => true,
+        _ => return Err("Expected range operator".into()),
+    };
+
+    let end = Box::new(build_expr_ast(&children[5])?);
+
+    let block = Box::new(build_block_expr(&children[6])?);
+
+    let span = Span::combine(first_span, &block.get_node_data().span);
+
+    Ok(ExprAST::For { label: None, variable: variable.clone(), start, end, inclusive, block, data: ASTNodeData::new(span) })
 }
 
 fn build_return_expr(tree: &ST<Token>) -> Result<ExprAST, ASTError> {
@@ -912,6 +1533,117 @@ fn build_return_expr(tree: &ST<Token>) -> Result<ExprAST, ASTError> {
     Ok(ExprAST::Return(expr, ASTNodeData::new(span)))
 }
 
+fn build_break_expr(tree: &ST<Token>) -> Result<ExprAST, ASTError> {
+    let children = assert_rule_get_children(tree, "BreakExpression")?;
+
+    let [ ST::TokenNode(Token {body: TB::Keyword(Kw::Break), span: ref break_span }), rest @ .. ] = children
+        else { return Err("Expected keyword break".into()) };
+
+    let mut span = break_span.clone();
+
+    let (label, rest) = match rest {
+        [ ST::TokenNode(Token { body: TB::Label(name), span: label_span }), rest @ .. ] => {
+            span = Span::combine(&span, label_span);
+            (Some(name.clone()), rest)
+        },
+        _ => (None, rest),
+    };
+
+    let value = match rest {
+        [] => None,
+        [ expr_node ] => {
+            let expr = Box::new(build_expr_ast(expr_node)?);
+            span = Span::combine(&span, &expr.get_node_data().span);
+            Some(expr)
+        },
+        _ => return Err("Failed to build break expression".into()),
+    };
+
+    Ok(ExprAST::Break { label, value, data: ASTNodeData::new(span) })
+}
+
+fn build_continue_expr(tree: &ST<Token>) -> Result<ExprAST, ASTError> {
+    let children = assert_rule_get_children(tree, "ContinueExpression")?;
+
+    match children {
+        [ ST::TokenNode(Token {body: TB::Keyword(Kw::Continue), span }) ] =>
+            Ok(ExprAST::Continue { label: None, data: ASTNodeData::new(span.clone()) }),
+        [ ST::TokenNode(Token {body: TB::Keyword(Kw::Continue), span: first_span })
+        , ST::TokenNode(Token { body: TB::Label(name), span: last_span })
+        ] =>
+            Ok(ExprAST::Continue { label: Some(name.clone()), data: ASTNodeData::new(Span::combine(first_span, last_span)) }),
+        _ => Err("Failed to build continue expression".into()),
+    }
+}
+
+fn build_comptime_expr(tree: &ST<Token>) -> Result<ExprAST, ASTError> {
+    let children = assert_rule_get_children(tree, "ComptimeExpression")?;
+
+    if children.len() != 2 {
+        return Err("Expected 2 subexpressions for ComptimeExpression".into());
+    }
+
+    let ST::TokenNode(Token {body: TB::Keyword(Kw::Comptime), span: ref first_span }) = children[0]
+        else { return Err("Expected keyword comptime".into()) };
+
+    let block = Box::new(build_block_expr(&children[1])?);
+
+    let span = Span::combine(first_span, &block.get_node_data().span);
+
+    Ok(ExprAST::Comptime(block, ASTNodeData::new(span)))
+}
+
+fn build_error_expr(tree: &ST<Token>) -> Result<ExprAST, ASTError> {
+    let children = assert_rule_get_children(tree, "ErrorExpression")?;
+
+    let [ ST::TokenNode(Token { body: TB::Keyword(Kw::Error), span: first_span })
+        , ST::TokenNode(Token { body: TB::Punctuation(Punc::LeftParenthesis), .. })
+        , inner
+        , ST::TokenNode(Token { body: TB::Punctuation(Punc::RightParenthesis), span: last_span })
+        ] = children
+        else { return Err("Failed to build error expression".into()) };
+
+    let inner = Box::new(build_expr_ast(inner)?);
+
+    Ok(ExprAST::ErrorValue(inner, ASTNodeData::new(Span::combine(first_span, last_span))))
+}
+
+fn build_closure_expr(tree: &ST<Token>) -> Result<ExprAST, ASTError> {
+    let children = assert_rule_get_children(tree, "ClosureExpression")?;
+
+    let [ ST::TokenNode(Token { body: TB::Operator(Op::Pipe), span: first_span }), rest @ .. ] = children
+        else { return Err("Expected | before closure parameters".into()) };
+
+    let closing_pipe = rest.iter()
+        .position(|node| matches!(node, ST::TokenNode(Token { body: TB::Operator(Op::Pipe), .. })))
+        .ok_or(ASTError::from("Expected | after closure parameters"))?;
+
+    // Each parameter is a name, a colon, a type, and then a comma unless it is the last.
+    let params = rest[..closing_pipe].chunks(4)
+        .map(|chunk| match chunk {
+            [ ST::TokenNode(Token {body: TB::Identifier(name), .. })
+            , ST::TokenNode(Token {body: TB::Punctuation(Punc::Colon), .. })
+            , type_node
+            , ..
+            ] => Ok((name.clone(), build_type(type_node)?)),
+            _ => Err("Failed to build closure parameter".into()),
+        })
+        .collect::<Result<Vec<_>, ASTError>>()?;
+
+    let (return_type, body) = match &rest[closing_pipe + 1..] {
+        [ body ] => (None, build_expr_ast(body)?),
+        [ ST::TokenNode(Token {body: TB::Operator(Op::ThinRightArrow), .. })
+        , return_type
+        , body
+        ] => (Some(build_type(return_type)?), build_block_expr(body)?),
+        _ => return Err("Failed to build closure body".into()),
+    };
+
+    let span = Span::combine(first_span, &body.get_node_data().span);
+
+    Ok(ExprAST::Closure { params, return_type, body: Box::new(body), data: ASTNodeData::new(span) })
+}
+
 
 /* Functions that build components of AST Nodes */
 
@@ -955,11 +1687,83 @@ fn build_parameter_list(node: &ST<Token>) -> Result<Vec<(String, String)>, ASTEr
     Ok(parameters)
 }
 
+fn build_type_parameter_list(node: &ST<Token>) -> Result<Vec<(String, Vec<String>)>, ASTError> {
+    let children = assert_rule_get_children(node, "TypeParameterList")?;
+
+    if !matches!(children.first(), Some(ST::TokenNode(Token {body: TB::Operator(Op::Less), .. }))) {
+        return Err("Expected <".into());
+    }
+    if !matches!(children.last(), Some(ST::TokenNode(Token {body: TB::Operator(Op::Greater), .. }))) {
+        return Err("Expected >".into());
+    }
+
+    // Type parameters at even positions, commas between them.
+    children[1..children.len() - 1].iter()
+        .step_by(2)
+        .map(build_type_parameter)
+        .collect()
+}
+
+// A name, followed by any number of trait bounds.
+fn build_type_parameter(node: &ST<Token>) -> Result<(String, Vec<String>), ASTError> {
+    let children = assert_rule_get_children(node, "TypeParameter")?;
+
+    let Some(ST::TokenNode(Token {body: TB::Identifier(name), .. })) = children.first()
+        else { return Err("Expected type parameter name".into()) };
+
+    // Bounds at even positions, after the colon, separated by pluses.
+    let bounds = children.iter()
+        .skip(2)
+        .step_by(2)
+        .map(build_trait_name)
+        .collect::<Result<Vec<_>, ASTError>>()?;
+
+    Ok((name.clone(), bounds))
+}
+
+// Returns the parameters after `self`.
+fn build_method_parameter_list(node: &ST<Token>) -> Result<Vec<(String, String)>, ASTError> {
+    let children = assert_rule_get_children(node, "MethodParameterList")?;
+
+    let [ ST::TokenNode(Token {body: TB::Punctuation(Punc::LeftParenthesis), .. })
+        , ST::TokenNode(Token {body: TB::Identifier(first), .. })
+        , rest @ ..
+        , ST::TokenNode(Token {body: TB::Punctuation(Punc::RightParenthesis), .. })
+        ] = children
+        else { return Err("Failed to build method parameter list".into()) };
+
+    if first != "self" {
+        return Err("Expected self as the first parameter of a method".into());
+    }
+
+    // Each parameter is a comma, a name, a colon, and a type.
+    rest.chunks(4)
+        .map(|chunk| match chunk {
+            [ ST::TokenNode(Token {body: TB::Punctuation(Punc::Comma), .. })
+            , ST::TokenNode(Token {body: TB::Identifier(name), .. })
+            , ST::TokenNode(Token {body: TB::Punctuation(Punc::Colon), .. })
+            , type_node
+            ] => Ok((name.clone(), build_type(type_node)?)),
+            _ => Err("Failed to build method parameter".into()),
+        })
+        .collect()
+}
+
+// Types are kept as they are spelled until analysis, with function types written out in
+// full, e.g. `fn(i32, bool) -> i32`, and optionals and error unions as `?i32` and `u8!i32`.
 fn build_type(tree: &ST<Token>) -> Result<String, ASTError> {
     if let ST::RuleNode { rule_name, subexpressions } = tree {
         if rule_name == "Type" {
-            if let ST::TokenNode(Token {body: TB::Identifier(ident), .. }) = &subexpressions[0] {
-                return Ok(ident.clone());
+            match subexpressions.as_slice() {
+                [ ST::TokenNode(Token {body: TB::Identifier(ident), .. }) ] => return Ok(ident.clone()),
+                [ ST::TokenNode(Token {body: TB::Identifier(error), .. })
+                , ST::TokenNode(Token {body: TB::Operator(Op::Bang), .. })
+                , payload
+                ] => return Ok(format!("{error}!{}", build_type(payload)?)),
+                [ ST::TokenNode(Token {body: TB::Operator(Op::Question), .. }), payload ] => 
+                    return Ok(format!("?{}", build_type(payload)?)),
+                [ function_type @ ST::RuleNode { .. } ] => return build_function_type(function_type),
+                _ => (),
             }
         }
     }
@@ -967,6 +1771,27 @@ fn build_type(tree: &ST<Token>) -> Result<String, ASTError> {
     Err("Could not build Type node".into())
 }
 
+fn build_function_type(tree: &ST<Token>) -> Result<String, ASTError> {
+    let children = assert_rule_get_children(tree, "FunctionType")?;
+
+    let [ ST::TokenNode(Token {body: TB::Keyword(Kw::Fn), .. })
+        , ST::TokenNode(Token {body: TB::Punctuation(Punc::LeftParenthesis), .. })
+        , param_list @ ..
+        , ST::TokenNode(Token {body: TB::Punctuation(Punc::RightParenthesis), .. })
+        , ST::TokenNode(Token {body: TB::Operator(Op::ThinRightArrow), .. })
+        , return_type
+        ] = children
+        else { return Err("Failed to build function type".into()) };
+
+    // Types at even positions, commas between them.
+    let params = param_list.iter()
+        .step_by(2)
+        .map(build_type)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(format!("fn({}) -> {}", params.join(", "), build_type(return_type)?))
+}
+
 
 /* Helpers for AST build functions */
 
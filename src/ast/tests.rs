@@ -40,7 +40,7 @@ fn ast_has_spans(resource: &str) {
 
     validate_spans(&mut AnyAST::File(&mut ast));
 
-    crate::analysis::desugar(&mut ast);
+    crate::analysis::desugar(&mut ast).unwrap();
 
     validate_spans(&mut AnyAST::File(&mut ast));
 
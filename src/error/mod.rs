@@ -66,6 +66,68 @@ impl From<String> for TokenError {
     }
 }
 
+#[derive(Debug)]
+pub struct FormatError (pub String);
+
+impl From<&str> for FormatError {
+    fn from(value: &str) -> Self {
+        FormatError(value.to_string())
+    }
+}
+
+impl From<String> for FormatError {
+    fn from(value: String) -> Self {
+        FormatError(value)
+    }
+}
+
+#[derive(Debug)]
+pub struct VerifyError (pub String);
+
+impl From<&str> for VerifyError {
+    fn from(value: &str) -> Self {
+        VerifyError(value.to_string())
+    }
+}
+
+impl From<String> for VerifyError {
+    fn from(value: String) -> Self {
+        VerifyError(value)
+    }
+}
+
+#[derive(Debug)]
+pub struct AssemblyError (pub String);
+
+impl From<&str> for AssemblyError {
+    fn from(value: &str) -> Self {
+        AssemblyError(value.to_string())
+    }
+}
+
+impl From<String> for AssemblyError {
+    fn from(value: String) -> Self {
+        AssemblyError(value)
+    }
+}
+
+// Why a program stopped early, when the program itself did nothing wrong. Either way, it
+// can be run again to carry on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimeError {
+    OutOfFuel,
+    Interrupted,
+}
+
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuntimeError::OutOfFuel => write!(f, "Out of Fuel"),
+            RuntimeError::Interrupted => write!(f, "Interrupted"),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum CompileError {
     Direct (String),
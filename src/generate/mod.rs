@@ -2,21 +2,26 @@
 mod optimize_instructions;  // Makes optimizations at the instruction level.
 
 
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::hash::Hash;
 
-use crate::{CompilationEnvironment, util};
+use crate::{CompilationEnvironment, CompilationGoal, util};
 use crate::ast::{DeclarationAST, ExprAST, StatementAST};
-use crate::analysis::types::Type;
-use crate::instructions::{Instruction, IntSize, IntegerBinaryOperation, Constant};
+use crate::analysis::types::{Type, BuiltIn, payload_offset, representation};
+use crate::analysis::{Global, ConstantValue, Function, LocalScope, constant_name, comptime_block_name, closure_name};
+use crate::analysis::builtins::{lookup_builtin, BuiltinFunction, BuiltinKind};
+use crate::instructions::{Instruction, IntSize, IntegerBinaryOperation, Comparison, Constant};
+use crate::program::{Program, StackMap, FunctionSymbol, LocalSymbol, ValueFormat, LineEntry};
 use crate::util::reinterpret;
+use crate::token::Span;
 use crate::error::GenerateError;
 
 use optimize_instructions::optimize;
 
 
-pub struct CodeGenerator {
-    functions: HashMap<String, FunctionInfo>,
+pub struct CodeGenerator<'a> {
+    functions: HashMap<String, FunctionInfo<'a>>,
 }
 
 #[derive(Clone, Debug)]
@@ -28,73 +33,205 @@ enum PseudoInstruction {
 #[derive(Clone, Debug)]
 enum TempInstruction {
     Call (String),  // Call a function by name (we don't yet know its index).
+    PushFunction (String),  // Push a function as a value. Its index is tagged, see CallIndirect.
     #[allow(unused)] JumpIfTrue (u32),  // This is a unique id. This corresponds to a jump instruction later.
     Jump (u32),
     JumpIfFalse (u32),
     JumpFrom (u32),  // This will be removed (will not be an actual instruction), 
                      // but allows reasoning about jumps without counting instructions early on (before optimization).
+    StackMap (StackMap),  // Also not an actual instruction. Attaches a stack map to the safepoint that follows it.
+    Trap (String),  // Ends the program with the message, which is moved into the program's message table.
+    EnterSpan (Span),  // Not actual instructions either. Until the matching ExitSpan, instructions come from the span,
+    ExitSpan,          // unless a nested span says otherwise. These become the line table.
 }
 
 
-impl CodeGenerator {
-    pub fn new() -> CodeGenerator {
+impl<'a> CodeGenerator<'a> {
+    pub fn new() -> CodeGenerator<'a> {
         CodeGenerator { functions: HashMap::new() }
     }
 
-    pub(super) fn generate(mut self, env: &CompilationEnvironment) -> Result<Vec<Instruction>, GenerateError> {
+    pub(super) fn generate(self, env: &'a CompilationEnvironment) -> Result<Program, GenerateError> {
         use PseudoInstruction as PI;
         use Instruction as I;
 
-        // TODO: Pruning? Here or in lib / analysis
-        
-        let function_list = env.functions.iter()
-            .map(|(name, func)| (name, &func.ast))
-            .collect::<Vec<(_, _)>>();
+        // Functions that were never needed are never checked, so they are left out. Those
+        // run during compilation are already done.
+        // They are sorted, so that the same source always compiles to the same program.
+        let mut function_names = env.functions.keys()
+            .filter(|name| env.queue.is_processed(&CompilationGoal::TypeCheck((*name).clone())))
+            .filter(|name| !env.comptime.contains_key(*name))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        function_names.sort();
+
+        // Driver - initializes the global variables, then calls the main.
+        let mut instructions = vec![];
+
+        for global_name in &env.global_initializers {
+            let Some(Global::Variable { offset, global_type, .. }) = env.globals.get(global_name)
+                else { return Err(format!("Could not find global {global_name}").into()) };
+
+            let size = env.types.get(global_type).ok_or(GenerateError::from("Type not found"))?.size;
+
+            instructions.push(PI::Actual(I::AdvanceStackPtr(size.max(8))));  // Space for the value, like main's.
+            instructions.push(PI::Temp(TempInstruction::Call(crate::analysis::initializer_name(global_name))));
+            instructions.push(PI::Actual(I::RetractStackPtr(size.max(8) - size)));  // Move to the value
+
+            if size != 0 {
+                instructions.append(&mut pop_pieces(size, |piece, int_size| I::StoreGlobal(offset + piece, int_size))?);
+            }
+        }
+
+        // If main returns an error union, an error ends the program with its code. Otherwise,
+        // the value is printed as usual.
+        let main_type = env.functions.get("main").map(|main| &main.return_type);
+        let main_size = main_type.and_then(|main_type| env.types.get(main_type)).map_or(0, |type_info| type_info.size);
+
+        instructions.extend([
+            PI::Actual(I::AdvanceStackPtr(main_size.max(8))),  // Space for return value. Alignment for main()
+            PI::Temp(TempInstruction::Call("main".to_string())),
+        ]);
+
+        if let Some(main_type @ Type::ErrorUnion(error_type, payload_type)) = main_type {
+            let Type::BuiltIn(error_builtin) = representation(&env.distinct_types, error_type)
+                else { return Err("Expected an integer error type".into()) };
+
+            let error_size = env.types[error_type.as_ref()].size;
+            let payload_size = env.types[payload_type.as_ref()].size;
+            let offset = payload_offset(&env.types, main_type);
+            let value_jump_id = util::next_id();
+
+            instructions.extend([
+                PI::Actual(I::ReadBase(0, IntSize::OneByte)),  // The tag, at the bottom of the stack.
+                PI::Temp(TempInstruction::JumpIfTrue(value_jump_id)),
+                PI::Actual(I::RetractStackPtr(main_size.max(8) - offset - error_size)),
+                PI::Actual(I::ExitWithError(error_size.try_into()?, error_builtin.is_signed())),
+                PI::Temp(TempInstruction::JumpFrom(value_jump_id)),
+                PI::Actual(I::RetractStackPtr(main_size.max(8) - offset - payload_size)),  // Move to the value
+                PI::Actual(I::DebugPrintSigned(IntSize::FourByte)),
+                PI::Actual(I::Exit)
+            ]);
+
+            instructions = Self::resolve_jumps(instructions)?;
+        }
+        else {
+            instructions.extend([
+                PI::Actual(I::RetractStackPtr(4)),  // Move to return value
+                PI::Actual(I::DebugPrintSigned(IntSize::FourByte)),
+                PI::Actual(I::Exit)
+            ]);
+        }
+
+        self.link(env, &function_names, instructions)
+    }
+
+    // Generates a program that runs a single function during compilation, leaving its
+    // value at the bottom of the stack. `function_names` must include everything it calls.
+    pub(crate) fn generate_evaluation(self, env: &'a CompilationEnvironment, entry: &str, 
+        function_names: &[String]) -> Result<Program, GenerateError> {
+        use PseudoInstruction as PI;
+        use Instruction as I;
+
+        let return_type = &env.functions.get(entry).ok_or(GenerateError::from("Could not find function"))?.return_type;
+        let size = env.types.get(return_type).ok_or(GenerateError::from("Type not found"))?.size;
+
+        let driver = vec![
+            PI::Actual(I::AdvanceStackPtr(size.max(8))),  // Space for the value, like main's.
+            PI::Temp(TempInstruction::Call(entry.to_string())),
+            PI::Actual(I::RetractStackPtr(size.max(8) - size)),  // Move to the value
+            PI::Actual(I::Exit),
+        ];
+
+        self.link(env, function_names, driver)
+    }
+
+    // Generates the functions, and lays them out after the driver. Main comes first.
+    fn link(mut self, env: &'a CompilationEnvironment, function_names: &[String], 
+        mut instructions: Vec<PseudoInstruction>) -> Result<Program, GenerateError> {
 
         // Preprocess step: Determine the local variable storage locations
-        for (fn_name, _) in &function_list {
-            self.functions.insert((*fn_name).to_string(), FunctionInfo::new(env, fn_name)?);
+        for fn_name in function_names {
+            self.functions.insert(fn_name.clone(), FunctionInfo::new(env, fn_name)?);
         }
 
         // Process functions and generate code
 
-        for (fn_name, block) in &function_list {
-            let instructions = self.generate_function(env, block, fn_name)?;
+        for fn_name in function_names {
+            let instructions = self.generate_function(env, &env.functions[fn_name].ast, fn_name)?;
 
-            let fn_info = self.functions.get_mut(*fn_name)
+            let fn_info = self.functions.get_mut(fn_name)
                 .ok_or(GenerateError("Could not find function".to_string()))?;
 
             fn_info.initial_code = instructions;
         }
 
-        // Driver - calls the main.
-        let mut instructions = vec![
-            PI::Actual(I::AdvanceStackPtr(8)),  // Space for return value. Alignment for main()
-            PI::Temp(TempInstruction::Call("main".to_string())),
-            PI::Actual(I::RetractStackPtr(4)),  // Move to return value
-            PI::Actual(I::DebugPrintSigned(IntSize::FourByte)),
-            PI::Actual(I::Exit)
-        ];
+        let mut function_locations: HashMap<String, usize> = HashMap::new();
+        let mut program = Program { data_size: env.data_size, ..Default::default() };
 
+        let main_first = function_names.iter().filter(|name| *name == "main")
+            .chain(function_names.iter().filter(|name| *name != "main"));
 
-        let mut function_locations: HashMap<String, usize> = HashMap::new();
+        for fn_name in main_first {
+            let start = effective_len(&instructions);
+            self.layout_function(fn_name, &mut instructions)?;
 
-        function_locations.insert("main".to_string(), instructions.len());
-        self.layout_function("main", &mut instructions)?;
+            let fn_info = &self.functions[fn_name];
 
-        for (fn_name, _) in function_list {
-            if fn_name != "main" {
-                function_locations.insert(fn_name.to_string(), instructions.len());
-                self.layout_function(fn_name, &mut instructions)?;
+            function_locations.insert(fn_name.clone(), start);
+            program.functions.push(FunctionSymbol { 
+                name: fn_name.clone(), 
+                start, 
+                end: effective_len(&instructions),
+                locals: fn_info.local_symbols(env, fn_name),
+                operand_start: 16 + fn_info.body_depth(env, &env.functions[fn_name].ast)?,
+            });
+        }
+
+        for global in env.globals.values() {
+            if let Global::Variable { offset, global_type, .. } = global {
+                if let Some(reference_offset) = global_type.heap_reference_offset() {
+                    program.data_roots.push(offset + reference_offset);
+                }
             }
         }
 
-        instructions.into_iter()
-            .map(|instr| match instr {
-                PseudoInstruction::Actual(instr) => Ok(instr),
+        program.data_roots.sort();
+
+        // The spans enclosing the current instruction, innermost last.
+        let mut spans: Vec<Span> = vec![];
+
+        for instr in instructions {
+            if let (false, Some(span)) = (is_marker(&instr), spans.last()) {
+                add_line_entry(&mut program, span);
+            }
+
+            match instr {
+                PseudoInstruction::Actual(instr) => program.instructions.push(instr),
                 PseudoInstruction::Temp(TempInstruction::Call(name)) => {
                     let location = function_locations.get(&name).ok_or(GenerateError(format!("Could not find function named {name}")))?;
-                    Ok(Instruction::Call(*location))
+                    program.instructions.push(Instruction::Call(*location));
+                }
+                PseudoInstruction::Temp(TempInstruction::PushFunction(name)) => {
+                    let location = function_locations.get(&name).ok_or(GenerateError(format!("Could not find function named {name}")))?;
+                    program.instructions.push(Instruction::PushConstant(Constant::EightByte(((*location as u64) << 1) | 1)));
+                }
+                PseudoInstruction::Temp(TempInstruction::StackMap(stack_map)) => {
+                    program.stack_maps.insert(program.instructions.len(), stack_map);
+                }
+                PseudoInstruction::Temp(TempInstruction::Trap(message)) => {
+                    let index = program.messages.iter().position(|existing| *existing == message)
+                        .unwrap_or_else(|| {
+                            program.messages.push(message);
+                            program.messages.len() - 1
+                        });
+
+                    program.instructions.push(Instruction::Trap(index));
+                }
+                PseudoInstruction::Temp(TempInstruction::EnterSpan(span)) => spans.push(span),
+                PseudoInstruction::Temp(TempInstruction::ExitSpan) => {
+                    spans.pop().ok_or(GenerateError::from("Expected spans to be balanced"))?;
                 }
                 PseudoInstruction::Temp(
                     TempInstruction::JumpIfTrue(..) 
@@ -103,63 +240,47 @@ impl CodeGenerator {
                     | TempInstruction::Jump(..)
                 ) => {
                     
-                    Err("Expected jump pseudo instructions to be removed".into())
+                    return Err("Expected jump pseudo instructions to be removed".into());
                 }
-            })
-            .collect::<Result<_, _>>()
+            }
+        }
+
+        Ok(program)
     }
 
+    // A jump id has a single target, but may have many sources, such as the breaks of a loop.
     fn resolve_jumps(instructions: Vec<PseudoInstruction>) -> Result<Vec<PseudoInstruction>, GenerateError> {
         use PseudoInstruction as PI;
         use TempInstruction as T;
         use Instruction as I;
 
-        // Maps jump id to the source and target effective indices
-        let mut jumps: HashMap<u32, (Option<usize>, Option<usize>)> = HashMap::new();
+        // Maps jump id to the target effective index
+        let mut targets: HashMap<u32, usize> = HashMap::new();
 
+        // Instructions (without the targets), each paired with its effective index
         let mut final_instructions = vec![];
 
         let mut effective_index = 0;
         for instr in instructions {
             match instr {
-                PI::Temp(T::JumpIfTrue(i) | T::JumpIfFalse(i) | T::Jump(i)) => {
-                    jumps.entry(i).or_insert((None, None));
-
-                    let entry = jumps.get_mut(&i).unwrap();
-
-                    if entry.0.is_none() {
-                        entry.0 = Some(effective_index);
-                    }
-                    else {
-                        return Err("Two jump sources with same id".into());
-                    }
-                },
                 PI::Temp(T::JumpFrom(i)) => {
-                    jumps.entry(i).or_insert((None, None));
-
-                    let entry = jumps.get_mut(&i).unwrap();
-
-                    if entry.1.is_none() {
-                        entry.1 = Some(effective_index);
-                    }
-                    else {
-                        return Err("Two jump sources with same id".into());
+                    if targets.insert(i, effective_index).is_some() {
+                        return Err("Two jump targets with same id".into());
                     }
                 },
-                _ => (),
-            }
-
-            if !matches!(instr, PI::Temp(T::JumpFrom(_))) {
-                effective_index += 1;
-                final_instructions.push(instr);
+                _ if is_marker(&instr) => final_instructions.push((effective_index, instr)),  // Zero width, so it is kept until linking.
+                _ => {
+                    final_instructions.push((effective_index, instr));
+                    effective_index += 1;
+                }
             }
         }
 
         final_instructions.into_iter()
-            .map(|instr| match instr {
+            .map(|(start, instr)| match instr {
                 PI::Temp(ref temp @ (T::JumpIfTrue(i) | T::JumpIfFalse(i) | T::Jump(i))) => {
-                    if let Some((Some(start), Some(end))) = jumps.get(&i) {
-                        let shift = *end as i32 - *start as i32;
+                    if let Some(end) = targets.get(&i) {
+                        let shift = *end as i32 - start as i32;
                         match temp {
                             T::JumpIfTrue(_) => Ok(PI::Actual(I::RelativeJumpIfTrue(shift))),
                             T::JumpIfFalse(_) => Ok(PI::Actual(I::RelativeJumpIfFalse(shift))),
@@ -168,7 +289,7 @@ impl CodeGenerator {
                         }
                     }
                     else {
-                        Err("Could not find start and end to jump".into())
+                        Err("Could not find target to jump to".into())
                     }
                 },
                 i => Ok(i),
@@ -186,24 +307,59 @@ impl CodeGenerator {
         Ok(())
     }
 
-    fn generate_function(&self, env: &CompilationEnvironment, subtree: &ExprAST, name: &str) -> Result<Vec<PseudoInstruction>, GenerateError> {
+    fn generate_function(&self, env: &'a CompilationEnvironment, subtree: &'a ExprAST, name: &str) -> Result<Vec<PseudoInstruction>, GenerateError> {
         let function_info = self.functions.get(name).ok_or(GenerateError("Failed to find function".to_string()))?;
 
-        let mut instructions = vec![];
+        // The prologue and epilogue belong to the function as a whole.
+        let mut instructions = vec![PseudoInstruction::Temp(TempInstruction::EnterSpan(subtree.get_node_data().span.clone()))];
 
-        // TODO: Better alignment functions.
-        let expr_type = &env.type_index[&subtree.get_node_data().id];
-        let expr_type_info = env.types.get(expr_type).ok_or(GenerateError("Type not found".to_string()))?;
+        let depth = function_info.body_depth(env, subtree)?;
+
+        let closure = function_info.variables.get(&Variable::Closure);
 
-        let mut depth = function_info.top - 16;  // Skipping the saved registers is done by Call
-        let alignment = get_align_shift(depth, expr_type_info.alignment);  
-        depth += alignment;
+        // The prologue makes room for the locals before writing to them, and passes values
+        // through the stack above them, at alignment 8.
+        let above_locals = depth + get_align_shift(depth, 8);
+        let has_prologue = !function_info.heap_locals.is_empty() || closure.is_some();
+
+        if has_prologue {
+            instructions.push(PseudoInstruction::Actual(Instruction::AdvanceStackPtr(above_locals)));
+        }
+
+        // Locals holding heap addresses must never contain garbage, in case a collection
+        // happens before they are assigned.
+        for offset in &function_info.heap_locals {
+            instructions.push(PseudoInstruction::Actual(Instruction::PushConstant(Constant::EightByte(0))));
+            instructions.push(PseudoInstruction::Actual(Instruction::WriteBase(*offset, IntSize::EightByte)));
+        }
+
+        // A closure starts by copying what it captured into its locals.
+        if let Some((closure_offset, _)) = closure {
+            for (inner, offset, size) in closure_layout(env, name)?.captures {
+                let (local_offset, _) = function_info.variables[&Variable::Local(inner)];
+
+                for (piece, int_size) in pieces(size)? {
+                    instructions.extend([
+                        PseudoInstruction::Actual(Instruction::ReadBase(*closure_offset, IntSize::EightByte)),
+                        PseudoInstruction::Actual(Instruction::PushConstant(Constant::EightByte((offset + piece) as u64))),
+                        PseudoInstruction::Actual(Instruction::IntegerBinaryOperation(IntegerBinaryOperation::UnsignedAddition, IntSize::EightByte)),
+                        PseudoInstruction::Actual(Instruction::HeapRead(int_size)),
+                        PseudoInstruction::Actual(Instruction::WriteBase(local_offset + piece as isize, int_size)),
+                    ]);
+                }
+            }
+        }
+
+        if has_prologue {
+            instructions.push(PseudoInstruction::Actual(Instruction::RetractStackPtr(above_locals)));
+        }
 
         instructions.push(PseudoInstruction::Actual(Instruction::AdvanceStackPtr(depth)));
 
         instructions.append(&mut self.generate_expression(env, subtree, function_info, depth)?);  // TODO: Should this be zero or function_info.top. Can we call it depth?
 
         instructions.append(&mut Self::generate_return(function_info)?); //
+        instructions.push(PseudoInstruction::Temp(TempInstruction::ExitSpan));
 
         let instructions = optimize(instructions);
         let instructions = Self::resolve_jumps(instructions)?;
@@ -221,15 +377,8 @@ impl CodeGenerator {
         let (return_location, size) = function_info.variables.get(&Variable::Return)
             .ok_or(GenerateError("Return type not analyzed".to_string()))?;
         
-        match size {
-            0 => (),
-            1 | 2 | 4 | 8 => {
-                let int_size: IntSize = (*size).try_into()?;
-                instructions.push(PseudoInstruction::Actual(Instruction::WriteBase(*return_location, int_size)));
-            } 
-            _ => {
-                return Err("Not yet implemented: Moving types with weird sizes".into());
-            }
+        if *size != 0 {
+            instructions.append(&mut pop_pieces(*size, |piece, int_size| Instruction::WriteBase(return_location + piece as isize, int_size))?);
         }
 
         instructions.push(PseudoInstruction::Actual(Instruction::Return));
@@ -242,14 +391,14 @@ impl CodeGenerator {
     // Expressions are being evaluated as rvalues, not as lvalues. In particular, pass a variable here is you want to put its
     // value on the stack, but see generate_statement if you want to store something into that variable.
     #[allow(clippy::too_many_lines)]
-    fn generate_expression(&self, env: &CompilationEnvironment, subtree: &ExprAST, 
-        function_info: &FunctionInfo, depth: usize) -> Result<Vec<PseudoInstruction>, GenerateError> {
+    fn generate_expression(&self, env: &'a CompilationEnvironment, subtree: &'a ExprAST, 
+        function_info: &FunctionInfo<'a>, depth: usize) -> Result<Vec<PseudoInstruction>, GenerateError> {
 
         use ExprAST as E;
         use PseudoInstruction as PI;
         use Instruction as I;
 
-        let mut instructions = vec![];
+        let mut instructions = vec![PI::Temp(TempInstruction::EnterSpan(subtree.get_node_data().span.clone()))];
 
         match subtree {
             E::Add(left, right, ..)
@@ -292,29 +441,7 @@ impl CodeGenerator {
                 }
             },
             E::Comparison(left, right, comparison, ..) => {
-                let left_type = &env.type_index[&left.get_node_data().id];
-                let right_type = &env.type_index[&right.get_node_data().id];
-
-                if left_type != right_type {
-                    return Err("Left and Right part of comparison have different types".into())
-                }
-
-                let align_shift = get_align_shift(depth, env.types[left_type].alignment);
-
-                let Type::BuiltIn(builtin_type) = left_type
-                    else { return Err("Tried to compare non builtin types".into()) };
-
-                let Some(int_size) = builtin_type.get_int_size()
-                    else { return Err("Tried to compare builtin type without int_size".into()) };
-
-                instructions.push(PI::Actual(I::AdvanceStackPtr(align_shift)));
-
-                instructions.append(&mut self.generate_expression(env, left, function_info, depth + align_shift)?);
-                instructions.append(&mut self.generate_expression(env, right, function_info, depth + align_shift + env.types[left_type].size)?);
-
-                instructions.push(PI::Actual(I::IntegerComparisonOperation { comparison: *comparison, size: int_size, signed: builtin_type.is_signed() }));
-
-                instructions.push(PI::Actual(I::RetractMoving(align_shift, IntSize::OneByte)));
+                instructions.append(&mut self.generate_comparison(env, left, right, *comparison, function_info, depth)?);
             },
             E::And(left, right, _) | E::Or(left, right, _) => {
                 let jump_id = util::next_id();
@@ -341,48 +468,41 @@ impl CodeGenerator {
                 instructions.push(PI::Actual(I::BooleanNot));
             },
             E::IntegerLiteral(num, data) => {
-                let num_type = &env.type_index[&data.id];
-
-                let Type::BuiltIn(builtin) = num_type
-                    else { return Err("Literal has non built in type".into()) };
-
-                let int_size = builtin.get_int_size().ok_or(GenerateError::from("Literal type did not fit in int"))?;
-
-                if builtin.is_signed() {
-                    match int_size {
-                        IntSize::OneByte => instructions.push(PI::Actual(I::PushConstant(Constant::OneByte(reinterpret::<i8, u8>(*num as i8))))),
-                        IntSize::TwoByte => instructions.push(PI::Actual(I::PushConstant(Constant::TwoByte(reinterpret::<i16, u16>(*num as i16))))),
-                        IntSize::FourByte => instructions.push(PI::Actual(I::PushConstant(Constant::FourByte(reinterpret::<i32, u32>(*num as i32))))),
-                        IntSize::EightByte => instructions.push(PI::Actual(I::PushConstant(Constant::EightByte(reinterpret::<i64, u64>(*num as i64))))),
-                    }
-                }
-                else {
-                    match int_size {
-                        IntSize::OneByte => instructions.push(PI::Actual(I::PushConstant(Constant::OneByte(*num as u8)))),
-                        IntSize::TwoByte => instructions.push(PI::Actual(I::PushConstant(Constant::TwoByte(*num as u16)))),
-                        IntSize::FourByte => instructions.push(PI::Actual(I::PushConstant(Constant::FourByte(*num as u32)))),
-                        IntSize::EightByte => instructions.push(PI::Actual(I::PushConstant(Constant::EightByte(*num as u64)))),
-                    }
-                }
+                instructions.push(push_constant(ConstantValue::Integer(*num), &env.type_index[&data.id])?);
             }
-            E::BooleanLiteral(val, ..) => {
-                match val {
-                    true => instructions.push(PI::Actual(I::PushConstant(Constant::OneByte(1)))),
-                    false => instructions.push(PI::Actual(I::PushConstant(Constant::OneByte(0)))),
-                }
+            E::BooleanLiteral(val, data) => {
+                instructions.push(push_constant(ConstantValue::Boolean(*val), &env.type_index[&data.id])?);
+            }
+            E::Comptime(_, data) => {
+                let value = env.comptime[&comptime_block_name(data.id)].value
+                    .ok_or(GenerateError::from("Comptime block was not evaluated"))?;
+
+                instructions.push(push_constant(value, representation(&env.distinct_types, &env.type_index[&data.id]))?);
             }
             E::Variable(name, ..) => {
                 // This is placing a variable's value on the stack. See statement for storing
                 // a variable.
 
-                // TODO: Shadowing...
                 if let Some((offset, size)) = function_info.variable_info_by_name(name) {
                     if size != 0 {
-                        instructions.push(PI::Actual(I::ReadBase(offset, IntSize::try_from(size)?)));
+                        instructions.append(&mut push_pieces(size, |piece, int_size| I::ReadBase(offset + piece as isize, int_size))?);
+                    }
+                }
+                else if let Some((offset, size)) = global_variable_info(env, name)? {
+                    if size != 0 {
+                        instructions.append(&mut push_pieces(size, |piece, int_size| I::LoadGlobal(offset + piece, int_size))?);
                     }
                 }
+                else if let Some(Global::Constant { global_type, .. }) = env.globals.get(name) {
+                    let value = env.comptime[&constant_name(name)].value
+                        .ok_or(GenerateError::from("Constant was not evaluated"))?;
+
+                    instructions.push(push_constant(value, representation(&env.distinct_types, global_type))?);
+                }
             }
-            E::Block(statements, expr, ..) => {
+            E::Block(statements, expr, data) => {
+                function_info.defers.borrow_mut().push(vec![]);
+
                 for statement in statements {
                     instructions.append(&mut self.generate_statement(env, statement, function_info, depth)?);
                 }
@@ -390,7 +510,17 @@ impl CodeGenerator {
                 if let Some(expr) = expr {
                     instructions.append(&mut self.generate_expression(env, expr, function_info, depth)?);
                 }
+
+                // The defers of this block run above its value.
+                let level = function_info.defers.borrow().len() - 1;
+                instructions.append(&mut self.generate_defers(env, &env.type_index[&data.id], function_info, level, depth)?);
+                function_info.defers.borrow_mut().pop();
             }
+            E::FunctionCall(name, subexprs, data) if lookup_builtin(name).is_some() => {
+                let builtin = lookup_builtin(name).expect("known exists");
+
+                instructions.append(&mut self.generate_builtin_call(env, &builtin, subexprs, &data.span, function_info, depth)?);
+            },
             E::FunctionCall(name, subexprs, ..) => {
                 // We assume that the depth is already such that a value from the function
                 // Can be aligned. If the alignment is not 8 though, we shift, run the function,
@@ -417,32 +547,112 @@ impl CodeGenerator {
                     );
                 }
 
+                // Arguments already evaluated must survive any collection while later ones are.
+                let temporaries = function_info.live_temporaries.borrow().len();
+
                 for (expr, param) in subexprs.iter().zip(&info.parameters) {
                     let (param_loc, size) = info.variables.get(param).expect("Known exists");
 
                     instructions.push(PI::Actual(I::AdvanceStackPtr((param_loc - relative_position) as usize)));
                     relative_position = *param_loc;
+
+                    let arg_depth = depth + align_shift + (relative_position - relative_return_loc) as usize;
                     
-                    instructions.append(&mut self.generate_expression(
-                        env, 
-                        expr, 
-                        function_info, 
-                        depth + align_shift + (relative_position - relative_return_loc) as usize
-                    )?);
+                    instructions.append(&mut self.generate_expression(env, expr, function_info, arg_depth)?);
+                    function_info.hold_temporary(&env.type_index[&expr.get_node_data().id], arg_depth);
+
                     relative_position += *size as isize;
                 }
 
                 // Finally align to function call.
                 instructions.push(PI::Actual(I::AdvanceStackPtr((-relative_position) as usize)));
 
+                instructions.push(PI::Temp(TempInstruction::StackMap(function_info.stack_map())));
                 instructions.push(PI::Temp(TempInstruction::Call(name.clone())));
+                function_info.release_temporaries(temporaries);
                 
                 instructions.push(PI::Actual(I::RetractStackPtr((-relative_return_loc) as usize - return_size)));
 
                 // Retract moving to original expression location
-                match return_size {
-                    0 => instructions.push(PI::Actual(I::RetractStackPtr(align_shift))),
-                    _ => instructions.push(PI::Actual(I::RetractMoving(align_shift, (*return_size).try_into()?))),
+                instructions.append(&mut retract_moving(depth + align_shift, align_shift, *return_size)?);
+            },
+            // Like a call by name, except that the function value goes first, in a slot of
+            // its own below the return value. The layout comes from the type of the function.
+            E::IndirectCall(callee, subexprs, ..) => {
+                let callee_type = &env.type_index[&callee.get_node_data().id];
+                let Type::Function(parameter_types, return_type) = callee_type
+                    else { return Err("Expected to call a function".into()) };
+
+                let CallLayout { return_value: (return_loc, return_size), parameters, end } = call_layout(env, parameter_types, return_type)?;
+
+                let align_shift = get_align_shift(depth, 8);
+                let position = depth + align_shift;
+
+                instructions.push(PI::Actual(I::AdvanceStackPtr(align_shift)));
+                instructions.append(&mut self.generate_expression(env, callee, function_info, position)?);
+
+                let temporaries = function_info.hold_temporary(callee_type, position);
+
+                instructions.push(PI::Actual(I::AdvanceStackPtr(return_loc + return_size - 8)));
+                let mut relative_position = return_loc + return_size;
+
+                for (expr, (param_loc, size)) in subexprs.iter().zip(parameters) {
+                    instructions.push(PI::Actual(I::AdvanceStackPtr(param_loc - relative_position)));
+
+                    instructions.append(&mut self.generate_expression(env, expr, function_info, position + param_loc)?);
+                    function_info.hold_temporary(&env.type_index[&expr.get_node_data().id], position + param_loc);
+
+                    relative_position = param_loc + size;
+                }
+
+                instructions.push(PI::Actual(I::AdvanceStackPtr(end - relative_position)));
+
+                instructions.push(PI::Actual(I::ReadBase(16 + position as isize, IntSize::EightByte)));
+                instructions.push(PI::Temp(TempInstruction::StackMap(function_info.stack_map())));
+                instructions.push(PI::Actual(I::CallIndirect));
+                function_info.release_temporaries(temporaries);
+
+                instructions.push(PI::Actual(I::RetractStackPtr(end - return_loc - return_size)));
+
+                instructions.append(&mut retract_moving(position + return_loc, align_shift + return_loc, return_size)?);
+            },
+            E::FunctionValue(name, _) => {
+                instructions.push(PI::Temp(TempInstruction::PushFunction(name.clone())));
+            },
+            // Without captures, a closure is just its function. Otherwise, it is a heap block
+            // holding the function and a copy of each captured local.
+            E::Closure { data, .. } => {
+                let name = closure_name(data.id);
+                let ClosureLayout { captures, size } = closure_layout(env, &name)?;
+
+                if captures.is_empty() {
+                    instructions.push(PI::Temp(TempInstruction::PushFunction(name)));
+                }
+                else {
+                    instructions.extend([
+                        PI::Actual(I::PushConstant(Constant::EightByte(size as u64))),
+                        PI::Actual(I::PushConstant(Constant::EightByte(8))),
+                        PI::Temp(TempInstruction::StackMap(function_info.stack_map())),
+                        PI::Actual(I::HeapAlloc),
+                        PI::Actual(I::Duplicate(IntSize::EightByte)),
+                        PI::Temp(TempInstruction::PushFunction(name.clone())),
+                        PI::Actual(I::HeapWrite(IntSize::EightByte)),
+                    ]);
+
+                    for ((outer, _), (_, offset, size)) in env.functions[&name].captures.iter().zip(captures) {
+                        let (outer_offset, _) = function_info.variable_info_by_name(outer)
+                            .ok_or(GenerateError(format!("Could not find captured variable {outer}")))?;
+
+                        for (piece, int_size) in pieces(size)? {
+                            instructions.extend([
+                                PI::Actual(I::Duplicate(IntSize::EightByte)),
+                                PI::Actual(I::PushConstant(Constant::EightByte((offset + piece) as u64))),
+                                PI::Actual(I::IntegerBinaryOperation(IntegerBinaryOperation::UnsignedAddition, IntSize::EightByte)),
+                                PI::Actual(I::ReadBase(outer_offset + piece as isize, int_size)),
+                                PI::Actual(I::HeapWrite(int_size)),
+                            ]);
+                        }
+                    }
                 }
             },
             E::If { condition, block, else_branch: None, .. } => {
@@ -474,11 +684,13 @@ impl CodeGenerator {
 
                 instructions.push(PI::Temp(TempInstruction::JumpFrom(jump_2_id)));
             },
-            E::While { condition, block, .. } => {
-                let mut condition_instrs = self.generate_expression(env, condition, function_info, depth)?;
-                let mut block_instrs = self.generate_expression(env, block, function_info, depth)?;
+            E::While { condition, block, data, .. } => {
                 let skip_jump_id = util::next_id();
                 let back_jump_id = util::next_id();
+                function_info.loops.borrow_mut().insert(data.id, LoopJumps { start: back_jump_id, end: skip_jump_id, depth, defers: function_info.defers.borrow().len() });
+
+                let mut condition_instrs = self.generate_expression(env, condition, function_info, depth)?;
+                let mut block_instrs = self.generate_expression(env, block, function_info, depth)?;
 
                 instructions.push(PI::Temp(TempInstruction::JumpFrom(back_jump_id)));
                 instructions.append(&mut condition_instrs);
@@ -489,6 +701,52 @@ impl CodeGenerator {
 
                 instructions.push(PI::Temp(TempInstruction::JumpFrom(skip_jump_id)));
             },
+            // The block is unit, so nothing is left on the stack until a break puts the value there.
+            E::Loop { block, data, .. } => {
+                let start_jump_id = util::next_id();
+                let end_jump_id = util::next_id();
+                function_info.loops.borrow_mut().insert(data.id, LoopJumps { start: start_jump_id, end: end_jump_id, depth, defers: function_info.defers.borrow().len() });
+
+                instructions.push(PI::Temp(TempInstruction::JumpFrom(start_jump_id)));
+                instructions.append(&mut self.generate_expression(env, block, function_info, depth)?);
+                instructions.push(PI::Temp(TempInstruction::Jump(start_jump_id)));
+
+                instructions.push(PI::Temp(TempInstruction::JumpFrom(end_jump_id)));
+            },
+            // Anything pushed since the loop started is dropped, with the value (if any)
+            // moved down to where the value of the loop goes.
+            E::Break { value, data, .. } => {
+                let target = function_info.loop_target(env, data.id)?;
+
+                match value {
+                    Some(value) => {
+                        let value_type_info = env.types.get(&env.type_index[&value.get_node_data().id])
+                            .ok_or(GenerateError("Type not found".to_string()))?;
+                        let align_shift = get_align_shift(depth, value_type_info.alignment);
+
+                        instructions.push(PI::Actual(I::AdvanceStackPtr(align_shift)));
+                        instructions.append(&mut self.generate_expression(env, value, function_info, depth + align_shift)?);
+
+                        instructions.append(&mut self.generate_defers(env, &env.type_index[&value.get_node_data().id], function_info, 
+                            target.defers, depth + align_shift)?);
+
+                        instructions.append(&mut retract_moving(depth + align_shift, depth + align_shift - target.depth, value_type_info.size)?);
+                    },
+                    None => {
+                        instructions.append(&mut self.generate_defers(env, &Type::BuiltIn(BuiltIn::Unit), function_info, target.defers, depth)?);
+                        instructions.push(PI::Actual(I::RetractStackPtr(depth - target.depth)));
+                    },
+                }
+
+                instructions.push(PI::Temp(TempInstruction::Jump(target.end)));
+            },
+            E::Continue { data, .. } => {
+                let target = function_info.loop_target(env, data.id)?;
+
+                instructions.append(&mut self.generate_defers(env, &Type::BuiltIn(BuiltIn::Unit), function_info, target.defers, depth)?);
+                instructions.push(PI::Actual(I::RetractStackPtr(depth - target.depth)));
+                instructions.push(PI::Temp(TempInstruction::Jump(target.start)));
+            },
             E::Return(Some(expr), _) => {
                 let expr_type = &env.type_index[&expr.get_node_data().id];
                 let expr_type_info = env.types.get(expr_type).ok_or(GenerateError("Type not found".to_string()))?;
@@ -504,45 +762,332 @@ impl CodeGenerator {
                 
                 // Everything above can be its own function, see Statement::Expression too
 
+                instructions.append(&mut self.generate_defers(env, expr_type, function_info, 0, depth + align_shift)?);
                 instructions.append(&mut Self::generate_return(function_info)?); //
             },
             E::Return(None, _) => {
+                instructions.append(&mut self.generate_defers(env, &Type::BuiltIn(BuiltIn::Unit), function_info, 0, depth)?);
                 instructions.append(&mut Self::generate_return(function_info)?); //
             }
+            E::NoneLiteral(data) => {
+                instructions.append(&mut self.generate_wrapped(env, None, &env.type_index[&data.id], 0, function_info, depth)?);
+            },
+            E::ErrorValue(inner, data) => {
+                instructions.append(&mut self.generate_wrapped(env, Some(inner), &env.type_index[&data.id], 0, function_info, depth)?);
+            },
+            E::Wrap(inner, data) => {
+                instructions.append(&mut self.generate_wrapped(env, Some(inner), &env.type_index[&data.id], 1, function_info, depth)?);
+            },
+            E::Orelse(inner, fallback, ..) | E::Catch { expr: inner, handler: fallback, .. } => {
+                let fallback_jump_id = util::next_id();
+                let end_jump_id = util::next_id();
+
+                let (mut unwrap_instrs, position) = self.generate_unwrap(env, inner, function_info, depth, fallback_jump_id)?;
+                instructions.append(&mut unwrap_instrs);
+                instructions.push(PI::Temp(TempInstruction::Jump(end_jump_id)));
+
+                // The whole value is still on the stack. A catch first copies the error to its local.
+                let wrapper_type = &env.type_index[&inner.get_node_data().id];
+
+                instructions.push(PI::Temp(TempInstruction::JumpFrom(fallback_jump_id)));
+
+                if let E::Catch { binding: Some(binding), .. } = subtree {
+                    let (local_offset, size) = function_info.variable_info_by_name(binding)
+                        .ok_or(GenerateError(format!("Could not find variable {binding}")))?;
+                    let error_position = position + payload_offset(&env.types, wrapper_type);
+
+                    if size != 0 {
+                        instructions.push(PI::Actual(I::ReadBase(16 + error_position as isize, size.try_into()?)));
+                        instructions.push(PI::Actual(I::WriteBase(local_offset, size.try_into()?)));
+                    }
+                }
+
+                instructions.push(PI::Actual(I::RetractStackPtr(position + env.types[wrapper_type].size - depth)));
+                instructions.append(&mut self.generate_expression(env, fallback, function_info, depth)?);
+
+                instructions.push(PI::Temp(TempInstruction::JumpFrom(end_jump_id)));
+            },
+            // An error is returned in an error union of the function's return type, built on
+            // top of the one it came in.
+            E::Try(inner, ..) => {
+                let error_jump_id = util::next_id();
+                let end_jump_id = util::next_id();
+
+                let (mut unwrap_instrs, position) = self.generate_unwrap(env, inner, function_info, depth, error_jump_id)?;
+                instructions.append(&mut unwrap_instrs);
+                instructions.push(PI::Temp(TempInstruction::Jump(end_jump_id)));
+
+                let wrapper_type = &env.type_index[&inner.get_node_data().id];
+                let Type::ErrorUnion(error_type, _) = wrapper_type
+                    else { return Err("Expected try to unwrap an error union".into()) };
+
+                let error_position = position + payload_offset(&env.types, wrapper_type);
+                let error_size = env.types[error_type.as_ref()].size;
+
+                let top = position + env.types[wrapper_type].size;
+                let return_type_info = &env.types[&function_info.return_type];
+                let return_position = top + get_align_shift(top, return_type_info.alignment);
+                let return_error_offset = payload_offset(&env.types, &function_info.return_type);
+
+                instructions.extend([
+                    PI::Temp(TempInstruction::JumpFrom(error_jump_id)),
+                    PI::Actual(I::AdvanceStackPtr(return_position + return_error_offset - top)),
+                    PI::Actual(I::ReadBase(16 + error_position as isize, error_size.try_into()?)),
+                    PI::Actual(I::AdvanceStackPtr(return_type_info.size - return_error_offset - error_size)),
+                    PI::Actual(I::PushConstant(Constant::OneByte(0))),
+                    PI::Actual(I::WriteBase(16 + return_position as isize, IntSize::OneByte)),
+                ]);
+                instructions.append(&mut self.generate_defers(env, &function_info.return_type, function_info, 0, return_position)?);
+                instructions.append(&mut Self::generate_return(function_info)?);
+
+                instructions.push(PI::Temp(TempInstruction::JumpFrom(end_jump_id)));
+            },
+            // A distinct type is laid out like its base, so there is nothing to convert.
+            E::Cast(inner, ..) => {
+                instructions.append(&mut self.generate_expression(env, inner, function_info, depth)?);
+            },
+            E::MethodCall(..) => 
+                return Err("Expected method calls to have been resolved".into()),
+            E::For { .. } => 
+                return Err("Expected for loops to have been desugared".into()),
             E::Moved => panic!("ExprAST Moved"),
         }
 
+        instructions.push(PI::Temp(TempInstruction::ExitSpan));
+
         Ok(instructions)
     }
 
-    fn generate_statement(&self, env: &CompilationEnvironment, statement: &StatementAST, 
-        function_info: &FunctionInfo, depth: usize) -> Result<Vec<PseudoInstruction>, GenerateError> {
+    // Builds an optional or an error union with the tag, holding the value of the part (if any).
+    fn generate_wrapped(&self, env: &'a CompilationEnvironment, part: Option<&'a ExprAST>, wrapper_type: &Type, tag: u8,
+        function_info: &FunctionInfo<'a>, depth: usize) -> Result<Vec<PseudoInstruction>, GenerateError> {
+
+        use PseudoInstruction as PI;
+        use Instruction as I;
 
         let mut instructions = vec![];
 
-        match statement {
-            StatementAST::ExpressionStatement(expr, _) => {
-                let expr_type = &env.type_index[&expr.get_node_data().id];
-                let expr_type_info = env.types.get(expr_type).ok_or(GenerateError("Type not found".to_string()))?;
-                
-                let align_shift = get_align_shift(depth, expr_type_info.alignment);
+        let size = env.types.get(wrapper_type).ok_or(GenerateError::from("Type not found"))?.size;
+        let offset = payload_offset(&env.types, wrapper_type);
 
-                // Align
-                instructions.push(PseudoInstruction::Actual(
-                    Instruction::AdvanceStackPtr(align_shift)
-                ));
-    
-                instructions.append(&mut self.generate_expression(env, expr, function_info, depth + align_shift)?);
+        if let Some(part) = part {
+            let part_size = env.types[&env.type_index[&part.get_node_data().id]].size;
 
-                // Ignore generated expression
-                instructions.push(PseudoInstruction::Actual(
-                    Instruction::RetractStackPtr(expr_type_info.size)
-                ));
+            instructions.push(PI::Actual(I::AdvanceStackPtr(offset)));
+            instructions.append(&mut self.generate_expression(env, part, function_info, depth + offset)?);
+            instructions.push(PI::Actual(I::AdvanceStackPtr(size - offset - part_size)));
+        }
+        else {
+            instructions.push(PI::Actual(I::AdvanceStackPtr(size)));
+        }
 
-                // Remove alignment
-                instructions.push(PseudoInstruction::Actual(
-                    Instruction::RetractStackPtr(align_shift)
-                ));
+        instructions.push(PI::Actual(I::PushConstant(Constant::OneByte(tag))));
+        instructions.push(PI::Actual(I::WriteBase(16 + depth as isize, IntSize::OneByte)));
+
+        Ok(instructions)
+    }
+
+    // Evaluates an optional or an error union, and leaves its value at the depth. If there
+    // is none, it jumps to the id instead, leaving the whole thing at the position returned.
+    fn generate_unwrap(&self, env: &'a CompilationEnvironment, wrapped: &'a ExprAST, function_info: &FunctionInfo<'a>, 
+        depth: usize, jump_id: u32) -> Result<(Vec<PseudoInstruction>, usize), GenerateError> {
+
+        use PseudoInstruction as PI;
+        use Instruction as I;
+
+        let mut instructions = vec![];
+
+        let wrapper_type = &env.type_index[&wrapped.get_node_data().id];
+        let wrapper_type_info = env.types.get(wrapper_type).ok_or(GenerateError::from("Type not found"))?;
+        let payload_type = wrapper_type.payload().ok_or(GenerateError::from("Expected an optional or an error union"))?;
+        let payload_size = env.types[payload_type].size;
+        let offset = payload_offset(&env.types, wrapper_type);
+
+        let align_shift = get_align_shift(depth, wrapper_type_info.alignment);
+        let position = depth + align_shift;
+
+        instructions.push(PI::Actual(I::AdvanceStackPtr(align_shift)));
+        instructions.append(&mut self.generate_expression(env, wrapped, function_info, position)?);
+
+        instructions.push(PI::Actual(I::ReadBase(16 + position as isize, IntSize::OneByte)));
+        instructions.push(PI::Temp(TempInstruction::JumpIfFalse(jump_id)));
+
+        instructions.push(PI::Actual(I::RetractStackPtr(wrapper_type_info.size - offset - payload_size)));
+
+        instructions.append(&mut retract_moving(position + offset, align_shift + offset, payload_size)?);
+
+        Ok((instructions, position))
+    }
+
+    // Builtins have no body to call. Their arguments are evaluated in order at alignment 8
+    // (every argument but the last is 8 bytes), and consumed by instructions emitted inline.
+    // Pushes the bool result of comparing two values of the same type.
+    fn generate_comparison(&self, env: &'a CompilationEnvironment, left: &'a ExprAST, right: &'a ExprAST, comparison: Comparison,
+        function_info: &FunctionInfo<'a>, depth: usize) -> Result<Vec<PseudoInstruction>, GenerateError> {
+
+        use PseudoInstruction as PI;
+        use Instruction as I;
+
+        let mut instructions = vec![];
+
+        let left_type = &env.type_index[&left.get_node_data().id];
+        let right_type = &env.type_index[&right.get_node_data().id];
+
+        if left_type != right_type {
+            return Err("Left and Right part of comparison have different types".into())
+        }
+
+        let align_shift = get_align_shift(depth, env.types[left_type].alignment);
+
+        let Type::BuiltIn(builtin_type) = left_type
+            else { return Err("Tried to compare non builtin types".into()) };
+
+        let int_size = match builtin_type {
+            BuiltIn::Pointer => IntSize::EightByte,  // Addresses compare like u64
+            BuiltIn::Boolean => IntSize::OneByte,
+            _ => builtin_type.get_int_size()
+                .ok_or(GenerateError::from("Tried to compare builtin type without int_size"))?,
+        };
+
+        instructions.push(PI::Actual(I::AdvanceStackPtr(align_shift)));
+
+        instructions.append(&mut self.generate_expression(env, left, function_info, depth + align_shift)?);
+
+        let temporaries = function_info.hold_temporary(left_type, depth + align_shift);
+        instructions.append(&mut self.generate_expression(env, right, function_info, depth + align_shift + env.types[left_type].size)?);
+        function_info.release_temporaries(temporaries);
+
+        instructions.push(PI::Actual(I::IntegerComparisonOperation { comparison, size: int_size, signed: builtin_type.is_signed() }));
+
+        instructions.push(PI::Actual(I::RetractMoving(align_shift, IntSize::OneByte)));
+
+        Ok(instructions)
+    }
+
+    fn generate_builtin_call(&self, env: &'a CompilationEnvironment, builtin: &BuiltinFunction, args: &'a [ExprAST], span: &Span,
+        function_info: &FunctionInfo<'a>, depth: usize) -> Result<Vec<PseudoInstruction>, GenerateError> {
+
+        use PseudoInstruction as PI;
+        use Instruction as I;
+
+        let mut instructions = vec![];
+
+        // A trap's message is all there is to go on when it fires, so it says where it came from.
+        let location = format!("{}:{}", span.file, span.start_line);
+
+        if let BuiltinKind::AssertEq = builtin.kind {
+            let [left, right] = args
+                else { return Err("Builtin has wrong number of arguments".into()) };
+
+            let jump_id = util::next_id();
+
+            instructions.append(&mut self.generate_comparison(env, left, right, Comparison::Equals, function_info, depth)?);
+            instructions.extend([
+                PI::Temp(TempInstruction::JumpIfTrue(jump_id)),
+                PI::Temp(TempInstruction::Trap(format!("Equality Assertion Failed at {location}"))),
+                PI::Temp(TempInstruction::JumpFrom(jump_id)),
+            ]);
+
+            return Ok(instructions);
+        }
+
+        if args.len() != builtin.parameter_types.len() {
+            return Err("Builtin has wrong number of arguments".into());
+        }
+
+        let align_shift = get_align_shift(depth, 8);
+        let position = depth + align_shift;
+
+        instructions.push(PI::Actual(I::AdvanceStackPtr(align_shift)));
+
+        let temporaries = function_info.live_temporaries.borrow().len();
+
+        for (i, arg) in args.iter().enumerate() {
+            // The address and offset are combined before any value to store is evaluated.
+            let arg_position = position + 8 * i.min(1);
+
+            instructions.append(&mut self.generate_expression(env, arg, function_info, arg_position)?);
+
+            if i == 0 {
+                function_info.hold_temporary(&builtin.parameter_types[0], arg_position);
+            }
+
+            if i == 1 && matches!(builtin.kind, BuiltinKind::Load(..) | BuiltinKind::Store(..)) {
+                instructions.push(PI::Actual(I::IntegerBinaryOperation(IntegerBinaryOperation::UnsignedAddition, IntSize::EightByte)));
+            }
+        }
+
+        function_info.release_temporaries(temporaries);
+
+        match &builtin.kind {
+            BuiltinKind::Alloc => {
+                instructions.push(PI::Temp(TempInstruction::StackMap(function_info.stack_map())));
+                instructions.push(PI::Actual(I::HeapAlloc));
+            }
+            // Only closures that captured something are in the heap. Other function values
+            // are tagged, so they are odd, and there is nothing to free.
+            BuiltinKind::Free if matches!(env.type_index[&args[0].get_node_data().id], Type::Function(..)) => {
+                let (tagged, end) = (util::next_id(), util::next_id());
+
+                instructions.extend([
+                    PI::Actual(I::Duplicate(IntSize::EightByte)),
+                    PI::Actual(I::PushConstant(Constant::EightByte(2))),
+                    PI::Actual(I::IntegerBinaryOperation(IntegerBinaryOperation::UnsignedModulus, IntSize::EightByte)),
+                    PI::Actual(I::PushConstant(Constant::EightByte(0))),
+                    PI::Actual(I::IntegerComparisonOperation { comparison: Comparison::NotEquals, size: IntSize::EightByte, signed: false }),
+                    PI::Temp(TempInstruction::JumpIfTrue(tagged)),
+                    PI::Actual(I::HeapFree),
+                    PI::Temp(TempInstruction::Jump(end)),
+                    PI::Temp(TempInstruction::JumpFrom(tagged)),
+                    PI::Actual(I::RetractStackPtr(8)),
+                    PI::Temp(TempInstruction::JumpFrom(end)),
+                ]);
+            }
+            BuiltinKind::Free => instructions.push(PI::Actual(I::HeapFree)),
+            BuiltinKind::Null => instructions.push(PI::Actual(I::PushConstant(Constant::EightByte(0)))),
+            BuiltinKind::Load(loaded) => {
+                let size = env.types[&Type::BuiltIn(loaded.clone())].size;
+                instructions.push(PI::Actual(I::HeapRead(size.try_into()?)));
+            }
+            BuiltinKind::Store(stored) => {
+                let size = env.types[&Type::BuiltIn(stored.clone())].size;
+                instructions.push(PI::Actual(I::HeapWrite(size.try_into()?)));
+            }
+            BuiltinKind::Assert => {
+                let jump_id = util::next_id();
+
+                instructions.extend([
+                    PI::Temp(TempInstruction::JumpIfTrue(jump_id)),
+                    PI::Temp(TempInstruction::Trap(format!("Assertion Failed at {location}"))),
+                    PI::Temp(TempInstruction::JumpFrom(jump_id)),
+                ]);
+            }
+            BuiltinKind::Unreachable => {
+                instructions.push(PI::Temp(TempInstruction::Trap(format!("Reached Unreachable Code at {location}"))));
+            }
+            BuiltinKind::AssertEq => return Err("Expected assert_eq to have been generated already".into()),
+        }
+
+        // Move the result (if any) back to the original expression location
+        instructions.append(&mut retract_moving(position, align_shift, env.types[&builtin.return_type].size)?);
+
+        Ok(instructions)
+    }
+
+    fn generate_statement(&self, env: &'a CompilationEnvironment, statement: &'a StatementAST, 
+        function_info: &FunctionInfo<'a>, depth: usize) -> Result<Vec<PseudoInstruction>, GenerateError> {
+
+        let mut instructions = vec![PseudoInstruction::Temp(TempInstruction::EnterSpan(statement.get_node_data().span.clone()))];
+
+        match statement {
+            StatementAST::ExpressionStatement(expr, _) => {
+                instructions.append(&mut self.generate_discarded(env, expr, function_info, depth)?);
+            },
+            // Nothing runs yet. The expression is generated on each way out of the block instead.
+            StatementAST::Defer(expr, _) => {
+                function_info.defers.borrow_mut().last_mut()
+                    .ok_or(GenerateError("Expected defer to be inside a block".to_string()))?
+                    .push(expr);
             },
             StatementAST::Assignment(left, right, ..) => {
                 if let ExprAST::Variable(name, ..) = left {
@@ -557,7 +1102,9 @@ impl CodeGenerator {
             StatementAST::Declaration(decl, ..) => {
                 match decl {
                     DeclarationAST::Function { .. } => 
-                        return Err("Tried to build function in function".into()),  // Lambdas?
+                        return Err("Expected nested functions to have become functions of their own".into()),
+                    DeclarationAST::Trait { .. } | DeclarationAST::Impl { .. } | DeclarationAST::Import { .. } | DeclarationAST::Type { .. } => 
+                        return Err("Tried to build trait, impl, import, or type in function".into()),
                     DeclarationAST::Variable { name, expr, .. } => {
                         instructions.append(&mut self.generate_assignment(env, name, expr, function_info, depth)?);
                     }
@@ -565,11 +1112,73 @@ impl CodeGenerator {
             }
         }
 
+        instructions.push(PseudoInstruction::Temp(TempInstruction::ExitSpan));
+
         Ok(instructions)
     }
 
-    fn generate_assignment(&self, env: &CompilationEnvironment, var_name: &str, expr: &ExprAST,
-        function_info: &FunctionInfo, depth: usize) -> Result<Vec<PseudoInstruction>, GenerateError> {
+    // Evaluates the expression for its side effects only, leaving the stack as it was.
+    fn generate_discarded(&self, env: &'a CompilationEnvironment, expr: &'a ExprAST,
+        function_info: &FunctionInfo<'a>, depth: usize) -> Result<Vec<PseudoInstruction>, GenerateError> {
+
+        let mut instructions = vec![];
+
+        let expr_type = &env.type_index[&expr.get_node_data().id];
+        let expr_type_info = env.types.get(expr_type).ok_or(GenerateError("Type not found".to_string()))?;
+        
+        let align_shift = get_align_shift(depth, expr_type_info.alignment);
+
+        // Align
+        instructions.push(PseudoInstruction::Actual(
+            Instruction::AdvanceStackPtr(align_shift)
+        ));
+
+        instructions.append(&mut self.generate_expression(env, expr, function_info, depth + align_shift)?);
+
+        // Ignore generated expression
+        instructions.push(PseudoInstruction::Actual(
+            Instruction::RetractStackPtr(expr_type_info.size)
+        ));
+
+        // Remove alignment
+        instructions.push(PseudoInstruction::Actual(
+            Instruction::RetractStackPtr(align_shift)
+        ));
+
+        Ok(instructions)
+    }
+
+    // Runs the deferred expressions of the blocks from the level inwards, the latest first. They
+    // run above a value of the given type at the depth, which is being carried out of the blocks.
+    fn generate_defers(&self, env: &'a CompilationEnvironment, value_type: &Type, function_info: &FunctionInfo<'a>, 
+        level: usize, depth: usize) -> Result<Vec<PseudoInstruction>, GenerateError> {
+
+        let mut instructions = vec![];
+
+        let deferred = function_info.defers.borrow()[level..].iter()
+            .flatten()
+            .rev()
+            .copied()
+            .collect::<Vec<_>>();
+
+        if deferred.is_empty() {
+            return Ok(instructions);
+        }
+
+        let size = env.types.get(value_type).ok_or(GenerateError("Type not found".to_string()))?.size;
+        let temporaries = function_info.hold_temporary(value_type, depth);
+
+        for expr in deferred {
+            instructions.append(&mut self.generate_discarded(env, expr, function_info, depth + size)?);
+        }
+
+        function_info.release_temporaries(temporaries);
+
+        Ok(instructions)
+    }
+
+    fn generate_assignment(&self, env: &'a CompilationEnvironment, var_name: &str, expr: &'a ExprAST,
+        function_info: &FunctionInfo<'a>, depth: usize) -> Result<Vec<PseudoInstruction>, GenerateError> {
 
         let mut instructions = vec![];
         
@@ -585,14 +1194,19 @@ impl CodeGenerator {
 
         instructions.append(&mut self.generate_expression(env, expr, function_info, depth + align_shift)?);
 
-        let (offset, size) = function_info.variable_info_by_name(var_name)
-            .ok_or(GenerateError("Could not find local variable".to_string()))?;
-    
         // Store generated expression
-        if size != 0 {
-            instructions.push(PseudoInstruction::Actual(
-                Instruction::WriteBase(offset, size.try_into()?)
-            ));
+        if let Some((offset, size)) = function_info.variable_info_by_name(var_name) {
+            if size != 0 {
+                instructions.append(&mut pop_pieces(size, |piece, int_size| Instruction::WriteBase(offset + piece as isize, int_size))?);
+            }
+        }
+        else if let Some((offset, size)) = global_variable_info(env, var_name)? {
+            if size != 0 {
+                instructions.append(&mut pop_pieces(size, |piece, int_size| Instruction::StoreGlobal(offset + piece, int_size))?);
+            }
+        }
+        else {
+            return Err("Could not find variable".into());
         }
 
         // Remove alignment
@@ -604,6 +1218,121 @@ impl CodeGenerator {
     }
 }
 
+// The offset and size of a global variable, if there is one with that (qualified) name.
+fn global_variable_info(env: &CompilationEnvironment, name: &str) -> Result<Option<(usize, usize)>, GenerateError> {
+    let Some(Global::Variable { offset, global_type, .. }) = env.globals.get(name)
+        else { return Ok(None) };
+
+    let type_info = env.types.get(global_type).ok_or(GenerateError::from("Type not found"))?;
+
+    Ok(Some((*offset, type_info.size)))
+}
+
+// Where a closure keeps each captured local (by its name inside the closure), after the
+// function itself. Empty sized captures are left out.
+fn closure_layout(env: &CompilationEnvironment, name: &str) -> Result<ClosureLayout, GenerateError> {
+    let function = env.functions.get(name).ok_or(GenerateError::from("Could not find closure"))?;
+
+    let mut layout = ClosureLayout { captures: vec![], size: 8 };
+
+    for (_, inner) in &function.captures {
+        let capture_type = function.local_types.get(inner).cloned().flatten()
+            .ok_or(GenerateError::from("Type not specified"))?;
+        let type_info = env.types.get(&capture_type).ok_or(GenerateError::from("Type not found"))?;
+
+        if type_info.size != 0 {
+            layout.size += get_align_shift(layout.size, type_info.alignment);
+            layout.captures.push((inner.clone(), layout.size, type_info.size));
+            layout.size += type_info.size;
+        }
+    }
+
+    Ok(layout)
+}
+
+// Where the return value and arguments of a call to a function of this type go, relative
+// to the closure slot below them. This matches the layout the function itself uses, see
+// FunctionInfo::new.
+fn call_layout(env: &CompilationEnvironment, parameter_types: &[Type], return_type: &Type) -> Result<CallLayout, GenerateError> {
+    let mut top = 8;
+    let mut place = |value_type: &Type| {
+        let type_info = env.types.get(value_type).ok_or(GenerateError::from("Type not found"))?;
+
+        top += get_align_shift(top, type_info.alignment);
+        let position = (top, type_info.size);
+        top += type_info.size;
+
+        Ok::<_, GenerateError>(position)
+    };
+
+    let return_value = place(return_type)?;
+    let parameters = parameter_types.iter().map(&mut place).collect::<Result<Vec<_>, _>>()?;
+
+    Ok(CallLayout { return_value, parameters, end: top + get_align_shift(top, 8) })
+}
+
+// Pushes a value known during compilation.
+fn push_constant(value: ConstantValue, value_type: &Type) -> Result<PseudoInstruction, GenerateError> {
+    use PseudoInstruction as PI;
+    use Instruction as I;
+
+    let Type::BuiltIn(builtin) = value_type
+        else { return Err("Constant has non built in type".into()) };
+
+    let constant = match value {
+        ConstantValue::Boolean(val) => Constant::OneByte(u8::from(val)),
+        ConstantValue::Integer(num) => {
+            let int_size = builtin.get_int_size().ok_or(GenerateError::from("Literal type did not fit in int"))?;
+
+            if builtin.is_signed() {
+                match int_size {
+                    IntSize::OneByte => Constant::OneByte(reinterpret::<i8, u8>(num as i8)),
+                    IntSize::TwoByte => Constant::TwoByte(reinterpret::<i16, u16>(num as i16)),
+                    IntSize::FourByte => Constant::FourByte(reinterpret::<i32, u32>(num as i32)),
+                    IntSize::EightByte => Constant::EightByte(reinterpret::<i64, u64>(num as i64)),
+                }
+            }
+            else {
+                match int_size {
+                    IntSize::OneByte => Constant::OneByte(num as u8),
+                    IntSize::TwoByte => Constant::TwoByte(num as u16),
+                    IntSize::FourByte => Constant::FourByte(num as u32),
+                    IntSize::EightByte => Constant::EightByte(num as u64),
+                }
+            }
+        },
+    };
+
+    Ok(PI::Actual(I::PushConstant(constant)))
+}
+
+// Records that the next instruction comes from the span, unless the line table already says so.
+fn add_line_entry(program: &mut Program, span: &Span) {
+    let file = program.files.iter().position(|file| *file == *span.file)
+        .unwrap_or_else(|| {
+            program.files.push(span.file.to_string());
+            program.files.len() - 1
+        });
+
+    let entry = LineEntry { start: program.instructions.len(), file, line: span.start_line, column: span.start_col };
+
+    if program.lines.last().is_none_or(|last| (last.file, last.line, last.column) != (file, entry.line, entry.column)) {
+        program.lines.push(entry);
+    }
+}
+
+// The number of actual instructions these will become, once linked.
+fn effective_len(instructions: &[PseudoInstruction]) -> usize {
+    instructions.iter()
+        .filter(|instr| !is_marker(instr))
+        .count()
+}
+
+// Whether this only annotates the instructions around it, and will not become one.
+fn is_marker(instruction: &PseudoInstruction) -> bool {
+    matches!(instruction, PseudoInstruction::Temp(TempInstruction::StackMap(_) | TempInstruction::EnterSpan(_) | TempInstruction::ExitSpan))
+}
+
 fn get_align_shift(depth: usize, alignment: usize) -> usize {
     if depth % alignment != 0 {
         alignment - depth % alignment
@@ -613,28 +1342,122 @@ fn get_align_shift(depth: usize, alignment: usize) -> usize {
     }
 }
 
+// How a value of this size is moved: whole, or a word at a time for the 16 byte optionals
+// and error unions. Each piece is an offset into the value, and its size.
+fn pieces(size: usize) -> Result<Vec<(usize, IntSize)>, GenerateError> {
+    match size {
+        16 => Ok(vec![(0, IntSize::EightByte), (8, IntSize::EightByte)]),
+        _ => Ok(vec![(0, size.try_into()?)]),
+    }
+}
+
+// Pushes a value with an instruction for each piece, the lowest first.
+fn push_pieces(size: usize, push: impl Fn(usize, IntSize) -> Instruction) -> Result<Vec<PseudoInstruction>, GenerateError> {
+    Ok(pieces(size)?.into_iter()
+        .map(|(offset, int_size)| PseudoInstruction::Actual(push(offset, int_size)))
+        .collect())
+}
+
+// Pops a value with an instruction for each piece, the highest first.
+fn pop_pieces(size: usize, pop: impl Fn(usize, IntSize) -> Instruction) -> Result<Vec<PseudoInstruction>, GenerateError> {
+    Ok(pieces(size)?.into_iter()
+        .rev()
+        .map(|(offset, int_size)| PseudoInstruction::Actual(pop(offset, int_size)))
+        .collect())
+}
+
+// Moves the value at the top of the stack, which is at the position, down by the amount.
+fn retract_moving(position: usize, amount: usize, size: usize) -> Result<Vec<PseudoInstruction>, GenerateError> {
+    use PseudoInstruction as PI;
+    use Instruction as I;
+
+    match size {
+        0 => Ok(vec![PI::Actual(I::RetractStackPtr(amount))]),
+        16 if amount == 0 => Ok(vec![]),
+        // The first word is copied to the top, so it is moved before the second word can
+        // land on it.
+        16 => {
+            let target = 16 + (position - amount) as isize;
+
+            Ok(vec![
+                PI::Actual(I::ReadBase(16 + position as isize, IntSize::EightByte)),
+                PI::Actual(I::WriteBase(target, IntSize::EightByte)),
+                PI::Actual(I::WriteBase(target + 8, IntSize::EightByte)),
+                PI::Actual(I::RetractStackPtr(amount - 8)),
+            ])
+        },
+        _ => Ok(vec![PI::Actual(I::RetractMoving(amount, size.try_into()?))]),
+    }
+}
+
+// Numbers the locals of the scope and the blocks inside it, in the order they are declared.
+fn add_declared<'s>(scope: &'s LocalScope, declared: &mut HashMap<&'s str, usize>) {
+    for local in &scope.locals {
+        let next = declared.len();
+        declared.entry(local.as_str()).or_insert(next);
+    }
+
+    for block in &scope.blocks {
+        add_declared(block, declared);
+    }
+}
+
 // Information associated with each function. This is a working copy, so many of 
 // the fields are optional.
 #[derive(Debug)]
-struct FunctionInfo {
+struct FunctionInfo<'a> {
     variables: HashMap<Variable, (isize, usize)>, // maps parameters, locals, and the return value to their position and sizes in memory.  
+    return_type: Type,
     top: usize,  // Points to byte one past the topmost local variable
     initial_code: Vec<PseudoInstruction>, // Not optimized, and not linked
     parameters: Vec<Variable>,
+    heap_locals: Vec<isize>,  // Offsets of the words of locals holding heap addresses. These are zeroed on entry.
+    heap_parameters: Vec<isize>,  // Offsets of the words of parameters holding heap addresses.
+    live_temporaries: RefCell<Vec<isize>>,  // Offsets of heap addresses sitting on the stack mid expression.
+    loops: RefCell<HashMap<u32, LoopJumps>>,  // Loops (by id) are added as they are generated, for the breaks inside them.
+    defers: RefCell<Vec<Vec<&'a ExprAST>>>,  // The deferred expressions of each block being generated, innermost last.
+}
+
+// The heap block of a closure with captures. Each capture is a name, position, and size.
+struct ClosureLayout {
+    captures: Vec<(String, usize, usize)>,
+    size: usize,
+}
+
+// The position and size of the return value and each argument of an indirect call.
+struct CallLayout {
+    return_value: (usize, usize),
+    parameters: Vec<(usize, usize)>,
+    end: usize,  // Where the arguments end, aligned to 8.
+}
+
+// Where the breaks and continues of a loop jump to, and the depth the loop started at.
+#[derive(Clone, Copy, Debug)]
+struct LoopJumps {
+    start: u32,
+    end: u32,
+    depth: usize,
+    defers: usize,  // How many blocks were being generated when the loop started. Leaving the loop runs the defers of the rest.
 }
 
-impl FunctionInfo {
-    fn new(env: &CompilationEnvironment, name: &str) -> Result<FunctionInfo, GenerateError> {
+impl<'a> FunctionInfo<'a> {
+    fn new(env: &CompilationEnvironment, name: &str) -> Result<FunctionInfo<'a>, GenerateError> {
+        let analysis_info = env.functions.get(name)
+            .ok_or(GenerateError("Could not find analyzed function data".to_string()))?;
+
         let mut info = FunctionInfo {
             variables: HashMap::new(),
+            return_type: analysis_info.return_type.clone(),
             top: 0,
             initial_code: vec![],  // To be determined later
             parameters: vec![],
+            heap_locals: vec![],
+            heap_parameters: vec![],
+            live_temporaries: RefCell::new(vec![]),
+            loops: RefCell::new(HashMap::new()),
+            defers: RefCell::new(vec![]),
         };
 
-        let analysis_info = env.functions.get(name)
-            .ok_or(GenerateError("Could not find analyzed function data".to_string()))?;
-
         // We first allocate return value and arguments, then we push them behind
         // the base pointer and ensure they have 8 alignment. Then we allocate
         // local variables.
@@ -643,6 +1466,11 @@ impl FunctionInfo {
             .ok_or(GenerateError("Could not find analyzed type data".to_string()))?;
 
     
+        // A closure with captures is passed the heap block holding them, before everything else.
+        if !analysis_info.captures.is_empty() {
+            info.add_variable(Variable::Closure, 8, 8);
+        }
+
         info.add_variable(Variable::Return, return_type_info.size, return_type_info.alignment);
 
         for (name, param_type) in &analysis_info.parameter_types {
@@ -653,6 +1481,12 @@ impl FunctionInfo {
             info.parameters.push(Variable::Parameter(name.clone()));
         }
 
+        let heap_parameters = info.parameters.iter()
+            .zip(&analysis_info.parameter_types)
+            .filter_map(|(param, (_, param_type))| Some(info.variables[param].0 + param_type.heap_reference_offset()? as isize))
+            .chain(info.variables.get(&Variable::Closure).map(|(offset, _)| *offset))
+            .collect::<Vec<_>>();
+
         info.align_variables(8);
 
         // Bump everything added so far below the base pointer
@@ -660,22 +1494,118 @@ impl FunctionInfo {
             *offset -= info.top as isize;
         }
 
+        info.heap_parameters = heap_parameters.into_iter().map(|offset| offset - info.top as isize).collect();
+
         info.top = 16;  // Room for two u64 saved registers
 
-        for (name, local_type) in &analysis_info.local_types {
+        // Locals holding heap addresses are in every stack map, so they never share a slot.
+        let mut local_types = analysis_info.local_types.iter().collect::<Vec<_>>();
+        local_types.sort_by_key(|(name, _)| *name);
+
+        for (name, local_type) in local_types {
             if info.variables.contains_key(&Variable::Parameter(name.clone())) {
                 continue;
             }
 
             let local_type = local_type.clone().ok_or(GenerateError("Type not specified".into()))?;
 
+            if let Some(reference_offset) = local_type.heap_reference_offset() {
+                let local_type_info = env.types.get(&local_type)
+                    .ok_or(GenerateError("Could not find analyzed type data".to_string()))?;
+
+                info.add_variable(Variable::Local(name.clone()), local_type_info.size, local_type_info.alignment);
+                info.heap_locals.push(info.variables[&Variable::Local(name.clone())].0 + reference_offset as isize);
+            }
+        }
+
+        info.add_scope(env, analysis_info, &analysis_info.scope)?;
+
+        Ok(info)
+    }
+
+    // How far above the saved registers the body is evaluated: past the locals, and aligned for its value.
+    fn body_depth(&self, env: &CompilationEnvironment, body: &ExprAST) -> Result<usize, GenerateError> {
+        // TODO: Better alignment functions.
+        let body_type = &env.type_index[&body.get_node_data().id];
+        let body_type_info = env.types.get(body_type).ok_or(GenerateError("Type not found".to_string()))?;
+
+        let depth = self.top - 16;  // Skipping the saved registers is done by Call
+
+        Ok(depth + get_align_shift(depth, body_type_info.alignment))
+    }
+
+    // The parameters and locals, as a debugger shows them, in the order they lie on the stack.
+    fn local_symbols(&self, env: &CompilationEnvironment, name: &str) -> Vec<LocalSymbol> {
+        let analysis_info = &env.functions[name];
+
+        let mut declared = HashMap::new();
+
+        for (parameter, _) in &analysis_info.parameter_types {
+            declared.insert(parameter.as_str(), declared.len());
+        }
+        add_declared(&analysis_info.scope, &mut declared);
+
+        let mut locals = self.variables.iter()
+            .filter_map(|(variable, (offset, size))| {
+                let (Variable::Parameter(unique_name) | Variable::Local(unique_name)) = variable
+                    else { return None };
+
+                let local_type = analysis_info.parameter_types.iter()
+                    .find(|(parameter, _)| parameter == unique_name)
+                    .map(|(_, parameter_type)| parameter_type)
+                    .or_else(|| analysis_info.local_types.get(unique_name)?.as_ref())?;
+
+                let format = match representation(&env.distinct_types, local_type) {
+                    Type::BuiltIn(BuiltIn::Boolean) => ValueFormat::Boolean,
+                    Type::BuiltIn(builtin) if builtin.is_signed() => ValueFormat::Signed,
+                    Type::BuiltIn(builtin) if builtin.is_unsigned() => ValueFormat::Unsigned,
+                    _ => ValueFormat::Bytes,
+                };
+
+                // Shadowing renames locals, e.g. to `<x 42>`, but the source only says x.
+                let source_name = unique_name.strip_prefix('<')
+                    .and_then(|rest| rest.split_once(' '))
+                    .map_or(unique_name.as_str(), |(source_name, _)| source_name);
+
+                Some((declared.get(unique_name.as_str()).copied().unwrap_or(usize::MAX), LocalSymbol { name: source_name.to_string(), type_name: local_type.to_string(), offset: *offset, size: *size, format }))
+            })
+            .collect::<Vec<_>>();
+
+        // Locals of different blocks may share a slot, so those come in the order they were declared.
+        locals.sort_by_key(|(declared, local)| (local.offset, *declared));
+
+        locals.into_iter().map(|(_, local)| local).collect()
+    }
+
+    // Lays out the locals of a block, and then the blocks inside it. Those all start at the
+    // same place, since the locals of one are dead by the time the next one starts.
+    fn add_scope(&mut self, env: &CompilationEnvironment, analysis_info: &Function, scope: &LocalScope) -> Result<(), GenerateError> {
+        for name in &scope.locals {
+            if self.variables.contains_key(&Variable::Local(name.clone())) {
+                continue;  // Already given a slot of its own.
+            }
+
+            let local_type = analysis_info.local_types.get(name).cloned().flatten()
+                .ok_or(GenerateError("Type not specified".into()))?;
+
             let local_type_info = env.types.get(&local_type)
                 .ok_or(GenerateError("Could not find analyzed type data".to_string()))?;
 
-            info.add_variable(Variable::Local(name.clone()), local_type_info.size, local_type_info.alignment);
+            self.add_variable(Variable::Local(name.clone()), local_type_info.size, local_type_info.alignment);
         }
 
-        Ok(info)
+        let start = self.top;
+        let mut end = start;
+
+        for block in &scope.blocks {
+            self.top = start;
+            self.add_scope(env, analysis_info, block)?;
+            end = end.max(self.top);
+        }
+
+        self.top = end;
+
+        Ok(())
     }
 
     // Ensures correct alignment
@@ -690,6 +1620,39 @@ impl FunctionInfo {
         self.top += get_align_shift(self.top, alignment);
     }
 
+    // If a value of this type, sitting at this depth, holds a heap address, it is kept as a
+    // root until released. Returns the count to release back to.
+    fn hold_temporary(&self, value_type: &Type, depth: usize) -> usize {
+        let mut live_temporaries = self.live_temporaries.borrow_mut();
+        let count = live_temporaries.len();
+
+        if let Some(reference_offset) = value_type.heap_reference_offset() {
+            live_temporaries.push(16 + (depth + reference_offset) as isize);
+        }
+
+        count
+    }
+
+    fn release_temporaries(&self, count: usize) {
+        self.live_temporaries.borrow_mut().truncate(count);
+    }
+
+    // The loop a break or continue (by id) leaves. The loop is always generated first.
+    fn loop_target(&self, env: &CompilationEnvironment, id: u32) -> Result<LoopJumps, GenerateError> {
+        env.loop_targets.get(&id)
+            .and_then(|loop_id| self.loops.borrow().get(loop_id).copied())
+            .ok_or(GenerateError("Could not find the loop to jump out of".to_string()))
+    }
+
+    // Every slot which may hold a heap address at this point in the function.
+    fn stack_map(&self) -> StackMap {
+        self.heap_parameters.iter()
+            .chain(&self.heap_locals)
+            .chain(self.live_temporaries.borrow().iter())
+            .copied()
+            .collect()
+    }
+
     // Checks arguments and locals
     fn variable_info_by_name(&self, name: &str) -> Option<(isize, usize)> {
         if let Some(info) = self.variables.get(&Variable::Local(name.to_string())) {
@@ -704,6 +1667,7 @@ impl FunctionInfo {
 
 #[derive(PartialEq, Eq, Hash, Debug)]
 enum Variable {
+    Closure,
     Return,
     Parameter (String),
     Local (String),
@@ -1,7 +1,7 @@
 /* Generated instructions can be obviously inefficient. In fact, there are even
  * no-ops, such as AdvanceStackPtr(0) */
 
-use super::PseudoInstruction;
+use super::{PseudoInstruction, TempInstruction};
 use crate::instructions::Instruction;
 
 
@@ -29,6 +29,10 @@ pub(super) fn optimize(instructions: Vec<PseudoInstruction>) -> Vec<PseudoInstru
             PI::Actual(I::RetractMoving(0, _)) => {
                 continue;
             }
+            // Spans take up no space, so moves are combined across them.
+            PI::Temp(TempInstruction::EnterSpan(_) | TempInstruction::ExitSpan) => {
+                final_instructions.push(instr);
+            }
             other => {
                 if next_move > 0 {
                     final_instructions.push(PI::Actual(I::AdvanceStackPtr(next_move as usize)));
@@ -57,7 +57,7 @@ pub enum FloatSize {
     EightByte,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Instruction {
     // Both operands must be the same type. Pops two operands, pushes one as the result.
     // The result is the same size (and type) as the input.
@@ -111,6 +111,14 @@ pub enum Instruction {
     // pointer jumps to the instruction index.
     Call (usize),  
 
+    // Pops a u64 function value, and then acts as Call. If the low bit of the value is set,
+    // the rest is the instruction index. Otherwise, it is the address of a closure's heap
+    // block, whose first word is such a tagged index. The stack must have been prepared as
+    // for Call, with an extra 8 bytes below the return value, holding the closure. The index
+    // can only be known as the program runs, so it is an error unless it starts a function
+    // that the program pushes as a value.
+    CallIndirect,
+
     // Components are the size and signedness of the input, and the size and signedness of the output.
     // May emit an error.
     IntegerConversion (IntSize, bool, IntSize, bool),
@@ -136,11 +144,38 @@ pub enum Instruction {
     // Unconditional Jump
     RelativeJump (i32),
 
+    // Pops a u64 alignment and then a u64 size, and pushes the u64 address of a new
+    // heap block. Address 0 is never handed out, so it can act as null.
+    HeapAlloc,
+
+    // Pops a u64 address, and frees the heap block starting there. Freeing 0 does nothing.
+    HeapFree,
+
+    // Pops a u64 address, and pushes the value stored there. The access is validated.
+    HeapRead (IntSize),
+
+    // Pops a value and then a u64 address, and stores the value there. The access is validated.
+    HeapWrite (IntSize),
+
+    // An offset into the data segment, and a size. Pushes the global stored there.
+    LoadGlobal (usize, IntSize),
+
+    // As above. Pops a value, and stores it in the global.
+    StoreGlobal (usize, IntSize),
+
     // Exit the program
     Exit,
+
+    // Pops an error code of the size, which is signed or not, and exits the program,
+    // reporting the error. Used when main returns an error.
+    ExitWithError (IntSize, bool),
+
+    // Ends the program with a runtime error. The index is into the program's message table,
+    // since instructions hold no strings. Used by assertions.
+    Trap (usize),
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Comparison {
     Equals,
     NotEquals,
@@ -150,7 +185,7 @@ pub enum Comparison {
     Greater
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum IntegerBinaryOperation {
     UnsignedAddition,
     SignedAddition,
@@ -164,13 +199,13 @@ pub enum IntegerBinaryOperation {
     SignedModulus,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum IntegerUnaryOperation {
     NegateSigned,
 }
 
 #[allow(clippy::enum_variant_names)]
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Constant {
     OneByte (u8),
     TwoByte (u16),
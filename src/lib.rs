@@ -20,6 +20,7 @@ mod ast;  // Generate AST from parsley's concrete syntax tree
 mod analysis;  // Analyze an AST, checking correctness and learning type info.
 mod instructions;  // Define the instruction set of the VM.
 mod generate;  // Traverses an AST and returns instructions and other data.
+pub mod program;  // A compiled program: instructions along with their metadata.
 pub mod runtime;  // Runs generated instructions
 
 mod util;  // Utility functions, etc.
@@ -27,9 +28,11 @@ mod error;  // Error types
 
 
 use std::collections::{VecDeque, HashSet, HashMap};
+use std::path::{Path, PathBuf};
 
 use error::CompileError;
 pub use instructions::Instruction;
+pub use program::Program;
 
 
 static PARSER_DEFINITION: &str = include_str!("grammar.parsley");  // Drops the string right into the binary.
@@ -41,7 +44,18 @@ struct CompilationEnvironment {
     parser: parsley::Parser<token::Token>,
     queue: CompilationQueue,
     functions: HashMap<String, analysis::Function>,
+    generic_functions: HashMap<String, analysis::GenericFunction>,
+    modules: HashMap<PathBuf, String>,  // Maps each file (by canonical path) to the name of its module.
+    globals: HashMap<String, analysis::Global>,
+    global_initializers: Vec<String>,  // Global variables, in the order they are initialized.
+    data_size: usize,  // Bytes in the data segment, which holds the global variables.
+    traits: HashMap<String, analysis::Trait>,
+    impls: HashSet<(String, analysis::types::Type)>,  // Pairs of trait names and the types implementing them.
+    comptime: HashMap<String, analysis::Comptime>,  // Maps the functions run during compilation to their values.
+    call_targets: HashMap<u32, String>,  // Maps generic and method calls (by id) to the function called. Filled in by type_check goals
+    loop_targets: HashMap<u32, u32>,  // Maps breaks and continues (by id) to the loop (by id) they leave. Filled in by scope_check goals
     types: HashMap<analysis::types::Type, analysis::types::TypeInfo>,
+    distinct_types: HashMap<String, analysis::types::Type>,  // Maps each distinct type (by qualified name) to its base type.
     type_index: HashMap<u32, analysis::types::Type>,  // Maps expressions (by id) to types. Filled in by type_check goals
 }
 
@@ -51,7 +65,18 @@ impl CompilationEnvironment {
             parser: parsley::define_parser::<token::Token>(PARSER_DEFINITION).expect("Parser definition should be valid"),
             queue: CompilationQueue::new(),
             functions: HashMap::new(),
+            generic_functions: HashMap::new(),
+            modules: HashMap::new(),
+            globals: HashMap::new(),
+            global_initializers: vec![],
+            data_size: 0,
+            traits: HashMap::new(),
+            impls: HashSet::new(),
+            comptime: HashMap::new(),
+            call_targets: HashMap::new(),
+            loop_targets: HashMap::new(),
             types: analysis::types::get_default_types(),
+            distinct_types: HashMap::new(),
             type_index: HashMap::new(),
         }
     }
@@ -64,6 +89,7 @@ impl CompilationEnvironment {
                 CompilationGoal::ImportFile { file, define_all } => self.import_file(file, *define_all)?,
                 CompilationGoal::ScopeCheck(function_name) => self.scope_check(function_name)?,
                 CompilationGoal::TypeCheck(function_name) => self.type_check(function_name)?,
+                CompilationGoal::Instantiate { function, type_args } => self.instantiate(function, type_args)?,
             }
             
             self.queue.finalize_goal(goal);
@@ -75,41 +101,293 @@ impl CompilationEnvironment {
     // Locates data associated with the file, tokenizes and parses it, and generates
     // data about the declarations in the file. All declarations are parsed and stored, 
     // but definitions may or may not be created depending on if they are needed.
-    // If define_all is true, goals will be added to define every declaration.
+    // If define_all is true, goals will be added to define every declaration. Otherwise,
+    // functions are only defined once a call to them is found.
     fn import_file(&mut self, file: &FileOrString, define_all: bool) -> Result<(), CompileError> {
-        let (path, input) = match file {
+        let (path, input, canonical_path) = match file {
             FileOrString::File(path) => 
-                (path, std::fs::read_to_string(path).map_err(|_| "Could not open file")?),
+                (path, std::fs::read_to_string(path).map_err(|_| "Could not open file")?, 
+                    std::fs::canonicalize(path).map_err(|_| "Could not open file")?),
             FileOrString::String(path, data) => 
-                (path, data.clone()),
+                (path, data.clone(), PathBuf::from(path)),
         };
 
+        // Imported files were named when their import was found. Otherwise, this is the
+        // file compilation started from.
+        let module = self.modules.entry(canonical_path.clone()).or_default().clone();
+
         let tokens = token::tokenize(&input, path)?;
 
         let syntax_tree = self.parser.parse_tokens(&tokens, "Program")?;
         let mut ast = ast::build_ast(&syntax_tree)?;
-        analysis::desugar(&mut ast);
+        analysis::desugar(&mut ast)?;
+
+        // Types first, since anything else may name them. The names are replaced with what they stand for.
+        let type_names = self.import_types(&module, &ast.declarations)?;
+        analysis::substitute_ascriptions(&mut ast::AnyAST::File(&mut ast), &type_names);
+
+        // Traits before imports, so that impls in the imported files can be checked against
+        // them, even if those files import this one in turn.
+        for decl in &ast.declarations {
+            if let ast::DeclarationAST::Trait { name, methods, .. } = decl {
+                let name = analysis::qualify(&module, name);
+
+                if self.traits.contains_key(&name) {
+                    return Err("Double declaration".into());
+                }
+
+                self.traits.insert(name, analysis::Trait { methods: methods.clone() });
+            }
+        }
+
+        // Imports next, so every imported file is available before anything in this one is checked.
+        let mut imports = HashMap::new();
+        for decl in &ast.declarations {
+            if let ast::DeclarationAST::Import { path: import_path, .. } = decl {
+                let (import_name, imported_module) = self.import_module(&canonical_path, import_path)?;
+
+                if imports.insert(import_name.clone(), imported_module).is_some() {
+                    return Err(format!("{import_name} is imported twice").into());
+                }
+            }
+        }
+
+        let local_names = ast.declarations.iter()
+            .filter_map(|decl| match decl {
+                ast::DeclarationAST::Function { name, .. } => Some(name.clone()),
+                _ => None,
+            })
+            .collect::<HashSet<_>>();
+
+        for decl in &mut ast.declarations {
+            analysis::qualify_names(&mut ast::AnyAST::Declaration(decl), &module, &local_names, &imports)?;
+        }
 
         for decl in ast.declarations {
             match decl {
-                ast::DeclarationAST::Function { name, params, block, node_data: _, return_type } => {
-                    if self.functions.contains_key(&name) {
+                ast::DeclarationAST::Function { public, name, type_params, params, block, node_data: _, return_type } => {
+                    if analysis::builtins::lookup_builtin(&name).is_some() {
+                        return Err(format!("{name} is the name of a builtin function").into());
+                    }
+
+                    let name = analysis::qualify(&module, &name);
+
+                    if self.functions.contains_key(&name) || self.generic_functions.contains_key(&name) {
                         return Err("Double declaration".into());
                     }
+
+                    // Generic functions are only defined once instantiated.
+                    if !type_params.is_empty() {
+                        let generic = analysis::GenericFunction { 
+                            ast: block, 
+                            module: module.clone(), 
+                            public, 
+                            type_parameters: type_params, 
+                            parameters: params, 
+                            return_type 
+                        };
+                        self.generic_functions.insert(name, generic);
+                        continue;
+                    }
                     
                     // Expects all types in the file to be processed first.
-                    self.functions.insert(name.clone(), analysis::Function::new(self, block, params, return_type));
+                    self.functions.insert(name.clone(), analysis::Function::new(self, block, params, return_type, &module, public));
 
                     if define_all {
                         self.queue.add_goal(CompilationGoal::ScopeCheck(name));
                     }
                 }
-                ast::DeclarationAST::Variable { .. } => {
-                    return Err("Cannot yet process global variables".into());
+                ast::DeclarationAST::Variable { public, mutability, name, expr, type_ascription, node_data, .. } => {
+                    let global_type: analysis::types::Type = type_ascription
+                        .ok_or(CompileError::from(format!("Give the global {name} an explicit type")))?
+                        .into();
+
+                    if self.globals.contains_key(&analysis::qualify(&module, &name)) {
+                        return Err("Double declaration".into());
+                    }
+
+                    match mutability {
+                        ast::Mutability::Val => self.import_constant(&module, &name, public, global_type, expr, node_data.span),
+                        ast::Mutability::Var => self.import_global_variable(&module, &name, public, global_type, expr)?,
+                    }
+                }
+                ast::DeclarationAST::Trait { .. } | ast::DeclarationAST::Import { .. } | ast::DeclarationAST::Type { .. } => (),
+                ast::DeclarationAST::Impl { trait_name, target_type, methods, .. } => {
+                    self.import_impl(trait_name, &target_type, methods, &module, define_all)?;
                 }
             }
         }
-        
+
+        Ok(())
+    }
+
+    // Declares the types in a file, in order, so each may use the ones before it. An alias
+    // stands for its base type, while a distinct type is a new type laid out like its base.
+    // Returns the type each name in the file stands for.
+    fn import_types(&mut self, module: &str, declarations: &[ast::DeclarationAST]) 
+        -> Result<HashMap<String, analysis::types::Type>, CompileError> {
+
+        use analysis::types::{Type, TypeInfo};
+
+        let mut type_names = HashMap::new();
+
+        for decl in declarations {
+            let ast::DeclarationAST::Type { distinct, name, base, .. } = decl
+                else { continue };
+
+            if type_names.contains_key(name) || !matches!(Type::from(name.clone()), Type::Distinct(_)) {
+                return Err(format!("{name} is already a type").into());
+            }
+
+            let base = Type::from(analysis::substitute(base, &type_names));
+            analysis::types::add_type_info(&mut self.types, &base);
+
+            let Some(&TypeInfo { size, alignment }) = self.types.get(&base)
+                else { return Err(format!("Could not find a type named {base}").into()) };
+
+            if !*distinct {
+                type_names.insert(name.clone(), base);
+                continue;
+            }
+
+            // The collector only traces the builtin types holding heap addresses, and wrappers of them.
+            if base.heap_reference_offset().is_some() {
+                return Err(format!("{name} cannot be a distinct type of {base}, since it holds a heap address").into());
+            }
+
+            let qualified_name = analysis::qualify(module, name);
+            let distinct_type = Type::Distinct(qualified_name.clone());
+
+            self.types.insert(distinct_type.clone(), TypeInfo { size, alignment });
+            self.distinct_types.insert(qualified_name, base);
+            type_names.insert(name.clone(), distinct_type);
+        }
+
+        Ok(type_names)
+    }
+
+    // Finds the file named by an import, names its module after the file, and imports it
+    // right away. Files are only imported once, however many times (or however cyclically)
+    // they are imported. Returns the name the module is imported under, and the name of the module.
+    fn import_module(&mut self, importing_file: &Path, import_path: &str) -> Result<(String, String), CompileError> {
+        let directory = importing_file.parent().unwrap_or(Path::new("."));
+        let canonical_path = std::fs::canonicalize(directory.join(import_path))
+            .map_err(|_| CompileError::from(format!("Could not find {import_path}")))?;
+
+        let import_name = canonical_path.file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or(CompileError::from(format!("Could not name the module in {import_path}")))?
+            .to_string();
+
+        if let Some(module) = self.modules.get(&canonical_path) {
+            return Ok((import_name, module.clone()));
+        }
+
+        if self.modules.values().any(|module| *module == import_name) {
+            return Err(format!("Two different modules are named {import_name}").into());
+        }
+
+        self.modules.insert(canonical_path.clone(), import_name.clone());
+
+        let file = FileOrString::File(canonical_path.to_string_lossy().into_owned());
+        self.import_file(&file, false)?;
+
+        Ok((import_name.clone(), import_name))
+    }
+
+    // Reserves space for a global variable in the data segment. Its initializer becomes a
+    // function, which is always defined, since the driver calls it before main.
+    fn import_global_variable(&mut self, module: &str, name: &str, public: bool, global_type: analysis::types::Type, 
+        initializer: ast::ExprAST) -> Result<(), CompileError> {
+
+        analysis::types::add_type_info(&mut self.types, &global_type);
+
+        let type_info = self.types.get(&global_type)
+            .ok_or(CompileError::from(format!("Could not find type {global_type}")))?;
+
+        let offset = self.data_size.next_multiple_of(type_info.alignment);
+        self.data_size = offset + type_info.size;
+
+        let global_name = analysis::qualify(module, name);
+        let initializer_name = analysis::initializer_name(&global_name);
+
+        let function = analysis::Function::new(self, initializer, vec![], global_type.to_string(), module, false);
+        self.functions.insert(initializer_name.clone(), function);
+        self.queue.add_goal(CompilationGoal::ScopeCheck(initializer_name));
+
+        self.globals.insert(global_name.clone(), analysis::Global::Variable { offset, global_type, module: module.to_string(), public });
+        self.global_initializers.push(global_name);
+
+        Ok(())
+    }
+
+    // A constant's initializer becomes a function, which is always defined, and is run
+    // during compilation to find the constant's value.
+    fn import_constant(&mut self, module: &str, name: &str, public: bool, global_type: analysis::types::Type, 
+        initializer: ast::ExprAST, span: token::Span) {
+
+        let global_name = analysis::qualify(module, name);
+        let constant_name = analysis::constant_name(&global_name);
+
+        let function = analysis::Function::new(self, initializer, vec![], global_type.to_string(), module, false);
+        self.functions.insert(constant_name.clone(), function);
+        self.queue.add_goal(CompilationGoal::ScopeCheck(constant_name.clone()));
+
+        self.comptime.insert(constant_name, analysis::Comptime::new(global_type.clone(), span));
+        self.globals.insert(global_name, analysis::Global::Constant { global_type, module: module.to_string(), public });
+    }
+
+    // Checks an impl against its trait, and adds its methods as functions. Like other
+    // functions, methods are only defined once something needs them.
+    fn import_impl(&mut self, trait_name: String, target_type: &str, methods: Vec<ast::DeclarationAST>, 
+        module: &str, define_all: bool) -> Result<(), CompileError> {
+        let bindings = HashMap::from([("Self".to_string(), analysis::types::Type::from(target_type.to_string()))]);
+
+        // `Self` may be used in place of the target type.
+        let methods = methods.into_iter()
+            .map(|method| match method {
+                ast::DeclarationAST::Function { public, name, type_params, params, block, return_type, node_data } => 
+                    ast::DeclarationAST::Function {
+                        public,
+                        name,
+                        type_params,
+                        params: params.into_iter()
+                            .map(|(param_name, type_name)| (param_name, analysis::substitute(&type_name, &bindings)))
+                            .collect(),
+                        block,
+                        return_type: analysis::substitute(&return_type, &bindings),
+                        node_data,
+                    },
+                other => other,
+            })
+            .collect::<Vec<_>>();
+
+        // An impl method is called by name, with no way to give it type arguments.
+        if methods.iter().any(|method| matches!(method, ast::DeclarationAST::Function { type_params, .. } if !type_params.is_empty())) {
+            return Err(format!("In impl {trait_name} for {target_type}: generic impl methods are not supported").into());
+        }
+
+        analysis::check_impl(self, &trait_name, target_type, &methods)?;
+
+        let target_type = analysis::types::Type::from(target_type.to_string());
+
+        if !self.impls.insert((trait_name.clone(), target_type.clone())) {
+            return Err(format!("{trait_name} is implemented twice for {target_type}").into());
+        }
+
+        for method in methods {
+            let ast::DeclarationAST::Function { name, params, block, return_type, .. } = method
+                else { return Err("Expected impls to only contain methods".into()) };
+
+            let function_name = analysis::method_function_name(&trait_name, &target_type, &name);
+            // Methods can be called wherever the type is used, so they are always public.
+            self.functions.insert(function_name.clone(), analysis::Function::new(self, block, params, return_type, module, true));
+
+            if define_all {
+                self.queue.add_goal(CompilationGoal::ScopeCheck(function_name));
+            }
+        }
+
         Ok(())
     }
 
@@ -132,6 +410,16 @@ impl CompilationEnvironment {
 
         Ok(())
     }
+
+    // Creates a copy of a generic function for specific type arguments, and then
+    // enqueues the same goals any other function would get.
+    fn instantiate(&mut self, function_name: &str, type_args: &[analysis::types::Type]) -> Result<(), CompileError> {
+        let instance_name = analysis::instantiate(self, function_name, type_args)?;
+
+        self.queue.add_goal(CompilationGoal::ScopeCheck(instance_name));
+
+        Ok(())
+    }
 }
 
 
@@ -169,6 +457,10 @@ impl CompilationQueue {
         }
     }
 
+    fn is_processed(&self, goal: &CompilationGoal) -> bool {
+        self.processed.contains(goal)
+    }
+
     // Marks a goal a processed, so the same goal will not be popped again.
     fn finalize_goal(&mut self, goal: CompilationGoal) {
         self.processed.insert(goal);
@@ -204,6 +496,7 @@ enum CompilationGoal {
     ImportFile {file: FileOrString, define_all: bool},  // Depending on define_all, may start definition of these declarations.
     ScopeCheck (String),  // Upon completion, always enques type check. Should enqueue dependencies first (e.g. called functions in other files).
     TypeCheck (String),  // Upon completion, the function is ready to be passed to the code generator.
+    Instantiate { function: String, type_args: Vec<analysis::types::Type> },  // Upon completion, the instance is a function like any other, and is scope checked.
 }
 
 #[derive(PartialEq, Eq, Hash, Debug)]
@@ -213,20 +506,21 @@ enum FileOrString {
 }
 
 
-fn compile(file: FileOrString) -> Vec<instructions::Instruction> {
+fn compile(file: FileOrString) -> Program {
     let mut env = CompilationEnvironment::new();
     env.queue.add_goal(CompilationGoal::ImportFile { file, define_all: true });
     env.process_goals().expect("Goals should complete");
+    analysis::evaluate_comptime(&mut env).expect("Compile time code should run successfully");
 
     let generator = generate::CodeGenerator::new();
     
     generator.generate(&env).expect("Code should generate successfully")
 }
 
-pub fn compile_file(path: String) -> Vec<instructions::Instruction> {
+pub fn compile_file(path: String) -> Program {
     compile(FileOrString::File(path))
 }
 
-pub fn compile_string(input: String) -> Vec<instructions::Instruction> {
+pub fn compile_string(input: String) -> Program {
     compile(FileOrString::String("<input>".to_string(), input))
 }
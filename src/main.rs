@@ -1,25 +1,329 @@
 /* Eventually, this binary will be a tool for compiling (?) or running possibly many
- * Nom files. */
+ * Nom files.
+ *
+ * With no command, a program is read from stdin, compiled, dumped, and run.
+ * `nom build file.nom [-o file.nomc]` compiles a file to a .nomc file, and
+ * `nom exec file.nomc` runs one without compiling anything. `nom debug file.nom` compiles
+ * a file and runs it under a debugger, taking commands from stdin, and
+ * `nom profile file.nom [-o file.folded]` runs one and reports where its time went. */
 
- 
-use nom::compile_string;
-use nom::runtime::Runtime;
+
+use nom::{compile_file, compile_string, Program};
+use nom::runtime::{Runtime, RuntimeConfig, CollectionMode, Pause};
 
 use std::io::Read;
 
 
 fn main() {
+    let args = std::env::args().collect::<Vec<_>>();
+
+    match args.get(1).map(String::as_str) {
+        Some("build") => build(&args[2..]),
+        Some("exec") => exec(&args[2..]),
+        Some("debug") => debug(&args[2..]),
+        Some("profile") => profile(&args[2..]),
+        _ => run_stdin(),
+    }
+}
+
+fn run_stdin() {
     let mut buffer = String::new();
     std::io::stdin().read_to_string(&mut buffer).expect("Reading stdin should succeed");
 
-    let code = compile_string(buffer);
+    let program = compile_string(buffer);
 
-    for (i, instr) in code.iter().enumerate() {
-        println!("{i: <5}: {instr:?}");
-    }
+    print!("{}", program.disassemble());
 
     println!("\n-*-*-*-*- Running VM -*-*-*-*-\n");
 
-    let mut runtime = Runtime::new(code);
-    runtime.run_debug(&mut std::io::stdout());
+    run(program);
+}
+
+fn build(args: &[String]) {
+    let Some(source) = args.first()
+        else { fail("Usage: nom build file.nom [-o file.nomc]") };
+
+    let output = match args.iter().position(|arg| arg == "-o") {
+        Some(i) => args.get(i + 1).cloned().unwrap_or_else(|| fail("Expected a file name after -o")),
+        None => std::path::Path::new(source).with_extension("nomc").to_string_lossy().to_string(),
+    };
+
+    let program = compile_file(source.clone());
+
+    let file = std::fs::File::create(&output).unwrap_or_else(|err| fail(&format!("Could not create {output}: {err}")));
+    program.write_to(&mut std::io::BufWriter::new(file)).unwrap_or_else(|err| fail(&format!("Could not write {output}: {err}")));
+}
+
+fn exec(args: &[String]) {
+    let Some(path) = args.first()
+        else { fail("Usage: nom exec file.nomc") };
+
+    let file = std::fs::File::open(path).unwrap_or_else(|err| fail(&format!("Could not open {path}: {err}")));
+    let program = Program::read_from(&mut std::io::BufReader::new(file))
+        .unwrap_or_else(|err| fail(&format!("Could not load {path}: {}", err.0)));
+
+    if let Err(err) = program.verify() {
+        fail(&format!("{path} is not a valid program: {}", err.0));
+    }
+
+    run(program);
+}
+
+fn run(program: Program) {
+    let mut runtime = Runtime::with_config(program, runtime_config());
+    runtime.set_collection_mode(collection_mode());
+
+    // Tracing goes to stderr, so that it does not mix with what the program prints.
+    if std::env::args().any(|arg| arg == "--trace") {
+        runtime.run_traced(&mut std::io::stdout(), &mut std::io::BufWriter::new(std::io::stderr()))
+            .unwrap_or_else(|error| fail(&format!("Stopped: {error}")));
+    }
+    else {
+        runtime.run_debug(&mut std::io::stdout());
+    }
+
+    // Like a runtime error, an error returned from main fails the process.
+    if runtime.exit_error().is_some() {
+        std::process::exit(1);
+    }
+}
+
+// The report goes to stdout after the program's own output, and the folded stacks to a file.
+fn profile(args: &[String]) {
+    let Some(source) = args.first()
+        else { fail("Usage: nom profile file.nom [-o file.folded]") };
+
+    let output = match args.iter().position(|arg| arg == "-o") {
+        Some(i) => args.get(i + 1).cloned().unwrap_or_else(|| fail("Expected a file name after -o")),
+        None => std::path::Path::new(source).with_extension("folded").to_string_lossy().to_string(),
+    };
+
+    let mut runtime = Runtime::with_config(compile_file(source.clone()), runtime_config());
+    runtime.set_collection_mode(collection_mode());
+
+    let profile = runtime.run_profiled(&mut std::io::stdout())
+        .unwrap_or_else(|error| fail(&format!("Stopped: {error}")));
+
+    println!("\n-*-*-*-*- Profile -*-*-*-*-\n");
+    print!("{}", profile.report());
+
+    std::fs::write(&output, profile.folded_stacks()).unwrap_or_else(|err| fail(&format!("Could not write {output}: {err}")));
+    println!("\nFolded stacks written to {output}");
+}
+
+const DEBUG_HELP: &str = "\
+Commands, which repeat when given an empty line:
+    step, s              Run one instruction
+    over, o              Run one instruction, running calls until they return
+    line, l              Run until the source line changes
+    next, n              Run until the source line changes in this function, or it returns
+    continue, c          Run until a breakpoint, or the program exits
+    break, b LOCATION    Set a breakpoint on a line, file:line, or *instruction
+    break, b             List the breakpoints
+    delete, d INDEX      Clear the breakpoint on an instruction, or all of them
+    backtrace, bt        Show the running functions, innermost first
+    locals [FRAME]       Show the locals of a frame, the innermost by default
+    stack [FRAME]        Show the values being computed in a frame
+    where                Show the next instruction
+    quit, q              Stop debugging";
+
+fn debug(args: &[String]) {
+    let Some(source) = args.first()
+        else { fail("Usage: nom debug file.nom") };
+
+    let program = compile_file(source.clone());
+
+    // The runtime keeps the instructions to itself, so a copy is kept to show them.
+    let mut runtime = Runtime::with_config(program.clone(), runtime_config());
+    runtime.set_collection_mode(collection_mode());
+
+    // Runtime errors are shown as the program pauses on them, rather than as panics.
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let message = info.payload().downcast_ref::<String>().map(String::as_str)
+            .or_else(|| info.payload().downcast_ref::<&str>().copied());
+
+        if !message.is_some_and(|message| message.starts_with("Critical Runtime Error")) {
+            default_hook(info);
+        }
+    }));
+
+    println!("Paused before the first instruction. Type help for the commands.");
+    show_position(&program, &runtime);
+
+    let mut stdout = std::io::stdout();
+    let mut last_command = String::new();
+
+    for line in std::io::stdin().lines() {
+        let line = line.expect("Reading stdin should succeed");
+
+        if !line.trim().is_empty() {
+            last_command = line.trim().to_string();
+        }
+
+        let mut words = last_command.split_whitespace();
+        let Some(command) = words.next()
+            else { continue };
+        let argument = words.next();
+
+        let frame = match argument.map(str::parse::<usize>) {
+            Some(Ok(frame)) => frame,
+            Some(Err(_)) if matches!(command, "locals" | "stack") => {
+                println!("Expected a frame number");
+                continue;
+            },
+            _ => 0,
+        };
+
+        let pause = match command {
+            "step" | "s" => runtime.step(&mut stdout),
+            "over" | "o" => runtime.step_over(&mut stdout),
+            "line" | "l" => runtime.step_line(&mut stdout),
+            "next" | "n" => runtime.next_line(&mut stdout),
+            "continue" | "c" => runtime.resume(&mut stdout),
+            "break" | "b" => {
+                set_breakpoint(&mut runtime, source, argument);
+                continue;
+            },
+            "delete" | "d" => {
+                match argument {
+                    Some(index) => match index.trim_start_matches('*').parse() {
+                        Ok(index) if runtime.clear_breakpoint(index) => (),
+                        _ => println!("There is no breakpoint on instruction {index}"),
+                    },
+                    None => for index in runtime.breakpoints() {
+                        runtime.clear_breakpoint(index);
+                    },
+                }
+                continue;
+            },
+            "backtrace" | "bt" => {
+                for (i, description) in runtime.call_stack().iter().enumerate() {
+                    println!("{i:>4}  {description}");
+                }
+                continue;
+            },
+            "locals" => {
+                match runtime.locals(frame) {
+                    Some(locals) if locals.is_empty() => println!("Frame {frame} has no locals"),
+                    Some(locals) => for local in locals {
+                        println!("    {}: {} = {}", local.name, local.type_name, local.value.as_deref().unwrap_or("unavailable"));
+                    },
+                    None => println!("There are no locals to show for frame {frame}"),
+                }
+                continue;
+            },
+            "stack" => {
+                match runtime.operand_stack(frame) {
+                    Some(values) => for (i, word) in values.chunks(8).enumerate() {
+                        let bytes = word.iter().map(|byte| format!("{byte:02x}")).collect::<Vec<_>>();
+                        println!("    +{:<4} {}", i * 8, bytes.join(" "));
+                    },
+                    None => println!("There is no frame {frame}"),
+                }
+                continue;
+            },
+            "where" => {
+                show_position(&program, &runtime);
+                continue;
+            },
+            "help" | "h" => {
+                println!("{DEBUG_HELP}");
+                continue;
+            },
+            "quit" | "q" => return,
+            _ => {
+                println!("Unknown command {command}. Type help for the commands.");
+                continue;
+            },
+        };
+
+        match pause {
+            Pause::Exited => println!("The program has exited"),
+            Pause::Breakpoint => {
+                println!("Breakpoint");
+                show_position(&program, &runtime);
+            },
+            Pause::Stepped => show_position(&program, &runtime),
+            Pause::Stopped(error) => println!("Stopped: {error}"),
+            Pause::Failed(message) => {
+                println!("{message}");
+                show_position(&program, &runtime);
+            },
+        }
+    }
+}
+
+// Breakpoints given by line alone go in the file being debugged.
+fn set_breakpoint(runtime: &mut Runtime, source: &str, location: Option<&str>) {
+    let Some(location) = location else {
+        for index in runtime.breakpoints() {
+            println!("    *{index}");
+        }
+        return;
+    };
+
+    if let Some(index) = location.strip_prefix('*') {
+        match index.parse() {
+            Ok(index) if runtime.set_breakpoint(index) => (),
+            _ => println!("There is no instruction {index}"),
+        }
+        return;
+    }
+
+    let (file, line) = location.rsplit_once(':').unwrap_or((source, location));
+
+    let Ok(line) = line.parse() else {
+        println!("Expected a line, file:line, or *instruction");
+        return;
+    };
+
+    match runtime.set_line_breakpoint(file, line).as_slice() {
+        [] => println!("There is no code on {file}:{line}"),
+        indices => {
+            let indices = indices.iter().map(|index| format!("*{index}")).collect::<Vec<_>>();
+            println!("Breakpoint set on {}", indices.join(", "));
+        },
+    }
+}
+
+fn show_position(program: &Program, runtime: &Runtime) {
+    let index = runtime.position();
+    let instruction = program.instruction_text(index).unwrap_or_default();
+    let frame = runtime.call_stack().swap_remove(0);
+
+    println!("{index:>6}  {instruction:<24} in {frame}");
+}
+
+// The stack can be given more room than usual with `--stack-size bytes`.
+fn runtime_config() -> RuntimeConfig {
+    let args = std::env::args().collect::<Vec<_>>();
+
+    let Some(i) = args.iter().position(|arg| arg == "--stack-size")
+        else { return RuntimeConfig::new() };
+
+    let sizes = RuntimeConfig::MIN_SIZE..=RuntimeConfig::MAX_STACK_SIZE;
+
+    match args.get(i + 1).and_then(|size| size.parse::<usize>().ok()) {
+        Some(stack_size) if sizes.contains(&stack_size) => RuntimeConfig::new().stack_size(stack_size),
+        _ => fail(&format!("Expected a number of bytes from {} to {} after --stack-size", sizes.start(), sizes.end())),
+    }
+}
+
+// Heap blocks are freed manually unless a garbage collector is requested.
+fn collection_mode() -> CollectionMode {
+    if std::env::args().any(|arg| arg == "--gc-stress") {
+        CollectionMode::Stress
+    }
+    else if std::env::args().any(|arg| arg == "--gc") {
+        CollectionMode::Tracing
+    }
+    else {
+        CollectionMode::Manual
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{message}");
+    std::process::exit(1);
 }
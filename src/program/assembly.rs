@@ -0,0 +1,417 @@
+/* A textual form of programs, for reading generated code and for writing VM tests by hand.
+ * Each line is a label, an instruction, or blank, and anything after a `;` is a comment.
+ *
+ *  main:                      A function starts here, so it goes in the symbol table.
+ *  .L7:                       A label starting with . is only a jump target.
+ *       7  add.s 4            An instruction. The leading index is optional, and ignored.
+ *          jump_if_false .L7  Jumps and calls name their targets.
+ *          trap "Division"    Traps give their message.
+ *
+ * Operands are sizes in bytes (1, 2, 4, or 8), offsets, and constants. Where signedness
+ * matters, it is part of the mnemonic, as in `lt.s 4` or `convert.u.s 1 8`. Jumps may also
+ * be given a relative shift, like `jump -3`, and calls an instruction index.
+ *
+ * Where the program has a line table, the source line is noted in a comment as it changes.
+ * Stack maps, debug info, and the data segment have no textual form, so programs which
+ * need them cannot be written by hand yet. A function's values start right above the
+ * saved registers, as if it had no locals. */
+
+use std::collections::{HashMap, HashSet};
+
+use super::{Program, FunctionSymbol};
+use crate::instructions::{Instruction, IntegerBinaryOperation, IntegerUnaryOperation, Comparison, IntSize, Constant};
+use crate::error::AssemblyError;
+
+
+impl Program {
+    pub fn disassemble(&self) -> String {
+        use Instruction as I;
+
+        let jump_targets = self.instructions.iter().enumerate()
+            .filter_map(|(index, instruction)| match instruction {
+                I::RelativeJump(shift) | I::RelativeJumpIfTrue(shift) | I::RelativeJumpIfFalse(shift) =>
+                    index.checked_add_signed(*shift as isize),
+                _ => None,
+            })
+            .collect::<HashSet<_>>();
+
+        let function_names = self.function_names();
+
+        let mut text = String::new();
+        let mut last_line = None;
+
+        for (index, instruction) in self.instructions.iter().enumerate() {
+            for name in function_names.get(&index).into_iter().flatten() {
+                text.push_str(&format!("{name}:\n"));
+            }
+
+            if jump_targets.contains(&index) {
+                text.push_str(&format!(".L{index}:\n"));
+            }
+
+            // The source line is noted whenever it changes.
+            let line = self.location(index).map(|entry| (entry.file, entry.line));
+            if let (Some((file, line)), true) = (line, line != last_line) {
+                let file = self.files.get(file).map_or("?", String::as_str);
+                text.push_str(&format!("        ; {file}:{line}\n"));
+            }
+            last_line = line;
+
+            text.push_str(&format!("{index:>6}  {}\n", self.format_instruction(index, *instruction, &function_names)));
+        }
+
+        text
+    }
+
+    // One instruction as it would be disassembled, for showing where a program is.
+    pub fn instruction_text(&self, index: usize) -> Option<String> {
+        let instruction = self.instructions.get(index)?;
+
+        Some(self.format_instruction(index, *instruction, &self.function_names()))
+    }
+
+    // The functions starting at each index.
+    fn function_names(&self) -> HashMap<usize, Vec<&str>> {
+        let mut function_names: HashMap<usize, Vec<&str>> = HashMap::new();
+        for FunctionSymbol { name, start, .. } in &self.functions {
+            function_names.entry(*start).or_default().push(name);
+        }
+
+        function_names
+    }
+
+    fn format_instruction(&self, index: usize, instruction: Instruction, function_names: &HashMap<usize, Vec<&str>>) -> String {
+        use Instruction as I;
+
+        let jump = |shift: i32| match index.checked_add_signed(shift as isize) {
+            Some(target) if target < self.instructions.len() => format!(".L{target}"),
+            _ => format!("{shift:+}"),
+        };
+
+        match instruction {
+            I::Call(target) => match function_names.get(&target) {
+                Some(names) => format!("call {}", names[0]),
+                None => format!("call {target}"),
+            },
+            I::RelativeJump(shift) => format!("jump {}", jump(shift)),
+            I::RelativeJumpIfTrue(shift) => format!("jump_if_true {}", jump(shift)),
+            I::RelativeJumpIfFalse(shift) => format!("jump_if_false {}", jump(shift)),
+            I::Trap(message) => match self.messages.get(message) {
+                Some(text) => format!("trap {text:?}"),
+                None => format!("trap {message}"),
+            },
+            _ => format_alone(instruction),
+        }
+    }
+
+    pub fn assemble(text: &str) -> Result<Program, AssemblyError> {
+        let lines = text.lines()
+            .enumerate()
+            .map(|(i, line)| (i + 1, strip_comment(line).trim()))
+            .filter(|(_, line)| !line.is_empty())
+            .collect::<Vec<_>>();
+
+        let mut program = Program::default();
+
+        // Labels first, so that jumps can go forwards.
+        let mut labels = HashMap::new();
+        let mut index = 0;
+
+        for (line_number, line) in &lines {
+            let Some(label) = line.strip_suffix(':')
+                else { index += 1; continue };
+
+            if labels.insert(label.to_string(), index).is_some() {
+                return Err(format!("Line {line_number}: {label} is defined twice").into());
+            }
+
+            if !label.starts_with('.') {
+                program.functions.push(FunctionSymbol { name: label.to_string(), start: index, operand_start: 16, ..Default::default() });
+            }
+        }
+
+        // A function runs until the next one starts.
+        for i in 0..program.functions.len() {
+            program.functions[i].end = program.functions.get(i + 1).map_or(index, |next| next.start);
+        }
+
+        for (line_number, line) in lines {
+            if line.ends_with(':') {
+                continue;
+            }
+
+            let instruction = parse_instruction(line, program.instructions.len(), &labels, &mut program.messages)
+                .map_err(|message| AssemblyError(format!("Line {line_number}: {message}")))?;
+
+            program.instructions.push(instruction);
+        }
+
+        Ok(program)
+    }
+}
+
+// An instruction as it is written without the rest of the program to refer to, so jumps
+// give their shift, calls an index, and traps the number of their message.
+pub(crate) fn format_alone(instruction: Instruction) -> String {
+    use Instruction as I;
+
+    match instruction {
+        I::IntegerBinaryOperation(op, size) => format!("{} {}", binary_mnemonic(op), size.to_usize()),
+        I::IntegerComparisonOperation { comparison, size, signed } =>
+            format!("{}.{} {}", comparison_mnemonic(comparison), signedness(signed), size.to_usize()),
+        I::UnaryOperation(IntegerUnaryOperation::NegateSigned, size) => format!("neg.s {}", size.to_usize()),
+        I::BooleanNot => "not".to_string(),
+        I::AdvanceStackPtr(amount) => format!("advance {amount}"),
+        I::RetractStackPtr(amount) => format!("retract {amount}"),
+        I::RetractMoving(amount, size) => format!("retract_moving {amount} {}", size.to_usize()),
+        I::DebugPrintSigned(size) => format!("print {}", size.to_usize()),
+        I::Duplicate(size) => format!("dup {}", size.to_usize()),
+        I::PushConstant(Constant::OneByte(value)) => format!("push 1 {value}"),
+        I::PushConstant(Constant::TwoByte(value)) => format!("push 2 {value}"),
+        I::PushConstant(Constant::FourByte(value)) => format!("push 4 {value}"),
+        I::PushConstant(Constant::EightByte(value)) => format!("push 8 {value}"),
+        I::ReadBase(offset, size) => format!("read_base {offset} {}", size.to_usize()),
+        I::WriteBase(offset, size) => format!("write_base {offset} {}", size.to_usize()),
+        I::Call(target) => format!("call {target}"),
+        I::CallIndirect => "call_indirect".to_string(),
+        I::IntegerConversion(start_size, start_signed, end_size, end_signed) => format!("convert.{}.{} {} {}",
+            signedness(start_signed), signedness(end_signed), start_size.to_usize(), end_size.to_usize()),
+        I::Return => "return".to_string(),
+        I::RelativeJump(shift) => format!("jump {shift:+}"),
+        I::RelativeJumpIfTrue(shift) => format!("jump_if_true {shift:+}"),
+        I::RelativeJumpIfFalse(shift) => format!("jump_if_false {shift:+}"),
+        I::HeapAlloc => "heap_alloc".to_string(),
+        I::HeapFree => "heap_free".to_string(),
+        I::HeapRead(size) => format!("heap_read {}", size.to_usize()),
+        I::HeapWrite(size) => format!("heap_write {}", size.to_usize()),
+        I::LoadGlobal(offset, size) => format!("load_global {offset} {}", size.to_usize()),
+        I::StoreGlobal(offset, size) => format!("store_global {offset} {}", size.to_usize()),
+        I::Exit => "exit".to_string(),
+        I::ExitWithError(size, signed) => format!("exit_with_error.{} {}", signedness(signed), size.to_usize()),
+        I::Trap(message) => format!("trap {message}"),
+    }
+}
+
+// Removes a comment, unless the ; is inside a string.
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (i, ch) in line.char_indices() {
+        match ch {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &line[..i],
+            _ => (),
+        }
+    }
+
+    line
+}
+
+fn parse_instruction(line: &str, index: usize, labels: &HashMap<String, usize>, messages: &mut Vec<String>) -> Result<Instruction, String> {
+    use Instruction as I;
+    use IntegerBinaryOperation as B;
+
+    // The index column is only for reading.
+    let line = match line.split_once(char::is_whitespace) {
+        Some((first, rest)) if first.chars().all(|ch| ch.is_ascii_digit()) => rest.trim(),
+        _ => line,
+    };
+
+    let (mnemonic, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let rest = rest.trim();
+
+    // Function names and messages may contain spaces, so these take the rest of the line.
+    match mnemonic {
+        "call" => {
+            return match labels.get(rest) {
+                Some(target) => Ok(I::Call(*target)),
+                None => rest.parse::<usize>().map(I::Call).map_err(|_| format!("No function named {rest}")),
+            };
+        },
+        "trap" => {
+            if !rest.starts_with('"') {
+                return rest.parse::<usize>().map(I::Trap).map_err(|_| format!("Expected a message, not {rest}"));
+            }
+
+            let message = unquote(rest)?;
+            let index = messages.iter().position(|existing| *existing == message).unwrap_or_else(|| {
+                messages.push(message);
+                messages.len() - 1
+            });
+
+            return Ok(I::Trap(index));
+        },
+        _ => (),
+    }
+
+    let operands = rest.split_whitespace().collect::<Vec<_>>();
+    let (name, suffixes) = match mnemonic.split_once('.') {
+        Some((name, suffixes)) => (name, suffixes.split('.').collect::<Vec<_>>()),
+        None => (mnemonic, vec![]),
+    };
+
+    let expected_operands = match name {
+        "not" | "call_indirect" | "return" | "heap_alloc" | "heap_free" | "exit" => 0,
+        "retract_moving" | "push" | "read_base" | "write_base" | "load_global" | "store_global" | "convert" => 2,
+        _ => 1,
+    };
+
+    if operands.len() != expected_operands {
+        return Err(format!("{mnemonic} takes {expected_operands} operands, but {} were given", operands.len()));
+    }
+
+    let size = |i: usize| parse_size(operands[i]);
+    let number = |i: usize| operands[i].parse::<usize>().map_err(|_| format!("Expected a number, not {}", operands[i]));
+    let offset = |i: usize| operands[i].parse::<isize>().map_err(|_| format!("Expected an offset, not {}", operands[i]));
+
+    let jump = || match labels.get(operands[0]) {
+        Some(target) => i32::try_from(*target as i64 - index as i64).map_err(|_| "Jump is too far".to_string()),
+        None => operands[0].parse::<i32>().map_err(|_| format!("No label named {}", operands[0])),
+    };
+
+    let binary = |unsigned_op, signed_op, signed| {
+        Ok::<_, String>(I::IntegerBinaryOperation(if parse_signedness(signed)? { signed_op } else { unsigned_op }, size(0)?))
+    };
+
+    let comparison = |comparison, signed| {
+        Ok::<_, String>(I::IntegerComparisonOperation { comparison, size: size(0)?, signed: parse_signedness(signed)? })
+    };
+
+    let instruction = match (name, suffixes.as_slice()) {
+        ("add", [signed]) => binary(B::UnsignedAddition, B::SignedAddition, signed)?,
+        ("sub", [signed]) => binary(B::UnsignedSubtraction, B::SignedSubtraction, signed)?,
+        ("mul", [signed]) => binary(B::UnsignedMultiplication, B::SignedMultiplication, signed)?,
+        ("div", [signed]) => binary(B::UnsignedDivision, B::SignedDivision, signed)?,
+        ("mod", [signed]) => binary(B::UnsignedModulus, B::SignedModulus, signed)?,
+        ("eq", [signed]) => comparison(Comparison::Equals, signed)?,
+        ("ne", [signed]) => comparison(Comparison::NotEquals, signed)?,
+        ("le", [signed]) => comparison(Comparison::LessEquals, signed)?,
+        ("ge", [signed]) => comparison(Comparison::GreaterEquals, signed)?,
+        ("lt", [signed]) => comparison(Comparison::Less, signed)?,
+        ("gt", [signed]) => comparison(Comparison::Greater, signed)?,
+        ("neg", ["s"]) => I::UnaryOperation(IntegerUnaryOperation::NegateSigned, size(0)?),
+        ("not", []) => I::BooleanNot,
+        ("advance", []) => I::AdvanceStackPtr(number(0)?),
+        ("retract", []) => I::RetractStackPtr(number(0)?),
+        ("retract_moving", []) => I::RetractMoving(number(0)?, size(1)?),
+        ("print", []) => I::DebugPrintSigned(size(0)?),
+        ("dup", []) => I::Duplicate(size(0)?),
+        ("push", []) => {
+            let value = operands[1].parse::<u64>().map_err(|_| format!("Expected a constant, not {}", operands[1]))?;
+            let too_big = || format!("{value} does not fit in {} bytes", operands[0]);
+
+            I::PushConstant(match size(0)? {
+                IntSize::OneByte => Constant::OneByte(u8::try_from(value).map_err(|_| too_big())?),
+                IntSize::TwoByte => Constant::TwoByte(u16::try_from(value).map_err(|_| too_big())?),
+                IntSize::FourByte => Constant::FourByte(u32::try_from(value).map_err(|_| too_big())?),
+                IntSize::EightByte => Constant::EightByte(value),
+            })
+        },
+        ("read_base", []) => I::ReadBase(offset(0)?, size(1)?),
+        ("write_base", []) => I::WriteBase(offset(0)?, size(1)?),
+        ("call_indirect", []) => I::CallIndirect,
+        ("convert", [start_signed, end_signed]) =>
+            I::IntegerConversion(size(0)?, parse_signedness(start_signed)?, size(1)?, parse_signedness(end_signed)?),
+        ("return", []) => I::Return,
+        ("jump", []) => I::RelativeJump(jump()?),
+        ("jump_if_true", []) => I::RelativeJumpIfTrue(jump()?),
+        ("jump_if_false", []) => I::RelativeJumpIfFalse(jump()?),
+        ("heap_alloc", []) => I::HeapAlloc,
+        ("heap_free", []) => I::HeapFree,
+        ("heap_read", []) => I::HeapRead(size(0)?),
+        ("heap_write", []) => I::HeapWrite(size(0)?),
+        ("load_global", []) => I::LoadGlobal(number(0)?, size(1)?),
+        ("store_global", []) => I::StoreGlobal(number(0)?, size(1)?),
+        ("exit", []) => I::Exit,
+        ("exit_with_error", [signed]) => I::ExitWithError(size(0)?, parse_signedness(signed)?),
+        _ => return Err(format!("Unknown instruction {mnemonic}")),
+    };
+
+    Ok(instruction)
+}
+
+fn parse_size(text: &str) -> Result<IntSize, String> {
+    match text {
+        "1" => Ok(IntSize::OneByte),
+        "2" => Ok(IntSize::TwoByte),
+        "4" => Ok(IntSize::FourByte),
+        "8" => Ok(IntSize::EightByte),
+        _ => Err(format!("Expected a size of 1, 2, 4, or 8, not {text}")),
+    }
+}
+
+fn parse_signedness(text: &str) -> Result<bool, String> {
+    match text {
+        "s" => Ok(true),
+        "u" => Ok(false),
+        _ => Err(format!("Expected s or u, not {text}")),
+    }
+}
+
+fn signedness(signed: bool) -> &'static str {
+    if signed { "s" } else { "u" }
+}
+
+fn binary_mnemonic(op: IntegerBinaryOperation) -> &'static str {
+    use IntegerBinaryOperation as B;
+
+    match op {
+        B::UnsignedAddition => "add.u",
+        B::SignedAddition => "add.s",
+        B::UnsignedSubtraction => "sub.u",
+        B::SignedSubtraction => "sub.s",
+        B::UnsignedMultiplication => "mul.u",
+        B::SignedMultiplication => "mul.s",
+        B::UnsignedDivision => "div.u",
+        B::SignedDivision => "div.s",
+        B::UnsignedModulus => "mod.u",
+        B::SignedModulus => "mod.s",
+    }
+}
+
+fn comparison_mnemonic(comparison: Comparison) -> &'static str {
+    match comparison {
+        Comparison::Equals => "eq",
+        Comparison::NotEquals => "ne",
+        Comparison::LessEquals => "le",
+        Comparison::GreaterEquals => "ge",
+        Comparison::Less => "lt",
+        Comparison::Greater => "gt",
+    }
+}
+
+// Reads a string written with {:?}.
+fn unquote(text: &str) -> Result<String, String> {
+    let inner = text.strip_prefix('"').and_then(|text| text.strip_suffix('"'))
+        .ok_or(format!("Expected a quoted message, not {text}"))?;
+
+    let mut message = String::new();
+    let mut chars = inner.chars();
+
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            message.push(ch);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => message.push('\n'),
+            Some('t') => message.push('\t'),
+            Some('r') => message.push('\r'),
+            Some('0') => message.push('\0'),
+            Some(ch @ ('\\' | '"' | '\'')) => message.push(ch),
+            Some('u') => {
+                let code = chars.by_ref().skip(1).take_while(|ch| *ch != '}').collect::<String>();
+                let ch = u32::from_str_radix(&code, 16).ok().and_then(char::from_u32)
+                    .ok_or(format!("Bad escape \\u{{{code}}}"))?;
+                message.push(ch);
+            },
+            other => return Err(format!("Bad escape \\{}", other.map(String::from).unwrap_or_default())),
+        }
+    }
+
+    Ok(message)
+}
@@ -0,0 +1,526 @@
+/* The .nomc file format, which holds a compiled program so it can be run without
+ * compiling it again. Everything is little endian. A file is:
+ *
+ *  - The magic bytes "NOMC", and a u32 format version.
+ *  - Sections, until the end of the file. Each is a u8 tag, a u64 length in bytes,
+ *    and then its contents. Each section appears at most once.
+ *
+ * A missing section is read as empty. Strings are a u32 length followed by UTF-8 bytes.
+ * Enums are written as the index of their variant. Any change to the layout of a section,
+ * or to the order of the variants of an enum, must bump the version. */
+
+use std::io::{Read, Write};
+
+use super::{Program, FunctionSymbol, LocalSymbol, ValueFormat, LineEntry};
+use crate::instructions::{Instruction, IntegerBinaryOperation, IntegerUnaryOperation, Comparison, IntSize, Constant};
+use crate::error::FormatError;
+
+
+const MAGIC: &[u8; 4] = b"NOMC";
+pub const FORMAT_VERSION: u32 = 3;
+
+const CODE_SECTION: u8 = 1;  // The instructions
+const SYMBOL_SECTION: u8 = 2;  // The name, range, and locals of each function
+const MESSAGE_SECTION: u8 = 3;  // Strings that instructions refer to by index
+const DATA_SECTION: u8 = 4;  // The size of the data segment, and its roots
+const STACK_MAP_SECTION: u8 = 5;  // Stack maps, by the instruction they belong to
+const LINE_SECTION: u8 = 6;  // The source files, and the line table
+
+
+impl Program {
+    pub fn write_to(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        out.write_all(MAGIC)?;
+        out.write_all(&FORMAT_VERSION.to_le_bytes())?;
+
+        let mut section = Encoder::default();
+        section.u32(self.instructions.len() as u32);
+        for instruction in &self.instructions {
+            section.instruction(*instruction);
+        }
+        section.write_section(out, CODE_SECTION)?;
+
+        let mut section = Encoder::default();
+        section.u32(self.functions.len() as u32);
+        for FunctionSymbol { name, start, end, locals, operand_start } in &self.functions {
+            section.string(name);
+            section.u64(*start as u64);
+            section.u64(*end as u64);
+            section.u64(*operand_start as u64);
+            section.u32(locals.len() as u32);
+            for LocalSymbol { name, type_name, offset, size, format } in locals {
+                section.string(name);
+                section.string(type_name);
+                section.i64(*offset as i64);
+                section.u64(*size as u64);
+                section.u8(*format as u8);
+            }
+        }
+        section.write_section(out, SYMBOL_SECTION)?;
+
+        let mut section = Encoder::default();
+        section.u32(self.messages.len() as u32);
+        for message in &self.messages {
+            section.string(message);
+        }
+        section.write_section(out, MESSAGE_SECTION)?;
+
+        let mut section = Encoder::default();
+        section.u64(self.data_size as u64);
+        section.u32(self.data_roots.len() as u32);
+        for root in &self.data_roots {
+            section.u64(*root as u64);
+        }
+        section.write_section(out, DATA_SECTION)?;
+
+        // Sorted, so that the same program is always written the same way.
+        let mut stack_maps = self.stack_maps.iter().collect::<Vec<_>>();
+        stack_maps.sort_by_key(|(index, _)| **index);
+
+        let mut section = Encoder::default();
+        section.u32(stack_maps.len() as u32);
+        for (index, stack_map) in stack_maps {
+            section.u64(*index as u64);
+            section.u32(stack_map.len() as u32);
+            for slot in stack_map {
+                section.i64(*slot as i64);
+            }
+        }
+        section.write_section(out, STACK_MAP_SECTION)?;
+
+        let mut section = Encoder::default();
+        section.u32(self.files.len() as u32);
+        for file in &self.files {
+            section.string(file);
+        }
+        section.u32(self.lines.len() as u32);
+        for LineEntry { start, file, line, column } in &self.lines {
+            section.u64(*start as u64);
+            section.u32(*file as u32);
+            section.u32(*line as u32);
+            section.u32(*column as u32);
+        }
+        section.write_section(out, LINE_SECTION)?;
+
+        Ok(())
+    }
+
+    pub fn read_from(input: &mut dyn Read) -> Result<Program, FormatError> {
+        let mut bytes = vec![];
+        input.read_to_end(&mut bytes).map_err(|err| FormatError(format!("Could not read program: {err}")))?;
+
+        let mut file = Decoder { bytes: &bytes };
+
+        if file.take(4)? != MAGIC {
+            return Err("Not a compiled Nom program".into());
+        }
+
+        let version = file.u32()?;
+        if version != FORMAT_VERSION {
+            return Err(format!("Compiled with format version {version}, but only version {FORMAT_VERSION} can be read").into());
+        }
+
+        let mut program = Program::default();
+        let mut seen = vec![];
+
+        while !file.bytes.is_empty() {
+            let tag = file.u8()?;
+            let length = file.u64()? as usize;
+            let mut section = Decoder { bytes: file.take(length)? };
+
+            if seen.contains(&tag) {
+                return Err(format!("Section {tag} appears twice").into());
+            }
+            seen.push(tag);
+
+            match tag {
+                CODE_SECTION => {
+                    for _ in 0..section.u32()? {
+                        program.instructions.push(section.instruction()?);
+                    }
+                },
+                SYMBOL_SECTION => {
+                    for _ in 0..section.u32()? {
+                        let name = section.string()?;
+                        let start = section.u64()? as usize;
+                        let end = section.u64()? as usize;
+                        let operand_start = section.u64()? as usize;
+                        let locals = (0..section.u32()?)
+                            .map(|_| section.local())
+                            .collect::<Result<_, _>>()?;
+                        program.functions.push(FunctionSymbol { name, start, end, locals, operand_start });
+                    }
+                },
+                MESSAGE_SECTION => {
+                    for _ in 0..section.u32()? {
+                        program.messages.push(section.string()?);
+                    }
+                },
+                DATA_SECTION => {
+                    program.data_size = section.u64()? as usize;
+                    for _ in 0..section.u32()? {
+                        program.data_roots.push(section.u64()? as usize);
+                    }
+                },
+                STACK_MAP_SECTION => {
+                    for _ in 0..section.u32()? {
+                        let index = section.u64()? as usize;
+                        let stack_map = (0..section.u32()?)
+                            .map(|_| section.i64().map(|slot| slot as isize))
+                            .collect::<Result<_, _>>()?;
+                        program.stack_maps.insert(index, stack_map);
+                    }
+                },
+                LINE_SECTION => {
+                    for _ in 0..section.u32()? {
+                        program.files.push(section.string()?);
+                    }
+                    for _ in 0..section.u32()? {
+                        program.lines.push(LineEntry {
+                            start: section.u64()? as usize,
+                            file: section.u32()? as usize,
+                            line: section.u32()? as usize,
+                            column: section.u32()? as usize,
+                        });
+                    }
+                },
+                _ => return Err(format!("Unknown section {tag}").into()),
+            }
+
+            if !section.bytes.is_empty() {
+                return Err(format!("Section {tag} is longer than its contents").into());
+            }
+        }
+
+        Ok(program)
+    }
+}
+
+
+#[derive(Default)]
+struct Encoder {
+    bytes: Vec<u8>,
+}
+
+impl Encoder {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend(value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes.extend(value.to_le_bytes());
+    }
+
+    fn i64(&mut self, value: i64) {
+        self.bytes.extend(value.to_le_bytes());
+    }
+
+    fn string(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.bytes.extend(value.as_bytes());
+    }
+
+    fn write_section(self, out: &mut dyn Write, tag: u8) -> std::io::Result<()> {
+        out.write_all(&[tag])?;
+        out.write_all(&(self.bytes.len() as u64).to_le_bytes())?;
+        out.write_all(&self.bytes)
+    }
+
+    fn instruction(&mut self, instruction: Instruction) {
+        use Instruction as I;
+
+        match instruction {
+            I::IntegerBinaryOperation(op, size) => {
+                self.u8(0);
+                self.u8(op as u8);
+                self.u8(size as u8);
+            },
+            I::IntegerComparisonOperation { comparison, size, signed } => {
+                self.u8(1);
+                self.u8(comparison as u8);
+                self.u8(size as u8);
+                self.u8(u8::from(signed));
+            },
+            I::UnaryOperation(op, size) => {
+                self.u8(2);
+                self.u8(op as u8);
+                self.u8(size as u8);
+            },
+            I::BooleanNot => self.u8(3),
+            I::AdvanceStackPtr(amount) => {
+                self.u8(4);
+                self.u64(amount as u64);
+            },
+            I::RetractStackPtr(amount) => {
+                self.u8(5);
+                self.u64(amount as u64);
+            },
+            I::RetractMoving(amount, size) => {
+                self.u8(6);
+                self.u64(amount as u64);
+                self.u8(size as u8);
+            },
+            I::DebugPrintSigned(size) => {
+                self.u8(7);
+                self.u8(size as u8);
+            },
+            I::Duplicate(size) => {
+                self.u8(8);
+                self.u8(size as u8);
+            },
+            I::PushConstant(constant) => {
+                self.u8(9);
+                match constant {
+                    Constant::OneByte(value) => {
+                        self.u8(IntSize::OneByte as u8);
+                        self.u64(u64::from(value));
+                    },
+                    Constant::TwoByte(value) => {
+                        self.u8(IntSize::TwoByte as u8);
+                        self.u64(u64::from(value));
+                    },
+                    Constant::FourByte(value) => {
+                        self.u8(IntSize::FourByte as u8);
+                        self.u64(u64::from(value));
+                    },
+                    Constant::EightByte(value) => {
+                        self.u8(IntSize::EightByte as u8);
+                        self.u64(value);
+                    },
+                }
+            },
+            I::ReadBase(offset, size) => {
+                self.u8(10);
+                self.i64(offset as i64);
+                self.u8(size as u8);
+            },
+            I::WriteBase(offset, size) => {
+                self.u8(11);
+                self.i64(offset as i64);
+                self.u8(size as u8);
+            },
+            I::Call(index) => {
+                self.u8(12);
+                self.u64(index as u64);
+            },
+            I::CallIndirect => self.u8(13),
+            I::IntegerConversion(start_size, start_signed, end_size, end_signed) => {
+                self.u8(14);
+                self.u8(start_size as u8);
+                self.u8(u8::from(start_signed));
+                self.u8(end_size as u8);
+                self.u8(u8::from(end_signed));
+            },
+            I::Return => self.u8(15),
+            I::RelativeJumpIfTrue(shift) => {
+                self.u8(16);
+                self.i64(i64::from(shift));
+            },
+            I::RelativeJumpIfFalse(shift) => {
+                self.u8(17);
+                self.i64(i64::from(shift));
+            },
+            I::RelativeJump(shift) => {
+                self.u8(18);
+                self.i64(i64::from(shift));
+            },
+            I::HeapAlloc => self.u8(19),
+            I::HeapFree => self.u8(20),
+            I::HeapRead(size) => {
+                self.u8(21);
+                self.u8(size as u8);
+            },
+            I::HeapWrite(size) => {
+                self.u8(22);
+                self.u8(size as u8);
+            },
+            I::LoadGlobal(offset, size) => {
+                self.u8(23);
+                self.u64(offset as u64);
+                self.u8(size as u8);
+            },
+            I::StoreGlobal(offset, size) => {
+                self.u8(24);
+                self.u64(offset as u64);
+                self.u8(size as u8);
+            },
+            I::Exit => self.u8(25),
+            I::ExitWithError(size, signed) => {
+                self.u8(26);
+                self.u8(size as u8);
+                self.u8(u8::from(signed));
+            },
+            I::Trap(message) => {
+                self.u8(27);
+                self.u64(message as u64);
+            },
+        }
+    }
+}
+
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], FormatError> {
+        if count > self.bytes.len() {
+            return Err("Unexpected end of program".into());
+        }
+
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, FormatError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, FormatError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().expect("4 bytes")))
+    }
+
+    fn u64(&mut self) -> Result<u64, FormatError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().expect("8 bytes")))
+    }
+
+    fn i64(&mut self) -> Result<i64, FormatError> {
+        Ok(i64::from_le_bytes(self.take(8)?.try_into().expect("8 bytes")))
+    }
+
+    fn string(&mut self) -> Result<String, FormatError> {
+        let length = self.u32()? as usize;
+
+        String::from_utf8(self.take(length)?.to_vec()).map_err(|_| "String is not UTF-8".into())
+    }
+
+    fn bool(&mut self) -> Result<bool, FormatError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            other => Err(format!("Bad boolean {other}").into()),
+        }
+    }
+
+    fn local(&mut self) -> Result<LocalSymbol, FormatError> {
+        Ok(LocalSymbol {
+            name: self.string()?,
+            type_name: self.string()?,
+            offset: self.i64()? as isize,
+            size: self.u64()? as usize,
+            format: match self.u8()? {
+                0 => ValueFormat::Signed,
+                1 => ValueFormat::Unsigned,
+                2 => ValueFormat::Boolean,
+                3 => ValueFormat::Bytes,
+                other => return Err(format!("Bad value format {other}").into()),
+            },
+        })
+    }
+
+    fn jump(&mut self) -> Result<i32, FormatError> {
+        i32::try_from(self.i64()?).map_err(|_| "Jump is too far".into())
+    }
+
+    fn size(&mut self) -> Result<IntSize, FormatError> {
+        match self.u8()? {
+            0 => Ok(IntSize::OneByte),
+            1 => Ok(IntSize::TwoByte),
+            2 => Ok(IntSize::FourByte),
+            3 => Ok(IntSize::EightByte),
+            other => Err(format!("Bad integer size {other}").into()),
+        }
+    }
+
+    fn instruction(&mut self) -> Result<Instruction, FormatError> {
+        use Instruction as I;
+        use IntegerBinaryOperation as B;
+        use Comparison as C;
+
+        let instruction = match self.u8()? {
+            0 => {
+                let op = match self.u8()? {
+                    0 => B::UnsignedAddition,
+                    1 => B::SignedAddition,
+                    2 => B::UnsignedSubtraction,
+                    3 => B::SignedSubtraction,
+                    4 => B::UnsignedMultiplication,
+                    5 => B::SignedMultiplication,
+                    6 => B::UnsignedDivision,
+                    7 => B::SignedDivision,
+                    8 => B::UnsignedModulus,
+                    9 => B::SignedModulus,
+                    other => return Err(format!("Bad binary operation {other}").into()),
+                };
+
+                I::IntegerBinaryOperation(op, self.size()?)
+            },
+            1 => {
+                let comparison = match self.u8()? {
+                    0 => C::Equals,
+                    1 => C::NotEquals,
+                    2 => C::LessEquals,
+                    3 => C::GreaterEquals,
+                    4 => C::Less,
+                    5 => C::Greater,
+                    other => return Err(format!("Bad comparison {other}").into()),
+                };
+
+                I::IntegerComparisonOperation { comparison, size: self.size()?, signed: self.bool()? }
+            },
+            2 => {
+                let op = match self.u8()? {
+                    0 => IntegerUnaryOperation::NegateSigned,
+                    other => return Err(format!("Bad unary operation {other}").into()),
+                };
+
+                I::UnaryOperation(op, self.size()?)
+            },
+            3 => I::BooleanNot,
+            4 => I::AdvanceStackPtr(self.u64()? as usize),
+            5 => I::RetractStackPtr(self.u64()? as usize),
+            6 => I::RetractMoving(self.u64()? as usize, self.size()?),
+            7 => I::DebugPrintSigned(self.size()?),
+            8 => I::Duplicate(self.size()?),
+            9 => {
+                let size = self.size()?;
+                let value = self.u64()?;
+                let too_big = || FormatError(format!("Constant {value} does not fit in {} bytes", size.to_usize()));
+
+                I::PushConstant(match size {
+                    IntSize::OneByte => Constant::OneByte(u8::try_from(value).map_err(|_| too_big())?),
+                    IntSize::TwoByte => Constant::TwoByte(u16::try_from(value).map_err(|_| too_big())?),
+                    IntSize::FourByte => Constant::FourByte(u32::try_from(value).map_err(|_| too_big())?),
+                    IntSize::EightByte => Constant::EightByte(value),
+                })
+            },
+            10 => I::ReadBase(self.i64()? as isize, self.size()?),
+            11 => I::WriteBase(self.i64()? as isize, self.size()?),
+            12 => I::Call(self.u64()? as usize),
+            13 => I::CallIndirect,
+            14 => I::IntegerConversion(self.size()?, self.bool()?, self.size()?, self.bool()?),
+            15 => I::Return,
+            16 => I::RelativeJumpIfTrue(self.jump()?),
+            17 => I::RelativeJumpIfFalse(self.jump()?),
+            18 => I::RelativeJump(self.jump()?),
+            19 => I::HeapAlloc,
+            20 => I::HeapFree,
+            21 => I::HeapRead(self.size()?),
+            22 => I::HeapWrite(self.size()?),
+            23 => I::LoadGlobal(self.u64()? as usize, self.size()?),
+            24 => I::StoreGlobal(self.u64()? as usize, self.size()?),
+            25 => I::Exit,
+            26 => I::ExitWithError(self.size()?, self.bool()?),
+            27 => I::Trap(self.u64()? as usize),
+            other => return Err(format!("Bad opcode {other}").into()),
+        };
+
+        Ok(instruction)
+    }
+}
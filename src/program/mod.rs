@@ -0,0 +1,132 @@
+/* A compiled program. This is the instruction stream, along with any tables the
+ * runtime needs to make sense of it. Since instructions deliberately contain no
+ * strings or other bulky data, anything like that lives here instead. */
+
+#[cfg(test)]
+mod tests;
+
+mod format;  // Reading and writing programs as .nomc files
+mod verify;  // Checks that a program is safe to run
+mod assembly;  // A readable, writable text form of programs
+
+use std::collections::{HashMap, HashSet};
+
+use crate::instructions::{Instruction, Constant};
+
+pub use format::FORMAT_VERSION;
+pub(crate) use assembly::format_alone;
+
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Program {
+    pub instructions: Vec<Instruction>,
+
+    // Maps the index of each safepoint (an instruction that may start a garbage
+    // collection, or a call to a function that might) to its stack map.
+    pub stack_maps: HashMap<usize, StackMap>,
+
+    // The size in bytes of the data segment, which holds global variables. It starts zeroed.
+    pub data_size: usize,
+
+    // Offsets into the data segment of the globals that may hold heap addresses. These
+    // are always roots for the garbage collector.
+    pub data_roots: Vec<usize>,
+
+    // The messages of traps, which refer to them by index.
+    pub messages: Vec<String>,
+
+    // Every function, in the order they are laid out.
+    pub functions: Vec<FunctionSymbol>,
+
+    // Maps instructions back to the source they were generated from. Each entry covers
+    // the instructions from its start up to the start of the next, and they are sorted.
+    // Instructions before the first entry, like the driver, have no location.
+    pub lines: Vec<LineEntry>,
+
+    // The source files that the line table refers to by index.
+    pub files: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FunctionSymbol {
+    pub name: String,
+    pub start: usize,  // The index of its first instruction
+    pub end: usize,  // One past its last instruction
+
+    // The parameters and locals, for debuggers. Locals of different blocks may share a slot.
+    pub locals: Vec<LocalSymbol>,
+    pub operand_start: usize,  // Where values being computed start, as an offset from the base pointer
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LocalSymbol {
+    pub name: String,  // As written in the source
+    pub type_name: String,
+    pub offset: isize,  // From the base pointer
+    pub size: usize,
+    pub format: ValueFormat,
+}
+
+// How a debugger should show a value.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ValueFormat {
+    Signed,
+    Unsigned,
+    Boolean,
+    Bytes,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LineEntry {
+    pub start: usize,
+    pub file: usize,
+    pub line: usize,  // 1 based, like Span
+    pub column: usize,
+}
+
+// Lists the frame slots that may hold heap addresses while execution is paused at
+// a safepoint. Each slot is 8 bytes, and is given as an offset from the base pointer
+// of the frame containing the safepoint.
+pub type StackMap = Vec<isize>;
+
+impl From<Vec<Instruction>> for Program {
+    fn from(instructions: Vec<Instruction>) -> Self {
+        Program { instructions, ..Default::default() }
+    }
+}
+
+impl Program {
+    // The function containing the instruction, if it is in one.
+    pub fn function_at(&self, index: usize) -> Option<&FunctionSymbol> {
+        let after = self.functions.partition_point(|symbol| symbol.start <= index);
+
+        self.functions[..after].last().filter(|symbol| index < symbol.end)
+    }
+
+    // The line table entry covering the instruction, if it has a location.
+    pub fn location(&self, index: usize) -> Option<&LineEntry> {
+        let after = self.lines.partition_point(|entry| entry.start <= index);
+
+        self.lines[..after].last()
+    }
+
+    // The functions that are pushed as values, which are all that CallIndirect may call.
+    // Closures hold the same values, which were pushed before being stored.
+    pub fn function_values(&self) -> HashSet<usize> {
+        self.instructions.iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::PushConstant(Constant::EightByte(value)) if value & 1 == 1 => Some((value >> 1) as usize),
+                _ => None,
+            })
+            .filter(|index| self.functions.iter().any(|symbol| symbol.start == *index))
+            .collect()
+    }
+
+    // Describes where the instruction came from, as file:line:column.
+    pub fn describe_location(&self, index: usize) -> Option<String> {
+        let entry = self.location(index)?;
+        let file = self.files.get(entry.file)?;
+
+        Some(format!("{file}:{}:{}", entry.line, entry.column))
+    }
+}
@@ -0,0 +1,322 @@
+use super::{Program, FunctionSymbol, LocalSymbol, ValueFormat, LineEntry, FORMAT_VERSION};
+
+use crate::instructions::{Instruction, Constant, IntSize, IntegerBinaryOperation, IntegerUnaryOperation, Comparison};
+
+
+use Instruction as I;
+
+fn write(program: &Program) -> Vec<u8> {
+    let mut bytes = vec![];
+    program.write_to(&mut bytes).expect("Writes to memory");
+    bytes
+}
+
+fn read_error(bytes: &[u8]) -> String {
+    Program::read_from(&mut &bytes[..]).expect_err("Should not read").0
+}
+
+#[test]
+fn round_trips_every_section() {
+    let mut program = Program {
+        data_size: 24,
+        data_roots: vec![8, 16],
+        messages: vec!["Assertion Failed at a.nom:1".to_string()],
+        functions: vec![FunctionSymbol {
+            name: "main".to_string(),
+            start: 0,
+            end: 5,
+            locals: vec![LocalSymbol { name: "count".to_string(), type_name: "?u16".to_string(), offset: -8, size: 4, format: ValueFormat::Bytes }],
+            operand_start: 24,
+        }],
+        files: vec!["a.nom".to_string()],
+        lines: vec![LineEntry { start: 0, file: 0, line: 1, column: 5 }, LineEntry { start: 3, file: 0, line: 2, column: 1 }],
+        ..Program::from(vec![
+            I::PushConstant(Constant::TwoByte(513)),
+            I::ReadBase(-24, IntSize::EightByte),
+            I::RelativeJumpIfFalse(-3),
+            I::Trap(0),
+            I::Exit,
+        ])
+    };
+    program.stack_maps.insert(1, vec![-16, 8]);
+
+    let copy = Program::read_from(&mut write(&program).as_slice()).expect("Reads");
+
+    assert_eq!(program, copy);
+}
+
+#[test]
+fn rejects_other_files() {
+    assert_eq!(read_error(b"fn main() -> i32 { 0 }"), "Not a compiled Nom program");
+    assert_eq!(read_error(b"NO"), "Unexpected end of program");
+}
+
+#[test]
+fn rejects_other_versions() {
+    let mut bytes = write(&Program::default());
+    bytes[4..8].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+
+    assert!(read_error(&bytes).starts_with(&format!("Compiled with format version {}", FORMAT_VERSION + 1)));
+}
+
+#[test]
+fn rejects_truncated_programs() {
+    let bytes = write(&Program::from(vec![I::Call(7), I::Exit]));
+
+    assert_eq!(read_error(&bytes[..bytes.len() - 1]), "Unexpected end of program");
+}
+
+fn verify_error(instructions: Vec<Instruction>) -> String {
+    Program::from(instructions).verify().expect_err("Should not verify").0
+}
+
+#[test]
+fn verifies_loops_and_calls() {
+    let program = Program::from(vec![
+        I::AdvanceStackPtr(8),
+        I::Call(6),
+        I::RetractStackPtr(4),
+        I::DebugPrintSigned(IntSize::FourByte),
+        I::Exit,
+        I::Exit,  // Unreachable, so never checked
+        I::PushConstant(Constant::OneByte(1)),  // A function looping until it returns
+        I::RelativeJumpIfFalse(3),
+        I::PushConstant(Constant::OneByte(0)),
+        I::RelativeJump(-2),
+        I::Return,
+    ]);
+
+    assert!(program.verify().is_ok());
+}
+
+#[test]
+fn rejects_jumps_and_calls_out_of_range() {
+    assert!(verify_error(vec![I::RelativeJump(-1)]).contains("outside of the program"));
+    assert!(verify_error(vec![I::AdvanceStackPtr(8), I::Call(5), I::Exit]).contains("past the last instruction"));
+}
+
+#[test]
+fn rejects_calls_into_the_middle_of_functions() {
+    let program = Program {
+        functions: vec![FunctionSymbol { name: "main".to_string(), start: 2, end: 4, ..Default::default() }],
+        ..Program::from(vec![I::AdvanceStackPtr(8), I::Call(3), I::Exit, I::Return])
+    };
+
+    assert!(program.verify().expect_err("Should not verify").0.contains("does not start a function"));
+}
+
+#[test]
+fn rejects_inconsistent_joins() {
+    let error = verify_error(vec![
+        I::PushConstant(Constant::OneByte(1)),
+        I::RelativeJumpIfTrue(2),
+        I::PushConstant(Constant::OneByte(7)),
+        I::Exit,
+    ]);
+
+    assert_eq!(error, "Instruction 3 is reached with 1 bytes on the stack, and also with 0");
+}
+
+#[test]
+fn rejects_popping_too_much() {
+    let error = verify_error(vec![
+        I::PushConstant(Constant::FourByte(1)),
+        I::IntegerBinaryOperation(IntegerBinaryOperation::SignedAddition, IntSize::FourByte),
+        I::Exit,
+    ]);
+
+    assert!(error.contains("pops 4 bytes, but the stack only holds 0"));
+}
+
+#[test]
+fn rejects_misaligned_values() {
+    let error = verify_error(vec![I::PushConstant(Constant::OneByte(1)), I::PushConstant(Constant::FourByte(1)), I::Exit]);
+
+    assert!(error.contains("needs an alignment of 4"));
+}
+
+#[test]
+fn rejects_running_off_the_end() {
+    assert!(verify_error(vec![I::PushConstant(Constant::OneByte(1))]).contains("past the last instruction"));
+}
+
+#[test]
+fn rejects_returning_from_the_driver() {
+    assert!(verify_error(vec![I::Return]).contains("returns from outside of a function"));
+}
+
+#[test]
+fn rejects_missing_messages() {
+    assert!(verify_error(vec![I::Trap(0)]).contains("uses message 0, but there are only 0"));
+}
+
+#[test]
+fn rejects_frame_slots_outside_the_frame() {
+    let function = |body: Vec<Instruction>| {
+        let mut instructions = vec![I::AdvanceStackPtr(8), I::Call(3), I::Exit];
+        instructions.extend(body);
+        verify_error(instructions)
+    };
+
+    // Above the stack, or misaligned.
+    assert!(function(vec![I::ReadBase(16, IntSize::FourByte), I::Return]).contains("uses 4 bytes at offset 16, but the stack is only 16 bytes high"));
+    assert!(function(vec![I::AdvanceStackPtr(8), I::ReadBase(18, IntSize::FourByte), I::Return]).contains("not aligned to 4"));
+
+    // The saved registers, and below what callers push.
+    assert!(function(vec![I::PushConstant(Constant::EightByte(0)), I::WriteBase(8, IntSize::EightByte), I::Return])
+        .contains("holds the saved return address and base pointer"));
+    assert!(function(vec![I::ReadBase(-16, IntSize::EightByte), I::Return]).contains("only push 8 bytes"));
+
+    assert!(verify_error(vec![I::ReadBase(-8, IntSize::EightByte), I::Exit]).contains("below the driver's frame"));
+
+    // Taller than any stack, without overflowing the height.
+    assert!(verify_error(vec![I::AdvanceStackPtr(8), I::AdvanceStackPtr(usize::MAX), I::Exit]).contains("past the largest stack"));
+}
+
+#[test]
+fn rejects_stack_maps_outside_the_frame() {
+    let mut program = Program::from(vec![I::AdvanceStackPtr(8), I::Call(3), I::Exit, I::AdvanceStackPtr(8), I::Call(7), I::RetractStackPtr(8), I::Return, I::Return]);
+    program.stack_maps.insert(4, vec![16, -8]);
+
+    assert!(program.verify().is_ok());
+
+    program.stack_maps.insert(4, vec![24]);
+    assert!(program.verify().expect_err("Should not verify").0.contains("The stack map of instruction 4 uses 8 bytes at offset 24"));
+
+    program.stack_maps.insert(4, vec![-16]);
+    assert!(program.verify().expect_err("Should not verify").0.contains("only push 8 bytes"));
+
+    program.stack_maps.clear();
+    program.stack_maps.insert(8, vec![]);
+    assert!(program.verify().expect_err("Should not verify").0.contains("past the last instruction"));
+}
+
+#[test]
+fn rejects_globals_outside_the_data_segment() {
+    let program = |instructions, data_roots| Program { data_size: 12, data_roots, ..Program::from(instructions) };
+
+    assert!(program(vec![I::LoadGlobal(8, IntSize::FourByte), I::Exit], vec![0]).verify().is_ok());
+
+    let error = |program: Program| program.verify().expect_err("Should not verify").0;
+
+    assert!(error(program(vec![I::LoadGlobal(12, IntSize::FourByte), I::Exit], vec![])).contains("inside the 12 byte data segment"));
+    assert!(error(program(vec![I::LoadGlobal(2, IntSize::FourByte), I::Exit], vec![])).contains("not aligned"));
+    assert!(error(program(vec![I::Exit], vec![8])).contains("The data root at 8"));
+    assert!(error(program(vec![I::Exit], vec![4])).contains("The data root at 4"));
+
+    let huge = Program { data_size: 1 << 62, ..Program::from(vec![I::Exit]) };
+    assert!(error(huge).contains("The data segment takes 4611686018427387904 bytes"));
+}
+
+#[test]
+fn rejects_debug_info_outside_frames() {
+    let program = |operand_start, offset, size| Program {
+        functions: vec![FunctionSymbol {
+            name: "f".to_string(),
+            start: 3,
+            end: 4,
+            locals: vec![LocalSymbol { name: "x".to_string(), type_name: "i32".to_string(), offset, size, format: ValueFormat::Signed }],
+            operand_start,
+        }],
+        ..Program::from(vec![I::AdvanceStackPtr(8), I::Call(3), I::Exit, I::Return])
+    };
+    let error = |program: Program| program.verify().expect_err("Should not verify").0;
+
+    assert!(program(24, -8, 4).verify().is_ok());
+    assert!(program(16, 16, 16).verify().is_ok());
+
+    assert!(error(program(8, 16, 4)).contains("The values of f start at offset 8"));
+    assert!(error(program(1 << 40, 16, 4)).contains("The values of f start at offset 1099511627776"));
+    assert!(error(program(24, 8, 4)).contains("The local x of f takes 4 bytes at offset 8"));
+    assert!(error(program(24, -4, 8)).contains("at offset -4"));
+    assert!(error(program(24, isize::MIN, 4)).contains("at offset -9223372036854775808"));
+    assert!(error(program(24, 16, 17)).contains("takes 17 bytes"));
+}
+
+#[test]
+fn assembles_labels_functions_and_messages() {
+    let program = Program::assemble(r#"
+        ; A comment, then the driver
+            advance 8
+            call <closure 3>
+            exit
+        <closure 3>:
+        .L3:
+        3   push 1 1          ; the index column is ignored
+            jump_if_true .L3
+            trap "Reached; \"here\""
+    "#).expect("Assembles");
+
+    assert_eq!(program.instructions, [
+        I::AdvanceStackPtr(8),
+        I::Call(3),
+        I::Exit,
+        I::PushConstant(Constant::OneByte(1)),
+        I::RelativeJumpIfTrue(-1),
+        I::Trap(0),
+    ]);
+    assert_eq!(program.functions, [FunctionSymbol { name: "<closure 3>".to_string(), start: 3, end: 6, operand_start: 16, ..Default::default() }]);
+    assert_eq!(program.messages, ["Reached; \"here\""]);
+}
+
+#[test]
+fn disassembles_what_it_assembles() {
+    let program = Program {
+        messages: vec!["Assertion Failed at \"odd\" name.nom:2".to_string()],
+        functions: vec![FunctionSymbol { name: "math::gcd".to_string(), start: 2, end: 9, operand_start: 16, ..Default::default() }],
+        ..Program::from(vec![
+            I::Call(2),
+            I::Exit,
+            I::IntegerComparisonOperation { comparison: Comparison::LessEquals, size: IntSize::TwoByte, signed: true },
+            I::IntegerConversion(IntSize::OneByte, false, IntSize::EightByte, true),
+            I::UnaryOperation(IntegerUnaryOperation::NegateSigned, IntSize::EightByte),
+            I::ExitWithError(IntSize::FourByte, true),
+            I::RelativeJump(-4),
+            I::RelativeJumpIfFalse(100),
+            I::Trap(0),
+        ])
+    };
+
+    let text = program.disassemble();
+
+    assert!(text.contains("math::gcd:\n"));
+    assert!(text.contains("call math::gcd"));
+    assert!(text.contains("jump .L2"));
+    assert!(text.contains("jump_if_false +100"));
+
+    assert_eq!(Program::assemble(&text).expect("Assembles"), program);
+}
+
+#[test]
+fn reports_assembly_errors_by_line() {
+    let error = |text: &str| Program::assemble(text).expect_err("Should not assemble").0;
+
+    assert_eq!(error("exit\nfrobnicate 4"), "Line 2: Unknown instruction frobnicate");
+    assert_eq!(error("push 4"), "Line 1: push takes 2 operands, but 1 were given");
+    assert_eq!(error("push 1 256"), "Line 1: 256 does not fit in 1 bytes");
+    assert_eq!(error("add.x 4"), "Line 1: Expected s or u, not x");
+    assert_eq!(error("jump .L_nowhere"), "Line 1: No label named .L_nowhere");
+    assert_eq!(error("a:\na:\nexit"), "Line 2: a is defined twice");
+}
+
+#[test]
+fn finds_functions_and_locations() {
+    let program = Program {
+        functions: vec![
+            FunctionSymbol { name: "main".to_string(), start: 2, end: 4, ..Default::default() },
+            FunctionSymbol { name: "square".to_string(), start: 4, end: 6, ..Default::default() },
+        ],
+        files: vec!["a.nom".to_string()],
+        lines: vec![LineEntry { start: 2, file: 0, line: 3, column: 5 }, LineEntry { start: 4, file: 0, line: 7, column: 9 }],
+        ..Program::from(vec![I::AdvanceStackPtr(8), I::Call(2), I::Return, I::Return, I::Return, I::Return])
+    };
+
+    let function = |index| program.function_at(index).map(|symbol| symbol.name.as_str());
+
+    assert_eq!([function(0), function(1), function(2), function(3), function(4), function(5), function(6)],
+        [None, None, Some("main"), Some("main"), Some("square"), Some("square"), None]);
+
+    assert_eq!(program.describe_location(1), None);
+    assert_eq!(program.describe_location(3).as_deref(), Some("a.nom:3:5"));
+    assert_eq!(program.describe_location(5).as_deref(), Some("a.nom:7:9"));
+}
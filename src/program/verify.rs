@@ -0,0 +1,400 @@
+/* Checks that a program can run without corrupting the VM, since programs may be loaded
+ * from files and the runtime trusts its instructions completely. Every instruction that
+ * can be reached is followed, tracking the height of the stack above the base of its
+ * frame. Along every path:
+ *
+ *  - Jumps and calls land on instructions, and calls land on functions.
+ *  - Values are never popped from below the frame, and are pushed and popped at an
+ *    alignment that suits their size. Calls happen at an alignment of 8.
+ *  - Wherever paths join, they agree on the height of the stack, and on the function.
+ *  - Execution ends with Return, Exit, or a trap, rather than running off the end.
+ *  - Slots of the frame are read and written at an alignment that suits their size, and
+ *    lie below the stack's height before the instruction. Slots below the frame, where
+ *    arguments are passed, go no deeper than any call to the function has pushed. Stack
+ *    maps are held to the same.
+ *  - The debug info only describes slots that a frame can have, with values that fit in
+ *    16 bytes, so a debugger can read them once they are below the stack pointer.
+ *  - Globals and the data roots lie inside the data segment, which is no larger than a
+ *    runtime allows. A frame is no taller than the largest stack, though only the
+ *    runtime knows the stack it actually has.
+ *
+ * The driver at index 0 starts on an empty stack, and may not return. Functions start
+ * above the saved return address and base pointer that Call pushes, which are never
+ * read or written. CallIndirect may call any function pushed as a value, so those must
+ * accept the fewest bytes that any CallIndirect pushes. */
+
+use std::collections::HashMap;
+
+use super::{Program, LocalSymbol};
+use crate::instructions::{Instruction, IntSize, Constant};
+use crate::error::VerifyError;
+use crate::runtime::RuntimeConfig;
+
+
+const FUNCTION_START: usize = 16;  // The return address and the previous base pointer
+
+
+// Where a path has reached, and the stack along it.
+#[derive(Clone, Copy)]
+struct State {
+    index: usize,
+    height: usize,
+    floor: usize,  // The height of an empty operand stack in this frame
+    function: Option<usize>,  // Where the function running starts, or None in the driver
+}
+
+// What the paths have found about frames, which can only be checked once every call is known.
+#[derive(Default)]
+struct Frames {
+    passed: HashMap<usize, usize>,  // The fewest bytes pushed by any call to each function
+    passed_indirectly: Option<usize>,  // The same for CallIndirect, which may call any function value
+    below: Vec<Below>,
+}
+
+// A use of a slot below the frame of a function, where its arguments are.
+struct Below {
+    index: usize,
+    function: usize,
+    offset: isize,
+    stack_map: bool,  // Whether it is in the stack map of the instruction, rather than used by it
+}
+
+impl State {
+    fn pop(&mut self, size: usize) -> Result<(), String> {
+        if self.height < self.floor + size {
+            return Err(format!("pops {size} bytes, but the stack only holds {}", self.height - self.floor));
+        }
+
+        self.height -= size;
+        aligned(self.height, size)
+    }
+
+    fn push(&mut self, size: usize) -> Result<(), String> {
+        aligned(self.height, size)?;
+        self.height += size;
+
+        Ok(())
+    }
+
+    fn advance(&mut self, amount: usize) -> Result<(), String> {
+        match self.height.checked_add(amount) {
+            Some(height) if height <= RuntimeConfig::MAX_STACK_SIZE => self.height = height,
+            _ => return Err(format!("advances {amount} bytes, past the largest stack of {} bytes", RuntimeConfig::MAX_STACK_SIZE)),
+        }
+
+        Ok(())
+    }
+
+    fn retract(&mut self, amount: usize) -> Result<(), String> {
+        if self.height < self.floor + amount {
+            return Err(format!("retracts {amount} bytes, but the stack only holds {}", self.height - self.floor));
+        }
+
+        self.height -= amount;
+
+        Ok(())
+    }
+
+    // Checks that the frame slot of size bytes can be used. Slots below the frame are
+    // recorded, to be checked once the calls are known.
+    fn slot(&self, offset: isize, size: usize, frames: &mut Frames, stack_map: bool) -> Result<(), String> {
+        if offset.rem_euclid(size as isize) != 0 {
+            return Err(format!("uses offset {offset}, which is not aligned to {size}"));
+        }
+
+        if offset < 0 {
+            let Some(function) = self.function
+                else { return Err(format!("uses offset {offset}, below the driver's frame")) };
+
+            frames.below.push(Below { index: self.index, function, offset, stack_map });
+        }
+        else if (offset as usize) < self.floor {
+            return Err(format!("uses offset {offset}, which holds the saved return address and base pointer"));
+        }
+        else if offset as usize + size > self.height {
+            return Err(format!("uses {size} bytes at offset {offset}, but the stack is only {} bytes high", self.height));
+        }
+
+        Ok(())
+    }
+}
+
+fn aligned(height: usize, alignment: usize) -> Result<(), String> {
+    if !height.is_multiple_of(alignment) {
+        return Err(format!("needs an alignment of {alignment}, but the stack is {height} bytes high"));
+    }
+
+    Ok(())
+}
+
+
+impl Program {
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        let instructions = &self.instructions;
+        let function_starts = self.functions.iter().map(|symbol| symbol.start).collect::<Vec<_>>();
+
+        let function_state = |start: usize| State { index: start, height: FUNCTION_START, floor: FUNCTION_START, function: Some(start) };
+
+        let mut paths = vec![State { index: 0, height: 0, floor: 0, function: None }];
+
+        for start in &function_starts {
+            paths.push(function_state(*start));
+        }
+
+        for (index, instruction) in instructions.iter().enumerate() {
+            if let Instruction::Call(target) = instruction {
+                if *target >= instructions.len() {
+                    return Err(format!("Instruction {index} calls {target}, past the last instruction").into());
+                }
+
+                // Without a symbol table, any instruction may start a function.
+                if !function_starts.is_empty() && !function_starts.contains(target) {
+                    return Err(format!("Instruction {index} calls {target}, which does not start a function").into());
+                }
+
+                paths.push(function_state(*target));
+            }
+        }
+
+        // The state before each instruction, once it has been reached.
+        let mut reached: Vec<Option<State>> = vec![None; instructions.len()];
+        let mut frames = Frames::default();
+
+        while let Some(state) = paths.pop() {
+            let Some(instruction) = instructions.get(state.index)
+                else { return Err(format!("Execution can reach {}, past the last instruction", state.index).into()) };
+
+            match reached[state.index] {
+                Some(earlier) if earlier.function != state.function => return Err(format!(
+                    "Instruction {} is reached in {}, and also in {}", state.index, describe(state.function), describe(earlier.function)).into()),
+                Some(earlier) if earlier.height == state.height => continue,
+                Some(earlier) => return Err(format!(
+                    "Instruction {} is reached with {} bytes on the stack, and also with {}", state.index, state.height, earlier.height).into()),
+                None => reached[state.index] = Some(state),
+            }
+
+            let successors = self.step(*instruction, state, &mut frames)
+                .map_err(|message| VerifyError(format!("Instruction {} ({instruction:?}) {message}", state.index)))?;
+
+            paths.extend(successors);
+        }
+
+        let mut stack_maps = self.stack_maps.iter().collect::<Vec<_>>();
+        stack_maps.sort();
+
+        for (index, offsets) in stack_maps {
+            if *index >= instructions.len() {
+                return Err(format!("There is a stack map for instruction {index}, past the last instruction").into());
+            }
+
+            // A stack map of an instruction that never runs is never used.
+            let Some(state) = reached[*index] else { continue };
+
+            for offset in offsets {
+                state.slot(*offset, 8, &mut frames, true)
+                    .map_err(|message| VerifyError(format!("The stack map of instruction {index} {message}")))?;
+            }
+        }
+
+        let function_values = self.function_values();
+
+        for Below { index, function, offset, stack_map } in frames.below {
+            let passed_indirectly = frames.passed_indirectly.filter(|_| function_values.contains(&function));
+            let passed = [frames.passed.get(&function).copied(), passed_indirectly].into_iter().flatten().min();
+
+            // A function that is never called never runs.
+            let Some(passed) = passed else { continue };
+
+            if offset.unsigned_abs() > passed {
+                let user = if stack_map { "The stack map of instruction" } else { "Instruction" };
+                return Err(format!("{user} {index} uses offset {offset}, but calls to the function at {function} only push {passed} bytes").into());
+            }
+        }
+
+        for symbol in &self.functions {
+            if !(FUNCTION_START..=RuntimeConfig::MAX_STACK_SIZE).contains(&symbol.operand_start) {
+                return Err(format!("The values of {} start at offset {}, which is not inside a frame", symbol.name, symbol.operand_start).into());
+            }
+
+            for LocalSymbol { name, offset, size, .. } in &symbol.locals {
+                let in_frame = match *offset {
+                    ..0 => offset.unsigned_abs() <= RuntimeConfig::MAX_STACK_SIZE && offset.unsigned_abs() >= *size,
+                    offset => (FUNCTION_START..=RuntimeConfig::MAX_STACK_SIZE).contains(&(offset as usize)),
+                };
+
+                if *size > 16 || !in_frame {
+                    return Err(format!("The local {name} of {} takes {size} bytes at offset {offset}, which is not a slot of a frame", symbol.name).into());
+                }
+            }
+        }
+
+        if self.data_size > RuntimeConfig::MAX_DATA_SIZE {
+            return Err(format!("The data segment takes {} bytes, but runtimes allow at most {}", self.data_size, RuntimeConfig::MAX_DATA_SIZE).into());
+        }
+
+        for offset in &self.data_roots {
+            if !offset.is_multiple_of(8) || offset.checked_add(8).is_none_or(|end| end > self.data_size) {
+                return Err(format!("The data root at {offset} is not a word inside the {} byte data segment", self.data_size).into());
+            }
+        }
+
+        Ok(())
+    }
+
+    // Applies the instruction to the stack, and gives the states that can follow it.
+    fn step(&self, instruction: Instruction, mut state: State, frames: &mut Frames) -> Result<Vec<State>, String> {
+        use Instruction as I;
+
+        let size = |int_size: IntSize| int_size.to_usize();
+        let mut jump_target = None;
+
+        match instruction {
+            I::IntegerBinaryOperation(_, int_size) => {
+                state.pop(size(int_size))?;
+                state.pop(size(int_size))?;
+                state.push(size(int_size))?;
+            },
+            I::IntegerComparisonOperation { size: int_size, .. } => {
+                state.pop(size(int_size))?;
+                state.pop(size(int_size))?;
+                state.push(1)?;
+            },
+            I::UnaryOperation(_, int_size) | I::DebugPrintSigned(int_size) => {
+                state.pop(size(int_size))?;
+                state.push(size(int_size))?;
+            },
+            I::BooleanNot => {
+                state.pop(1)?;
+                state.push(1)?;
+            },
+            I::AdvanceStackPtr(amount) => state.advance(amount)?,
+            I::RetractStackPtr(amount) => state.retract(amount)?,
+            I::RetractMoving(amount, int_size) => {
+                state.pop(size(int_size))?;
+                state.retract(amount)?;
+                state.push(size(int_size))?;
+            },
+            I::Duplicate(int_size) => {
+                state.pop(size(int_size))?;
+                state.push(size(int_size))?;
+                state.push(size(int_size))?;
+            },
+            I::PushConstant(constant) => {
+                let constant_size = match constant {
+                    Constant::OneByte(_) => 1,
+                    Constant::TwoByte(_) => 2,
+                    Constant::FourByte(_) => 4,
+                    Constant::EightByte(_) => 8,
+                };
+
+                state.push(constant_size)?;
+            },
+            I::ReadBase(offset, int_size) => {
+                state.slot(offset, size(int_size), frames, false)?;
+                state.push(size(int_size))?;
+            },
+            // The value may be written back where it was popped from, which is how codegen
+            // zeroes slots before making room for them.
+            I::WriteBase(offset, int_size) => {
+                state.slot(offset, size(int_size), frames, false)?;
+                state.pop(size(int_size))?;
+            },
+            I::LoadGlobal(offset, int_size) => {
+                self.global(offset, size(int_size))?;
+                state.push(size(int_size))?;
+            },
+            I::StoreGlobal(offset, int_size) => {
+                self.global(offset, size(int_size))?;
+                state.pop(size(int_size))?;
+            },
+            // The callee returns with the stack as it found it.
+            I::Call(target) => {
+                aligned(state.height, 8)?;
+
+                let passed = frames.passed.entry(target).or_insert(usize::MAX);
+                *passed = (*passed).min(state.height - state.floor);
+            },
+            // The target is only known as it runs, when it is checked against the function values.
+            I::CallIndirect => {
+                state.pop(8)?;
+                aligned(state.height, 8)?;
+
+                let passed = state.height - state.floor;
+                frames.passed_indirectly = Some(frames.passed_indirectly.map_or(passed, |fewest| fewest.min(passed)));
+            },
+            I::IntegerConversion(start_size, _, end_size, _) => {
+                state.pop(size(start_size))?;
+                state.push(size(end_size))?;
+            },
+            I::Return => {
+                if state.floor != FUNCTION_START {
+                    return Err("returns from outside of a function".to_string());
+                }
+
+                return Ok(vec![]);
+            },
+            I::RelativeJump(shift) => {
+                return Ok(vec![State { index: self.jump(state.index, shift)?, ..state }]);
+            },
+            I::RelativeJumpIfTrue(shift) | I::RelativeJumpIfFalse(shift) => {
+                state.pop(1)?;
+                jump_target = Some(self.jump(state.index, shift)?);
+            },
+            I::HeapAlloc => {
+                state.pop(8)?;
+                state.pop(8)?;
+                state.push(8)?;
+            },
+            I::HeapFree => state.pop(8)?,
+            I::HeapRead(int_size) => {
+                state.pop(8)?;
+                state.push(size(int_size))?;
+            },
+            I::HeapWrite(int_size) => {
+                state.pop(size(int_size))?;
+                state.pop(8)?;
+            },
+            I::Exit => return Ok(vec![]),
+            I::ExitWithError(int_size, _) => {
+                state.pop(size(int_size))?;
+                return Ok(vec![]);
+            },
+            I::Trap(message) => {
+                if message >= self.messages.len() {
+                    return Err(format!("uses message {message}, but there are only {}", self.messages.len()));
+                }
+
+                return Ok(vec![]);
+            },
+        }
+
+        let mut successors = vec![State { index: state.index + 1, ..state }];
+
+        if let Some(index) = jump_target {
+            successors.push(State { index, ..state });
+        }
+
+        Ok(successors)
+    }
+
+    fn global(&self, offset: usize, size: usize) -> Result<(), String> {
+        if !offset.is_multiple_of(size) || offset.checked_add(size).is_none_or(|end| end > self.data_size) {
+            return Err(format!("uses {size} bytes at {offset}, which are not aligned inside the {} byte data segment", self.data_size));
+        }
+
+        Ok(())
+    }
+
+    fn jump(&self, index: usize, shift: i32) -> Result<usize, String> {
+        index.checked_add_signed(shift as isize)
+            .filter(|target| *target < self.instructions.len())
+            .ok_or(format!("jumps by {shift}, outside of the program"))
+    }
+}
+
+fn describe(function: Option<usize>) -> String {
+    match function {
+        Some(start) => format!("the function at {start}"),
+        None => "the driver".to_string(),
+    }
+}
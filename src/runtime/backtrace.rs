@@ -0,0 +1,99 @@
+/* Walking the call stack, and describing it when a program fails. Every Call pushes the
+ * return index and the previous base pointer at the bottom of the new frame, so frames
+ * form a chain from the innermost out to the driver, which has no frame of its own.
+ *
+ * Runtime errors end the program with a panic, like always, but the message goes on to
+ * say which functions were running and where, using the program's debug info. */
+
+use super::Runtime;
+
+
+const BACKTRACE_LIMIT: usize = 16;  // Frames shown before the middle of a backtrace is left out.
+
+// A function that is running, and the instruction it is at. That is the current
+// instruction for the innermost frame, and the call being waited on for the rest.
+#[derive(Clone, Copy)]
+pub(super) struct Frame {
+    pub(super) index: usize,
+    pub(super) base: *mut u8,
+}
+
+impl Runtime {
+    // Innermost first, given the instruction the innermost frame is at.
+    pub(super) fn frames(&self, mut index: usize) -> Vec<Frame> {
+        let mut frames = vec![];
+
+        let mut base = self.base_pointer;
+
+        // The driver always reserves space for a return value before calling, so no frame
+        // starts at the bottom of the stack.
+        while base.cast_const() != self.stack_bottom {
+            frames.push(Frame { index, base });
+
+            let return_index = unsafe { base.cast::<u64>().read() } as usize;
+            base = unsafe { base.add(8).cast::<u64>().read() } as *mut u8;
+
+            index = return_index - 1;  // The call instruction.
+        }
+
+        frames
+    }
+
+    // Ends the program with a runtime error, while running an instruction.
+    pub(super) fn fail(&self, message: &str) -> ! {
+        // The index was advanced before execution.
+        panic!("Critical Runtime Error: {message}{}", self.describe_backtrace(self.instruction_index - 1));
+    }
+
+    // As above, but between instructions.
+    pub(super) fn fail_before(&self, message: &str) -> ! {
+        panic!("Critical Runtime Error: {message}{}", self.describe_backtrace(self.instruction_index));
+    }
+
+    // A line for each frame, each starting with a newline. Deep recursion repeats the same
+    // frame many times, so runs of it are shown once, and the middle of a long backtrace
+    // is left out.
+    fn describe_backtrace(&self, index: usize) -> String {
+        let mut runs: Vec<(usize, usize)> = vec![];  // Each frame's index, and how many times it repeats
+
+        for frame in self.frames(index) {
+            match runs.last_mut() {
+                Some((index, count)) if *index == frame.index => *count += 1,
+                _ => runs.push((frame.index, 1)),
+            }
+        }
+
+        let mut text = String::new();
+
+        for (i, (index, count)) in runs.iter().enumerate() {
+            if runs.len() > BACKTRACE_LIMIT && i == BACKTRACE_LIMIT / 2 {
+                let hidden = runs[i..runs.len() - BACKTRACE_LIMIT / 2].iter().map(|(_, count)| count).sum::<usize>();
+                text.push_str(&format!("\n    ... {hidden} more frames"));
+            }
+
+            if runs.len() > BACKTRACE_LIMIT && (BACKTRACE_LIMIT / 2..runs.len() - BACKTRACE_LIMIT / 2).contains(&i) {
+                continue;
+            }
+
+            text.push_str(&format!("\n    at {}", self.describe_frame(*index)));
+
+            if *count > 1 {
+                text.push_str(&format!("\n    ... repeated {} more times", count - 1));
+            }
+        }
+
+        text
+    }
+
+    pub(super) fn describe_frame(&self, index: usize) -> String {
+        let name = match self.debug_info.function_at(index) {
+            Some(symbol) => symbol.name.clone(),
+            None => format!("instruction {index}"),
+        };
+
+        match self.debug_info.describe_location(index) {
+            Some(location) => format!("{name} ({location})"),
+            None => name,
+        }
+    }
+}
@@ -0,0 +1,51 @@
+/* How much memory a runtime may use, chosen before it starts. The defaults suit ordinary
+ * programs, while a host can give deep recursion more room, or hold untrusted programs to
+ * less. Running past any limit is a runtime error.
+ *
+ *      let config = RuntimeConfig::new().stack_size(8 << 20).max_call_depth(Some(10_000));
+ *      let runtime = Runtime::with_config(program, config); */
+
+
+const DEFAULT_STACK_SIZE: usize = 1_048_576;  // In terms of u8 units. This is exactly a megabyte.
+const DEFAULT_HEAP_SIZE: usize = 16_777_216;  // 16 megabytes.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RuntimeConfig {
+    pub(super) stack_size: usize,
+    pub(super) heap_size: usize,
+    pub(super) max_call_depth: Option<usize>,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        RuntimeConfig { stack_size: DEFAULT_STACK_SIZE, heap_size: DEFAULT_HEAP_SIZE, max_call_depth: None }
+    }
+}
+
+impl RuntimeConfig {
+    pub const MIN_SIZE: usize = 8;  // One word, which the driver needs on the stack, and null takes in the heap.
+    pub const MAX_STACK_SIZE: usize = 1 << 30;  // A gigabyte
+    pub const MAX_HEAP_SIZE: usize = 1 << 30;
+    pub const MAX_DATA_SIZE: usize = 1 << 30;  // For globals, whose size is fixed by the program rather than chosen here.
+
+    pub fn new() -> RuntimeConfig {
+        RuntimeConfig::default()
+    }
+
+    // In bytes, rounded up to a whole number of words, and kept between MIN_SIZE and
+    // MAX_STACK_SIZE.
+    pub fn stack_size(self, bytes: usize) -> RuntimeConfig {
+        RuntimeConfig { stack_size: bytes.clamp(Self::MIN_SIZE, Self::MAX_STACK_SIZE).next_multiple_of(8), ..self }
+    }
+
+    // In bytes, rounded up to a whole number of words, and kept between MIN_SIZE and
+    // MAX_HEAP_SIZE. The first word is never handed out, so that address 0 can be null.
+    pub fn heap_size(self, bytes: usize) -> RuntimeConfig {
+        RuntimeConfig { heap_size: bytes.clamp(Self::MIN_SIZE, Self::MAX_HEAP_SIZE).next_multiple_of(8), ..self }
+    }
+
+    // How many calls may be running at once, if limited by more than the stack size.
+    pub fn max_call_depth(self, depth: Option<usize>) -> RuntimeConfig {
+        RuntimeConfig { max_call_depth: depth, ..self }
+    }
+}
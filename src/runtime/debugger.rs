@@ -0,0 +1,275 @@
+/* Pausing a program part way through, and looking at it. A debugger drives the program
+ * with these instead of run, and it is always paused before some instruction:
+ *
+ *  - step runs one instruction, and step_over runs a call as if it were one instruction.
+ *  - step_line runs until the source line changes, and next_line does the same while
+ *    running over calls.
+ *  - resume runs until a breakpoint. Breakpoints go on instructions, or on source lines,
+ *    which puts one wherever execution can enter the line.
+ *
+ * Running stops early at any breakpoint, once the program exits, or when it runs out of fuel
+ * or is interrupted. A runtime error stops it for good, paused at the instruction that
+ * failed, so that the frames leading up to it can still be looked at. While paused, every
+ * frame can be inspected, from the innermost (0) out to the driver at the bottom of the
+ * stack. */
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use super::{Runtime, RuntimeError};
+use super::backtrace::Frame;
+use crate::instructions::Instruction;
+use crate::program::ValueFormat;
+
+
+// Why running stopped.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Pause {
+    Stepped,  // The step finished.
+    Breakpoint,  // A breakpoint was reached first.
+    Exited,  // The program has ended, so nothing more will run.
+    Stopped(RuntimeError),  // Out of fuel or interrupted, before the next instruction.
+    Failed(String),  // A runtime error, with its message. Nothing more will run.
+}
+
+// A parameter or local of a paused frame.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Local {
+    pub name: String,
+    pub type_name: String,
+    pub value: Option<String>,  // None if its slot is not on the stack yet.
+}
+
+impl Runtime {
+    // The index of the instruction that runs next.
+    pub fn position(&self) -> usize {
+        self.instruction_index
+    }
+
+    pub fn has_exited(&self) -> bool {
+        self.exited
+    }
+
+    // Whether there is such an instruction to pause at.
+    pub fn set_breakpoint(&mut self, index: usize) -> bool {
+        if index >= self.instructions.len() {
+            return false;
+        }
+
+        self.breakpoints.insert(index);
+
+        true
+    }
+
+    // Whether there was a breakpoint there.
+    pub fn clear_breakpoint(&mut self, index: usize) -> bool {
+        self.breakpoints.remove(&index)
+    }
+
+    pub fn breakpoints(&self) -> Vec<usize> {
+        self.breakpoints.iter().copied().collect()
+    }
+
+    // Sets a breakpoint wherever execution can enter the line, in any file whose path ends
+    // with the one given. Gives the instructions chosen, which are none if the line has no code.
+    pub fn set_line_breakpoint(&mut self, file: &str, line: usize) -> Vec<usize> {
+        let mut previous = None;
+        let mut indices = vec![];
+
+        for entry in &self.debug_info.lines {
+            let current = Some((entry.file, entry.line));
+
+            let matches = entry.line == line && self.debug_info.files.get(entry.file).is_some_and(|name| name.ends_with(file));
+
+            if matches && current != previous {
+                indices.push(entry.start);
+            }
+
+            previous = current;
+        }
+
+        self.breakpoints.extend(&indices);
+
+        indices
+    }
+
+    pub fn step(&mut self, out: &mut dyn std::io::Write) -> Pause {
+        self.run_until(out, |_| true)
+    }
+
+    // Like step, but a call runs until it returns.
+    pub fn step_over(&mut self, out: &mut dyn std::io::Write) -> Pause {
+        let base = self.base_pointer;
+
+        match self.instructions.get(self.instruction_index) {
+            Some(Instruction::Call(_) | Instruction::CallIndirect) => self.run_until(out, |runtime| runtime.base_pointer <= base),
+            _ => self.step(out),
+        }
+    }
+
+    // Runs until a different source line is reached, in this function or any other.
+    pub fn step_line(&mut self, out: &mut dyn std::io::Write) -> Pause {
+        let base = self.base_pointer;
+        let line = self.line_at(self.instruction_index);
+
+        self.run_until(out, |runtime| runtime.base_pointer != base || runtime.line_at(runtime.instruction_index) != line)
+    }
+
+    // Runs until a different source line is reached in this function, or it returns.
+    pub fn next_line(&mut self, out: &mut dyn std::io::Write) -> Pause {
+        let base = self.base_pointer;
+        let line = self.line_at(self.instruction_index);
+
+        self.run_until(out, |runtime| runtime.base_pointer < base
+            || (runtime.base_pointer == base && runtime.line_at(runtime.instruction_index) != line))
+    }
+
+    pub fn resume(&mut self, out: &mut dyn std::io::Write) -> Pause {
+        self.run_until(out, |_| false)
+    }
+
+    // Describes each frame, innermost first, like a backtrace.
+    pub fn call_stack(&self) -> Vec<String> {
+        self.paused_frames().iter().map(|frame| self.describe_frame(frame.index)).collect()
+    }
+
+    // The values being computed in the frame, from the bottom up, or None if there is no
+    // such frame.
+    pub fn operand_stack(&self, frame: usize) -> Option<&[u8]> {
+        let frames = self.paused_frames();
+        let Frame { index, base } = *frames.get(frame)?;
+
+        // The driver has no locals or saved registers. Functions written by hand have no
+        // locals either.
+        let operand_start = match self.debug_info.function_at(index) {
+            _ if base.cast_const() == self.stack_bottom => 0,
+            Some(symbol) => symbol.operand_start,
+            None => 16,
+        };
+
+        let end = self.frame_end(&frames, frame);
+        let start = (base as usize + operand_start).min(end);
+
+        Some(unsafe { std::slice::from_raw_parts(start as *const u8, end - start) })
+    }
+
+    // The parameters and locals of the frame, if it is in a function with debug info.
+    // Locals of blocks that are not running may share their slot with others.
+    pub fn locals(&self, frame: usize) -> Option<Vec<Local>> {
+        let frames = self.paused_frames();
+        let Frame { index, base } = *frames.get(frame)?;
+
+        if base.cast_const() == self.stack_bottom {
+            return None;
+        }
+
+        let symbol = self.debug_info.function_at(index)?;
+        let end = self.frame_end(&frames, frame);
+
+        // Slots above the frame have not been pushed yet, and only hold what an earlier
+        // frame left there.
+        let locals = symbol.locals.iter()
+            .map(|local| {
+                let value = (base as usize).checked_add_signed(local.offset)
+                    .filter(|start| *start >= self.stack_bottom as usize && start + local.size <= end)
+                    .map(|start| format_value(unsafe { std::slice::from_raw_parts(start as *const u8, local.size) }, local.format));
+
+                Local { name: local.name.clone(), type_name: local.type_name.clone(), value }
+            })
+            .collect();
+
+        Some(locals)
+    }
+
+    // A frame ends where the frame inside it starts, or at the stack pointer.
+    fn frame_end(&self, frames: &[Frame], frame: usize) -> usize {
+        match frame {
+            0 => self.stack_pointer as usize,
+            _ => frames[frame - 1].base as usize,
+        }
+    }
+
+    // Runs instructions until the condition holds after one, a breakpoint is reached, or the
+    // program exits. At least one instruction runs, so running from a breakpoint leaves it.
+    fn run_until(&mut self, out: &mut dyn std::io::Write, condition: impl Fn(&Runtime) -> bool) -> Pause {
+        if self.exited {
+            return Pause::Exited;
+        }
+        if let Some(message) = &self.failure {
+            return Pause::Failed(message.clone());
+        }
+
+        self.running = true;
+
+        loop {
+            if let Err(error) = self.check_limits() {
+                return Pause::Stopped(error);
+            }
+
+            // Runtime errors are panics, so they are caught here, and execution is left at
+            // the failed instruction.
+            let index = self.instruction_index;
+
+            if let Err(payload) = catch_unwind(AssertUnwindSafe(|| self.step_impl(&mut Some(&mut *out)))) {
+                let message = payload.downcast_ref::<&str>().map(ToString::to_string)
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or("Unknown error".to_string());
+
+                self.instruction_index = index;
+                self.running = false;
+                self.failure = Some(message.clone());
+
+                return Pause::Failed(message);
+            }
+
+            if self.exited {
+                return Pause::Exited;
+            }
+            if self.breakpoints.contains(&self.instruction_index) {
+                return Pause::Breakpoint;
+            }
+            if condition(self) {
+                return Pause::Stepped;
+            }
+        }
+    }
+
+    fn line_at(&self, index: usize) -> Option<(usize, usize)> {
+        self.debug_info.location(index).map(|entry| (entry.file, entry.line))
+    }
+
+    // The frames of functions, then the driver's.
+    fn paused_frames(&self) -> Vec<Frame> {
+        let mut frames = self.frames(self.instruction_index);
+
+        let driver_index = match frames.last() {
+            Some(outermost) => (unsafe { outermost.base.cast::<u64>().read() }) as usize - 1,  // The call to it
+            None => self.instruction_index,
+        };
+
+        frames.push(Frame { index: driver_index, base: self.stack_bottom.cast_mut() });
+
+        frames
+    }
+}
+
+fn format_value(bytes: &[u8], mut format: ValueFormat) -> String {
+    // Numbers are read into a u128, so other sizes are only shown as bytes.
+    if !(1..=16).contains(&bytes.len()) {
+        format = ValueFormat::Bytes;
+    }
+
+    let unsigned = bytes.iter().rev().fold(0u128, |value, byte| (value << 8) | u128::from(*byte));
+
+    match format {
+        ValueFormat::Unsigned => unsigned.to_string(),
+        ValueFormat::Signed => {
+            let unused = 128 - 8 * bytes.len() as u32;
+            ((unsigned << unused) as i128 >> unused).to_string()
+        },
+        ValueFormat::Boolean => (unsigned != 0).to_string(),
+        ValueFormat::Bytes => {
+            let bytes = bytes.iter().map(|byte| format!("{byte:02x}")).collect::<Vec<_>>();
+            format!("[{}]", bytes.join(" "))
+        },
+    }
+}
@@ -0,0 +1,53 @@
+/* Garbage collection. In manual mode (the default), heap blocks live until freed.
+ * Otherwise, the heap is traced from the roots on the stack, which are found using
+ * the stack maps the compiler attaches to each safepoint, and from the global variables
+ * that hold heap addresses. Explicit frees still work in every mode. */
+
+use super::Runtime;
+
+
+const COLLECTION_THRESHOLD: usize = 1_048_576;  // Bytes allocated between collections in tracing mode.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum CollectionMode {
+    #[default]
+    Manual,  // Never collect. Blocks left at exit are reported as leaks.
+    Tracing,  // Collect once enough has been allocated, or when the heap is full.
+    Stress,  // Collect before every allocation. Slow, but quickly exposes missing roots.
+}
+
+impl Runtime {
+    pub fn set_collection_mode(&mut self, mode: CollectionMode) {
+        self.collection_mode = mode;
+    }
+
+    // Called by HeapAlloc, before the block is allocated.
+    pub(super) fn should_collect(&self) -> bool {
+        match self.collection_mode {
+            CollectionMode::Manual => false,
+            CollectionMode::Tracing => self.heap.allocated_since_collection() >= COLLECTION_THRESHOLD,
+            CollectionMode::Stress => true,
+        }
+    }
+
+    // Must be called while executing a safepoint, so that every frame on the stack is
+    // paused at a safepoint with a stack map.
+    pub(super) fn collect_garbage(&mut self) {
+        let mut roots = vec![];
+
+        // Each frame is paused at a safepoint: this one, or a call.
+        for frame in self.frames(self.instruction_index - 1) {
+            if let Some(stack_map) = self.stack_maps.get(&frame.index) {
+                for offset in stack_map {
+                    roots.push(unsafe { frame.base.offset(*offset).cast::<u64>().read() });
+                }
+            }
+        }
+
+        for offset in &self.data_roots {
+            roots.push(self.data[offset / 8]);  // Heap addresses are words, so are 8-aligned.
+        }
+
+        self.heap.collect(roots);
+    }
+}
@@ -0,0 +1,191 @@
+/* The VM heap. This is one contiguous region of memory (which, like the stack,
+ * lives in Rust's heap), carved into blocks with a simple first fit free list.
+ *
+ * Nom code never sees real pointers. An address is an offset into the region, so
+ * that 0 can serve as null, and so that every access can be validated against the
+ * table of live allocations before it happens. */
+
+use std::alloc::{Layout, alloc, dealloc, handle_alloc_error};
+use std::collections::{BTreeMap, BTreeSet};
+
+
+const MAX_ALIGNMENT: usize = 8;  // The region itself is only aligned to 8.
+
+pub(super) struct Heap {
+    memory: *mut u8,
+    layout: Layout,
+    free_blocks: BTreeMap<usize, usize>,  // Maps start address to size. Adjacent free blocks are always merged.
+    allocations: BTreeMap<usize, usize>,  // Maps start address to size, for every live allocation.
+    allocated_since_collection: usize,  // Bytes handed out since the last garbage collection.
+}
+
+impl Heap {
+    // The size is in bytes, and a whole number of words.
+    pub(super) fn new(size: usize) -> Heap {
+        let layout = Layout::array::<u64>(size / 8).expect("Memory should be allocated");
+        let memory = unsafe { alloc(layout) };
+
+        if memory.is_null() {
+            handle_alloc_error(layout);
+        }
+
+        let mut free_blocks = BTreeMap::new();
+        if size > MAX_ALIGNMENT {
+            free_blocks.insert(MAX_ALIGNMENT, size - MAX_ALIGNMENT);  // Address 0 is never handed out (null).
+        }
+
+        Heap { memory, layout, free_blocks, allocations: BTreeMap::new(), allocated_since_collection: 0 }
+    }
+
+    // Returns the address of a new zeroed block, or None if no block is large enough, which
+    // includes sizes too large to add up. Zero sized requests still get a unique address.
+    pub(super) fn alloc(&mut self, size: usize, alignment: usize) -> Result<Option<usize>, &'static str> {
+        if !alignment.is_power_of_two() || alignment > MAX_ALIGNMENT {
+            return Err("Bad Alignment");
+        }
+
+        let size = size.max(1);
+
+        let Some((block_start, block_size, address)) = self.free_blocks.iter()
+            .map(|(start, block_size)| (*start, *block_size, start.next_multiple_of(alignment)))
+            .find(|(start, block_size, address)| address.checked_add(size).is_some_and(|end| end <= start + block_size))
+            else { return Ok(None) };
+
+        self.free_blocks.remove(&block_start);
+
+        if address > block_start {
+            self.free_blocks.insert(block_start, address - block_start);
+        }
+        if address + size < block_start + block_size {
+            self.free_blocks.insert(address + size, block_start + block_size - address - size);
+        }
+
+        self.allocations.insert(address, size);
+        self.allocated_since_collection += size;
+
+        // Stale data could otherwise look like a reference to the collector.
+        unsafe { self.memory.add(address).write_bytes(0, size) };
+
+        Ok(Some(address))
+    }
+
+    // Freeing null does nothing. Anything else must be the start of a live allocation.
+    pub(super) fn free(&mut self, address: usize) -> Result<(), &'static str> {
+        if address == 0 {
+            return Ok(());
+        }
+
+        let Some(size) = self.allocations.remove(&address)
+            else { return Err("Invalid Free") };
+
+        let mut start = address;
+        let mut end = address + size;
+
+        // Merge with the neighbors on either side, if they are free.
+        if let Some((&prev_start, &prev_size)) = self.free_blocks.range(..start).next_back() {
+            if prev_start + prev_size == start {
+                self.free_blocks.remove(&prev_start);
+                start = prev_start;
+            }
+        }
+        if let Some(next_size) = self.free_blocks.remove(&end) {
+            end += next_size;
+        }
+
+        self.free_blocks.insert(start, end - start);
+
+        Ok(())
+    }
+
+    // Fails unless [address, address + size) lies within one live allocation.
+    // The address must also be aligned to the size, like on the stack.
+    fn validate(&self, address: usize, size: usize) -> Result<(), &'static str> {
+        let valid = self.allocations.range(..=address).next_back()
+            .is_some_and(|(start, len)| address.saturating_add(size) <= start + len);
+
+        if !valid {
+            return Err("Invalid Heap Access");
+        }
+        if address & (size - 1) != 0 {  // Sizes are powers of two.
+            return Err("Misaligned Heap Access");
+        }
+
+        Ok(())
+    }
+
+    pub(super) fn read<T: Copy>(&self, address: usize) -> Result<T, &'static str> {
+        self.validate(address, std::mem::size_of::<T>())?;
+
+        Ok(unsafe { self.memory.add(address).cast::<T>().read() })
+    }
+
+    pub(super) fn write<T: Copy>(&mut self, address: usize, val: T) -> Result<(), &'static str> {
+        self.validate(address, std::mem::size_of::<T>())?;
+
+        unsafe { self.memory.add(address).cast::<T>().write(val) };
+
+        Ok(())
+    }
+
+    // Mark and sweep. Every allocation containing a root address is kept, along with
+    // everything reachable from it. The collector does not know the layout of heap
+    // objects, so any aligned word inside a kept block that happens to point into an
+    // allocation keeps that allocation alive too. Returns the number of bytes freed.
+    pub(super) fn collect(&mut self, roots: impl IntoIterator<Item = u64>) -> usize {
+        let mut marked = BTreeSet::new();
+        let mut worklist = roots.into_iter()
+            .filter_map(|root| self.allocation_containing(root))
+            .collect::<Vec<_>>();
+
+        while let Some(start) = worklist.pop() {
+            if !marked.insert(start) {
+                continue;
+            }
+
+            let end = start + self.allocations[&start];
+
+            for word in (start.next_multiple_of(8)..end).step_by(8).take_while(|word| word + 8 <= end) {
+                let value = unsafe { self.memory.add(word).cast::<u64>().read() };
+
+                worklist.extend(self.allocation_containing(value));
+            }
+        }
+
+        let garbage = self.allocations.iter()
+            .filter(|(start, _)| !marked.contains(*start))
+            .map(|(start, size)| (*start, *size))
+            .collect::<Vec<_>>();
+
+        for (start, _) in &garbage {
+            self.free(*start).expect("Garbage is a live allocation");
+        }
+
+        self.allocated_since_collection = 0;
+
+        garbage.iter().map(|(_, size)| size).sum()
+    }
+
+    // The start of the live allocation that an address points into, if there is one.
+    fn allocation_containing(&self, address: u64) -> Option<usize> {
+        let address = usize::try_from(address).ok()?;
+
+        self.allocations.range(..=address).next_back()
+            .filter(|(start, size)| address < *start + *size)
+            .map(|(start, _)| *start)
+    }
+
+    pub(super) fn allocated_since_collection(&self) -> usize {
+        self.allocated_since_collection
+    }
+
+    // Live allocations in address order, as (address, size) pairs.
+    pub(super) fn live_allocations(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.allocations.iter().map(|(start, size)| (*start, *size))
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.memory, self.layout) }
+    }
+}
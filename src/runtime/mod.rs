@@ -2,62 +2,202 @@
 #[cfg(test)]
 mod tests;
 
+mod heap;  // The VM's managed heap
+mod gc;  // Optional garbage collection of the heap
+mod backtrace;  // Describes the call stack when a program fails
+mod debugger;  // Pausing and inspecting a running program
+mod trace;  // Logging each instruction as it runs
+mod profile;  // Counting what a program spends its instructions on
+mod config;  // Limits on the memory a runtime uses
 
-use std::alloc::{Layout, alloc, dealloc};
 
+use std::alloc::{Layout, alloc, dealloc, handle_alloc_error};
+use std::collections::{HashMap, HashSet, BTreeSet};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::program::{Program, StackMap};
 use crate::instructions::{Instruction, IntegerBinaryOperation, IntegerUnaryOperation, IntSize, Constant, Comparison};
 use crate::util::reinterpret;
+use crate::error::VerifyError;
+pub use crate::error::RuntimeError;
 
+use heap::Heap;
 
-const STACK_SIZE: usize = 1_048_576;  // In terms of u8 units. This is exactly a megabyte.
+pub use gc::CollectionMode;
+pub use debugger::{Pause, Local};
+pub use profile::{Profile, FunctionProfile};
+pub use config::RuntimeConfig;
 
 pub struct Runtime {
     instructions: Vec<Instruction>,
+    stack_maps: HashMap<usize, StackMap>,
+    data: Vec<u64>,  // The data segment, in words so that it is aligned for any global.
+    data_roots: Vec<usize>,
+    messages: Vec<String>,  // Indexed by traps.
+    function_values: HashSet<usize>,  // The functions CallIndirect may call.
+    debug_info: Program,  // Only the function symbols and line table, for describing where execution is.
     instruction_index: usize,  // Really just an index
     stack_pointer: *mut u8,  // Current location of the top of the stack, i.e. no value lives here.
     base_pointer: *mut u8,  // Current location of bottom of the frame. Locals are available, as well as return value and previous frame pointer.
     stack_bottom: *const u8,
+    stack_top: *const u8,  // One past the end of the stack.
     stack_layout: Layout,
+    call_depth: usize,  // Calls that have not returned yet.
+    max_call_depth: Option<usize>,
+    heap: Heap,
+    collection_mode: CollectionMode,
+    fuel: Option<u64>,  // Instructions left to run, if limited.
+    interrupt: Option<Arc<AtomicBool>>,  // Stops the program once set, from anywhere.
+    overflow_checks: bool,
     running: bool,
+    exited: bool,
+    exit_error: Option<i128>,  // The error code main returned, if it returned an error.
+    breakpoints: BTreeSet<usize>,  // Instruction indices where a debugger pauses.
+    failure: Option<String>,  // The runtime error a debugger caught, which ends the program.
 }
 
 
 impl Runtime {
-    pub fn new(instructions: Vec<Instruction>) -> Runtime {
-        let stack_layout = Layout::array::<u64>(STACK_SIZE / 8).expect("Memory should be allocated");
+    // The program is verified first, and an invalid one is a runtime error.
+    pub fn new(program: impl Into<Program>) -> Runtime {
+        Runtime::with_config(program, RuntimeConfig::default())
+    }
+
+    pub fn with_config(program: impl Into<Program>, config: RuntimeConfig) -> Runtime {
+        let program = program.into();
+
+        if let Err(VerifyError(message)) = program.verify() {
+            panic!("Critical Runtime Error: Invalid Program. {message}");
+        }
+
+        let function_values = program.function_values();
+        let Program { instructions, stack_maps, data_size, data_roots, messages, functions, lines, files } = program;
+
+        let stack_layout = Layout::array::<u64>(config.stack_size / 8).expect("Memory should be allocated");
         let stack = unsafe { alloc(stack_layout) };
+
+        if stack.is_null() {
+            handle_alloc_error(stack_layout);
+        }
         
         // See Drop implementation
 
         Runtime { 
             instructions, 
+            stack_maps,
+            data: vec![0; data_size.div_ceil(8)],
+            data_roots,
+            messages,
+            function_values,
+            debug_info: Program { functions, lines, files, ..Default::default() },
             instruction_index: 0, 
             stack_pointer: stack, 
             stack_bottom: stack, 
+            stack_top: unsafe { stack.add(config.stack_size) },
             base_pointer: stack, 
             stack_layout, 
+            call_depth: 0,
+            max_call_depth: config.max_call_depth,
+            heap: Heap::new(config.heap_size),
+            collection_mode: CollectionMode::Manual,
+            fuel: None,
+            interrupt: None,
+            overflow_checks: false,
             running: false,
+            exited: false,
+            exit_error: None,
+            breakpoints: BTreeSet::new(),
+            failure: None,
         }   
     }
 
+    // Limits the number of instructions that can run. Running out stops the program before
+    // the next instruction, so it can carry on once given more.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    // Setting the flag, say from another thread, stops the program before its next
+    // instruction. It carries on if run again once the flag is cleared.
+    pub fn set_interrupt_flag(&mut self, flag: Arc<AtomicBool>) {
+        self.interrupt = Some(flag);
+    }
+
+    // Makes integer overflow a runtime error, rather than depending on how the VM was built.
+    pub fn set_overflow_checks(&mut self, overflow_checks: bool) {
+        self.overflow_checks = overflow_checks;
+    }
+
+    // The error code main returned, if the program ended with an error rather than a value.
+    pub fn exit_error(&self) -> Option<i128> {
+        self.exit_error
+    }
+
+    // Everything between the bottom of the stack and the stack pointer. Once a program has
+    // exited, this is whatever its driver left behind.
+    pub(crate) fn stack_contents(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.stack_bottom, self.stack_pointer as usize - self.stack_bottom as usize) }
+    }
+
+    // Running out of fuel or being interrupted is a runtime error here. Use try_run to
+    // be able to carry on afterwards.
     pub fn run(&mut self) {
-        self.run_impl(None);
+        if let Err(error) = self.run_impl(None) {
+            self.fail_before(&error.to_string());
+        }
     }
 
     pub fn run_debug(&mut self, debug_out: &mut dyn std::io::Write) {
-        self.run_impl(Some(debug_out));
+        if let Err(error) = self.run_impl(Some(debug_out)) {
+            self.fail_before(&error.to_string());
+        }
+    }
+
+    // Runs until the program exits, or stops early. Running again after stopping carries on
+    // where the program left off.
+    pub fn try_run(&mut self) -> Result<(), RuntimeError> {
+        self.run_impl(None)
     }
-    
-    fn run_impl(&mut self, mut debug_out: Option<&mut dyn std::io::Write>) {
+
+    pub fn try_run_debug(&mut self, debug_out: &mut dyn std::io::Write) -> Result<(), RuntimeError> {
+        self.run_impl(Some(debug_out))
+    }
+
+    fn run_impl(&mut self, mut debug_out: Option<&mut dyn std::io::Write>) -> Result<(), RuntimeError> {
         self.running = true;
 
         while self.running {
-            let instruction = self.instructions[self.instruction_index];
+            self.check_limits()?;
+            self.step_impl(&mut debug_out);
+        }
+
+        Ok(())
+    }
+
+    // Uses up fuel for the next instruction, unless the program has to stop first.
+    fn check_limits(&mut self) -> Result<(), RuntimeError> {
+        if self.interrupt.as_ref().is_some_and(|flag| flag.load(Ordering::Relaxed)) {
+            return Err(RuntimeError::Interrupted);
+        }
+
+        match &mut self.fuel {
+            Some(0) => Err(RuntimeError::OutOfFuel),
+            Some(fuel) => {
+                *fuel -= 1;
+                Ok(())
+            },
+            None => Ok(()),
+        }
+    }
+
+    // Runs the next instruction.
+    fn step_impl(&mut self, debug_out: &mut Option<&mut dyn std::io::Write>) {
+        let instruction = self.instructions[self.instruction_index];
 
-            self.instruction_index += 1;  // Might be overriden by running a jump
+        self.instruction_index += 1;  // Might be overriden by running a jump
 
-            self.eval_instruction(instruction, &mut debug_out);
-        } 
+        self.eval_instruction(instruction, debug_out);
     }
 
     #[allow(clippy::too_many_lines)]
@@ -83,6 +223,10 @@ impl Runtime {
                 }
             },
             Instruction::AdvanceStackPtr(amount) => {
+                if amount > self.stack_top as usize - self.stack_pointer as usize {
+                    self.fail("Stack Overflow");
+                }
+
                 self.stack_pointer = unsafe { self.stack_pointer.add(amount) };
             },
             Instruction::RetractStackPtr(amount) => {
@@ -152,8 +296,44 @@ impl Runtime {
                     Constant::EightByte(val) => u64::push(val, self),
                 }
             }
+            Instruction::LoadGlobal(offset, size) => {
+                match size {
+                    IntSize::OneByte => self.load_global::<u8>(offset),
+                    IntSize::TwoByte => self.load_global::<u16>(offset),
+                    IntSize::FourByte => self.load_global::<u32>(offset),
+                    IntSize::EightByte => self.load_global::<u64>(offset),
+                }
+            }
+            Instruction::StoreGlobal(offset, size) => {
+                match size {
+                    IntSize::OneByte => self.store_global::<u8>(offset),
+                    IntSize::TwoByte => self.store_global::<u16>(offset),
+                    IntSize::FourByte => self.store_global::<u32>(offset),
+                    IntSize::EightByte => self.store_global::<u64>(offset),
+                }
+            }
             Instruction::Exit => {
                 self.running = false;
+                self.exited = true;
+
+                // Under a garbage collector, unreachable blocks are not leaks.
+                if let (Some(out), CollectionMode::Manual) = (debug_out, self.collection_mode) {
+                    self.report_leaks(&mut **out);
+                }
+            }
+            // Not a panic, since the program decided to end this way.
+            Instruction::ExitWithError(size, signed) => {
+                let code = self.pop_integer(size, signed);
+                self.exit_error = Some(code);
+
+                if let Some(out) = debug_out {
+                    writeln!(out, "Runtime Error: main returned error {code}").expect("prints");
+                }
+
+                self.eval_instruction(Instruction::Exit, debug_out);
+            }
+            Instruction::Trap(index) => {
+                self.fail(&self.messages[index]);
             }
             Instruction::ReadBase(offset, size) => {
                 match size {
@@ -195,15 +375,21 @@ impl Runtime {
                     },
                 }
             }
-            Instruction::Call(index) => {
-                let prev_base = self.base_pointer;
-                self.base_pointer = self.stack_pointer;
+            Instruction::Call(index) => self.call(index),
+            Instruction::CallIndirect => {
+                let mut value = u64::pop(self);
 
-                // Alignment, bounds checked in these functions.
-                u64::push(self.instruction_index as u64, self);  // index is already 1 past the call instruction
-                u64::push(prev_base as u64, self);
+                if value & 1 == 0 {
+                    value = self.heap.read::<u64>(value as usize).unwrap_or_else(|message| self.fail(message));
+                }
 
-                self.instruction_index = index;
+                let index = (value >> 1) as usize;
+
+                if value & 1 == 0 || !self.function_values.contains(&index) {
+                    self.fail("Bad Function Value");
+                }
+
+                self.call(index);
             },
             Instruction::Return => {
                 // The stack_pointer should maybe already be at this position.
@@ -211,6 +397,7 @@ impl Runtime {
                 
                 self.base_pointer = u64::pop(self) as *mut u8;
                 self.instruction_index = u64::pop(self) as usize;
+                self.call_depth -= 1;
             }
             Instruction::IntegerConversion(start_size, start_sign, end_size, end_sign) => {
                 self.convert_integer(start_size, start_sign, end_size, end_sign);
@@ -233,6 +420,104 @@ impl Runtime {
                     self.instruction_index = (self.instruction_index as i32 + i) as usize;
                 }
             }
+            Instruction::HeapAlloc => {
+                let alignment = u64::pop(self) as usize;
+                let size = u64::pop(self) as usize;
+
+                if self.should_collect() {
+                    self.collect_garbage();
+                }
+
+                let mut address = self.heap.alloc(size, alignment).unwrap_or_else(|message| self.fail(message));
+
+                if address.is_none() && self.collection_mode != CollectionMode::Manual {
+                    self.collect_garbage();
+                    address = self.heap.alloc(size, alignment).unwrap_or_else(|message| self.fail(message));
+                }
+
+                let Some(address) = address
+                    else { self.fail("Out of Heap Memory") };
+
+                u64::push(address as u64, self);
+            }
+            Instruction::HeapFree => {
+                let address = u64::pop(self) as usize;
+                self.heap.free(address).unwrap_or_else(|message| self.fail(message));
+            }
+            Instruction::HeapRead(size) => {
+                match size {
+                    IntSize::OneByte => self.heap_read::<u8>(),
+                    IntSize::TwoByte => self.heap_read::<u16>(),
+                    IntSize::FourByte => self.heap_read::<u32>(),
+                    IntSize::EightByte => self.heap_read::<u64>(),
+                }
+            }
+            Instruction::HeapWrite(size) => {
+                match size {
+                    IntSize::OneByte => self.heap_write::<u8>(),
+                    IntSize::TwoByte => self.heap_write::<u16>(),
+                    IntSize::FourByte => self.heap_write::<u32>(),
+                    IntSize::EightByte => self.heap_write::<u64>(),
+                }
+            }
+        }
+    }
+
+    fn call(&mut self, index: usize) {
+        if self.max_call_depth.is_some_and(|max_call_depth| self.call_depth >= max_call_depth) {
+            self.fail("Maximum Call Depth Exceeded");
+        }
+
+        let base = self.stack_pointer;
+
+        // Alignment, bounds checked in these functions. The frame only starts once they
+        // succeed, so that a stack overflow is reported from the caller.
+        u64::push(self.instruction_index as u64, self);  // index is already 1 past the call instruction
+        u64::push(self.base_pointer as u64, self);
+
+        self.base_pointer = base;
+        self.instruction_index = index;
+        self.call_depth += 1;
+    }
+
+    fn heap_read<S: Stackable>(&mut self) {
+        let address = u64::pop(self) as usize;
+        let val = self.heap.read::<S>(address).unwrap_or_else(|message| self.fail(message));
+        S::push(val, self);
+    }
+
+    fn heap_write<S: Stackable>(&mut self) {
+        let val = S::pop(self);
+        let address = u64::pop(self) as usize;
+        self.heap.write::<S>(address, val).unwrap_or_else(|message| self.fail(message));
+    }
+
+    fn pop_integer(&mut self, size: IntSize, signed: bool) -> i128 {
+        match (size, signed) {
+            (IntSize::OneByte, true) => i128::from(reinterpret::<u8, i8>(u8::pop(self))),
+            (IntSize::OneByte, false) => i128::from(u8::pop(self)),
+            (IntSize::TwoByte, true) => i128::from(reinterpret::<u16, i16>(u16::pop(self))),
+            (IntSize::TwoByte, false) => i128::from(u16::pop(self)),
+            (IntSize::FourByte, true) => i128::from(reinterpret::<u32, i32>(u32::pop(self))),
+            (IntSize::FourByte, false) => i128::from(u32::pop(self)),
+            (IntSize::EightByte, true) => i128::from(reinterpret::<u64, i64>(u64::pop(self))),
+            (IntSize::EightByte, false) => i128::from(u64::pop(self)),
+        }
+    }
+
+    // Run at exit in debug mode. Lists every heap block that was never freed.
+    fn report_leaks(&self, out: &mut dyn std::io::Write) {
+        let leaks = self.heap.live_allocations().collect::<Vec<_>>();
+
+        if leaks.is_empty() {
+            return;
+        }
+
+        let total = leaks.iter().map(|(_, size)| size).sum::<usize>();
+        writeln!(out, "Leak report: {} heap block(s) never freed, {total} bytes total", leaks.len()).expect("prints");
+
+        for (address, size) in leaks {
+            writeln!(out, "    address {address}: {size} bytes").expect("prints");
         }
     }
 
@@ -272,18 +557,38 @@ impl Runtime {
         unsafe {
             let ptr = self.base_pointer.offset(offset);
 
-            // TODO: Alignment check, bounds check
+            // The verifier checked that the slot is aligned, and below the stack pointer, and
+            // the stack pointer never passes the top of the stack.
 
             ptr.cast::<S>().read()
         }
     }
 
+    fn load_global<S: Stackable>(&mut self, offset: usize) {
+        let val = unsafe { self.global_pointer::<S>(offset).read() };
+        S::push(val, self);
+    }
+
+    fn store_global<S: Stackable>(&mut self, offset: usize) {
+        let val = S::pop(self);
+        unsafe { self.global_pointer::<S>(offset).write(val) };
+    }
+
+    fn global_pointer<S>(&mut self, offset: usize) -> *mut S {
+        if offset + std::mem::size_of::<S>() > self.data.len() * 8 || !offset.is_multiple_of(std::mem::align_of::<S>()) {
+            self.fail("Bad global access");
+        }
+
+        unsafe { self.data.as_mut_ptr().cast::<u8>().add(offset).cast::<S>() }
+    }
+
     // Unlike the Instruction, this does nothing to the stack
     fn write_base<S: Stackable>(&mut self, offset: isize, val: S) {
         unsafe {
             let ptr = self.base_pointer.offset(offset);
 
-            // TODO: Alignment check, bounds check
+            // The verifier checked that the slot is aligned, and below the stack pointer, and
+            // the stack pointer never passes the top of the stack.
 
             ptr.cast::<S>().write(val);
         }
@@ -317,6 +622,10 @@ impl Runtime {
         let right = U::pop(self);
         let left = U::pop(self);
 
+        if self.overflow_checks && !binary_fits::<U, S>(op, left, right) {
+            self.fail("Integer Overflow");
+        }
+
         let result = match op {
             IntegerBinaryOperation::UnsignedAddition =>
                 left + right,
@@ -331,7 +640,9 @@ impl Runtime {
             IntegerBinaryOperation::SignedMultiplication => 
                 reinterpret::<S, U>(reinterpret::<U, S>(left) * reinterpret::<U, S>(right)),
             IntegerBinaryOperation::UnsignedDivision => {
-                assert!(right != U::zero(), "Critical Runtime Error: Division by Zero");
+                if right == U::zero() {
+                    self.fail("Division by Zero");
+                }
 
                 left / right
             }
@@ -339,12 +650,16 @@ impl Runtime {
                 let s_left = reinterpret::<U, S>(left);
                 let s_right = reinterpret::<U, S>(right);
 
-                assert!(s_right != S::zero(), "Critical Runtime Error: Division by Zero");
+                if s_right == S::zero() {
+                    self.fail("Division by Zero");
+                }
 
                 reinterpret::<S, U>(s_left / s_right)
             }
             IntegerBinaryOperation::UnsignedModulus => {
-                assert!(right != U::zero(), "Critical Runtime Error: Modulus by Zero");
+                if right == U::zero() {
+                    self.fail("Modulus by Zero");
+                }
 
                 left % right
             }
@@ -352,7 +667,9 @@ impl Runtime {
                 let s_left = reinterpret::<U, S>(left);
                 let s_right = reinterpret::<U, S>(right);
 
-                assert!(s_right != S::zero(), "Critical Runtime Error: Modulus by Zero");
+                if s_right == S::zero() {
+                    self.fail("Modulus by Zero");
+                }
 
                 reinterpret::<S, U>(s_left % s_right)
             }
@@ -366,6 +683,12 @@ impl Runtime {
         
         let result = match op {
             IntegerUnaryOperation::NegateSigned => {
+                let exact = -reinterpret::<U, S>(val).as_i128();
+
+                if self.overflow_checks && !fits::<S>(exact) {
+                    self.fail("Integer Overflow");
+                }
+
                 reinterpret::<S, U>(- reinterpret::<U, S>(val))
             }
         };
@@ -408,6 +731,30 @@ impl Runtime {
     }
 }
 
+// Computes the exact result of the operation, and checks that it fits. Division by zero
+// is left for the operation itself to report.
+fn binary_fits<U: RuntimeInt, S: RuntimeInt>(op: IntegerBinaryOperation, left: U, right: U) -> bool {
+    use IntegerBinaryOperation as Op;
+
+    let (unsigned_left, unsigned_right) = (left.as_i128(), right.as_i128());
+    let (signed_left, signed_right) = (reinterpret::<U, S>(left).as_i128(), reinterpret::<U, S>(right).as_i128());
+
+    match op {
+        Op::UnsignedAddition => fits::<U>(unsigned_left + unsigned_right),
+        Op::SignedAddition => fits::<S>(signed_left + signed_right),
+        Op::UnsignedSubtraction => fits::<U>(unsigned_left - unsigned_right),
+        Op::SignedSubtraction => fits::<S>(signed_left - signed_right),
+        Op::UnsignedMultiplication => fits::<U>(unsigned_left * unsigned_right),
+        Op::SignedMultiplication => fits::<S>(signed_left * signed_right),
+        Op::SignedDivision | Op::SignedModulus if signed_right != 0 => fits::<S>(signed_left / signed_right),
+        Op::UnsignedDivision | Op::UnsignedModulus | Op::SignedDivision | Op::SignedModulus => true,
+    }
+}
+
+fn fits<R: RuntimeInt>(exact: i128) -> bool {
+    R::from_i128(exact).as_i128() == exact
+}
+
 impl Drop for Runtime {
     fn drop(&mut self) {
         unsafe { dealloc(self.stack_bottom.cast_mut(), self.stack_layout) }
@@ -433,7 +780,9 @@ impl Stackable for u8 {
         // pointer::offset is UB if it goes outside of the allocation though, hence
         // the checks above being done in usize.
 
-        assert!(runtime.stack_pointer as usize + 1 <= runtime.stack_bottom as usize + STACK_SIZE, "Critical Runtime Error: Stack Overflow");
+        if runtime.stack_pointer as usize + 1 > runtime.stack_top as usize {
+            runtime.fail("Stack Overflow");
+        }
 
         // Skip alignment check.
 
@@ -459,7 +808,9 @@ impl Stackable for u8 {
 impl Stackable for u16 {
     #[allow(clippy::cast_ptr_alignment)]
     fn push(val: Self, runtime: &mut Runtime) {
-        assert!(runtime.stack_pointer as usize + 2 <= runtime.stack_bottom as usize + STACK_SIZE, "Critical Runtime Error: Stack Overflow");
+        if runtime.stack_pointer as usize + 2 > runtime.stack_top as usize {
+            runtime.fail("Stack Overflow");
+        }
         
         assert!(runtime.stack_pointer as usize % 2 == 0, "Stack pointer misaligned");
 
@@ -485,7 +836,9 @@ impl Stackable for u16 {
 impl Stackable for u32 {
     #[allow(clippy::cast_ptr_alignment)]
     fn push(val: Self, runtime: &mut Runtime) {
-        assert!(runtime.stack_pointer as usize + 4 <= runtime.stack_bottom as usize + STACK_SIZE, "Critical Runtime Error: Stack Overflow");
+        if runtime.stack_pointer as usize + 4 > runtime.stack_top as usize {
+            runtime.fail("Stack Overflow");
+        }
         
         assert!(runtime.stack_pointer as usize % 4 == 0, "Stack pointer misaligned");
 
@@ -511,7 +864,9 @@ impl Stackable for u32 {
 impl Stackable for u64 {
     #[allow(clippy::cast_ptr_alignment)]
     fn push(val: Self, runtime: &mut Runtime) {
-        assert!(runtime.stack_pointer as usize + 8 <= runtime.stack_bottom as usize + STACK_SIZE, "Critical Runtime Error: Stack Overflow");
+        if runtime.stack_pointer as usize + 8 > runtime.stack_top as usize {
+            runtime.fail("Stack Overflow");
+        }
         
         assert!(runtime.stack_pointer as usize % 8 == 0, "Stack pointer misaligned");
 
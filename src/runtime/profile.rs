@@ -0,0 +1,177 @@
+/* Counting where a program spends its instructions, to find the Nom code worth optimizing.
+ * Like tracing, profiling has its own loop, so running normally pays nothing for it.
+ *
+ * Every Call enters a function and every Return leaves one, so the profiler follows the
+ * call stack as a tree of the distinct stacks seen, and counts the instructions each runs
+ * itself. From that come each function's calls, its exclusive count (instructions it ran
+ * itself) and inclusive count (along with everything it called), and stacks in the folded
+ * format flamegraph tools read. Instructions are also counted by kind. The driver's own
+ * few instructions count towards the total, but no function. */
+
+use std::collections::HashMap;
+
+use super::{Runtime, RuntimeError};
+use crate::instructions::Instruction;
+use crate::program::format_alone;
+
+
+// A distinct call stack. The root is the driver, and each other node is a function called
+// from its parent's stack.
+struct Node {
+    parent: usize,
+    function: usize,  // Where the function starts
+    entries: u64,
+    instructions: u64,  // Run by the function itself, with this stack
+}
+
+pub struct FunctionProfile {
+    pub name: String,
+    pub calls: u64,
+    pub inclusive: u64,
+    pub exclusive: u64,
+}
+
+pub struct Profile {
+    pub total: u64,
+    pub functions: Vec<FunctionProfile>,  // The most exclusive instructions first
+    pub instruction_kinds: Vec<(String, u64)>,  // The most common first
+    pub stacks: Vec<(Vec<String>, u64)>,  // Outermost first, with the instructions run at the top
+}
+
+impl Runtime {
+    // Like try_run_debug, but counting what runs. A program that stops early gives no profile.
+    pub fn run_profiled(&mut self, debug_out: &mut dyn std::io::Write) -> Result<Profile, RuntimeError> {
+        let mut debug_out = Some(debug_out);
+
+        let mut nodes = vec![Node { parent: 0, function: 0, entries: 1, instructions: 0 }];
+        let mut children: HashMap<(usize, usize), usize> = HashMap::new();
+        let mut node = 0;
+
+        let mut counts = vec![0u64; self.instructions.len()];  // By index
+
+        self.running = true;
+
+        while self.running {
+            self.check_limits()?;
+
+            let index = self.instruction_index;
+
+            counts[index] += 1;
+            nodes[node].instructions += 1;
+
+            self.step_impl(&mut debug_out);
+
+            match self.instructions[index] {
+                Instruction::Call(_) | Instruction::CallIndirect => {
+                    let function = self.instruction_index;
+
+                    node = *children.entry((node, function)).or_insert_with(|| {
+                        nodes.push(Node { parent: node, function, entries: 0, instructions: 0 });
+                        nodes.len() - 1
+                    });
+
+                    nodes[node].entries += 1;
+                },
+                Instruction::Return => node = nodes[node].parent,
+                _ => (),
+            }
+        }
+
+        Ok(self.summarize_profile(&nodes, &counts))
+    }
+
+    fn summarize_profile(&self, nodes: &[Node], counts: &[u64]) -> Profile {
+        let name = |function: usize| match self.debug_info.function_at(function) {
+            Some(symbol) => symbol.name.clone(),
+            None => format!("instruction {function}"),
+        };
+
+        // The functions on each node's stack, outermost first.
+        let path = |mut node: usize| {
+            let mut path = vec![];
+
+            while node != 0 {
+                path.push(nodes[node].function);
+                node = nodes[node].parent;
+            }
+
+            path.reverse();
+            path
+        };
+
+        let mut functions: HashMap<usize, FunctionProfile> = HashMap::new();
+        let mut stacks = vec![];
+
+        for (i, node) in nodes.iter().enumerate().skip(1) {
+            let path = path(i);
+
+            let profile = functions.entry(node.function)
+                .or_insert_with(|| FunctionProfile { name: name(node.function), calls: 0, inclusive: 0, exclusive: 0 });
+
+            profile.calls += node.entries;
+            profile.exclusive += node.instructions;
+
+            // Recursion puts a function on the stack more than once, but each instruction
+            // counts towards it once.
+            let mut seen = path.clone();
+            seen.sort_unstable();
+            seen.dedup();
+
+            for function in seen {
+                if let Some(profile) = functions.get_mut(&function) {
+                    profile.inclusive += node.instructions;
+                }
+            }
+
+            if node.instructions > 0 {
+                stacks.push((path.into_iter().map(name).collect::<Vec<_>>(), node.instructions));
+            }
+        }
+
+        let mut functions = functions.into_values().collect::<Vec<_>>();
+        functions.sort_by(|a, b| b.exclusive.cmp(&a.exclusive).then_with(|| a.name.cmp(&b.name)));
+
+        let mut instruction_kinds: HashMap<String, u64> = HashMap::new();
+        for (instruction, count) in self.instructions.iter().zip(counts).filter(|(_, count)| **count > 0) {
+            let text = format_alone(*instruction);
+            let kind = text.split(' ').next().unwrap_or_default();
+
+            *instruction_kinds.entry(kind.to_string()).or_default() += count;
+        }
+
+        let mut instruction_kinds = instruction_kinds.into_iter().collect::<Vec<_>>();
+        instruction_kinds.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        stacks.sort();
+
+        Profile { total: counts.iter().sum(), functions, instruction_kinds, stacks }
+    }
+}
+
+impl Profile {
+    // A table of functions, then of instruction kinds.
+    pub fn report(&self) -> String {
+        let width = self.functions.iter().map(|function| function.name.len()).max().unwrap_or(0).max(8);
+
+        let mut text = format!("Instructions run: {}\n\n", self.total);
+
+        text.push_str(&format!("{:<width$}  {:>10}  {:>12}  {:>12}\n", "Function", "Calls", "Inclusive", "Exclusive"));
+        for FunctionProfile { name, calls, inclusive, exclusive } in &self.functions {
+            text.push_str(&format!("{name:<width$}  {calls:>10}  {inclusive:>12}  {exclusive:>12}\n"));
+        }
+
+        text.push_str(&format!("\n{:<16}  {:>12}\n", "Instruction", "Count"));
+        for (kind, count) in &self.instruction_kinds {
+            text.push_str(&format!("{kind:<16}  {count:>12}\n"));
+        }
+
+        text
+    }
+
+    // A line for each stack, like `main;fib;fib 1234`.
+    pub fn folded_stacks(&self) -> String {
+        self.stacks.iter()
+            .map(|(stack, count)| format!("{} {count}\n", stack.join(";")))
+            .collect()
+    }
+}
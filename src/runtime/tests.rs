@@ -1,14 +1,15 @@
 
-use super::Runtime;
+use super::{Runtime, RuntimeConfig, CollectionMode};
 
+use crate::program::{Program, FunctionSymbol, LocalSymbol, ValueFormat};
 use crate::instructions::{Instruction, Constant, IntegerBinaryOperation, IntSize};
 use crate::util::reinterpret;
 
 
 use Instruction as I;
 
-fn run_collecting_output(instructions: Vec<Instruction>) -> Vec<String> {
-    let mut runtime = Runtime::new(instructions);
+fn run_collecting_output(program: impl Into<Program>) -> Vec<String> {
+    let mut runtime = Runtime::new(program);
 
     let mut buf = std::io::BufWriter::new(vec![]);
     runtime.run_debug(&mut buf);
@@ -18,6 +19,32 @@ fn run_collecting_output(instructions: Vec<Instruction>) -> Vec<String> {
     a.lines().map(|a| a.to_string()).collect::<Vec<String>>()
 }
 
+// As above, but the program is written in assembly, so that jumps and calls can use labels.
+fn run_assembly(text: &str) -> Vec<String> {
+    let program = Program::assemble(text).expect("Assembles");
+    let mut runtime = Runtime::new(program);
+
+    let mut buf = std::io::BufWriter::new(vec![]);
+    runtime.run_debug(&mut buf);
+
+    String::from_utf8(buf.into_inner().expect("No IO Error")).expect("Good Conversion")
+        .lines().map(ToString::to_string).collect()
+}
+
+// Runs assembly that should fail, giving the message it fails with.
+fn failure_message(text: &str) -> String {
+    configured_failure_message(text, RuntimeConfig::default())
+}
+
+fn configured_failure_message(text: &str, config: RuntimeConfig) -> String {
+    let mut runtime = Runtime::with_config(Program::assemble(text).expect("Assembles"), config);
+
+    let payload = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| runtime.run()))
+        .expect_err("Should fail");
+
+    payload.downcast_ref::<String>().expect("Runtime errors are formatted").clone()
+}
+
 
 #[test]
 fn minimal_test() {
@@ -168,6 +195,418 @@ fn function_call() {
     assert_eq!(lines, ["81"]);
 }
 
+#[test]
+fn indirect_call() {
+    // Calls square(7) through a function value, then square(8) through a closure holding it.
+    let instructions = vec![
+        I::AdvanceStackPtr(8),  // The closure, unused
+        I::AdvanceStackPtr(4),  // Space for return value
+        I::PushConstant(Constant::FourByte(7)),
+        I::PushConstant(Constant::EightByte((23 << 1) | 1)),
+        I::CallIndirect,
+        I::RetractStackPtr(4),
+        I::DebugPrintSigned(IntSize::FourByte),  // Should be 49
+        I::RetractStackPtr(12),
+        I::PushConstant(Constant::EightByte(8)),  // Size
+        I::PushConstant(Constant::EightByte(8)),  // Alignment
+        I::HeapAlloc,  // The closure, which only holds the function
+        I::Duplicate(IntSize::EightByte),
+        I::PushConstant(Constant::EightByte((23 << 1) | 1)),
+        I::HeapWrite(IntSize::EightByte),
+        I::AdvanceStackPtr(4),  // Space for return value
+        I::PushConstant(Constant::FourByte(8)),
+        I::ReadBase(0, IntSize::EightByte),  // The closure again
+        I::CallIndirect,
+        I::RetractStackPtr(4),
+        I::DebugPrintSigned(IntSize::FourByte),  // Should be 64
+        I::RetractStackPtr(4),
+        I::HeapFree,
+        I::Exit,
+        I::ReadBase(-4, IntSize::FourByte),  // instruction 23: Start of square(a: u32).
+        I::Duplicate(IntSize::FourByte),
+        I::IntegerBinaryOperation(IntegerBinaryOperation::UnsignedMultiplication, IntSize::FourByte),
+        I::WriteBase(-8, IntSize::FourByte),
+        I::Return,
+    ];
+
+    let square = FunctionSymbol { name: "square".to_string(), start: 23, end: 28, operand_start: 16, ..Default::default() };
+    let lines = run_collecting_output(Program { functions: vec![square], ..Program::from(instructions) });
+
+    assert_eq!(lines, ["49", "64"]);
+}
+
+#[test]
+fn indirect_calls_only_reach_function_values() {
+    // 3 is instruction 1 tagged, which does not start a function.
+    let message = failure_message("
+            advance 8
+            call main
+            exit
+        main:
+            advance 8
+            push 8 3
+            call_indirect
+            retract 8
+            return
+    ");
+
+    assert_eq!(message, "Critical Runtime Error: Bad Function Value\n    at main");
+}
+
+#[test]
+fn assembled_loop() {
+    // Sums 1 through 10, with the total and the counter in the driver's frame.
+    let lines = run_assembly("
+            push 4 0        ; total
+            push 4 10       ; counter
+        .L_loop:
+            read_base 4 4
+            push 4 0
+            gt.s 4
+            jump_if_false .L_done
+            read_base 0 4
+            read_base 4 4
+            add.s 4
+            write_base 0 4
+            read_base 4 4
+            push 4 1
+            sub.s 4
+            write_base 4 4
+            jump .L_loop
+        .L_done:
+            read_base 0 4
+            print 4
+            exit
+    ");
+
+    assert_eq!(lines, ["55"]);
+}
+
+#[test]
+fn assembled_recursion() {
+    // factorial(5), in u64
+    let lines = run_assembly("
+            advance 8       ; return value
+            push 8 5
+            call factorial
+            retract 8
+            print 8
+            exit
+
+        factorial:
+            read_base -8 8
+            push 8 1
+            gt.u 8
+            jump_if_true .L_recurse
+            push 8 1
+            write_base -16 8
+            return
+        .L_recurse:
+            read_base -8 8
+            advance 8       ; return value
+            read_base -8 8
+            push 8 1
+            sub.u 8
+            call factorial
+            retract 8
+            mul.u 8
+            write_base -16 8
+            return
+    ");
+
+    assert_eq!(lines, ["120"]);
+}
+
+#[test]
+fn failures_list_the_running_functions() {
+    let message = failure_message("
+            advance 8
+            call outer
+            exit
+        outer:
+            call inner
+            return
+        inner:
+            push 4 1
+            push 4 0
+            div.s 4
+            return
+    ");
+
+    assert_eq!(message, "Critical Runtime Error: Division by Zero\n    at inner\n    at outer");
+}
+
+#[test]
+fn long_backtraces_leave_out_the_middle() {
+    // Mutual recursion, so frames alternate rather than repeat.
+    let message = failure_message("
+            advance 8
+            call ping
+            exit
+        ping:
+            call pong
+            return
+        pong:
+            call ping
+            return
+    ");
+
+    let lines = message.lines().collect::<Vec<_>>();
+
+    assert_eq!(lines[0], "Critical Runtime Error: Stack Overflow");
+    assert_eq!(lines[1..9], ["    at ping", "    at pong"].repeat(4));
+    assert_eq!(lines[9], "    ... 65519 more frames");
+    assert_eq!(lines[10..], ["    at pong", "    at ping"].repeat(4));
+}
+
+const FACTORIAL: &str = "
+        advance 8       ; return value
+        push 8 3
+        call factorial
+        retract 8
+        print 8
+        exit
+
+    factorial:
+        read_base -8 8
+        push 8 1
+        gt.u 8
+        jump_if_true .L_recurse
+        push 8 1
+        write_base -16 8
+        return
+    .L_recurse:
+        read_base -8 8
+        advance 8       ; return value
+        read_base -8 8
+        push 8 1
+        sub.u 8
+        call factorial
+        retract 8
+        mul.u 8
+        write_base -16 8
+        return
+";
+
+#[test]
+fn breakpoints_pause_before_their_instruction() {
+    let mut runtime = Runtime::new(Program::assemble(FACTORIAL).expect("Assembles"));
+    let mut out = vec![];
+
+    assert!(runtime.set_breakpoint(6));
+    assert!(!runtime.set_breakpoint(100));
+
+    for depth in 1..=3 {
+        assert_eq!(runtime.resume(&mut out), super::Pause::Breakpoint);
+        assert_eq!(runtime.position(), 6);
+        assert_eq!(runtime.call_stack().len(), depth + 1);  // Along with the driver
+    }
+
+    // The caller was partway through multiplying, and had pushed a return slot and argument.
+    assert_eq!(runtime.operand_stack(0), Some(&[][..]));
+    assert_eq!(runtime.operand_stack(1).map(<[u8]>::len), Some(24));
+    assert_eq!(runtime.operand_stack(4), None);
+
+    assert!(runtime.clear_breakpoint(6));
+    assert_eq!(runtime.resume(&mut out), super::Pause::Exited);
+    assert_eq!(runtime.step(&mut out), super::Pause::Exited);
+    assert_eq!(String::from_utf8(out).expect("Good Conversion"), "6\n");
+}
+
+#[test]
+fn stepping_over_a_call_runs_it_to_completion() {
+    let mut runtime = Runtime::new(Program::assemble(FACTORIAL).expect("Assembles"));
+    let mut out = vec![];
+
+    runtime.step(&mut out);
+    runtime.step(&mut out);
+    assert_eq!(runtime.step_over(&mut out), super::Pause::Stepped);
+
+    assert_eq!(runtime.position(), 3);
+    assert_eq!(runtime.call_stack(), ["instruction 3"]);
+
+    // Stepping into it instead stops at its first instruction.
+    let mut runtime = Runtime::new(Program::assemble(FACTORIAL).expect("Assembles"));
+
+    runtime.step(&mut out);
+    runtime.step(&mut out);
+    runtime.step(&mut out);
+
+    assert_eq!(runtime.position(), 6);
+    assert_eq!(runtime.call_stack(), ["factorial", "instruction 2"]);
+}
+
+#[test]
+fn runtime_errors_pause_at_the_failed_instruction() {
+    let mut runtime = Runtime::new(Program::assemble("
+            advance 8
+            call outer
+            exit
+        outer:
+            call inner
+            return
+        inner:
+            push 4 1
+            push 4 0
+            div.s 4
+            return
+    ").expect("Assembles"));
+    let mut out = vec![];
+
+    let failed = super::Pause::Failed("Critical Runtime Error: Division by Zero\n    at inner\n    at outer".to_string());
+
+    assert_eq!(runtime.resume(&mut out), failed);
+    assert_eq!(runtime.position(), 7);
+    assert_eq!(runtime.call_stack().len(), 3);  // inner, outer, and the driver
+
+    // Nothing more runs.
+    assert_eq!(runtime.step(&mut out), failed);
+    assert_eq!(runtime.position(), 7);
+}
+
+#[test]
+fn locals_are_read_from_compiled_frames() {
+    let program = crate::compile_string("
+        fn square(x: i32) -> i32 {
+            val y: i32 = x * x;
+            y
+        }
+
+        fn main() -> i32 {
+            var a: i32 = 0 - 3;
+            val ok: bool = square(a) == 9;
+            a
+        }
+    ".to_string());
+
+    let mut runtime = Runtime::new(program);
+    let mut out = vec![];
+
+    assert!(!runtime.set_line_breakpoint("<input>", 4).is_empty());
+    assert_eq!(runtime.resume(&mut out), super::Pause::Breakpoint);
+
+    let values = |locals: Vec<super::Local>| locals.into_iter()
+        .map(|local| format!("{}: {} = {}", local.name, local.type_name, local.value.as_deref().unwrap_or("unavailable")))
+        .collect::<Vec<_>>();
+
+    assert_eq!(values(runtime.locals(0).expect("In square")), ["x: i32 = -3", "y: i32 = 9"]);
+    assert_eq!(values(runtime.locals(1).expect("In main"))[0], "a: i32 = -3");  // ok is not set yet
+    assert_eq!(runtime.locals(2), None);  // The driver
+
+    // On to square's closing brace, back partway through the line that called it, and past that.
+    runtime.next_line(&mut out);
+    runtime.next_line(&mut out);
+    assert_eq!(runtime.call_stack().len(), 2);
+    runtime.next_line(&mut out);
+    assert_eq!(values(runtime.locals(0).expect("In main")), ["a: i32 = -3", "ok: bool = true"]);
+}
+
+#[test]
+fn locals_above_the_frame_are_unavailable() {
+    let program = crate::compile_string("
+        fn f(x: i32) -> i32 {
+            val a: i32 = x + 1;
+            a
+        }
+
+        fn main() -> i32 {
+            f(2) + f(5)
+        }
+    ".to_string());
+
+    let mut runtime = Runtime::new(program);
+    let mut out = vec![];
+
+    let f = runtime.debug_info.functions.iter().find(|symbol| symbol.name == "f").expect("Has f").start;
+
+    // The second call starts where the first one left its local.
+    assert!(runtime.set_breakpoint(f));
+    assert_eq!(runtime.resume(&mut out), super::Pause::Breakpoint);
+    assert_eq!(runtime.resume(&mut out), super::Pause::Breakpoint);
+
+    let locals = runtime.locals(0).expect("In f");
+    assert_eq!(locals.iter().map(|local| (local.name.as_str(), local.value.as_deref())).collect::<Vec<_>>(), [("x", Some("5")), ("a", None)]);
+}
+
+#[test]
+fn empty_and_wide_locals_are_formatted() {
+    let locals = vec![
+        LocalSymbol { name: "nothing".to_string(), type_name: "unit".to_string(), offset: 16, size: 0, format: ValueFormat::Signed },
+        LocalSymbol { name: "wide".to_string(), type_name: "?i64".to_string(), offset: 16, size: 16, format: ValueFormat::Signed },
+    ];
+    let program = Program {
+        functions: vec![FunctionSymbol { name: "f".to_string(), start: 3, end: 7, locals, operand_start: 32 }],
+        ..Program::from(vec![
+            I::AdvanceStackPtr(8),
+            I::Call(3),
+            I::Exit,
+            I::PushConstant(Constant::EightByte(7)),
+            I::PushConstant(Constant::EightByte(0)),
+            I::RetractStackPtr(16),
+            I::Return,
+        ])
+    };
+
+    let mut runtime = Runtime::new(program);
+    runtime.set_breakpoint(5);
+    assert_eq!(runtime.resume(&mut vec![]), super::Pause::Breakpoint);
+
+    let values = runtime.locals(0).expect("In f").into_iter().map(|local| local.value).collect::<Vec<_>>();
+    assert_eq!(values, [Some("[]".to_string()), Some("7".to_string())]);
+}
+
+#[test]
+fn profiles_count_calls_and_instructions() {
+    let mut runtime = Runtime::new(Program::assemble(FACTORIAL).expect("Assembles"));
+
+    let mut out = vec![];
+    let profile = runtime.run_profiled(&mut out).expect("Runs to the end");
+
+    assert_eq!(String::from_utf8(out).expect("Good Conversion"), "6\n");
+
+    // Two calls recurse, each running 14 instructions, and the last runs 7. The driver runs 6.
+    assert_eq!(profile.total, 41);
+
+    let [factorial] = &profile.functions[..]
+        else { panic!("Only factorial was called") };
+    assert_eq!((factorial.name.as_str(), factorial.calls, factorial.inclusive, factorial.exclusive), ("factorial", 3, 35, 35));
+
+    assert_eq!(profile.instruction_kinds[..2], [("push".to_string(), 7), ("read_base".to_string(), 7)]);
+    assert_eq!(profile.folded_stacks(), "factorial 14\nfactorial;factorial 14\nfactorial;factorial;factorial 7\n");
+}
+
+#[test]
+fn tracing_logs_each_instruction_before_it_runs() {
+    let mut runtime = Runtime::new(Program::assemble("
+            push 4 7
+            push 2 1
+            push 2 2
+            add.u 2
+            push 2 0
+            push 8 4294967296
+            retract 10
+            print 2
+            exit
+    ").expect("Assembles"));
+
+    let mut out = vec![];
+    let mut trace = vec![];
+    runtime.run_traced(&mut out, &mut trace).expect("Runs to the end");
+
+    assert_eq!(String::from_utf8(out).expect("Good Conversion"), "3\n");
+    assert_eq!(String::from_utf8(trace).expect("Good Conversion").lines().collect::<Vec<_>>(), [
+        "     0  push 4 7                  sp=0 bp=0",
+        "     1  push 2 1                  sp=4 bp=0  07 00 00 00",
+        "     2  push 2 2                  sp=6 bp=0  07 00 00 00 01 00",
+        "     3  add.u 2                   sp=8 bp=0  07 00 00 00 01 00 02 00",
+        "     4  push 2 0                  sp=6 bp=0  07 00 00 00 03 00",
+        "     5  push 8 4294967296         sp=8 bp=0  07 00 00 00 03 00 00 00",
+        "     6  retract 10                sp=16 bp=0  07 00 00 00 03 00 00 00 00 00 00 00 01 00 00 00",
+        "     7  print 2                   sp=6 bp=0  07 00 00 00 03 00",
+        "     8  exit                      sp=6 bp=0  07 00 00 00 03 00",
+    ]);
+}
+
 #[test]
 fn conversion() {
 
@@ -191,3 +630,312 @@ fn conversion() {
 
     assert_eq!(lines, ["100"]);
 }
+
+#[test]
+fn heap_alloc_read_write() {
+    let lines = run_collecting_output(vec![
+        I::PushConstant(Constant::EightByte(16)),  // Size
+        I::PushConstant(Constant::EightByte(8)),  // Alignment
+        I::HeapAlloc,
+        I::Duplicate(IntSize::EightByte),
+        I::Duplicate(IntSize::EightByte),
+        I::PushConstant(Constant::FourByte(1234)),
+        I::HeapWrite(IntSize::FourByte),
+        I::HeapRead(IntSize::FourByte),
+        I::DebugPrintSigned(IntSize::FourByte),
+        I::RetractStackPtr(4),
+        I::HeapFree,
+        I::Exit,
+    ]);
+
+    assert_eq!(lines, ["1234"]);
+}
+
+#[test]
+fn heap_reuses_freed_blocks() {
+    // Two allocations of the same size, with a free in between, land at the same address.
+    let lines = run_collecting_output(vec![
+        I::PushConstant(Constant::EightByte(24)),
+        I::PushConstant(Constant::EightByte(8)),
+        I::HeapAlloc,
+        I::DebugPrintSigned(IntSize::EightByte),
+        I::HeapFree,
+        I::PushConstant(Constant::EightByte(24)),
+        I::PushConstant(Constant::EightByte(8)),
+        I::HeapAlloc,
+        I::DebugPrintSigned(IntSize::EightByte),
+        I::HeapFree,
+        I::Exit,
+    ]);
+
+    assert_eq!(lines, ["8", "8"]);
+}
+
+#[test]
+fn heap_leak_report() {
+    let lines = run_collecting_output(vec![
+        I::PushConstant(Constant::EightByte(3)),
+        I::PushConstant(Constant::EightByte(1)),
+        I::HeapAlloc,
+        I::Exit,
+    ]);
+
+    assert_eq!(lines, ["Leak report: 1 heap block(s) never freed, 3 bytes total", "    address 8: 3 bytes"]);
+}
+
+#[test]
+#[should_panic(expected = "Critical Runtime Error: Invalid Heap Access")]
+fn heap_out_of_bounds() {
+    run_collecting_output(vec![
+        I::PushConstant(Constant::EightByte(4)),
+        I::PushConstant(Constant::EightByte(4)),
+        I::HeapAlloc,
+        I::PushConstant(Constant::EightByte(4)),
+        I::IntegerBinaryOperation(IntegerBinaryOperation::UnsignedAddition, IntSize::EightByte),
+        I::HeapRead(IntSize::FourByte),
+        I::Exit,
+    ]);
+}
+
+#[test]
+fn gc_frees_only_unreachable_blocks() {
+    let instructions = vec![
+        I::AdvanceStackPtr(8),  // Like the driver, so that the frame is above the stack bottom.
+        I::Call(3),
+        I::Exit,
+        I::PushConstant(Constant::EightByte(8)),  // Block A, kept in the frame at offset 16.
+        I::PushConstant(Constant::EightByte(8)),
+        I::HeapAlloc,
+        I::PushConstant(Constant::EightByte(16)),  // Block B, dropped right away.
+        I::PushConstant(Constant::EightByte(8)),
+        I::HeapAlloc,
+        I::RetractStackPtr(8),
+        I::PushConstant(Constant::EightByte(16)),  // Block C takes the place of B.
+        I::PushConstant(Constant::EightByte(8)),
+        I::HeapAlloc,
+        I::DebugPrintSigned(IntSize::EightByte),
+        I::RetractStackPtr(8),
+        I::HeapRead(IntSize::EightByte),  // A is still valid.
+        I::DebugPrintSigned(IntSize::EightByte),
+        I::Return,
+    ];
+
+    let mut program = Program::from(instructions);
+    program.stack_maps.insert(8, vec![16]);
+    program.stack_maps.insert(12, vec![16]);
+
+    let mut runtime = Runtime::new(program);
+    runtime.set_collection_mode(CollectionMode::Stress);
+
+    let mut buf = std::io::BufWriter::new(vec![]);
+    runtime.run_debug(&mut buf);
+
+    let output = String::from_utf8(buf.into_inner().expect("No IO Error")).expect("Good Conversion");
+
+    assert_eq!(output.lines().collect::<Vec<_>>(), ["16", "0"]);
+}
+
+#[test]
+fn globals_start_zeroed_and_keep_stored_values() {
+    let instructions = vec![
+        I::LoadGlobal(8, IntSize::FourByte),
+        I::DebugPrintSigned(IntSize::FourByte),
+        I::RetractStackPtr(4),
+        I::PushConstant(Constant::TwoByte(513)),
+        I::StoreGlobal(2, IntSize::TwoByte),
+        I::LoadGlobal(2, IntSize::TwoByte),
+        I::DebugPrintSigned(IntSize::TwoByte),
+        I::Exit,
+    ];
+
+    let program = Program { data_size: 12, ..Program::from(instructions) };
+
+    let mut runtime = Runtime::new(program);
+
+    let mut buf = std::io::BufWriter::new(vec![]);
+    runtime.run_debug(&mut buf);
+
+    let output = String::from_utf8(buf.into_inner().expect("No IO Error")).expect("Good Conversion");
+
+    assert_eq!(output.lines().collect::<Vec<_>>(), ["0", "513"]);
+}
+
+#[test]
+#[should_panic(expected = "Critical Runtime Error: Invalid Program. Instruction 0 (LoadGlobal(8, FourByte)) uses 4 bytes at 8")]
+fn global_out_of_bounds() {
+    let program = Program { data_size: 4, ..Program::from(vec![I::LoadGlobal(8, IntSize::FourByte), I::Exit]) };
+
+    Runtime::new(program).run();
+}
+
+#[test]
+#[should_panic(expected = "Critical Runtime Error: Stack Overflow")]
+fn advancing_past_the_stack_overflows() {
+    Runtime::new(Program::assemble("advance 268435456\nwrite_base 134217728 8\nretract 268435448\nexit").expect("Assembles")).run();
+}
+
+#[test]
+#[should_panic(expected = "Critical Runtime Error: Assertion Failed at test.nom:3")]
+fn trap_reports_its_message() {
+    let program = Program { 
+        messages: vec!["Unused".to_string(), "Assertion Failed at test.nom:3".to_string()], 
+        ..Program::from(vec![I::Trap(1), I::Exit]) 
+    };
+
+    Runtime::new(program).run();
+}
+
+#[test]
+#[should_panic(expected = "Critical Runtime Error: Invalid Program. Instruction 0 (RelativeJump(5)) jumps by 5, outside of the program")]
+fn invalid_programs_do_not_run() {
+    Runtime::new(vec![I::RelativeJump(5), I::Exit]).run();
+}
+
+#[test]
+#[should_panic(expected = "Critical Runtime Error: Out of Fuel")]
+fn runs_out_of_fuel() {
+    let mut runtime = Runtime::new(vec![I::RelativeJump(0)]);
+    runtime.set_fuel(Some(1000));
+
+    runtime.run();
+}
+
+#[test]
+fn fuel_is_enough_to_finish() {
+    let mut runtime = Runtime::new(vec![I::PushConstant(Constant::OneByte(1)), I::Exit]);
+    runtime.set_fuel(Some(2));
+
+    runtime.run();
+
+    assert_eq!(runtime.stack_contents(), [1]);
+}
+
+#[test]
+fn the_stack_size_is_configurable() {
+    // factorial(3) needs 128 bytes of stack at its deepest.
+    let message = configured_failure_message(FACTORIAL, RuntimeConfig::new().stack_size(64));
+    assert_eq!(message, "Critical Runtime Error: Stack Overflow\n    at factorial");
+
+    let mut runtime = Runtime::with_config(Program::assemble(FACTORIAL).expect("Assembles"), RuntimeConfig::new().stack_size(128));
+    let mut out = vec![];
+    runtime.run_debug(&mut out);
+
+    assert_eq!(String::from_utf8(out).expect("Good Conversion"), "6\n");
+}
+
+#[test]
+fn configured_sizes_are_whole_words_within_limits() {
+    let config = RuntimeConfig::new();
+
+    assert_eq!(config.stack_size(0), config.stack_size(RuntimeConfig::MIN_SIZE));
+    assert_eq!(config.stack_size(9), config.stack_size(16));
+    assert_eq!(config.stack_size(usize::MAX), config.stack_size(RuntimeConfig::MAX_STACK_SIZE));
+    assert_eq!(config.heap_size(usize::MAX), config.heap_size(RuntimeConfig::MAX_HEAP_SIZE));
+}
+
+#[test]
+fn call_depth_can_be_limited() {
+    let message = configured_failure_message(FACTORIAL, RuntimeConfig::new().max_call_depth(Some(2)));
+    assert_eq!(message, "Critical Runtime Error: Maximum Call Depth Exceeded\n    at factorial\n    ... repeated 1 more times");
+
+    let mut runtime = Runtime::with_config(Program::assemble(FACTORIAL).expect("Assembles"), RuntimeConfig::new().max_call_depth(Some(3)));
+    runtime.run();
+}
+
+#[test]
+fn the_heap_size_is_configurable() {
+    let program = "
+            push 8 16
+            push 8 8
+            heap_alloc
+            exit
+    ";
+
+    let message = configured_failure_message(program, RuntimeConfig::new().heap_size(16));
+    assert_eq!(message, "Critical Runtime Error: Out of Heap Memory");
+
+    let mut runtime = Runtime::with_config(Program::assemble(program).expect("Assembles"), RuntimeConfig::new().heap_size(24));
+    runtime.run();
+}
+
+#[test]
+fn more_fuel_resumes_where_the_program_stopped() {
+    let mut runtime = Runtime::new(Program::assemble(FACTORIAL).expect("Assembles"));
+    let mut out = vec![];
+
+    // 41 instructions are needed in all.
+    runtime.set_fuel(Some(30));
+    assert_eq!(runtime.try_run_debug(&mut out), Err(super::RuntimeError::OutOfFuel));
+    assert_eq!(runtime.try_run_debug(&mut out), Err(super::RuntimeError::OutOfFuel));
+    assert!(out.is_empty());
+
+    runtime.set_fuel(Some(11));
+    assert_eq!(runtime.try_run_debug(&mut out), Ok(()));
+    assert_eq!(String::from_utf8(out).expect("Good Conversion"), "6\n");
+}
+
+#[test]
+fn setting_the_interrupt_flag_stops_the_program() {
+    use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+
+    let mut runtime = Runtime::new(Program::assemble("
+        .L_forever:
+            jump .L_forever
+    ").expect("Assembles"));
+
+    let flag = Arc::new(AtomicBool::new(false));
+    runtime.set_interrupt_flag(flag.clone());
+
+    let interrupter = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        flag.store(true, Ordering::Relaxed);
+    });
+
+    assert_eq!(runtime.try_run(), Err(super::RuntimeError::Interrupted));
+    interrupter.join().expect("Sets the flag");
+}
+
+#[test]
+#[should_panic(expected = "Critical Runtime Error: Integer Overflow")]
+fn signed_overflow_is_checked() {
+    let mut runtime = Runtime::new(vec![
+        I::PushConstant(Constant::OneByte(reinterpret::<i8, u8>(-100))),
+        I::PushConstant(Constant::OneByte(reinterpret::<i8, u8>(-29))),
+        I::IntegerBinaryOperation(IntegerBinaryOperation::SignedAddition, IntSize::OneByte),
+        I::Exit,
+    ]);
+    runtime.set_overflow_checks(true);
+
+    runtime.run();
+}
+
+#[test]
+#[should_panic(expected = "Critical Runtime Error: Integer Overflow")]
+fn unsigned_underflow_is_checked() {
+    let mut runtime = Runtime::new(vec![
+        I::PushConstant(Constant::FourByte(3)),
+        I::PushConstant(Constant::FourByte(4)),
+        I::IntegerBinaryOperation(IntegerBinaryOperation::UnsignedSubtraction, IntSize::FourByte),
+        I::Exit,
+    ]);
+    runtime.set_overflow_checks(true);
+
+    runtime.run();
+}
+
+#[test]
+fn exit_with_error_reports_the_code() {
+    let mut runtime = Runtime::new(vec![
+        I::PushConstant(Constant::TwoByte(reinterpret::<i16, u16>(-12))),
+        I::ExitWithError(IntSize::TwoByte, true),
+        I::DebugPrintSigned(IntSize::TwoByte),  // Never reached
+    ]);
+
+    let mut buf = std::io::BufWriter::new(vec![]);
+    runtime.run_debug(&mut buf);
+
+    let output = String::from_utf8(buf.into_inner().expect("No IO Error")).expect("Good Conversion");
+
+    assert_eq!(output.lines().collect::<Vec<_>>(), ["Runtime Error: main returned error -12"]);
+    assert_eq!(runtime.exit_error(), Some(-12));
+}
@@ -0,0 +1,44 @@
+/* Logging every instruction as it runs, for finding out where generated code goes wrong.
+ * Each line shows the state just before the instruction runs:
+ *
+ *      3  print 4                   sp=4 bp=0  fd ff ff ff
+ *
+ * That is the index, the instruction, the stack and base pointers as offsets from the
+ * bottom of the stack, and the top bytes of the stack in address order, so the value
+ * pushed last comes last. Tracing has its own loop, so running normally pays nothing for it. */
+
+use super::{Runtime, RuntimeError};
+use crate::program::format_alone;
+
+
+const TRACE_BYTES: usize = 16;  // How much of the top of the stack each line shows.
+
+impl Runtime {
+    // Like try_run_debug, but with a line written to the trace for every instruction.
+    pub fn run_traced(&mut self, debug_out: &mut dyn std::io::Write, trace_out: &mut dyn std::io::Write) -> Result<(), RuntimeError> {
+        let mut debug_out = Some(debug_out);
+
+        self.running = true;
+
+        while self.running {
+            self.check_limits()?;
+            self.trace_instruction(trace_out);
+            self.step_impl(&mut debug_out);
+        }
+
+        Ok(())
+    }
+
+    fn trace_instruction(&self, trace_out: &mut dyn std::io::Write) {
+        let stack_height = self.stack_pointer as usize - self.stack_bottom as usize;
+        let base_height = self.base_pointer as usize - self.stack_bottom as usize;
+
+        let top = &self.stack_contents()[stack_height.saturating_sub(TRACE_BYTES)..];
+        let bytes = top.iter().map(|byte| format!("{byte:02x}")).collect::<Vec<_>>();
+
+        let instruction = format_alone(self.instructions[self.instruction_index]);
+
+        let line = format!("{:>6}  {instruction:<24}  sp={stack_height} bp={base_height}  {}", self.instruction_index, bytes.join(" "));
+        writeln!(trace_out, "{}", line.trim_end()).expect("traces");
+    }
+}
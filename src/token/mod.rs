@@ -49,6 +49,12 @@ impl Span {
     }
 }
 
+impl std::fmt::Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.start_line, self.start_col)
+    }
+}
+
 /* Token Definitions */
 
 #[derive(Debug, Clone)]
@@ -67,6 +73,7 @@ pub enum TokenBody {
     Keyword (Keyword),
     StringLiteral (String),  // Content, with escapes processed, and no double quotes.
     CharLiteral (char),  // Content, with escapes processed, and no single quotes.
+    Label (String),  // Names a loop, e.g. `'outer`. The name has no quote.
     NumericLiteral (String),  // TODO: Replace with enum for all numeric literal values.
     Operator (Operator),
     Punctuation (Punctuation),
@@ -86,6 +93,25 @@ pub enum Keyword {
     Or,
     While,
     Return,
+    Trait,
+    Impl,
+    For,
+    Import,
+    Pub,
+    Comptime,
+    In,
+    Break,
+    Continue,
+    Loop,
+    None,
+    Error,
+    Try,
+    Orelse,
+    Catch,
+    Defer,
+    Type,
+    Distinct,
+    As,
 }
 
 impl FromStr for Keyword {
@@ -107,6 +133,25 @@ impl FromStr for Keyword {
             "or" => K::Or,
             "while" => K::While,
             "return" => K::Return,
+            "trait" => K::Trait,
+            "impl" => K::Impl,
+            "for" => K::For,
+            "import" => K::Import,
+            "pub" => K::Pub,
+            "comptime" => K::Comptime,
+            "in" => K::In,
+            "break" => K::Break,
+            "continue" => K::Continue,
+            "loop" => K::Loop,
+            "none" => K::None,
+            "error" => K::Error,
+            "try" => K::Try,
+            "orelse" => K::Orelse,
+            "catch" => K::Catch,
+            "defer" => K::Defer,
+            "type" => K::Type,
+            "distinct" => K::Distinct,
+            "as" => K::As,
             _ => Err(TokenError("Not a keyword".to_string()))?
         })
     }
@@ -132,14 +177,22 @@ pub enum Operator {  // Operators currently accepted greedily
     GreaterEquals,
     Less,
     Greater,
+    Dot,
+    DotDot,
+    DotDotEquals,
+    Pipe,  // Surrounds the parameters of a closure, e.g. `|x: i32| x + 1`
+    Bang,  // Separates the error type of an error union from its payload, e.g. `u8!i32`
+    Question,  // Starts an optional type, e.g. `?i32`
 }
 
-// All punctuation is a single character that cannot be part of another token.
+// All punctuation is a single character that cannot be part of another token, except
+// for `::`, which is two colons with nothing between them.
 #[derive(Debug, Clone)]
 pub enum Punctuation {
     Semicolon,
     Comma,
     Colon,
+    DoubleColon,  // Separates a module from a name in it, e.g. `math::gcd`
     LeftCurlyBrace,
     RightCurlyBrace,
     LeftParenthesis,
@@ -211,7 +264,7 @@ pub fn tokenize(input: &str, file_path: &str) -> Result<Vec<Token>, TokenError>
             tokens.push(Token { body: token, span});
         }
         else if *ch == '\'' {
-            let (token, span) = take_char_literal(&mut iter)?;
+            let (token, span) = take_char_literal_or_label(&mut iter)?;
             tokens.push(Token { body: token, span });
         }
         else if is_operator_char(*ch) {
@@ -226,7 +279,14 @@ pub fn tokenize(input: &str, file_path: &str) -> Result<Vec<Token>, TokenError>
         }
         else if let Ok(punct) = Punctuation::try_from(*ch) {
             let (_, span) = iter.next().expect("Known to exist.");
-            tokens.push(Token { body: TokenBody::Punctuation (punct), span });
+
+            if matches!(punct, Punctuation::Colon) && matches!(iter.peek(), Some((':', _))) {
+                let (_, second_span) = iter.next().expect("Known to exist.");
+                tokens.push(Token { body: TokenBody::Punctuation (Punctuation::DoubleColon), span: Span::combine(&span, &second_span) });
+            }
+            else {
+                tokens.push(Token { body: TokenBody::Punctuation (punct), span });
+            }
         }
         else if is_identifier_char(*ch) {
             let (token, span) = take_identifier_or_keyword(&mut iter)?;
@@ -275,7 +335,8 @@ fn take_string_literal(iter: &mut impl std::iter::Iterator<Item = (char, Span)>)
     Err("Expected character '\"'. String literal does not terminate.".into())
 }
 
-fn take_char_literal(iter: &mut impl std::iter::Iterator<Item = (char, Span)>) 
+// A label, like `'outer`, also starts with a single quote, but is not closed by another.
+fn take_char_literal_or_label(iter: &mut std::iter::Peekable<impl std::iter::Iterator<Item = (char, Span)>>) 
         -> Result<(TokenBody, Span), TokenError> {
     
     let mut spans = vec![];
@@ -288,6 +349,20 @@ fn take_char_literal(iter: &mut impl std::iter::Iterator<Item = (char, Span)>)
 
     let mut string = String::new();
 
+    while let Some((ch, _)) = iter.peek() {
+        if !is_identifier_char(*ch) {
+            break
+        }
+
+        let (ch, ch_span) = iter.next().expect("Known to exist");
+        string.push(ch);
+        spans.push(ch_span);
+    }
+
+    if !string.is_empty() && !matches!(iter.peek(), Some(('\'', _))) {
+        return Ok((TokenBody::Label(string), Span::combine_all(&spans)));
+    }
+
     for (ch, ch_span) in iter {
         // TODO: Allow [\'] to escape the single quote
         
@@ -457,6 +532,24 @@ fn take_operators(iter: &mut std::iter::Peekable<impl std::iter::Iterator<Item =
         else if slice.starts_with('=') {
             (Operator::Equals, 1)
         }
+        else if slice.starts_with("..=") {
+            (Operator::DotDotEquals, 3)
+        }
+        else if slice.starts_with("..") {
+            (Operator::DotDot, 2)
+        }
+        else if slice.starts_with('.') {
+            (Operator::Dot, 1)
+        }
+        else if slice.starts_with('|') {
+            (Operator::Pipe, 1)
+        }
+        else if slice.starts_with('!') {
+            (Operator::Bang, 1)
+        }
+        else if slice.starts_with('?') {
+            (Operator::Question, 1)
+        }
         else {
             return Err(format!("Could not split operators: {slice}").into());
         };
@@ -472,7 +565,7 @@ fn take_operators(iter: &mut std::iter::Peekable<impl std::iter::Iterator<Item =
 
 
 fn is_operator_char(ch: char) -> bool {
-    let operators = ['+', '-', '*', '/', '=', '>', '<', '!', '%'];
+    let operators = ['+', '-', '*', '/', '=', '>', '<', '!', '%', '.', '|', '?'];
 
     operators.contains(&ch)
 }
@@ -520,6 +613,8 @@ impl parsley::Token for Token {
         Ok(match token_type {
             "Identifier"     => matches!(token, T { body: TB::Identifier(_), .. }),
             "NumericLiteral" => matches!(token, T { body: TB::NumericLiteral(_), .. }),
+            "StringLiteral"  => matches!(token, T { body: TB::StringLiteral(_), .. }),
+            "Label"          => matches!(token, T { body: TB::Label(_), .. }),
 
             "LeftCurlyBrace"     => matches!(token, T { body: TB::Punctuation(P::LeftCurlyBrace), .. }),
             "RightCurlyBrace"    => matches!(token, T { body: TB::Punctuation(P::RightCurlyBrace), .. }),
@@ -530,6 +625,7 @@ impl parsley::Token for Token {
             "Semicolon"          => matches!(token, T { body: TB::Punctuation(P::Semicolon), .. }),
             "Comma"              => matches!(token, T { body: TB::Punctuation(P::Comma), .. }),
             "Colon"              => matches!(token, T { body: TB::Punctuation(P::Colon), .. }),
+            "DoubleColon"        => matches!(token, T { body: TB::Punctuation(P::DoubleColon), .. }),
 
             "Plus"           => matches!(token, T { body: TB::Operator(O::Plus), .. }),
             "Minus"          => matches!(token, T { body: TB::Operator(O::Minus), .. }),
@@ -549,6 +645,12 @@ impl parsley::Token for Token {
             "TimesEquals"     => matches!(token, T { body: TB::Operator(O::TimesEquals), .. }),
             "DivideEquals"     => matches!(token, T { body: TB::Operator(O::DivideEquals), .. }),
             "ModulusEquals"     => matches!(token, T { body: TB::Operator(O::ModulusEquals), .. }),
+            "Dot"            => matches!(token, T { body: TB::Operator(O::Dot), .. }),
+            "DotDot"         => matches!(token, T { body: TB::Operator(O::DotDot), .. }),
+            "DotDotEquals"   => matches!(token, T { body: TB::Operator(O::DotDotEquals), .. }),
+            "Pipe"           => matches!(token, T { body: TB::Operator(O::Pipe), .. }),
+            "Bang"           => matches!(token, T { body: TB::Operator(O::Bang), .. }),
+            "Question"       => matches!(token, T { body: TB::Operator(O::Question), .. }),
 
             "Var" => matches!(token, T { body: TB::Keyword(K::Var), .. }),
             "Val" => matches!(token, T { body: TB::Keyword(K::Val), .. }),
@@ -562,6 +664,25 @@ impl parsley::Token for Token {
             "Or" => matches!(token, T { body: TB::Keyword(K::Or), .. }),
             "While" => matches!(token, T { body: TB::Keyword(K::While), .. }),
             "Return" => matches!(token, T { body: TB::Keyword(K::Return), .. }),
+            "Trait" => matches!(token, T { body: TB::Keyword(K::Trait), .. }),
+            "Impl" => matches!(token, T { body: TB::Keyword(K::Impl), .. }),
+            "For" => matches!(token, T { body: TB::Keyword(K::For), .. }),
+            "Import" => matches!(token, T { body: TB::Keyword(K::Import), .. }),
+            "Pub" => matches!(token, T { body: TB::Keyword(K::Pub), .. }),
+            "Comptime" => matches!(token, T { body: TB::Keyword(K::Comptime), .. }),
+            "In" => matches!(token, T { body: TB::Keyword(K::In), .. }),
+            "Break" => matches!(token, T { body: TB::Keyword(K::Break), .. }),
+            "Continue" => matches!(token, T { body: TB::Keyword(K::Continue), .. }),
+            "Loop" => matches!(token, T { body: TB::Keyword(K::Loop), .. }),
+            "None" => matches!(token, T { body: TB::Keyword(K::None), .. }),
+            "Error" => matches!(token, T { body: TB::Keyword(K::Error), .. }),
+            "Try" => matches!(token, T { body: TB::Keyword(K::Try), .. }),
+            "Orelse" => matches!(token, T { body: TB::Keyword(K::Orelse), .. }),
+            "Catch" => matches!(token, T { body: TB::Keyword(K::Catch), .. }),
+            "Defer" => matches!(token, T { body: TB::Keyword(K::Defer), .. }),
+            "Type" => matches!(token, T { body: TB::Keyword(K::Type), .. }),
+            "Distinct" => matches!(token, T { body: TB::Keyword(K::Distinct), .. }),
+            "As" => matches!(token, T { body: TB::Keyword(K::As), .. }),
             
             _ => return Err(format!("Bad token type: \"{token_type}\"").into())
         })
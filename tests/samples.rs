@@ -5,8 +5,8 @@ use std::io::Read;
 use std::panic;
 
 use nom::compile_file;
-use nom::runtime::Runtime;
-use nom::Instruction;
+use nom::runtime::{Runtime, CollectionMode};
+use nom::Program;
 
 // Retrieves expected output or panic messages etc
 // Returns a string with all lines that begin with //!, ignoring the prefix "//! " (note the space)
@@ -37,13 +37,36 @@ fn read_file(resource: &str) -> String {
     input
 }
 
-// Convenience - dump an array of instructions
-pub fn dump_instructions(instrs: &[Instruction]) -> String {
-    instrs.iter()
-        .enumerate()
-        .map(|(i, instr)| format!("{i:<6}  {instr:?}"))
-        .collect::<Vec<_>>()
-        .join("\n")
+// Every sample is written out as a .nomc file and read back, and it is the copy that is run.
+// It is also disassembled and assembled again.
+fn compile_round_tripped(resource: &str) -> Program {
+    let program = compile_file(resource.to_string());
+
+    let mut bytes = vec![];
+    program.write_to(&mut bytes).expect("Writes to memory");
+    let copy = Program::read_from(&mut bytes.as_slice()).expect("Reads what was written");
+
+    assert_eq!(program, copy);
+
+    // The text form has no stack maps, debug info, or data segment, but is otherwise the same program.
+    let assembled = Program::assemble(&program.disassemble()).expect("Assembles what was disassembled");
+    let ranges = |program: &Program| program.functions.iter()
+        .map(|symbol| (symbol.name.clone(), symbol.start, symbol.end))
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        (&program.instructions, ranges(&program), &program.messages), 
+        (&assembled.instructions, ranges(&assembled), &assembled.messages)
+    );
+
+    // Everything generated from source can be traced back to it.
+    for function in &program.functions {
+        for index in function.start..function.end {
+            assert!(program.describe_location(index).is_some(), "Instruction {index} of {} has no location", function.name);
+        }
+    }
+
+    copy
 }
 
 
@@ -52,10 +75,32 @@ fn run_successful(resource: &str) {
     let input = read_file(resource);
     let expected_output = get_marked_comments(&input);
     
-    let code: Vec<Instruction> = compile_file(resource.to_string());
-    println!("{}", dump_instructions(&code));
+    let program = compile_round_tripped(resource);
+    print!("{}", program.disassemble());
 
-    let mut runtime = Runtime::new(code);
+    let mut runtime = Runtime::new(program);
+
+    let mut buf = std::io::BufWriter::new(vec![]);
+    runtime.run_debug(&mut buf);
+
+    let output = String::from_utf8(buf.into_inner().expect("No IO Error")).expect("Good Conversion");
+
+    assert_eq!(expected_output, output);
+}
+
+
+// These programs never free anything, and are run with a collection before every
+// allocation, so any heap address the collector fails to find will be reused.
+#[test_resources("samples/gc/**/*.nom")]
+fn run_garbage_collected(resource: &str) {
+    let input = read_file(resource);
+    let expected_output = get_marked_comments(&input);
+    
+    let program = compile_round_tripped(resource);
+    print!("{}", program.disassemble());
+
+    let mut runtime = Runtime::new(program);
+    runtime.set_collection_mode(CollectionMode::Stress);
 
     let mut buf = std::io::BufWriter::new(vec![]);
     runtime.run_debug(&mut buf);
@@ -72,16 +117,69 @@ fn run_panics(resource: &str) {
     let input = read_file(resource);
     let expected_output = get_marked_comments(&input);
     
-    let code: Vec<Instruction> = compile_file(resource.to_string());
-    println!("{}", dump_instructions(&code));
+    let program = compile_round_tripped(resource);
+    print!("{}", program.disassemble());
 
-    let mut runtime = Runtime::new(code);
+    let mut runtime = Runtime::new(program);
 
     match panic::catch_unwind(move || runtime.run()) {
         Ok(_) => panic!("Success is unexpected"),
         Err(boxed_msg) => {
-            let actual_msg = *(boxed_msg.downcast::<&str>().unwrap());
-            assert!(actual_msg == expected_output.trim());
+            // Messages with formatted parts, like the location of a failed assertion, are Strings.
+            let actual_msg = boxed_msg.downcast_ref::<&str>().map(ToString::to_string)
+                .or_else(|| boxed_msg.downcast_ref::<String>().cloned())
+                .unwrap();
+            assert_eq!(actual_msg, expected_output.trim());
+        },
+    }
+}
+
+
+// Programs the compiler rejects. The marked comment is a part of the error message, which
+// is wrapped in more detail about where compilation stopped.
+#[test_resources("samples/compile-error/**/*.nom")]
+fn run_compile_errors(resource: &str) {
+    let input = read_file(resource);
+    let expected_output = get_marked_comments(&input);
+
+    let path = resource.to_string();
+
+    match panic::catch_unwind(move || compile_file(path)) {
+        Ok(_) => panic!("Compiling is expected to fail"),
+        Err(boxed_msg) => {
+            let actual_msg = boxed_msg.downcast_ref::<&str>().map(ToString::to_string)
+                .or_else(|| boxed_msg.downcast_ref::<String>().cloned())
+                .unwrap();
+            assert!(actual_msg.contains(expected_output.trim()), "Expected {:?} in {actual_msg:?}", expected_output.trim());
         },
     }
 }
+
+
+// The same source always compiles to the same bytes, however the compiler's tables are
+// ordered in each run.
+#[test]
+fn builds_are_reproducible() {
+    let build = |source: &str, i: usize| {
+        let output = std::env::temp_dir().join(format!("nom-reproducible-{}-{i}.nomc", std::process::id()));
+
+        let status = std::process::Command::new(env!("CARGO_BIN_EXE_nom"))
+            .args(["build", source, "-o"])
+            .arg(&output)
+            .status()
+            .expect("Runs the compiler");
+        assert!(status.success());
+
+        let bytes = std::fs::read(&output).expect("Reads the build");
+        std::fs::remove_file(&output).expect("Removes the build");
+        bytes
+    };
+
+    for source in ["samples/successful/defer.nom", "samples/successful/closure_free.nom", "samples/gc/globals.nom"] {
+        let first = build(source, 0);
+
+        for i in 1..4 {
+            assert!(build(source, i) == first, "{source} compiled differently");
+        }
+    }
+}